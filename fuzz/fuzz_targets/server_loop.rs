@@ -0,0 +1,138 @@
+//! Feeds arbitrary bytes through a connection the way the server does, reading, checking limits
+//! and executing each message against a fresh database, then checks the KeyDir still points at
+//! the entries it names.
+//!
+//! The data file lives on tmpfs when there is one, so no iteration touches a real disk.
+//!
+//!     cargo +nightly fuzz run server_loop
+
+#![no_main]
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use hash_db::{
+    serverv2::{
+        auth::User,
+        connection::Connection,
+        message::{Limits, Message},
+    },
+    storagev2::{
+        db::{Db, Options},
+        disk::Durability,
+        log::EntryType,
+    },
+};
+use libfuzzer_sys::fuzz_target;
+use tokio::time::timeout;
+
+/// Messages read from one input before giving up on it.
+const MAX_STEPS: usize = 256;
+
+/// How long a single read or exec can take before it counts as a hang.
+const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+const DB_FILE: &str = "fuzz.db";
+
+fuzz_target!(
+    init: {
+        // SNAPSHOT and EXPORT write into the working directory
+        let dir = scratch_dir();
+        fs::create_dir_all(&dir).expect("should create scratch directory");
+        std::env::set_current_dir(&dir).expect("should enter scratch directory");
+    },
+    |data: &[u8]| {
+        clear().expect("should clear scratch directory");
+
+        // A runtime per input, dropping it stops the database's background tasks
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .expect("should build runtime")
+            .block_on(run(data));
+    }
+);
+
+async fn run(data: &[u8]) {
+    let options = Options {
+        durability: Durability::Never,
+        ..Default::default()
+    };
+    let db = Db::open(DB_FILE, options).await.expect("should open");
+    let user = User::default();
+
+    let mut conn = Connection::new(data, Vec::new()).with_limits(Limits::default());
+    for _ in 0..MAX_STEPS {
+        let message = match timeout(STEP_TIMEOUT, conn.read()).await.expect("read hung") {
+            Ok(Some(message)) => message,
+            Ok(None) => continue,
+            // The input is used up or malformed past recovery
+            Err(_) => break,
+        };
+
+        let reply = timeout(STEP_TIMEOUT, message.exec(&db, &user))
+            .await
+            .unwrap_or_else(|_| panic!("exec hung on {:?}", message));
+        if let Message::None = reply {
+            continue;
+        }
+        if conn.write(reply).await.is_err() {
+            break;
+        }
+    }
+
+    check(&db).await;
+}
+
+/// Every key in the KeyDir should point at a live entry for that key.
+async fn check(db: &Db) {
+    let kd = db.kd.read().await;
+    for (key, data) in kd.iter() {
+        let entry = db
+            .pc
+            .fetch_entry(data.page_id, data.offset)
+            .await
+            .unwrap_or_else(|e| panic!("could not fetch {:?} at {:?}: {}", key, data, e))
+            .unwrap_or_else(|| panic!("no entry for {:?} at {:?}", key, data));
+
+        assert!(
+            entry.key == key,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            key,
+            entry.key
+        );
+        assert!(
+            entry.t == EntryType::Put,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            EntryType::Put,
+            entry.t
+        );
+    }
+}
+
+fn scratch_dir() -> PathBuf {
+    let tmp = match Path::new("/dev/shm") {
+        shm if shm.is_dir() => shm.to_path_buf(),
+        _ => std::env::temp_dir(),
+    };
+
+    tmp.join(format!("hash_db_fuzz.{}", std::process::id()))
+}
+
+/// Removes everything the last input left behind.
+fn clear() -> io::Result<()> {
+    for entry in fs::read_dir(".")? {
+        let path = entry?.path();
+        if path.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,466 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::BuildHasher,
+    io,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use hash_db::client::{Client, ClientError};
+
+/// Drives a running server with a mix of GET, INSERT and DELETE requests and reports the
+/// throughput and latency percentiles of each.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Server to connect to.
+    #[arg(long, default_value = "127.0.0.1:4444")]
+    addr: String,
+
+    /// Database to pick with `use` on each connection.
+    #[arg(long)]
+    db: Option<String>,
+
+    /// Connections to open.
+    #[arg(short, long, default_value_t = 50)]
+    connections: usize,
+
+    /// Requests each connection keeps in flight.
+    #[arg(short = 'P', long, default_value_t = 1)]
+    pipeline: usize,
+
+    /// Requests to send in total.
+    #[arg(short = 'n', long, default_value_t = 100_000)]
+    requests: u64,
+
+    /// Relative weights of GET, INSERT and DELETE, e.g. 8:1:1.
+    #[arg(long, default_value = "9:1:0", value_parser = |s: &str| s.parse::<Ratio>())]
+    ratio: Ratio,
+
+    /// Number of distinct keys, named key:000000000000 and up.
+    #[arg(short, long, default_value_t = 10_000)]
+    keys: u64,
+
+    /// uniform or zipfian. With zipfian the lower numbered keys are the hot ones.
+    #[arg(long, default_value = "uniform", value_parser = |s: &str| s.parse::<Distribution>())]
+    distribution: Distribution,
+
+    /// How skewed zipfian is, between 0 and 1. YCSB uses 0.99.
+    #[arg(long, default_value_t = 0.99)]
+    zipf_theta: f64,
+
+    /// Size of inserted values in bytes.
+    #[arg(short = 'd', long, default_value_t = 64)]
+    value_size: usize,
+
+    /// Inserts every key before starting, so GETs hit.
+    #[arg(long)]
+    preload: bool,
+
+    /// Seed for picking requests and keys, random if not set.
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Ratio {
+    get: u64,
+    insert: u64,
+    delete: u64,
+}
+
+impl Ratio {
+    fn pick(&self, rng: &mut Rng) -> Op {
+        let n = rng.next() % (self.get + self.insert + self.delete);
+        if n < self.get {
+            Op::Get
+        } else if n < self.get + self.insert {
+            Op::Insert
+        } else {
+            Op::Delete
+        }
+    }
+}
+
+impl FromStr for Ratio {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("expected get:insert:delete weights, got {:?}", s),
+            )
+        };
+
+        let weights = s
+            .split(':')
+            .map(|w| w.parse::<u64>().map_err(|_| invalid()))
+            .collect::<io::Result<Vec<_>>>()?;
+        match weights[..] {
+            [get, insert, delete] if get + insert + delete > 0 => Ok(Ratio {
+                get,
+                insert,
+                delete,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Distribution {
+    Uniform,
+    Zipfian,
+}
+
+impl FromStr for Distribution {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(Distribution::Uniform),
+            "zipfian" => Ok(Distribution::Zipfian),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown distribution {:?}", s),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Get,
+    Insert,
+    Delete,
+}
+
+const OPS: [Op; 3] = [Op::Get, Op::Insert, Op::Delete];
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Get => write!(f, "GET"),
+            Op::Insert => write!(f, "INSERT"),
+            Op::Delete => write!(f, "DELETE"),
+        }
+    }
+}
+
+/// xorshift64*, plenty for picking keys and cheap enough not to show up in the latencies.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Zero would only ever produce zero
+        Self(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    /// Uniform in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Picks key numbers, see "Quickly Generating Billion-Record Synthetic Databases", Gray et al.
+/// Set up is linear in the number of keys, picking is constant.
+enum Keys {
+    Uniform(u64),
+    Zipfian {
+        n: u64,
+        theta: f64,
+        alpha: f64,
+        zetan: f64,
+        eta: f64,
+    },
+}
+
+impl Keys {
+    fn new(n: u64, distribution: Distribution, theta: f64) -> Self {
+        if distribution == Distribution::Uniform {
+            return Keys::Uniform(n);
+        }
+
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zetan = zeta(n);
+        Keys::Zipfian {
+            n,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta(2) / zetan),
+        }
+    }
+
+    fn pick(&self, rng: &mut Rng) -> u64 {
+        match *self {
+            Keys::Uniform(n) => rng.next() % n,
+            Keys::Zipfian {
+                n,
+                theta,
+                alpha,
+                zetan,
+                eta,
+            } => {
+                let u = rng.next_f64();
+                let uz = u * zetan;
+                if uz < 1.0 {
+                    0
+                } else if uz < 1.0 + 0.5f64.powf(theta) {
+                    1.min(n - 1)
+                } else {
+                    ((n as f64 * (eta * u - eta + 1.0).powf(alpha)) as u64).min(n - 1)
+                }
+            }
+        }
+    }
+}
+
+fn key(i: u64) -> String {
+    format!("key:{:012}", i)
+}
+
+/// What a worker saw, merged into the report once they're all done.
+#[derive(Default)]
+struct Results {
+    /// Latencies in microseconds, by `Op`.
+    latencies: [Vec<u32>; 3],
+    hits: u64,
+    misses: u64,
+    errors: u64,
+    first_error: Option<String>,
+}
+
+impl Results {
+    fn merge(&mut self, other: Results) {
+        for (l, o) in self.latencies.iter_mut().zip(other.latencies) {
+            l.extend(o);
+        }
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.errors += other.errors;
+        self.first_error = self.first_error.take().or(other.first_error);
+    }
+}
+
+struct Bench {
+    ratio: Ratio,
+    keys: Keys,
+    value: Vec<u8>,
+    requests: u64,
+    /// Requests handed out to workers so far.
+    sent: AtomicU64,
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run(Cli::parse()).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), ClientError> {
+    if cli.keys == 0 || cli.connections == 0 || cli.pipeline == 0 {
+        return Err(invalid(
+            "--keys, --connections and --pipeline must be at least 1",
+        ));
+    }
+    if cli.distribution == Distribution::Zipfian && !(cli.zipf_theta > 0.0 && cli.zipf_theta < 1.0)
+    {
+        return Err(invalid("--zipf-theta must be between 0 and 1"));
+    }
+
+    let seed = cli
+        .seed
+        .unwrap_or_else(|| RandomState::new().hash_one(Instant::now()));
+    let bench = Arc::new(Bench {
+        ratio: cli.ratio,
+        keys: Keys::new(cli.keys, cli.distribution, cli.zipf_theta),
+        value: vec![b'x'; cli.value_size],
+        requests: cli.requests,
+        sent: AtomicU64::new(0),
+    });
+
+    let mut clients = Vec::with_capacity(cli.connections);
+    for _ in 0..cli.connections {
+        let client = Client::connect(cli.addr.clone()).await?;
+        if let Some(db) = &cli.db {
+            client.use_db(db).await?;
+        }
+        clients.push(client);
+    }
+
+    if cli.preload {
+        let start = Instant::now();
+        preload(&clients, cli.keys, &bench.value).await?;
+        eprintln!("preloaded {} keys in {:?}", cli.keys, start.elapsed());
+    }
+
+    let start = Instant::now();
+    let mut workers = Vec::with_capacity(cli.connections * cli.pipeline);
+    for (i, client) in clients.iter().enumerate() {
+        for j in 0..cli.pipeline {
+            let rng = Rng::new(seed.wrapping_add((i * cli.pipeline + j) as u64));
+            workers.push(tokio::spawn(work(client.clone(), bench.clone(), rng)));
+        }
+    }
+
+    let mut results = Results::default();
+    for worker in workers {
+        results.merge(worker.await.map_err(io::Error::other)?);
+    }
+    let elapsed = start.elapsed();
+
+    print_report(&cli, seed, elapsed, &mut results);
+
+    Ok(())
+}
+
+fn invalid(message: &str) -> ClientError {
+    ClientError::Io(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
+
+async fn preload(clients: &[Client], keys: u64, value: &[u8]) -> Result<(), ClientError> {
+    let next = Arc::new(AtomicU64::new(0));
+    let mut loaders = Vec::with_capacity(clients.len());
+    for client in clients {
+        let (client, next, value) = (client.clone(), next.clone(), value.to_vec());
+        loaders.push(tokio::spawn(async move {
+            loop {
+                let i = next.fetch_add(1, Relaxed);
+                if i >= keys {
+                    return Ok::<_, ClientError>(());
+                }
+                client.insert(key(i), &value).await?;
+            }
+        }));
+    }
+
+    for loader in loaders {
+        loader.await.map_err(io::Error::other)??;
+    }
+
+    Ok(())
+}
+
+/// Sends one request at a time until the requests have all been handed out.
+async fn work(client: Client, bench: Arc<Bench>, mut rng: Rng) -> Results {
+    let mut results = Results::default();
+
+    while bench.sent.fetch_add(1, Relaxed) < bench.requests {
+        let op = bench.ratio.pick(&mut rng);
+        let key = key(bench.keys.pick(&mut rng));
+
+        let start = Instant::now();
+        let res = match op {
+            Op::Get => client.get(key).await.map(|v| match v {
+                Some(_) => results.hits += 1,
+                None => results.misses += 1,
+            }),
+            Op::Insert => client.insert(key, &bench.value).await,
+            Op::Delete => client.delete(key).await,
+        };
+        let micros = start.elapsed().as_micros().min(u32::MAX as u128) as u32;
+
+        match res {
+            Ok(()) => results.latencies[op as usize].push(micros),
+            Err(e) => {
+                results.errors += 1;
+                results.first_error.get_or_insert_with(|| e.to_string());
+            }
+        }
+    }
+
+    results
+}
+
+fn print_report(cli: &Cli, seed: u64, elapsed: Duration, results: &mut Results) {
+    let done: usize = results.latencies.iter().map(Vec::len).sum();
+    println!(
+        "{} requests in {:.2?} over {} connections, {} in flight each",
+        done, elapsed, cli.connections, cli.pipeline
+    );
+    println!(
+        "{} keys, {:?}, {}:{}:{}, {} byte values, seed {}",
+        cli.keys,
+        cli.distribution,
+        cli.ratio.get,
+        cli.ratio.insert,
+        cli.ratio.delete,
+        cli.value_size,
+        seed
+    );
+    println!(
+        "throughput: {:.0} requests/s",
+        done as f64 / elapsed.as_secs_f64()
+    );
+    println!();
+
+    println!(
+        "{:<8}{:>10}{:>12}{:>10}{:>10}{:>10}{:>10}{:>10}",
+        "", "count", "requests/s", "p50", "p90", "p99", "p99.9", "max"
+    );
+    for op in OPS {
+        let latencies = &mut results.latencies[op as usize];
+        if latencies.is_empty() {
+            continue;
+        }
+        latencies.sort_unstable();
+
+        let p = |p: f64| {
+            let i = ((latencies.len() as f64 * p).ceil() as usize).clamp(1, latencies.len());
+            format_micros(latencies[i - 1])
+        };
+        println!(
+            "{:<8}{:>10}{:>12.0}{:>10}{:>10}{:>10}{:>10}{:>10}",
+            op.to_string(),
+            latencies.len(),
+            latencies.len() as f64 / elapsed.as_secs_f64(),
+            p(0.5),
+            p(0.9),
+            p(0.99),
+            p(0.999),
+            p(1.0)
+        );
+    }
+    println!();
+
+    if results.hits + results.misses > 0 {
+        println!(
+            "hits: {} misses: {} ({:.1}% hit)",
+            results.hits,
+            results.misses,
+            100.0 * results.hits as f64 / (results.hits + results.misses) as f64
+        );
+    }
+    if results.errors > 0 {
+        println!(
+            "errors: {} (first: {})",
+            results.errors,
+            results.first_error.as_deref().unwrap_or("")
+        );
+    }
+}
+
+fn format_micros(micros: u32) -> String {
+    if micros < 1000 {
+        format!("{}us", micros)
+    } else {
+        format!("{:.2}ms", micros as f64 / 1000.0)
+    }
+}
@@ -0,0 +1,742 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{Shutdown, TcpStream},
+    os::fd::{AsRawFd, RawFd},
+    path::PathBuf,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use clap::{Parser, Subcommand};
+use hash_db::{
+    serverv2::message::Message,
+    storagev2::{
+        db::{Db, Options},
+        dump::Format,
+        verify,
+    },
+};
+use nix::{
+    sys::termios::{self, InputFlags, LocalFlags, SetArg, SpecialCharacterIndices, Termios},
+    unistd,
+};
+
+/// Tools for a database. The offline ones read the file directly, the server must not have it
+/// open.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Writes every live key with its value and expiry.
+    Dump {
+        /// Database file to read.
+        #[arg(long)]
+        db: PathBuf,
+
+        /// json or csv.
+        #[arg(long, default_value = "json", value_parser = |s: &str| s.parse::<Format>())]
+        format: Format,
+
+        /// File to write to instead of stdout.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Loads keys written by dump, in either format. Loaded keys replace existing ones.
+    Load {
+        /// Database file to load into, created if it doesn't exist.
+        #[arg(long)]
+        db: PathBuf,
+
+        /// File to read from instead of stdin.
+        #[arg(short, long)]
+        input: Option<PathBuf>,
+    },
+    /// Checks every page's trailer and entries and that the keys can be rebuilt from them,
+    /// without changing anything unless asked to quarantine. Exits non-zero if anything is wrong.
+    Verify {
+        /// Database file to check.
+        #[arg(long)]
+        db: PathBuf,
+
+        /// Copies bad pages into this directory, then zeroes them so the next open skips them.
+        #[arg(long)]
+        quarantine: Option<PathBuf>,
+    },
+    /// Connects to a running server and reads commands interactively, with line editing, history
+    /// and tab completion of command names.
+    Connect {
+        /// Server to connect to.
+        #[arg(long, default_value = "127.0.0.1:4444")]
+        addr: String,
+
+        /// Speaks the binary protocol, which carries get, insert, delete and use.
+        #[arg(long)]
+        binary: bool,
+
+        /// Sends the commands on stdin, one per line, without waiting for each reply, then
+        /// reports how many failed. For bulk loading.
+        #[arg(long)]
+        pipe: bool,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run(Cli::parse().command).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(command: Command) -> io::Result<()> {
+    match command {
+        Command::Dump { db, format, output } => {
+            let db = Db::open(db, Options::default()).await?;
+            let w: Box<dyn Write> = match output {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(BufWriter::new(io::stdout().lock())),
+            };
+
+            let n = db.export_to(w, format).await?;
+            eprintln!("dumped {} keys", n);
+        }
+        Command::Load { db, input } => {
+            let db = Db::open(db, Options::default()).await?;
+            let r: Box<dyn Read> = match input {
+                Some(path) => Box::new(BufReader::new(File::open(path)?)),
+                None => Box::new(io::stdin().lock()),
+            };
+
+            let n = db.import_from(r).await?;
+            eprintln!("loaded {} keys", n);
+        }
+        Command::Verify { db, quarantine } => {
+            let report = verify::verify(db, quarantine.as_deref()).await?;
+            println!("{}", report);
+            if !report.is_ok() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "verify failed"));
+            }
+        }
+        Command::Connect { addr, binary, pipe } => {
+            // Terminal and socket IO here is blocking
+            tokio::task::spawn_blocking(move || {
+                let session = Session::connect(&addr, binary)?;
+                match pipe {
+                    true => session.pipe(io::stdin().lock()),
+                    false => session.repl(&addr),
+                }
+            })
+            .await
+            .map_err(io::Error::other)??;
+        }
+    }
+
+    Ok(())
+}
+
+/// Line protocol commands, completed on tab. Those followed by a space take arguments.
+const COMMANDS: [&str; 52] = [
+    "append ",
+    "begin",
+    "commit",
+    "compaction estimate",
+    "config get ",
+    "config set ",
+    "dbsize",
+    "delete ",
+    "discard",
+    "exec",
+    "exists ",
+    "expire ",
+    "expireat ",
+    "expiryforecast",
+    "export-keys ",
+    "export-sstable ",
+    "fence ",
+    "flushdb",
+    "get ",
+    "getdel ",
+    "getset ",
+    "health",
+    "info",
+    "insert ",
+    "keys ",
+    "memory usage ",
+    "merge ",
+    "mget ",
+    "mget-consistent ",
+    "mset ",
+    "multi",
+    "notifications",
+    "page fill",
+    "persist ",
+    "publish ",
+    "range ",
+    "scan ",
+    "select ",
+    "setnx ",
+    "setrange ",
+    "snapshot ",
+    "stats bootstrap",
+    "strlen ",
+    "subscribe ",
+    "subscribe-oplog ",
+    "ttl ",
+    "unlink ",
+    "unvalidate ",
+    "use ",
+    "validate ",
+    "validators",
+    "watch ",
+];
+
+/// How long to wait for the first byte of a reply.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The line protocol doesn't mark where a reply ends and some replies span lines, so a reply is
+/// taken to be over once the server has been quiet this long after a newline.
+const REPLY_IDLE: Duration = Duration::from_millis(50);
+
+/// Lines of history kept in `~/.hash_db_history`.
+const HISTORY_LEN: usize = 1000;
+
+struct Session {
+    stream: TcpStream,
+    binary: bool,
+    /// Id of the last binary request.
+    id: u32,
+    buf: Vec<u8>,
+}
+
+impl Session {
+    fn connect(addr: &str, binary: bool) -> io::Result<Self> {
+        Ok(Self {
+            stream: TcpStream::connect(addr)?,
+            binary,
+            id: 0,
+            buf: Vec::new(),
+        })
+    }
+
+    fn repl(mut self, addr: &str) -> io::Result<()> {
+        let history =
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".hash_db_history"));
+        let mut editor = Editor::new(history);
+        let prompt = format!("{}> ", addr);
+
+        while let Some(line) = editor.read_line(&prompt)? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            editor.add(&line);
+
+            let mut request = format!("{}\n", line);
+            while is_incomplete(&request) {
+                let Some(line) = editor.read_line("... ")? else {
+                    return Ok(());
+                };
+                editor.add(&line);
+                request.push_str(&line);
+                request.push('\n');
+            }
+
+            let b = match encode(&request, self.binary, self.id + 1) {
+                Ok(b) => b,
+                Err(e) => {
+                    println!("(error) {}", e);
+                    continue;
+                }
+            };
+            self.stream.write_all(&b)?;
+            match self.binary {
+                true => {
+                    self.id += 1;
+                    println!("{}", pretty_message(&self.read_frame()?));
+                }
+                false => match self.read_lines()? {
+                    lines if lines.is_empty() => println!("(no reply)"),
+                    lines => println!("{}", pretty_lines(&lines)),
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a reply in the line protocol, see `REPLY_IDLE`.
+    fn read_lines(&mut self) -> io::Result<String> {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 4096];
+        self.stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::ConnectionReset)),
+                Ok(n) => {
+                    buf.extend_from_slice(&chunk[..n]);
+                    if buf.ends_with(b"\n") {
+                        self.stream.set_read_timeout(Some(REPLY_IDLE))?;
+                    }
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    /// Reads the reply to the last binary request.
+    fn read_frame(&mut self) -> io::Result<Message> {
+        self.stream.set_read_timeout(None)?;
+        let mut chunk = [0; 4096];
+        loop {
+            if let Some((id, message, n)) = Message::parse_reply_frame(&self.buf)? {
+                self.buf.drain(..n);
+                if id == self.id {
+                    return Ok(message);
+                }
+                continue;
+            }
+
+            match self.stream.read(&mut chunk)? {
+                0 => return Err(io::Error::from(io::ErrorKind::ConnectionReset)),
+                n => self.buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+
+    /// Sends every command in `r` while replies are read back, then waits for the server to
+    /// answer them all. Failed commands are printed as they come back.
+    fn pipe(self, r: impl BufRead) -> io::Result<()> {
+        let mut w = BufWriter::new(self.stream.try_clone()?);
+        let mut reader = BufReader::new(self.stream);
+        let binary = self.binary;
+
+        let (mut sent, mut failed) = (0, 0);
+        let replies = std::thread::spawn(move || -> io::Result<usize> {
+            let mut failed = 0;
+            match binary {
+                true => {
+                    let mut buf = Vec::new();
+                    reader.read_to_end(&mut buf)?;
+                    let mut pos = 0;
+                    while let Some((id, message, n)) = Message::parse_reply_frame(&buf[pos..])? {
+                        if let Message::Error(_, _) = message {
+                            eprintln!("{}: {}", id, pretty_message(&message));
+                            failed += 1;
+                        }
+                        pos += n;
+                    }
+                }
+                false => {
+                    for line in reader.lines() {
+                        let line = line?;
+                        if line.starts_with("Error ") {
+                            eprintln!("{}", pretty_line(&line));
+                            failed += 1;
+                        }
+                    }
+                }
+            }
+
+            Ok(failed)
+        });
+
+        let mut request = String::new();
+        for (i, line) in r.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() && request.is_empty() {
+                continue;
+            }
+            request.push_str(&line);
+            request.push('\n');
+            if is_incomplete(&request) {
+                continue;
+            }
+
+            // Binary requests are numbered by the line they end on
+            match encode(&std::mem::take(&mut request), binary, i as u32 + 1) {
+                Ok(b) => {
+                    w.write_all(&b)?;
+                    sent += 1;
+                }
+                Err(e) => {
+                    eprintln!("{}: (error) {}", i + 1, e);
+                    failed += 1;
+                }
+            }
+        }
+        w.flush()?;
+        // The server closes the connection once it has answered everything
+        w.get_ref().shutdown(Shutdown::Write)?;
+
+        failed += replies
+            .join()
+            .map_err(|_| io::Error::other("reading replies panicked"))??;
+        eprintln!("sent {} commands, {} failed", sent, failed);
+        if failed > 0 {
+            return Err(io::Error::other(format!("{} commands failed", failed)));
+        }
+
+        Ok(())
+    }
+}
+
+/// A batch carries on until exec, any other request is a single line.
+fn is_incomplete(request: &str) -> bool {
+    request.starts_with("multi\n") && Message::parse(request.as_bytes()).is_none()
+}
+
+/// Encodes a request typed or piped in for the protocol spoken. Requests the server would reject
+/// without reading them are caught here rather than sent.
+fn encode(request: &str, binary: bool, id: u32) -> Result<Bytes, &'static str> {
+    match Message::parse(request.as_bytes()).map(Message::unquoted) {
+        None => Err("unknown command"),
+        Some(Message::Invalid(_, (_, text))) => Err(text),
+        Some(message) if binary => message
+            .request_frame(id)
+            .ok_or("not carried by the binary protocol"),
+        Some(_) => Ok(Bytes::copy_from_slice(request.as_bytes())),
+    }
+}
+
+/// Formats a reply in the line protocol, numbering its lines if there's more than one.
+fn pretty_lines(reply: &str) -> String {
+    let lines: Vec<_> = reply.lines().collect();
+    if lines.len() == 1 {
+        return pretty_line(lines[0]);
+    }
+
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| format!("{}) {}", i + 1, pretty_line(line)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn pretty_line(line: &str) -> String {
+    match line {
+        "Success" => "OK".into(),
+        "NotFound" => "(nil)".into(),
+        line => match line.strip_prefix("Error ") {
+            Some(e) => format!("(error) {}", e),
+            None => line.into(),
+        },
+    }
+}
+
+fn pretty_message(message: &Message) -> String {
+    match message {
+        Message::Success => "OK".into(),
+        Message::NotFound => "(nil)".into(),
+        Message::Error(code, text) => format!("(error) {} {}", printable(code), printable(text)),
+        Message::Text(text) => printable(text),
+        Message::Result(k, v) => format!("{} {}", printable(k), printable(v)),
+        message => format!("{:?}", message),
+    }
+}
+
+/// Keys and values that aren't UTF-8 are shown escaped.
+fn printable(b: &[u8]) -> String {
+    match std::str::from_utf8(b) {
+        Ok(s) => s.into(),
+        Err(_) => b.escape_ascii().to_string(),
+    }
+}
+
+/// Reads lines from the terminal with editing, history and completion of `COMMANDS`. Lines are
+/// read as they are when stdin isn't a terminal.
+struct Editor {
+    history: Vec<String>,
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Tab,
+    /// Ctrl-U, deletes up to the cursor.
+    KillStart,
+    /// Ctrl-K, deletes from the cursor.
+    KillEnd,
+    /// Ctrl-C, drops the line.
+    Interrupt,
+    /// Ctrl-D, ends the session on an empty line.
+    Eof,
+    Other,
+}
+
+impl Editor {
+    fn new(file: Option<PathBuf>) -> Self {
+        let mut history: Vec<String> = file
+            .as_ref()
+            .and_then(|file| std::fs::read_to_string(file).ok())
+            .map(|s| s.lines().map(String::from).collect())
+            .unwrap_or_default();
+        let skip = history.len().saturating_sub(HISTORY_LEN);
+        history.drain(..skip);
+
+        Self { history, file }
+    }
+
+    fn add(&mut self, line: &str) {
+        if self.history.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.history.push(line.into());
+
+        let Some(file) = &self.file else {
+            return;
+        };
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)
+            .and_then(|mut f| writeln!(f, "{}", line));
+        if let Err(e) = written {
+            eprintln!(
+                "error: could not write history to {} - {}",
+                file.display(),
+                e
+            );
+            self.file = None;
+        }
+    }
+
+    /// Reads a line, `None` at the end of input.
+    fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        let stdin = io::stdin();
+        let fd = stdin.as_raw_fd();
+        if !unistd::isatty(fd).unwrap_or(false) {
+            let mut line = String::new();
+            return match stdin.lock().read_line(&mut line)? {
+                0 => Ok(None),
+                _ => Ok(Some(line.trim_end_matches(['\r', '\n']).into())),
+            };
+        }
+
+        let _raw = RawMode::enable(fd)?;
+        let mut r = stdin.lock();
+        let mut out = io::stdout().lock();
+
+        let (mut line, mut cursor) = (Vec::<char>::new(), 0);
+        // Where in the history the line came from, and what was being typed before going there
+        let (mut index, mut draft) = (self.history.len(), Vec::new());
+        redraw(&mut out, prompt, &line, cursor)?;
+        loop {
+            match read_key(&mut r)? {
+                Key::Char(c) => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
+                Key::Enter => break,
+                Key::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                Key::Delete if cursor < line.len() => {
+                    line.remove(cursor);
+                }
+                Key::Left => cursor = cursor.saturating_sub(1),
+                Key::Right => cursor = (cursor + 1).min(line.len()),
+                Key::Home => cursor = 0,
+                Key::End => cursor = line.len(),
+                Key::Up if index > 0 => {
+                    if index == self.history.len() {
+                        draft = line;
+                    }
+                    index -= 1;
+                    line = self.history[index].chars().collect();
+                    cursor = line.len();
+                }
+                Key::Down if index < self.history.len() => {
+                    index += 1;
+                    line = match self.history.get(index) {
+                        Some(entry) => entry.chars().collect(),
+                        None => std::mem::take(&mut draft),
+                    };
+                    cursor = line.len();
+                }
+                Key::Tab => {
+                    let prefix: String = line[..cursor].iter().collect();
+                    let candidates: Vec<_> =
+                        COMMANDS.iter().filter(|c| c.starts_with(&prefix)).collect();
+                    let common = candidates.iter().fold(None, |common: Option<&str>, c| {
+                        Some(match common {
+                            Some(common) => {
+                                let n = common
+                                    .chars()
+                                    .zip(c.chars())
+                                    .take_while(|(a, b)| a == b)
+                                    .count();
+                                &common[..common
+                                    .char_indices()
+                                    .nth(n)
+                                    .map_or(common.len(), |(i, _)| i)]
+                            }
+                            None => c,
+                        })
+                    });
+
+                    match common {
+                        Some(common) if common.len() > prefix.len() => {
+                            for c in common[prefix.len()..].chars() {
+                                line.insert(cursor, c);
+                                cursor += 1;
+                            }
+                        }
+                        Some(_) if candidates.len() > 1 => {
+                            let names: Vec<_> = candidates.iter().map(|c| c.trim_end()).collect();
+                            write!(out, "\r\n{}\r\n", names.join("  "))?;
+                        }
+                        _ => {}
+                    }
+                }
+                Key::KillStart => {
+                    line.drain(..cursor);
+                    cursor = 0;
+                }
+                Key::KillEnd => line.truncate(cursor),
+                Key::Interrupt => {
+                    write!(out, "^C\r\n")?;
+                    return Ok(Some(String::new()));
+                }
+                Key::Eof if line.is_empty() => {
+                    write!(out, "\r\n")?;
+                    return Ok(None);
+                }
+                _ => {}
+            }
+            redraw(&mut out, prompt, &line, cursor)?;
+        }
+        write!(out, "\r\n")?;
+        out.flush()?;
+
+        Ok(Some(line.into_iter().collect()))
+    }
+}
+
+fn redraw(out: &mut impl Write, prompt: &str, line: &[char], cursor: usize) -> io::Result<()> {
+    let text: String = line.iter().collect();
+    write!(out, "\r{}{}\x1b[K", prompt, text)?;
+    if cursor < line.len() {
+        write!(out, "\x1b[{}D", line.len() - cursor)?;
+    }
+
+    out.flush()
+}
+
+fn read_key(r: &mut impl Read) -> io::Result<Key> {
+    let byte = |r: &mut dyn Read| -> io::Result<u8> {
+        let mut b = [0];
+        r.read_exact(&mut b)?;
+        Ok(b[0])
+    };
+
+    let key = match byte(r)? {
+        b'\r' | b'\n' => Key::Enter,
+        0x7f | 0x08 => Key::Backspace,
+        b'\t' => Key::Tab,
+        0x01 => Key::Home,
+        0x05 => Key::End,
+        0x02 => Key::Left,
+        0x06 => Key::Right,
+        0x10 => Key::Up,
+        0x0e => Key::Down,
+        0x15 => Key::KillStart,
+        0x0b => Key::KillEnd,
+        0x03 => Key::Interrupt,
+        0x04 => Key::Eof,
+        0x1b => match byte(r)? {
+            b'[' | b'O' => match byte(r)? {
+                b'A' => Key::Up,
+                b'B' => Key::Down,
+                b'C' => Key::Right,
+                b'D' => Key::Left,
+                b'H' => Key::Home,
+                b'F' => Key::End,
+                // ESC [ n ~
+                n @ b'0'..=b'9' => match (n, byte(r)?) {
+                    (b'3', b'~') => Key::Delete,
+                    (b'1' | b'7', b'~') => Key::Home,
+                    (b'4' | b'8', b'~') => Key::End,
+                    _ => Key::Other,
+                },
+                _ => Key::Other,
+            },
+            _ => Key::Other,
+        },
+        b if b < 0x20 => Key::Other,
+        b if b < 0x80 => Key::Char(b as char),
+        // The rest of a UTF-8 sequence
+        b => {
+            let mut buf = vec![b];
+            for _ in 1..b.leading_ones().clamp(1, 4) {
+                buf.push(byte(r)?);
+            }
+            match std::str::from_utf8(&buf)
+                .ok()
+                .and_then(|s| s.chars().next())
+            {
+                Some(c) => Key::Char(c),
+                None => Key::Other,
+            }
+        }
+    };
+
+    Ok(key)
+}
+
+/// Puts the terminal into raw mode for as long as it's held, so keys arrive as they're pressed
+/// rather than a line at a time.
+struct RawMode {
+    fd: RawFd,
+    saved: Termios,
+}
+
+impl RawMode {
+    fn enable(fd: RawFd) -> io::Result<Self> {
+        let saved = termios::tcgetattr(fd)?;
+
+        let mut raw = saved.clone();
+        raw.local_flags
+            .remove(LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::ISIG | LocalFlags::IEXTEN);
+        raw.input_flags.remove(InputFlags::ICRNL | InputFlags::IXON);
+        raw.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        raw.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        termios::tcsetattr(fd, SetArg::TCSAFLUSH, &raw)?;
+
+        Ok(Self { fd, saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Err(e) = termios::tcsetattr(self.fd, SetArg::TCSAFLUSH, &self.saved) {
+            eprintln!("error: could not restore the terminal - {}", e);
+        }
+    }
+}
@@ -1,114 +1,430 @@
-use std::{collections::HashMap, io, ops::Range, sync::Arc, time::Duration};
-
-use hash_db::storagev2::test::CleanUp;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::ToSocketAddrs,
-    sync::Notify,
-    time::Instant,
+//! Runs the server on turmoil's simulated network while clients read and write through it, and
+//! partitions clients from it, breaks links, and crashes and restarts it underneath them.
+//!
+//! Clients keep track of every value a key could have, given which of their writes were
+//! acknowledged, and check each read against it. Once they're done the server is restarted one
+//! last time and every key is read back, then the data file is checked offline and opened
+//! directly, and both have to agree with what the server answered.
+//!
+//! A run is repeated by passing the seed it printed, apart from the timing of disk I/O.
+
+use std::{
+    cell::RefCell,
+    collections::{hash_map::RandomState, HashMap},
+    error::Error,
+    hash::BuildHasher,
+    io,
+    net::{IpAddr, Ipv4Addr},
+    rc::Rc,
+    time::{Duration, Instant},
 };
 
-macro_rules! client {
-    ($id:expr, $addr:expr, $range:expr) => {
-        tokio::spawn(async {
-            let ins = Instant::now();
+use bytes::{Buf, Bytes, BytesMut};
+use clap::Parser;
+use hash_db::{
+    serverv2::{
+        config::{Config, DatabaseConfig},
+        message::Message,
+        server::Server,
+    },
+    storagev2::{db::Db, disk::Durability, test::CleanUp, verify},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use turmoil::net::{TcpListener, TcpStream};
 
-            let c = Client::new($id, $addr, $range);
-            if let Err(e) = c.run().await {
-                eprintln!("{} failed with error: {}", c.id, e);
-            }
+/// Runs the server under simulated network faults and crashes, then checks nothing acknowledged
+/// was lost and the data file is consistent.
+#[derive(Debug, Parser)]
+#[command(about)]
+struct Cli {
+    /// Seed for the network, the faults and the clients, random if not set.
+    #[arg(long)]
+    seed: Option<u64>,
 
-            eprintln!("{} finished in {:?}", c.id, ins.elapsed());
-        });
-    };
+    /// Simulated seconds to run the clients for.
+    #[arg(long, default_value_t = 30)]
+    duration: u64,
+
+    /// Chance each tick that a link breaks, dropping what's in flight on it.
+    #[arg(long, default_value_t = 0.0005)]
+    fail_rate: f64,
+
+    /// Clients, each with keys of its own.
+    #[arg(long, default_value_t = 3)]
+    clients: u64,
 }
 
-fn generate_inserts(range: Range<u16>) -> HashMap<String, String> {
-    let mut ret = HashMap::with_capacity(range.len());
+const SERVER: &str = "server";
+const PORT: u16 = 4444;
+const DB_FILE: &str = "turmoil.db";
+
+/// Keys each client writes to.
+const KEYS: u64 = 100;
+
+/// How long a request waits for its reply before the connection is given up on. Replies are
+/// lost rather than late when a link breaks, so this is how a partition shows up.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a client waits before reconnecting after a request failed.
+const BACKOFF: Duration = Duration::from_millis(100);
+
+/// Chance each tick that a client is partitioned from the server, and for how long.
+const PARTITION_CHANCE: f64 = 0.0005;
+const PARTITION_MS: (u64, u64) = (100, 3000);
+
+/// Chance each tick that the server crashes, and how long before it restarts.
+const CRASH_CHANCE: f64 = 0.0002;
+const DOWN_MS: (u64, u64) = (10, 2000);
 
-    for i in range {
-        let key = format!("key_{}", i);
-        let value = format!("value_{}", i);
+/// Chance each tick that a link that broke by itself is repaired.
+const REPAIR_RATE: f64 = 0.01;
 
-        ret.insert(key, value);
+/// What a key could be read as, given which writes to it were acknowledged. `None` is deleted.
+#[derive(Debug, Default)]
+struct Possible {
+    acked: Option<Bytes>,
+    /// Writes whose connection failed before they were acknowledged. They may have been applied,
+    /// or be applied later if they were held up on the way to the server.
+    unknown: Vec<Option<Bytes>>,
+}
+
+impl Possible {
+    fn check(&self, key: &Bytes, got: Option<Bytes>) -> Result<(), String> {
+        if got == self.acked || self.unknown.contains(&got) {
+            return Ok(());
+        }
+
+        Err(format!(
+            "{:?} read as {:?}, expected {:?} or one of {:?}",
+            key, got, self.acked, self.unknown
+        ))
     }
+}
 
-    ret
+#[derive(Debug, Default)]
+struct Model {
+    keys: HashMap<Bytes, Possible>,
+    requests: usize,
+    failed: usize,
 }
 
-const DB_FILE: &str = "main.db";
+type SharedModel = Rc<RefCell<Model>>;
+
+/// xorshift64*, so runs only depend on the seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Zero would only ever produce zero
+        Self(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
 
-struct Client<T: ToSocketAddrs> {
-    id: u8,
-    addr: T,
-    range: Range<u16>,
+    fn between(&mut self, (lo, hi): (u64, u64)) -> Duration {
+        Duration::from_millis(lo + self.next() % (hi - lo))
+    }
 }
 
-impl<T: ToSocketAddrs> Client<T> {
-    pub fn new(id: u8, addr: T, range: Range<u16>) -> Self {
-        Self { id, addr, range }
+fn config() -> Config {
+    Config {
+        unix_socket: None,
+        databases: vec![DatabaseConfig {
+            name: "main".into(),
+            path: DB_FILE.into(),
+        }],
+        // So acknowledged writes have to survive a crash
+        durability: Durability::Always,
+        ..Default::default()
     }
+}
 
-    pub async fn run(&self) -> io::Result<()> {
-        let mut socket = tokio::net::TcpStream::connect(&self.addr).await?;
+/// A binary protocol connection to the server, sending one request at a time.
+struct Conn {
+    stream: TcpStream,
+    buf: BytesMut,
+    id: u32,
+}
 
-        let inserts = generate_inserts(self.range.clone());
-        let mut buf = [0; 256];
-        for (k, v) in &inserts {
-            socket
-                .write(format!("insert {} {}\n", k, v).as_bytes())
-                .await?;
-            socket.flush().await?;
+impl Conn {
+    async fn connect() -> io::Result<Self> {
+        Ok(Self {
+            stream: TcpStream::connect((SERVER, PORT)).await?,
+            buf: BytesMut::new(),
+            id: 0,
+        })
+    }
 
-            let n = socket.read(&mut buf).await?;
+    async fn request(&mut self, message: &Message) -> io::Result<Message> {
+        self.id = self.id.wrapping_add(1);
+        let frame = message.request_frame(self.id).expect("a binary request");
+        self.stream.write_all(&frame).await?;
 
-            assert!(&buf[0..n] == b"Success\n");
+        loop {
+            if let Some((id, reply, n)) = Message::parse_reply_frame(&self.buf)? {
+                self.buf.advance(n);
+                if id == self.id {
+                    return Ok(reply);
+                }
+                continue;
+            }
+
+            if 0 == self.stream.read_buf(&mut self.buf).await? {
+                return Err(io::Error::from(io::ErrorKind::ConnectionReset));
+            }
         }
+    }
+}
 
-        for (k, v) in &inserts {
-            socket.write(format!("get {}\n", k).as_bytes()).await?;
-            socket.flush().await?;
+/// Sends `message`, connecting first if needed. The connection is dropped if the request fails
+/// or times out.
+async fn request(conn: &mut Option<Conn>, message: &Message) -> io::Result<Message> {
+    let res = tokio::time::timeout(TIMEOUT, async {
+        let c = match conn {
+            Some(c) => c,
+            None => conn.insert(Conn::connect().await?),
+        };
+        c.request(message).await
+    })
+    .await
+    .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)));
 
-            let n = socket.read(&mut buf).await?;
+    if res.is_err() {
+        *conn = None;
+    }
+
+    res
+}
+
+async fn serve() -> turmoil::Result {
+    let server = Server::open(config()).await?;
+    let listener = TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await?;
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            let (r, w) = stream.into_split();
+            server.serve(r, w).await
+        });
+    }
+}
 
-            let exp = format!("{} {}\n", k, v);
-            let got = &buf[0..n];
+/// Reads, inserts and deletes random keys of its own until `until`, checking each read against
+/// the writes before it.
+async fn client(id: u64, seed: u64, model: SharedModel, until: Duration) -> turmoil::Result {
+    let mut rng = Rng::new(seed);
+    let mut conn = None;
+    let mut n = 0;
+
+    while turmoil::elapsed() < until {
+        let key = Bytes::from(format!("key_{}_{}", id, rng.next() % KEYS));
+        let (message, write) = match rng.next() % 10 {
+            0..=4 => (Message::Get(key.clone()), None),
+            5..=8 => {
+                n += 1;
+                let value = Bytes::from(format!("value_{}_{}", id, n));
+                (
+                    Message::Insert(key.clone(), value.clone()),
+                    Some(Some(value)),
+                )
+            }
+            _ => (Message::Delete(key.clone()), Some(None)),
+        };
 
-            assert!(
-                got == exp.as_bytes(),
-                "\nExpected: {}\nGot: {}\n",
-                exp,
-                std::str::from_utf8(got).unwrap()
-            );
+        let res = request(&mut conn, &message).await;
+        {
+            let mut model = model.borrow_mut();
+            model.requests += 1;
+            model.failed += res.is_err() as usize;
+
+            let possible = model.keys.entry(key.clone()).or_default();
+            match (res, write) {
+                (Ok(Message::Success), Some(value)) => possible.acked = value,
+                (Ok(Message::Result(_, value)), None) => possible.check(&key, Some(value))?,
+                (Ok(Message::NotFound), None) => possible.check(&key, None)?,
+                (Err(_), Some(value)) => possible.unknown.push(value),
+                (Err(_), None) => {}
+                (Ok(reply), _) => {
+                    return Err(format!("unexpected reply to {:?}: {:?}", message, reply).into())
+                }
+            }
         }
 
-        Ok(())
+        if conn.is_none() {
+            tokio::time::sleep(BACKOFF).await;
+        }
     }
+
+    Ok(())
 }
 
-#[tokio::main]
-pub async fn main() -> io::Result<()> {
+/// Reads back every key once the server has recovered, recording what each was read as.
+async fn read_back(
+    model: SharedModel,
+    observed: Rc<RefCell<HashMap<Bytes, Option<Bytes>>>>,
+) -> turmoil::Result {
+    let mut keys: Vec<_> = model.borrow().keys.keys().cloned().collect();
+    keys.sort();
+
+    let mut conn = None;
+    for key in keys {
+        let got = loop {
+            match request(&mut conn, &Message::Get(key.clone())).await {
+                Ok(Message::Result(_, value)) => break Some(value),
+                Ok(Message::NotFound) => break None,
+                Ok(reply) => return Err(format!("unexpected reply: {:?}", reply).into()),
+                // Still recovering
+                Err(_) => tokio::time::sleep(BACKOFF).await,
+            }
+        };
+
+        model.borrow().keys[&key].check(&key, got.clone())?;
+        observed.borrow_mut().insert(key, got);
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let seed = cli
+        .seed
+        .unwrap_or_else(|| RandomState::new().hash_one(Instant::now()));
+    eprintln!("seed {}", seed);
+
+    if let Err(e) = simulate(&cli, seed) {
+        eprintln!("error: {} (seed {})", e, seed);
+        std::process::exit(1);
+    }
+}
+
+fn simulate(cli: &Cli, seed: u64) -> Result<(), Box<dyn Error>> {
+    // Left behind by a run that was killed, the model would start out wrong
+    let _ = std::fs::remove_file(DB_FILE);
     let _cu = CleanUp::file(DB_FILE);
 
-    let notify = Arc::new(Notify::new());
+    let duration = Duration::from_secs(cli.duration);
+    let mut sim = turmoil::Builder::new()
+        .simulation_duration(duration * 2 + Duration::from_secs(60))
+        .fail_rate(cli.fail_rate)
+        .repair_rate(REPAIR_RATE)
+        .rng_seed(seed)
+        .build();
+
+    let model = SharedModel::default();
+    sim.host(SERVER, serve);
+
+    let clients: Vec<_> = (0..cli.clients).map(|id| format!("client{}", id)).collect();
+    for (id, name) in clients.iter().enumerate() {
+        let id = id as u64;
+        let seed = seed.wrapping_add(id + 1);
+        sim.client(name.as_str(), client(id, seed, model.clone(), duration));
+    }
+
+    let mut rng = Rng::new(seed);
+    let mut partitioned: Vec<(&str, Duration)> = Vec::new();
+    let mut restart_at = None;
+    let (mut partitions, mut crashes) = (0, 0);
+    loop {
+        let now = sim.elapsed();
 
-    let sh_notify = notify.clone();
-    tokio::spawn(async move {
-        tokio::select! {
-            _ = hash_db::serverv2::server::run() => {}
-            _ = sh_notify.notified() => {
-                eprintln!("shutting down server");
+        partitioned.retain(|(name, heal_at)| {
+            let heal = now >= *heal_at;
+            if heal {
+                sim.repair(SERVER, *name);
             }
+            !heal
+        });
+        if restart_at.is_some_and(|at| now >= at) {
+            sim.bounce(SERVER);
+            restart_at = None;
+        }
+
+        if rng.chance(PARTITION_CHANCE) {
+            let name = clients[(rng.next() % cli.clients) as usize].as_str();
+            if !partitioned.iter().any(|(n, _)| *n == name) {
+                sim.partition(SERVER, name);
+                partitioned.push((name, now + rng.between(PARTITION_MS)));
+                partitions += 1;
+            }
+        }
+        if restart_at.is_none() && rng.chance(CRASH_CHANCE) {
+            sim.crash(SERVER);
+            restart_at = Some(now + rng.between(DOWN_MS));
+            crashes += 1;
         }
-    });
-    tokio::time::sleep(Duration::from_secs(2)).await;
 
-    client!(0, "0.0.0.0:4444", 0..1000);
-    client!(1, "0.0.0.0:4444", 1000..2000);
-    client!(2, "0.0.0.0:4444", 3000..4000);
+        if sim.step()? {
+            break;
+        }
+    }
+
+    // Recover from whatever was written last, acknowledged or not
+    for (name, _) in partitioned {
+        sim.repair(SERVER, name);
+    }
+    sim.set_fail_rate(0.0);
+    sim.crash(SERVER);
+    sim.bounce(SERVER);
+
+    let observed = Rc::new(RefCell::new(HashMap::new()));
+    sim.client("reader", read_back(model.clone(), observed.clone()));
+    while !sim.step()? {}
+    drop(sim);
+
+    let model = model.borrow();
+    eprintln!(
+        "{} requests, {} failed, {} partitions, {} crashes, {} keys read back",
+        model.requests,
+        model.failed,
+        partitions,
+        crashes,
+        observed.borrow().len()
+    );
+
+    let observed = observed.borrow();
+    tokio::runtime::Runtime::new()?.block_on(check_file(&observed))
+}
+
+/// Checks the data file offline, then that opening it directly gives what the server answered.
+async fn check_file(observed: &HashMap<Bytes, Option<Bytes>>) -> Result<(), Box<dyn Error>> {
+    let report = verify::verify(DB_FILE, None).await?;
+    if !report.is_ok() {
+        return Err(format!("data file failed verification:\n{}", report).into());
+    }
+
+    let live = observed.values().filter(|v| v.is_some()).count();
+    if report.keys != live {
+        return Err(format!(
+            "data file has {} live keys, the server answered {}",
+            report.keys, live
+        )
+        .into());
+    }
+
+    let db = Db::open(DB_FILE, config().db_options()).await?;
+    for (key, expected) in observed {
+        let got = db.get(key).await?.map(|e| e.value.freeze());
+        if got != *expected {
+            return Err(format!(
+                "{:?} is {:?} in the data file, the server answered {:?}",
+                key, got, expected
+            )
+            .into());
+        }
+    }
 
-    tokio::time::sleep(Duration::from_secs(1)).await;
-    notify.notify_one();
+    eprintln!("ok");
 
     Ok(())
 }
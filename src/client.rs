@@ -0,0 +1,547 @@
+//! An async client that speaks the binary protocol. Requests from every clone of a `Client` are
+//! pipelined over one connection and matched to their replies by frame id, so a request doesn't
+//! wait for the ones before it to be answered before being sent.
+//!
+//! If the connection drops the requests waiting on it are sent once more on a new one, after
+//! picking the database that was last picked with `use_db` again. Every request the binary
+//! protocol carries can be applied twice without changing the result, so this is safe even for
+//! requests the server got before the connection dropped.
+
+use std::{
+    collections::HashMap,
+    fmt, future, io,
+    sync::{Arc, Mutex},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::serverv2::message::Message;
+
+/// Requests waiting to be written. Callers wait for room once the connection falls this far
+/// behind.
+const QUEUE_SIZE: usize = 1024;
+
+/// Why a request failed.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The connection failed, and the request failed again on a new one or one couldn't be
+    /// opened.
+    Io(io::Error),
+    /// The server refused the request, with the error code and message it gave, e.g. `NOPERM`.
+    Server(Bytes, Bytes),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "{}", e),
+            ClientError::Server(code, message) => write!(
+                f,
+                "{} {}",
+                String::from_utf8_lossy(code),
+                String::from_utf8_lossy(message)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+type Reply = oneshot::Sender<io::Result<Message>>;
+
+struct Request {
+    message: Message,
+    reply: Reply,
+}
+
+/// A request that was written and is waiting for its reply.
+struct Waiting {
+    message: Message,
+    reply: Reply,
+    retried: bool,
+}
+
+type WaitingMap = Arc<Mutex<HashMap<u32, Waiting>>>;
+
+/// A connection to a hash_db server. Cloning it is cheap, clones share the connection.
+#[derive(Clone)]
+pub struct Client {
+    tx: mpsc::Sender<Request>,
+}
+
+impl Client {
+    /// Connects to the server at `addr`. Requests reconnect to it if the connection drops.
+    pub async fn connect(addr: impl Into<String>) -> io::Result<Self> {
+        let addr = addr.into();
+        let stream = connect(&addr).await?;
+
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(run(addr, stream, rx));
+
+        Ok(Self { tx })
+    }
+
+    /// Switches every clone of this client to the database named `name`.
+    pub async fn use_db(&self, name: impl AsRef<[u8]>) -> Result<(), ClientError> {
+        let name = Bytes::copy_from_slice(name.as_ref());
+        expect_success(self.request(Message::Use(name)).await?)
+    }
+
+    pub async fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Bytes>, ClientError> {
+        let key = Bytes::copy_from_slice(key.as_ref());
+        value(self.request(Message::Get(key)).await?)
+    }
+
+    /// Gets each of `keys`, sending them all before waiting for any of the replies.
+    pub async fn mget<K: AsRef<[u8]>>(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> Result<Vec<Option<Bytes>>, ClientError> {
+        let mut replies = Vec::new();
+        for key in keys {
+            let key = Bytes::copy_from_slice(key.as_ref());
+            replies.push(self.send(Message::Get(key)).await?);
+        }
+
+        let mut values = Vec::with_capacity(replies.len());
+        for rx in replies {
+            values.push(value(recv(rx).await?)?);
+        }
+
+        Ok(values)
+    }
+
+    pub async fn insert(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> Result<(), ClientError> {
+        let key = Bytes::copy_from_slice(key.as_ref());
+        let value = Bytes::copy_from_slice(value.as_ref());
+        expect_success(self.request(Message::Insert(key, value)).await?)
+    }
+
+    /// Inserts a key that expires after `secs` seconds.
+    pub async fn insert_ex(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        secs: u64,
+    ) -> Result<(), ClientError> {
+        let key = Bytes::copy_from_slice(key.as_ref());
+        let value = Bytes::copy_from_slice(value.as_ref());
+        expect_success(self.request(Message::InsertEx(key, value, secs)).await?)
+    }
+
+    pub async fn delete(&self, key: impl AsRef<[u8]>) -> Result<(), ClientError> {
+        let key = Bytes::copy_from_slice(key.as_ref());
+        expect_success(self.request(Message::Delete(key)).await?)
+    }
+
+    async fn request(&self, message: Message) -> Result<Message, ClientError> {
+        let rx = self.send(message).await?;
+        recv(rx).await
+    }
+
+    /// Queues `message` to be written, returning where its reply will arrive.
+    async fn send(&self, message: Message) -> io::Result<oneshot::Receiver<io::Result<Message>>> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(Request { message, reply })
+            .await
+            .map_err(|_| stopped())?;
+
+        Ok(rx)
+    }
+}
+
+async fn recv(rx: oneshot::Receiver<io::Result<Message>>) -> Result<Message, ClientError> {
+    match rx.await.map_err(|_| stopped())?? {
+        Message::Error(code, message) => Err(ClientError::Server(code, message)),
+        message => Ok(message),
+    }
+}
+
+fn value(message: Message) -> Result<Option<Bytes>, ClientError> {
+    match message {
+        Message::Result(_, v) => Ok(Some(v)),
+        Message::NotFound => Ok(None),
+        message => Err(unexpected(message)),
+    }
+}
+
+fn expect_success(message: Message) -> Result<(), ClientError> {
+    match message {
+        Message::Success => Ok(()),
+        message => Err(unexpected(message)),
+    }
+}
+
+fn unexpected(message: Message) -> ClientError {
+    ClientError::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected reply {:?}", message),
+    ))
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "client task stopped")
+}
+
+async fn connect(addr: &str) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+
+    Ok(stream)
+}
+
+struct Conn {
+    w: OwnedWriteHalf,
+    reader: JoinHandle<io::Error>,
+}
+
+impl Conn {
+    fn new(r: OwnedReadHalf, w: OwnedWriteHalf, buf: BytesMut, pending: WaitingMap) -> Self {
+        let reader = tokio::spawn(read_replies(r, buf, pending));
+
+        Self { w, reader }
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Writes requests as they're queued. Replies are read by a task of their own so a slow reader
+/// of replies can't stop requests being written, or the other way around.
+async fn run(addr: String, stream: TcpStream, mut rx: mpsc::Receiver<Request>) {
+    let pending = WaitingMap::default();
+    let (r, w) = stream.into_split();
+    let mut conn = Some(Conn::new(r, w, BytesMut::new(), pending.clone()));
+    let mut next_id = 0;
+    let mut db = None;
+
+    loop {
+        tokio::select! {
+            request = rx.recv() => {
+                let Some(Request { message, reply }) = request else {
+                    break;
+                };
+
+                let id = take_id(&mut next_id);
+                let Some(frame) = message.request_frame(id) else {
+                    let e = io::Error::new(io::ErrorKind::Unsupported, "not a binary request");
+                    let _ = reply.send(Err(e));
+                    continue;
+                };
+                if let Message::Use(name) = &message {
+                    db = Some(name.clone());
+                }
+                let p = Waiting {
+                    message,
+                    reply,
+                    retried: false,
+                };
+                pending.lock().unwrap().insert(id, p);
+
+                let res = match &mut conn {
+                    Some(c) => c.w.write_all(&frame).await,
+                    None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+                };
+                if let Err(e) = res {
+                    conn = reconnect(&addr, conn.take(), &pending, &db, &mut next_id, e).await;
+                }
+            }
+            e = closed(&mut conn) => {
+                conn = reconnect(&addr, conn.take(), &pending, &db, &mut next_id, e).await;
+            }
+        }
+    }
+}
+
+/// Resolves with the error the connection's reader stopped on, never if there's no connection.
+async fn closed(conn: &mut Option<Conn>) -> io::Error {
+    match conn {
+        Some(c) => (&mut c.reader).await.unwrap_or_else(io::Error::other),
+        None => future::pending().await,
+    }
+}
+
+fn take_id(next_id: &mut u32) -> u32 {
+    let id = *next_id;
+    *next_id = id.wrapping_add(1);
+
+    id
+}
+
+/// Replaces a connection that failed with `e`. Requests that already failed once are answered
+/// with `e`, the rest are written again on a new connection after picking `db` again. If nothing
+/// is waiting the new connection is left to the next request.
+async fn reconnect(
+    addr: &str,
+    conn: Option<Conn>,
+    pending: &WaitingMap,
+    db: &Option<Bytes>,
+    next_id: &mut u32,
+    e: io::Error,
+) -> Option<Conn> {
+    drop(conn);
+
+    let mut retry: Vec<_> = pending.lock().unwrap().drain().collect();
+    retry.sort_by_key(|(id, _)| *id);
+    let (failed, retry): (Vec<_>, Vec<_>) = retry.into_iter().partition(|(_, p)| p.retried);
+    fail(failed, &e);
+    if retry.is_empty() {
+        return None;
+    }
+
+    let (r, w, buf) = match reopen(addr, db, next_id).await {
+        Ok(parts) => parts,
+        Err(e) => {
+            eprintln!("error: could not reconnect to {}: {}", addr, e);
+            fail(retry, &e);
+            return None;
+        }
+    };
+    let mut conn = Conn::new(r, w, buf, pending.clone());
+
+    let mut frames = BytesMut::new();
+    {
+        let mut pending = pending.lock().unwrap();
+        for (_, mut p) in retry {
+            let id = take_id(next_id);
+            frames.extend_from_slice(&p.message.request_frame(id).expect("was written before"));
+            p.retried = true;
+            pending.insert(id, p);
+        }
+    }
+
+    match conn.w.write_all(&frames).await {
+        Ok(()) => Some(conn),
+        Err(e) => {
+            drop(conn);
+            let failed = pending.lock().unwrap().drain().collect();
+            fail(failed, &e);
+            None
+        }
+    }
+}
+
+/// Opens a new connection and picks `db` on it, returning what was read past the reply.
+async fn reopen(
+    addr: &str,
+    db: &Option<Bytes>,
+    next_id: &mut u32,
+) -> io::Result<(OwnedReadHalf, OwnedWriteHalf, BytesMut)> {
+    let (mut r, mut w) = connect(addr).await?.into_split();
+    let mut buf = BytesMut::new();
+    let Some(db) = db else {
+        return Ok((r, w, buf));
+    };
+
+    let id = take_id(next_id);
+    let frame = Message::Use(db.clone()).request_frame(id);
+    w.write_all(&frame.expect("use is a binary request"))
+        .await?;
+    loop {
+        if let Some((_, reply, n)) = Message::parse_reply_frame(&buf)? {
+            buf.advance(n);
+            return match reply {
+                Message::Success => Ok((r, w, buf)),
+                reply => Err(io::Error::other(format!(
+                    "could not use {} again: {:?}",
+                    String::from_utf8_lossy(db),
+                    reply
+                ))),
+            };
+        }
+        if 0 == r.read_buf(&mut buf).await? {
+            return Err(io::Error::from(io::ErrorKind::ConnectionReset));
+        }
+    }
+}
+
+fn fail(requests: Vec<(u32, Waiting)>, e: &io::Error) {
+    for (_, p) in requests {
+        let _ = p.reply.send(Err(io::Error::new(e.kind(), e.to_string())));
+    }
+}
+
+/// Answers pending requests as their replies arrive, until the connection fails.
+async fn read_replies(mut r: OwnedReadHalf, mut buf: BytesMut, pending: WaitingMap) -> io::Error {
+    loop {
+        loop {
+            match Message::parse_reply_frame(&buf) {
+                Ok(Some((id, reply, n))) => {
+                    buf.advance(n);
+                    if let Some(p) = pending.lock().unwrap().remove(&id) {
+                        let _ = p.reply.send(Ok(reply));
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => return e,
+            }
+        }
+
+        match r.read_buf(&mut buf).await {
+            Ok(0) => return io::Error::from(io::ErrorKind::ConnectionReset),
+            Ok(_) => {}
+            Err(e) => return e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        io,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use bytes::{Buf, Bytes, BytesMut};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        client::{Client, ClientError},
+        serverv2::message::Message,
+    };
+
+    type Store = Arc<Mutex<HashMap<(Bytes, Bytes), Bytes>>>;
+
+    /// Answers binary requests like the server does. The connection is closed without a reply
+    /// on getting `crash`, and the first time `drop` is got.
+    async fn serve(listener: TcpListener) -> io::Result<()> {
+        let store = Store::default();
+        let dropped = Arc::new(AtomicBool::new(false));
+        loop {
+            let (stream, _) = listener.accept().await?;
+            tokio::spawn(handle(stream, store.clone(), dropped.clone()));
+        }
+    }
+
+    async fn handle(
+        mut stream: TcpStream,
+        store: Store,
+        dropped: Arc<AtomicBool>,
+    ) -> io::Result<()> {
+        let mut db = Bytes::from("main");
+        let mut buf = BytesMut::new();
+        loop {
+            while let Some((id, message, n)) = Message::parse_frame(&buf)? {
+                buf.advance(n);
+                let res = match message {
+                    Message::Get(k) if k == "crash" => return Ok(()),
+                    Message::Get(k) if k == "drop" && !dropped.swap(true, Ordering::SeqCst) => {
+                        return Ok(())
+                    }
+                    Message::Get(k) => match store.lock().unwrap().get(&(db.clone(), k.clone())) {
+                        Some(v) => Message::Result(k, v.clone()),
+                        None => Message::NotFound,
+                    },
+                    Message::Insert(k, v) | Message::InsertEx(k, v, _) => {
+                        store.lock().unwrap().insert((db.clone(), k), v);
+                        Message::Success
+                    }
+                    Message::Delete(k) => {
+                        store.lock().unwrap().remove(&(db.clone(), k));
+                        Message::Success
+                    }
+                    Message::Use(name) if name == "missing" => {
+                        Message::Error("ERR".into(), "no such database".into())
+                    }
+                    Message::Use(name) => {
+                        db = name;
+                        Message::Success
+                    }
+                    message => message,
+                };
+                stream.write_all(&res.frame(id)).await?;
+            }
+
+            if 0 == stream.read_buf(&mut buf).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client() -> Result<(), ClientError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(serve(listener));
+
+        let client = Client::connect(addr.to_string()).await?;
+        client.insert(b"key1", b"value1").await?;
+        client.insert_ex(b"key2", b"value2", 60).await?;
+        client.insert(b"key3", b"value3").await?;
+        client.delete(b"key3").await?;
+
+        let got = client.get(b"key1").await?;
+        assert!(got.as_deref() == Some(&b"value1"[..]), "Got: {:?}", got);
+
+        let expected = vec![
+            Some(Bytes::from("value2")),
+            None,
+            Some(Bytes::from("value1")),
+        ];
+        let got = client.mget([b"key2", b"key3", b"key1"]).await?;
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        let got = client.use_db(b"missing").await;
+        assert!(
+            matches!(got, Err(ClientError::Server(_, _))),
+            "Got: {:?}",
+            got
+        );
+
+        // Requests are retried on a new connection, in the database that was picked
+        client.use_db(b"other").await?;
+        client.insert(b"key4", b"value4").await?;
+        let got = client.mget([&b"drop"[..], b"key4", b"key1"]).await?;
+        let expected = vec![None, Some(Bytes::from("value4")), None];
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        // But only once
+        let got = client.get(b"crash").await;
+        assert!(matches!(got, Err(ClientError::Io(_))), "Got: {:?}", got);
+
+        let got = client.get(b"key4").await?;
+        assert!(got.as_deref() == Some(&b"value4"[..]), "Got: {:?}", got);
+
+        Ok(())
+    }
+}
@@ -1,2 +1,5 @@
+#![allow(clippy::len_without_is_empty)]
+
+pub mod client;
 pub mod serverv2;
 pub mod storagev2;
@@ -1,6 +1,19 @@
-use hash_db::serverv2::server;
+use clap::Parser;
+use hash_db::serverv2::{
+    config::{Args, Config},
+    server,
+};
 
 #[tokio::main]
 async fn main() {
-    server::run().await
+    let args = Args::parse();
+    let config = match Config::load(args.clone()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    server::run(config, args).await
 }
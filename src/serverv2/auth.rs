@@ -0,0 +1,63 @@
+use tokio::net::unix::UCred;
+
+/// Authorizes unix socket clients by the credentials of the peer process, so local deployments
+/// don't need passwords. The user the server runs as is always allowed.
+#[derive(Debug, Clone, Default)]
+pub struct PeerAuth {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+    pub admins: Vec<u32>,
+}
+
+impl PeerAuth {
+    pub fn new(uids: &[u32], gids: &[u32]) -> Self {
+        Self {
+            uids: uids.to_vec(),
+            gids: gids.to_vec(),
+            admins: Vec::new(),
+        }
+    }
+
+    /// Users that can overwrite and delete keys owned by others, in addition to the user running
+    /// the server.
+    pub fn with_admins(mut self, admins: &[u32]) -> Self {
+        self.admins = admins.to_vec();
+
+        self
+    }
+
+    /// The user a connection from an allowed peer acts as.
+    pub fn user(&self, cred: &UCred) -> User {
+        let uid = cred.uid();
+        let admin = uid == nix::unistd::getuid().as_raw() || self.admins.contains(&uid);
+
+        User {
+            uid: Some(uid),
+            admin,
+        }
+    }
+
+    pub fn allows(&self, cred: &UCred) -> bool {
+        cred.uid() == nix::unistd::getuid().as_raw()
+            || self.uids.contains(&cred.uid())
+            || self.gids.contains(&cred.gid())
+    }
+}
+
+/// Who a connection acts as. Keys written by an authenticated user are owned by them and can only
+/// be overwritten or deleted by them or an admin. Anonymous connections, e.g. over TCP, write
+/// unowned keys and can't modify owned ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct User {
+    pub uid: Option<u32>,
+    pub admin: bool,
+}
+
+impl User {
+    pub fn may_modify(&self, owner: Option<u32>) -> bool {
+        match owner {
+            None => true,
+            Some(owner) => self.admin || self.uid == Some(owner),
+        }
+    }
+}
@@ -0,0 +1,596 @@
+use std::{io, path::PathBuf, str::FromStr, time::Duration};
+
+use clap::Parser;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    serverv2::message::Limits,
+    storagev2::{
+        alarm::Thresholds,
+        compaction::COMPACTION_INTERVAL,
+        db::Options,
+        disk::{Backend, Durability},
+        limit::{DEFAULT_MAX_FETCHES, DEFAULT_MAX_INSERTS},
+        log::Entry,
+        page::{PageCodec, PAGE_CAPACITY},
+        page_manager::DEFAULT_READ_SIZE,
+        pressure::{Pressure, DEFAULT_PRESSURE_FRAMES},
+        replacer::Policy,
+    },
+};
+
+/// Connections served at once by default, the same as Redis.
+pub const DEFAULT_MAX_CLIENTS: usize = 10000;
+
+#[derive(Debug, Default, Clone, Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// TOML file to read the configuration from. Flags override it.
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Address to accept TCP connections on.
+    #[arg(long)]
+    pub bind: Option<String>,
+
+    /// Unix socket to accept local connections on. Off unless set.
+    #[arg(long)]
+    pub unix_socket: Option<String>,
+
+    /// PEM certificate chain to terminate TLS with on the TCP listener. Requires --tls-key.
+    #[arg(long)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert.
+    #[arg(long)]
+    pub tls_key: Option<PathBuf>,
+
+    /// Database to serve as NAME=PATH, can be repeated. The first is the default.
+    #[arg(long = "db", value_parser = parse_database)]
+    pub databases: Vec<DatabaseConfig>,
+
+    /// When writes are fsynced: always, everysec or never.
+    #[arg(long, value_parser = |s: &str| s.parse::<Durability>())]
+    pub durability: Option<Durability>,
+
+    /// How pages are read and written: pread, or io_uring on builds with the io-uring feature.
+    #[arg(long, value_parser = |s: &str| s.parse::<Backend>())]
+    pub disk_backend: Option<Backend>,
+
+    /// Number of pages cached per database besides the current one.
+    #[arg(long)]
+    pub page_cache_size: Option<usize>,
+
+    /// Number of pages read into the cache after one that had to be read from disk, 0 for none.
+    #[arg(long)]
+    pub read_ahead_pages: Option<usize>,
+
+    /// Number of keys per database whose values are cached decoded, 0 for none.
+    #[arg(long)]
+    pub value_cache_size: Option<usize>,
+
+    /// How the page cache picks a page to evict: lru, clock, or lru-K for LRU-K, e.g. lru-2.
+    #[arg(long, value_parser = |s: &str| s.parse::<Policy>())]
+    pub replacer: Option<Policy>,
+
+    /// Seconds between compactions.
+    #[arg(long)]
+    pub compaction_interval: Option<u64>,
+
+    /// Connections served at once, past which new ones are sent an error and closed.
+    #[arg(long)]
+    pub max_clients: Option<usize>,
+
+    /// Bulk loads tab separated key and value lines from a file into the default database before
+    /// serving.
+    #[arg(long)]
+    pub import_fast: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: String,
+    /// With both set, TCP connections have to use TLS. The unix socket stays plain.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub unix_socket: Option<String>,
+    /// Users and groups allowed on the unix socket in addition to the user running the server.
+    pub unix_allowed_uids: Vec<u32>,
+    pub unix_allowed_gids: Vec<u32>,
+    /// Unix socket users that can overwrite and delete keys owned by others.
+    pub unix_admin_uids: Vec<u32>,
+
+    /// Each database has its own page cache, `KeyDir` and background tasks. Connections start
+    /// on the first and can switch with `use <name>`.
+    pub databases: Vec<DatabaseConfig>,
+    #[serde(deserialize_with = "durability")]
+    pub durability: Durability,
+    #[serde(deserialize_with = "backend")]
+    pub disk_backend: Backend,
+    /// Read pages that aren't cached from a memory mapping of the data file rather than through
+    /// the page cache.
+    pub mmap_reads: bool,
+    /// Keep the keys in order as well as hashed, so RANGE and SCAN only visit the keys they
+    /// return.
+    pub ordered_index: bool,
+    pub page_cache_size: usize,
+    /// Pages read into the cache in the background after one that had to be read from disk,
+    /// which helps sequential reads, e.g. scans and tailing the oplog. It evicts like any other
+    /// read once the cache is full. 0 turns it off.
+    pub read_ahead_pages: usize,
+    /// Keys per database whose values are kept decoded in front of the page cache, so GETs of
+    /// hot keys don't touch their page. 0 turns it off.
+    pub value_cache_size: usize,
+    #[serde(deserialize_with = "replacer")]
+    pub replacer: Policy,
+    pub compaction_interval_secs: u64,
+    /// GETs per database that can read pages from disk at once, and inserts that can be in
+    /// progress at once. Each is limited separately so one can't crowd out the other.
+    pub max_concurrent_fetches: usize,
+    pub max_concurrent_inserts: usize,
+    /// Values longer than this many bytes are stored LZ4 compressed when that saves space.
+    pub compression_threshold: Option<usize>,
+    /// Codec compaction rewrites cold pages with, only "lz4" for now. Unset leaves pages as they
+    /// are.
+    #[serde(deserialize_with = "page_codec")]
+    pub page_compression: Option<PageCodec>,
+    /// Alarms are logged, published to notification subscribers and reported by `health` while
+    /// a database has more keys, a larger data file or a larger fraction of dead bytes than
+    /// these. Unset ones aren't checked.
+    pub alarm_max_keys: Option<usize>,
+    pub alarm_max_disk_bytes: Option<u64>,
+    pub alarm_max_dead_ratio: Option<f64>,
+    /// Each database's page cache is shrunk to `memory_pressure_page_cache_size` frames, and
+    /// mapped pages dropped, while some task in the cgroup was stalled on memory for more than
+    /// this percentage of the last 10 seconds, or the server's resident set is larger than
+    /// `memory_pressure_max_rss`. It's restored once the pressure subsides. Unset ones aren't
+    /// checked.
+    pub memory_pressure_max_stall: Option<f64>,
+    pub memory_pressure_max_rss: Option<u64>,
+    pub memory_pressure_page_cache_size: usize,
+    /// Requests with longer keys or values are rejected. An entry with both at their longest has
+    /// to fit in a page.
+    ///
+    /// These, `durability` and `compaction_interval_secs` can be changed while the server runs,
+    /// see `Tunables`.
+    pub max_key_len: usize,
+    pub max_value_len: usize,
+
+    /// Connections past this many are sent an error and closed.
+    pub max_clients: usize,
+
+    /// How long connections keep being served after a shutdown signal.
+    pub shutdown_drain_timeout_secs: u64,
+    /// Whether reads are still served while draining. Writes are always rejected.
+    pub reads_during_shutdown: bool,
+
+    pub statsd_endpoint: Option<String>,
+    pub statsd_interval_secs: u64,
+    pub otlp_endpoint: Option<String>,
+    /// Another hash_db that writes to the default database are also forwarded to.
+    pub shadow_target: Option<String>,
+
+    /// Only set by flag, it's a one off rather than configuration.
+    #[serde(skip)]
+    pub import_fast: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:4444".into(),
+            tls_cert: None,
+            tls_key: None,
+            unix_socket: None,
+            unix_allowed_uids: Vec::new(),
+            unix_allowed_gids: Vec::new(),
+            unix_admin_uids: Vec::new(),
+
+            databases: vec![DatabaseConfig {
+                name: "main".into(),
+                path: "main.db".into(),
+            }],
+            durability: Durability::EverySec,
+            disk_backend: Backend::default(),
+            mmap_reads: false,
+            ordered_index: false,
+            page_cache_size: DEFAULT_READ_SIZE,
+            read_ahead_pages: 0,
+            value_cache_size: 0,
+            replacer: Policy::default(),
+            compaction_interval_secs: COMPACTION_INTERVAL.as_secs(),
+            max_concurrent_fetches: DEFAULT_MAX_FETCHES,
+            max_concurrent_inserts: DEFAULT_MAX_INSERTS,
+            compression_threshold: None,
+            page_compression: None,
+            alarm_max_keys: None,
+            alarm_max_disk_bytes: None,
+            alarm_max_dead_ratio: None,
+            memory_pressure_max_stall: None,
+            memory_pressure_max_rss: None,
+            memory_pressure_page_cache_size: DEFAULT_PRESSURE_FRAMES,
+            max_key_len: Limits::default().max_key_len,
+            max_value_len: Limits::default().max_value_len,
+
+            max_clients: DEFAULT_MAX_CLIENTS,
+
+            shutdown_drain_timeout_secs: 5,
+            reads_during_shutdown: true,
+
+            statsd_endpoint: None,
+            statsd_interval_secs: 10,
+            otlp_endpoint: None,
+            shadow_target: None,
+
+            import_fast: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file named in `args`, if any, and applies the flags on top.
+    pub fn load(args: Args) -> io::Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => Self::parse(&std::fs::read_to_string(path)?)?,
+            None => Self::default(),
+        };
+
+        if let Some(bind) = args.bind {
+            config.bind = bind;
+        }
+        if let Some(tls_cert) = args.tls_cert {
+            config.tls_cert = Some(tls_cert);
+        }
+        if let Some(tls_key) = args.tls_key {
+            config.tls_key = Some(tls_key);
+        }
+        if let Some(unix_socket) = args.unix_socket {
+            config.unix_socket = Some(unix_socket);
+        }
+        if !args.databases.is_empty() {
+            config.databases = args.databases;
+        }
+        if let Some(durability) = args.durability {
+            config.durability = durability;
+        }
+        if let Some(disk_backend) = args.disk_backend {
+            config.disk_backend = disk_backend;
+        }
+        if let Some(page_cache_size) = args.page_cache_size {
+            config.page_cache_size = page_cache_size;
+        }
+        if let Some(read_ahead_pages) = args.read_ahead_pages {
+            config.read_ahead_pages = read_ahead_pages;
+        }
+        if let Some(value_cache_size) = args.value_cache_size {
+            config.value_cache_size = value_cache_size;
+        }
+        if let Some(replacer) = args.replacer {
+            config.replacer = replacer;
+        }
+        if let Some(secs) = args.compaction_interval {
+            config.compaction_interval_secs = secs;
+        }
+        if let Some(max_clients) = args.max_clients {
+            config.max_clients = max_clients;
+        }
+        config.import_fast = args.import_fast;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn parse(s: &str) -> io::Result<Self> {
+        toml::from_str(s).map_err(|e| invalid(e.to_string()))
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        if self.databases.is_empty() {
+            return Err(invalid("at least one database is required".into()));
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(invalid("tls_cert and tls_key must be set together".into()));
+        }
+        if self.page_cache_size == 0 {
+            return Err(invalid("page_cache_size must be at least 1".into()));
+        }
+        if self.max_clients == 0 {
+            return Err(invalid("max_clients must be at least 1".into()));
+        }
+        if self.max_concurrent_fetches == 0 || self.max_concurrent_inserts == 0 {
+            return Err(invalid("concurrency limits must be at least 1".into()));
+        }
+        if self.compaction_interval_secs == 0 || self.statsd_interval_secs == 0 {
+            return Err(invalid("intervals must be at least 1 second".into()));
+        }
+        if self
+            .alarm_max_dead_ratio
+            .is_some_and(|ratio| !(0.0..1.0).contains(&ratio))
+        {
+            return Err(invalid(
+                "alarm_max_dead_ratio must be at least 0 and below 1".into(),
+            ));
+        }
+        if self
+            .memory_pressure_max_stall
+            .is_some_and(|stall| !(0.0..100.0).contains(&stall))
+        {
+            return Err(invalid(
+                "memory_pressure_max_stall must be at least 0 and below 100".into(),
+            ));
+        }
+        if !(1..=self.page_cache_size).contains(&self.memory_pressure_page_cache_size) {
+            return Err(invalid(
+                "memory_pressure_page_cache_size must be at least 1 and at most page_cache_size"
+                    .into(),
+            ));
+        }
+        self.tunables().validate()
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.tunables().limits()
+    }
+
+    pub fn tunables(&self) -> Tunables {
+        Tunables {
+            durability: self.durability,
+            compaction_interval_secs: self.compaction_interval_secs,
+            max_key_len: self.max_key_len,
+            max_value_len: self.max_value_len,
+        }
+    }
+
+    pub fn db_options(&self) -> Options {
+        Options {
+            durability: self.durability,
+            backend: self.disk_backend,
+            mmap_reads: self.mmap_reads,
+            ordered_index: self.ordered_index,
+            page_cache_size: self.page_cache_size,
+            read_ahead_pages: self.read_ahead_pages,
+            value_cache_size: self.value_cache_size,
+            replacer: self.replacer,
+            compaction_interval: self.tunables().compaction_interval(),
+            max_fetches: self.max_concurrent_fetches,
+            max_inserts: self.max_concurrent_inserts,
+            compression_threshold: self.compression_threshold,
+            page_codec: self.page_compression,
+            thresholds: Thresholds {
+                max_keys: self.alarm_max_keys,
+                max_disk_bytes: self.alarm_max_disk_bytes,
+                max_dead_ratio: self.alarm_max_dead_ratio,
+            },
+            pressure: Pressure {
+                max_stall: self.memory_pressure_max_stall,
+                max_rss: self.memory_pressure_max_rss,
+                frames: self.memory_pressure_page_cache_size,
+            },
+            ..Default::default()
+        }
+    }
+}
+
+/// The settings that can be changed while the server runs, with `config set` or by reloading the
+/// config file on SIGHUP. Databases keep their page caches. The rest take a restart.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tunables {
+    pub durability: Durability,
+    pub compaction_interval_secs: u64,
+    pub max_key_len: usize,
+    pub max_value_len: usize,
+}
+
+impl Tunables {
+    /// The value of the setting called `name`, `None` if it isn't one of them.
+    pub fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "durability" => Some(self.durability.to_string()),
+            "compaction_interval_secs" => Some(self.compaction_interval_secs.to_string()),
+            "max_key_len" => Some(self.max_key_len.to_string()),
+            "max_value_len" => Some(self.max_value_len.to_string()),
+            _ => None,
+        }
+    }
+
+    /// A copy with the setting called `name` parsed from `value`, if the result is valid.
+    pub fn set(&self, name: &str, value: &str) -> io::Result<Self> {
+        let mut tunables = *self;
+        match name {
+            "durability" => tunables.durability = value.parse()?,
+            "compaction_interval_secs" => tunables.compaction_interval_secs = number(name, value)?,
+            "max_key_len" => tunables.max_key_len = number(name, value)?,
+            "max_value_len" => tunables.max_value_len = number(name, value)?,
+            _ => {
+                return Err(invalid(format!(
+                    "{:?} is unknown or can't be changed while running",
+                    name
+                )))
+            }
+        }
+        tunables.validate()?;
+
+        Ok(tunables)
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        if self.compaction_interval_secs == 0 {
+            return Err(invalid("intervals must be at least 1 second".into()));
+        }
+        if !self.limits().fits_page() {
+            return Err(invalid(format!(
+                "max_key_len and max_value_len can add up to at most {}",
+                PAGE_CAPACITY - Entry::MAX_HEADER_LEN
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_key_len: self.max_key_len,
+            max_value_len: self.max_value_len,
+        }
+    }
+
+    pub fn compaction_interval(&self) -> Duration {
+        Duration::from_secs(self.compaction_interval_secs)
+    }
+}
+
+fn number<T: FromStr>(name: &str, value: &str) -> io::Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid(format!("{} must be a number, got {:?}", name, value)))
+}
+
+fn parse_database(s: &str) -> Result<DatabaseConfig, String> {
+    match s.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => Ok(DatabaseConfig {
+            name: name.into(),
+            path: path.into(),
+        }),
+        _ => Err(format!("expected NAME=PATH, got {:?}", s)),
+    }
+}
+
+fn durability<'de, D: Deserializer<'de>>(d: D) -> Result<Durability, D::Error> {
+    String::deserialize(d)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+fn backend<'de, D: Deserializer<'de>>(d: D) -> Result<Backend, D::Error> {
+    String::deserialize(d)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+fn replacer<'de, D: Deserializer<'de>>(d: D) -> Result<Policy, D::Error> {
+    String::deserialize(d)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+fn page_codec<'de, D: Deserializer<'de>>(d: D) -> Result<Option<PageCodec>, D::Error> {
+    String::deserialize(d)?
+        .parse()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn invalid(e: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::{
+        serverv2::config::{Args, Config, DatabaseConfig, Tunables},
+        storagev2::{disk::Durability, page::PageCodec, test::CleanUp},
+    };
+
+    #[test]
+    fn test_load() -> io::Result<()> {
+        const CONFIG_FILE: &str = "./test_load.toml";
+        let _cu = CleanUp::file(CONFIG_FILE);
+        std::fs::write(
+            CONFIG_FILE,
+            r#"
+            bind = "127.0.0.1:5555"
+            durability = "always"
+            page_cache_size = 16
+            page_compression = "lz4"
+
+            [[databases]]
+            name = "a"
+            path = "a.db"
+            "#,
+        )?;
+
+        let args = Args {
+            config: Some(CONFIG_FILE.into()),
+            durability: Some(Durability::Never),
+            ..Default::default()
+        };
+        let got = Config::load(args)?;
+        let expected = Config {
+            bind: "127.0.0.1:5555".into(),
+            databases: vec![DatabaseConfig {
+                name: "a".into(),
+                path: "a.db".into(),
+            }],
+            durability: Durability::Never,
+            page_cache_size: 16,
+            page_compression: Some(PageCodec::Lz4),
+            ..Default::default()
+        };
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        assert!(Config::parse("durability = \"sometimes\"").is_err());
+        assert!(Config::parse("page_compression = \"zip\"").is_err());
+        assert!(Config::parse("unknown = 1").is_err());
+
+        let config = Config::parse("max_value_len = 1000000")?;
+        assert!(config.validate().is_err());
+        let config = Config::parse("memory_pressure_page_cache_size = 0")?;
+        assert!(config.validate().is_err());
+        let config = Config::parse("max_clients = 0")?;
+        assert!(config.validate().is_err());
+
+        let args = Args {
+            tls_cert: Some("cert.pem".into()),
+            ..Default::default()
+        };
+        assert!(Config::load(args).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tunables() -> io::Result<()> {
+        let tunables = Config::default().tunables();
+
+        let got = tunables.set("durability", "always")?;
+        let expected = Tunables {
+            durability: Durability::Always,
+            ..tunables
+        };
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        let got = got.get("durability");
+        assert!(got.as_deref() == Some("always"), "Got: {:?}", got);
+
+        let got = tunables.set("compaction_interval_secs", "5")?;
+        assert!(got.compaction_interval_secs == 5, "Got: {:?}", got);
+
+        assert!(tunables.set("durability", "sometimes").is_err());
+        assert!(tunables.set("compaction_interval_secs", "0").is_err());
+        assert!(tunables.set("max_value_len", "1000000").is_err());
+        assert!(tunables.set("page_cache_size", "16").is_err());
+        assert!(tunables.get("page_cache_size").is_none());
+
+        Ok(())
+    }
+}
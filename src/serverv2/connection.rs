@@ -3,12 +3,31 @@ use std::io;
 use bytes::{Buf, Bytes, BytesMut};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::serverv2::message::Message;
+use crate::serverv2::{
+    message::{Limits, Message, FRAME_MAGIC},
+    resp::{self, Command, Version},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Unknown,
+    Line,
+    Resp(Version),
+    /// Length prefixed frames, see `Message::parse_frame`.
+    Binary,
+}
 
 pub struct Connection<R, W> {
     r: R,
     w: W,
     buf: bytes::BytesMut,
+    protocol: Protocol,
+    /// Id of the binary request being answered. Requests are answered in the order they arrive,
+    /// and each response carries the id of its request so pipelining clients can match them up.
+    id: u32,
+    /// Whether the next reply answers a `Command::Counted` request.
+    counted: bool,
+    limits: Limits,
 }
 
 impl<R, W> Connection<R, W>
@@ -18,16 +37,103 @@ where
 {
     pub fn new(r: R, w: W) -> Self {
         let buf = BytesMut::with_capacity(4 * 1024);
+        let protocol = Protocol::Unknown;
 
-        Self { r, w, buf }
+        Self {
+            r,
+            w,
+            buf,
+            protocol,
+            id: 0,
+            counted: false,
+            limits: Limits::default(),
+        }
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+
+        self
+    }
+
+    /// Requests already read were checked against the old limits.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
     }
 
     pub async fn read(&mut self) -> io::Result<Option<Message>> {
         loop {
-            if let Some(message) = Message::parse(&self.buf) {
-                self.buf.advance(message.len());
+            if self.protocol == Protocol::Unknown && !self.buf.is_empty() {
+                // Redis clients always send commands as RESP arrays
+                self.protocol = match self.buf[0] {
+                    b'*' => Protocol::Resp(Version::Resp2),
+                    FRAME_MAGIC => Protocol::Binary,
+                    _ => Protocol::Line,
+                };
+            }
 
-                return Ok(Some(message));
+            match self.protocol {
+                Protocol::Unknown => {}
+                Protocol::Line => {
+                    // Blank lines aren't requests, so they go unanswered
+                    if self.buf.starts_with(b"\n") {
+                        self.buf.advance(1);
+                        continue;
+                    }
+
+                    if let Some(message) = Message::parse(&self.buf) {
+                        self.buf.advance(message.len());
+
+                        match message.unquoted() {
+                            Message::Invalid(_, e) => self.write(Message::error(e)).await?,
+                            message => {
+                                if let Some(message) = self.within_limits(message).await? {
+                                    return Ok(Some(message));
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                }
+                Protocol::Resp(version) => {
+                    if let Some((args, n)) = resp::parse(&self.buf)? {
+                        self.buf.advance(n);
+
+                        match resp::command(args) {
+                            Command::Message(message) => {
+                                if let Some(message) = self.within_limits(message).await? {
+                                    return Ok(Some(message));
+                                }
+                            }
+                            Command::Counted(message) => {
+                                self.counted = true;
+                                if let Some(message) = self.within_limits(message).await? {
+                                    return Ok(Some(message));
+                                }
+                            }
+                            command => self.reply(command, version).await?,
+                        }
+
+                        continue;
+                    }
+                }
+                Protocol::Binary => {
+                    if let Some((id, message, n)) = Message::parse_frame(&self.buf)? {
+                        self.buf.advance(n);
+                        self.id = id;
+
+                        match message {
+                            Message::Error(_, _) => self.write(message).await?,
+                            message => {
+                                if let Some(message) = self.within_limits(message).await? {
+                                    return Ok(Some(message));
+                                }
+                            }
+                        }
+
+                        continue;
+                    }
+                }
             }
 
             if 0 == self.r.read_buf(&mut self.buf).await? {
@@ -37,10 +143,99 @@ where
     }
 
     pub async fn write(&mut self, m: Message) -> io::Result<()> {
-        let b: Bytes = m.into();
-        self.w.write_all(&b).await?;
+        let b: Bytes = match self.protocol {
+            Protocol::Resp(version) if std::mem::take(&mut self.counted) => {
+                resp::encode_count(m, version)
+            }
+            Protocol::Resp(version) => resp::encode(m, version),
+            Protocol::Binary => m.frame(self.id),
+            Protocol::Unknown | Protocol::Line => m.into(),
+        };
+
+        self.write_bytes(&b).await
+    }
+
+    /// Passes `message` on if it's within the limits, otherwise replies with why it isn't.
+    async fn within_limits(&mut self, message: Message) -> io::Result<Option<Message>> {
+        match message.check_limits(&self.limits) {
+            Ok(()) => Ok(Some(message)),
+            Err(e) => {
+                self.write(e).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Replies to RESP commands that are handled by the connection rather than executed against
+    /// the database.
+    async fn reply(&mut self, command: Command, version: Version) -> io::Result<()> {
+        let b = match command {
+            Command::Hello(None) => resp::encode_hello(version),
+            Command::Hello(Some(v)) => match &v[..] {
+                b"2" => {
+                    self.protocol = Protocol::Resp(Version::Resp2);
+                    resp::encode_hello(Version::Resp2)
+                }
+                b"3" => {
+                    self.protocol = Protocol::Resp(Version::Resp3);
+                    resp::encode_hello(Version::Resp3)
+                }
+                _ => resp::encode_error("NOPROTO unsupported protocol version"),
+            },
+            Command::Ping(payload) => resp::encode_pong(payload),
+            Command::Unknown(name) => resp::encode_error(&format!(
+                "ERR unknown command '{}'",
+                String::from_utf8_lossy(&name)
+            )),
+            Command::Message(_) | Command::Counted(_) => unreachable!(),
+        };
+
+        self.write_bytes(&b).await
+    }
+
+    async fn write_bytes(&mut self, b: &[u8]) -> io::Result<()> {
+        self.w.write_all(b).await?;
         self.w.flush().await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::{
+        serverv2::{auth::User, connection::Connection},
+        storagev2::{
+            db::{Db, Options},
+            test::CleanUp,
+        },
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unknown_command() -> io::Result<()> {
+        const DB_FILE: &str = "./test_unknown_command.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+
+        // Only the unknown line is dropped, the request after it is still answered
+        let mut out = Vec::new();
+        let mut conn = Connection::new(&b"foo\n\nget key1\n"[..], &mut out);
+        while let Ok(Some(message)) = conn.read().await {
+            let res = message.exec(&db, &User::default()).await;
+            conn.write(res).await?;
+        }
+        drop(conn);
+
+        let expected = b"Error ERR unknown command\nNotFound\n";
+        assert!(
+            out == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&out)
+        );
+
+        Ok(())
+    }
+}
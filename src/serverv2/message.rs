@@ -1,188 +1,4621 @@
-use std::{io::Cursor, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Cursor},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
-use bytes::{Buf, Bytes, BytesMut};
 use tokio::sync::RwLock;
 
-use crate::storagev2::{
-    key_dir::{KeyData, KeyDir},
-    log::{Entry, EntryType},
-    page::PageError,
-    page_manager::PageCache,
+use crate::{
+    serverv2::{auth::User, quote},
+    storagev2::{
+        alarm::Alarm,
+        compaction::Compactor,
+        db::Db,
+        error::StorageError,
+        expiry,
+        key_dir::{KeyData, KeyDir},
+        log::{self, Entry, EntryType},
+        merge::MergeOp,
+        page::{PAGE_CAPACITY, PAGE_SIZE},
+        txn::ReadTxn,
+        validate::Rule,
+    },
 };
 
-#[derive(Debug, PartialEq)]
+const CORRUPT: (&str, &str) = ("CORRUPT", "entry failed its checksum");
+const BATCH_WRITES_ONLY: (&str, &str) = ("ERR", "only inserts and deletes can be batched");
+const NOPERM: (&str, &str) = ("NOPERM", "key is owned by another user");
+const FENCED: (&str, &str) = (
+    "FENCED",
+    "a write to the key carried a higher fencing token",
+);
+const FENCED_WRITES_ONLY: (&str, &str) = ("ERR", "only inserts and deletes can be fenced");
+const GROUPED_WRITES_ONLY: (&str, &str) = ("ERR", "only inserts can be grouped");
+const INVALID_CURSOR: (&str, &str) = ("ERR", "invalid cursor");
+const ADMIN_ONLY: (&str, &str) = ("NOPERM", "only admins can change validators");
+const VALUE_TOO_LARGE: (&str, &str) = ("ERR", "value would not fit in a page");
+const BATCH_TOO_LARGE: (&str, &str) = ("ERR", "batch writes to a database must fit in a page");
+const MSET_TOO_LARGE: (&str, &str) = ("ERR", "mset must fit in a page");
+const KEY_TOO_LONG: (&str, &str) = ("ERR", "key is longer than max_key_len");
+const VALUE_TOO_LONG: (&str, &str) = ("ERR", "value is longer than max_value_len");
+pub const UNKNOWN_DATABASE: (&str, &str) = ("ERR", "unknown database");
+const UNKNOWN_COMMAND: (&str, &str) = ("ERR", "unknown command");
+const INVALID_EXPIRE_TIME: (&str, &str) = ("ERR", "invalid expire time");
+const WRONG_ARGUMENTS: (&str, &str) = ("ERR", "wrong number of arguments");
+const INVALID_DB_INDEX: (&str, &str) = ("ERR", "invalid DB index");
+const INVALID_LIMIT: (&str, &str) = ("ERR", "invalid limit");
+const INVALID_OPLOG_POSITION: (&str, &str) = ("ERR", "invalid oplog position");
+const INVALID_FENCE_TOKEN: (&str, &str) = ("ERR", "invalid fencing token");
+const EMPTY_GROUP: (&str, &str) = ("ERR", "group name is empty");
+const INVALID_OFFSET: (&str, &str) = ("ERR", "invalid offset");
+const INVALID_QUOTING: (&str, &str) = ("ERR", "invalid quoting");
+
+/// Longest keys by default, the rest of a page is left to the value.
+pub const DEFAULT_MAX_KEY_LEN: usize = PAGE_SIZE / 16;
+
+/// Longest keys and values clients can send. Requests are checked against them as they're read,
+/// see `Message::check_limits`, so an entry that can't fit in a page never reaches the write path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub max_key_len: usize,
+    pub max_value_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_key_len: DEFAULT_MAX_KEY_LEN,
+            max_value_len: PAGE_CAPACITY - Entry::MAX_HEADER_LEN - DEFAULT_MAX_KEY_LEN,
+        }
+    }
+}
+
+impl Limits {
+    /// Whether an entry with the longest key and value fits in a page.
+    pub fn fits_page(&self) -> bool {
+        self.max_key_len
+            .checked_add(self.max_value_len)
+            .and_then(|len| len.checked_add(Entry::MAX_HEADER_LEN))
+            .is_some_and(|len| len <= PAGE_CAPACITY)
+    }
+}
+
+/// Keys returned by each `scan`.
+pub const SCAN_COUNT: usize = 100;
+
+/// Cursor that starts a scan, and is returned once it is complete.
+const SCAN_START: &[u8] = b"0";
+
+const COMPACTION_ESTIMATE: &[u8] = b"compaction estimate\n";
+const PAGE_FILL: &[u8] = b"page fill\n";
+const NOTIFICATIONS: &[u8] = b"notifications\n";
+const STATS_BOOTSTRAP: &[u8] = b"stats bootstrap\n";
+const EXPIRY_FORECAST: &[u8] = b"expiryforecast\n";
+const VALIDATORS: &[u8] = b"validators\n";
+const DBSIZE: &[u8] = b"dbsize\n";
+const MGET_CONSISTENT: &[u8] = b"mget-consistent ";
+const MEMORY_USAGE: &[u8] = b"memory usage ";
+const GROUP_OPTION: &[u8] = b"--group ";
+const PREFIX_OPTION: &[u8] = b"--prefix ";
+const VALUES_OPTION: &[u8] = b"--values ";
+/// The commands that accept the quoted form, see `quote`. `mget-consistent ` comes before
+/// `mget ` so it isn't read as a key.
+const QUOTED_COMMANDS: [&[u8]; 10] = [
+    b"insert ",
+    b"get ",
+    b"delete ",
+    b"unlink ",
+    b"exists ",
+    b"strlen ",
+    MEMORY_USAGE,
+    MGET_CONSISTENT,
+    b"mget ",
+    b"mset ",
+];
+const HEALTH: &[u8] = b"health\n";
+const INFO: &[u8] = b"info\n";
+const BEGIN: &[u8] = b"begin\n";
+const COMMIT: &[u8] = b"commit\n";
+const DISCARD: &[u8] = b"discard\n";
+const FLUSHDB: &[u8] = b"flushdb\n";
+
+const INVALID_EXPORT_FILE: (&str, &str) = (
+    "ERR",
+    "export file must be a file name in the server directory",
+);
+const INVALID_SNAPSHOT_DIR: (&str, &str) = (
+    "ERR",
+    "snapshot directory must be a directory name in the server directory",
+);
+
+/// First byte of every binary protocol frame. Line protocol commands and RESP arrays never start
+/// with it, so connections pick the protocol from their first byte.
+pub const FRAME_MAGIC: u8 = 0xDB;
+
+// magic + opcode + request id + key_len + value_len
+const FRAME_HEADER_LEN: usize = 1 + 1 + 4 + 4 + 4;
+
+/// Frames are buffered whole before they're parsed, and an entry has to fit in a page anyway.
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+
+// Request opcodes. SETEX values start with the expiry in seconds as a u64
+const OP_GET: u8 = 0x01;
+const OP_SET: u8 = 0x02;
+const OP_SETEX: u8 = 0x03;
+const OP_DEL: u8 = 0x04;
+const OP_USE: u8 = 0x05;
+
+// Response opcodes. VALUE carries a key and value, ERROR the error code as its key and the
+// description as its value
+pub const OP_OK: u8 = 0x80;
+pub const OP_VALUE: u8 = 0x81;
+pub const OP_NOT_FOUND: u8 = 0x82;
+pub const OP_ERROR: u8 = 0x83;
+
+const UNKNOWN_OPCODE: (&str, &str) = ("ERR", "unknown opcode");
+const INVALID_SETEX: (&str, &str) = ("ERR", "SETEX value must start with a u64 expiry");
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Message {
     Insert(Bytes, Bytes),
+    InsertEx(Bytes, Bytes, u64),
     Delete(Bytes),
+    /// Overwrites part of a key's value from an offset, padding it with zeroes if it's shorter,
+    /// and reports the new length. A key that isn't set is treated as empty.
+    SetRange(Bytes, u64, Bytes),
+    /// Merges an operand into a key's value with the operator it names, e.g. `add`, and replies
+    /// with the new value, see `MergeOp`. The expiry is kept.
+    Merge(Bytes, Bytes, Bytes),
+    /// Appends to a key's value, setting it if it isn't, and reports the new length. The expiry
+    /// is kept.
+    Append(Bytes, Bytes),
+    /// Sets a key and replies with the value it had, or `NotFound`.
+    GetSet(Bytes, Bytes),
+    /// Sets a key only if it isn't set, reporting 1 if it was set and 0 if not.
+    SetNx(Bytes, Bytes),
+    /// Deletes a key and replies with the value it had, or `NotFound`.
+    GetDel(Bytes),
+    /// Deletes a key like `Delete`, reporting 1 if it was set and 0 if not from the `KeyDir`, so
+    /// the value isn't read. Only sent as RESP's `DEL`.
+    Del(Bytes),
+    /// Sets a key to expire in a number of seconds, reporting 1 if it's set and 0 if not. The
+    /// entry is rewritten so the expiry survives a restart.
+    Expire(Bytes, u64),
+    /// Like `Expire`, at a unix timestamp in seconds.
+    ExpireAt(Bytes, u64),
+    /// Removes a key's expiry, reporting 1 if it had one and 0 if not.
+    Persist(Bytes),
+    /// Reports the seconds until a key expires, -1 if it doesn't and -2 if it isn't set.
+    Ttl(Bytes),
+    /// Deletes a key without waiting for its tombstone to be written, see `Db::unlink_later`.
+    Unlink(Bytes),
     Get(Bytes),
+    /// Reports 1 if the key is live and 0 if not, from the `KeyDir` alone.
+    Exists(Bytes),
+    /// Reports the length of the key's value, 0 if it isn't set.
+    Strlen(Bytes),
+    /// Reports roughly how many bytes a key takes up in the `KeyDir` and the data file, see
+    /// `KeyDir::memory_usage`.
+    MemoryUsage(Bytes),
+    /// Reads many keys in one round trip, see `Db::read_many`. Consistent reads see every key as
+    /// it was at one point, see `Db::read_consistent`.
+    MGet(Vec<Bytes>, bool),
+    /// Sets many keys in one round trip. Like a `Batch`, the pairs have to fit in a page, so they
+    /// are written and become visible together or not at all.
+    MSet(Vec<(Bytes, Bytes)>),
+    Use(Bytes),
+    /// Switches to the database at an index in the configuration, like `Use` does by name.
+    Select(u64),
+    /// Reports the number of live keys in the database.
+    DbSize,
+    /// Deletes every key in the database.
+    FlushDb,
+    /// Writes framed by `multi` and `exec`, applied atomically. A `Use` inside the batch sends the
+    /// writes after it to another database, see `batch_across`.
+    Batch(Vec<Message>),
+    /// Continues a scan from a cursor, optionally only over keys with a prefix.
+    Scan(Bytes, Option<Bytes>),
+    /// Lists up to a limit of keys from a start key up to an end key, in order, optionally with
+    /// their values. Defaults to `SCAN_COUNT` keys, see `range`.
+    Range(Bytes, Bytes, Option<usize>, bool),
+    /// Lists the keys matching a glob pattern, see `glob`. The server streams them in chunks of
+    /// `SCAN_COUNT`, each written like a `Scan` reply, and the last with the start cursor.
+    KeysMatching(Bytes),
+    /// Reports what a compaction of the database would reclaim, without compacting.
+    CompactionEstimate,
+    /// Reports how full pages were when they were replaced.
+    PageFill,
+    /// Reports what bootstrapping the data file found when the database was opened.
+    BootstrapStats,
+    /// Reports `ok`, or `warn` with the alarms raised, see `alarm::check`.
+    Health,
+    /// Reports server, keyspace, page cache and compaction stats as `section.field:value` pairs.
+    /// The server adds its own section, see `info`.
+    Info,
+    /// Reports how many keys, and bytes, expire within the next minutes and hours, see
+    /// `expiry::forecast`.
+    ExpiryForecast,
+    /// Writes the sorted key list to a file in the server's directory, optionally with value
+    /// sizes and write times.
+    ExportKeys(Bytes, bool),
+    /// Writes the live dataset as an `SsTable` to a file in the server's directory.
+    ExportSstable(Bytes),
+    /// Copies the data file and `KeyDir` to a directory in the server's directory, without
+    /// blocking writers.
+    Snapshot(Bytes),
+    /// A write carrying a fencing token from an external lock service. It is rejected if an
+    /// earlier write to the key carried a higher token. The token is forgotten once the key is
+    /// deleted or expires.
+    Fenced(u64, Box<Message>),
+    /// An insert under a locality group, so its entry is kept in the same pages as the other
+    /// entries of the group where possible, see `Entry::group`.
+    Grouped(Bytes, Box<Message>),
+    /// A request read in the quoted form with the length of its line, since `len` can't
+    /// recompute it from the message. The connection unwraps it once read, see `unquoted`.
+    Quoted(usize, Box<Message>),
+    /// Adds a rule values inserted under a prefix have to pass, see `Rule`. Admins only.
+    Validate(Bytes, Bytes),
+    /// Removes every rule for a prefix. Admins only.
+    Unvalidate(Bytes),
+    /// Lists the prefixes with rules.
+    Validators,
+    /// Turns the connection into a stream of events for keys the database removed on its own,
+    /// see `Event`.
+    Notifications,
+    /// Starts a transaction. Reads until the `Commit` see the keyspace as it was when it began,
+    /// see `ReadTxn`, and inserts and deletes are queued until then and applied as a `Batch`.
+    Begin,
+    Commit,
+    /// Ends a transaction, dropping the writes it queued.
+    Discard,
+    /// Turns the connection into a stream of the keys clients set or delete, that are the key or
+    /// start with it if it's a prefix, see `Change`.
+    Watch(Bytes, bool),
+    /// Turns the connection into a stream of the payloads published to a channel. Channels are
+    /// independent of the keyspace and of databases.
+    Subscribe(Bytes),
+    /// Sends a payload to a channel's subscribers and reports how many there were.
+    Publish(Bytes, Bytes),
+    /// Turns the connection into a stream of every write from a sequence number on, read back
+    /// from the log and then followed as it grows, see `Oplog`.
+    SubscribeOplog(u64),
+    /// Reads a setting that can be changed while the server runs, see `Tunables`.
+    ConfigGet(Bytes),
+    /// Changes a setting for every database and connection without a restart. Admins only.
+    ConfigSet(Bytes, Bytes),
 
     Result(Bytes, Bytes),
+    /// The keys read by an `MGet`, in the order they were asked for, with their values if found.
+    Values(Vec<(Bytes, Option<Bytes>)>),
+    /// A chunk of scanned keys with the cursor to continue from.
+    Keys(Bytes, Vec<Bytes>),
+    /// Free form text, e.g. a report.
+    Text(Bytes),
 
     Success,
-    Ignore(usize),
+    /// The key that was asked for isn't set.
+    NotFound,
+    /// A one word code clients can match on, e.g. `ERR` or `NOPERM`, and a description.
+    Error(Bytes, Bytes),
+    /// A line that can't be parsed, the bytes it takes up and the error to answer it with.
+    Invalid(usize, (&'static str, &'static str)),
+    /// Nothing to reply, e.g. for a message the server handles itself.
     None,
 }
 
 impl Message {
-    pub async fn exec(&self, m: &PageCache, kd: &Arc<RwLock<KeyDir>>) -> Message {
+    pub fn error((code, text): (&'static str, &'static str)) -> Self {
+        Message::Error(code.into(), text.into())
+    }
+
+    pub async fn exec(&self, db: &Db, user: &User) -> Message {
+        let (m, kd) = (&db.pc, &db.kd);
+
         match self {
-            Message::Insert(k, v) => {
-                let mut current = m.get_current().await;
+            Message::Insert(k, v) => insert(db, user, k, v, None, None, None).await,
+            Message::InsertEx(k, v, secs) => {
+                insert(
+                    db,
+                    user,
+                    k,
+                    v,
+                    Some(db.now().saturating_add(*secs)),
+                    None,
+                    None,
+                )
+                .await
+            }
+            Message::Grouped(group, message) => grouped(db, user, group, message, None).await,
+            Message::Delete(k) => match delete(db, user, k, None).await {
+                Ok(_) => Message::Success,
+                Err(e) => e,
+            },
+            Message::Del(k) => match delete(db, user, k, None).await {
+                Ok(live) => Message::Text(if live { "1" } else { "0" }.into()),
+                Err(e) => e,
+            },
+            Message::Unlink(k) => unlink(db, user, k).await,
+            Message::SetRange(k, offset, v) => set_range(db, user, k, *offset, v).await,
+            Message::Merge(op, k, operand) => merge(db, user, op, k, operand).await,
+            Message::Append(k, v) => append(db, user, k, v).await,
+            Message::GetSet(k, v) => get_set(db, user, k, v).await,
+            Message::SetNx(k, v) => set_nx(db, user, k, v).await,
+            Message::GetDel(k) => get_del(db, user, k).await,
+            Message::Expire(k, secs) => {
+                set_expiry(db, user, k, Some(db.now().saturating_add(*secs))).await
+            }
+            Message::ExpireAt(k, ts) => set_expiry(db, user, k, Some(*ts)).await,
+            Message::Persist(k) => set_expiry(db, user, k, None).await,
+            Message::Ttl(k) => ttl(&*kd.read().await, k, db.now()),
+            Message::Fenced(token, message) => match &**message {
+                Message::Insert(k, v) => insert(db, user, k, v, None, Some(*token), None).await,
+                Message::InsertEx(k, v, secs) => {
+                    insert(
+                        db,
+                        user,
+                        k,
+                        v,
+                        Some(db.now().saturating_add(*secs)),
+                        Some(*token),
+                        None,
+                    )
+                    .await
+                }
+                Message::Grouped(group, message) => {
+                    grouped(db, user, group, message, Some(*token)).await
+                }
+                Message::Delete(k) => match delete(db, user, k, Some(*token)).await {
+                    Ok(_) => Message::Success,
+                    Err(e) => e,
+                },
+                _ => Message::error(FENCED_WRITES_ONLY),
+            },
+            Message::Get(k) => match db.read(k).await {
+                Ok(Some(entry)) => Message::Result(entry.key.into(), entry.value.into()),
+                Ok(None) => Message::NotFound,
+                Err(e) => storage_error(e),
+            },
+            Message::Exists(k) => {
+                let now = db.now();
+                let exists = kd
+                    .read()
+                    .await
+                    .get(k)
+                    .is_some_and(|data| !data.is_expired(now));
 
-                let entry = Entry::new(k, v, EntryType::Put);
-                let offset = match current.write_entry(&entry) {
-                    Ok(o) => o,
-                    Err(e) if e == PageError::NotEnoughSpace => {
-                        if let Err(e) = m.replace_current(&mut current).await {
-                            todo!()
-                        }
+                Message::Text(if exists { "1" } else { "0" }.into())
+            }
+            Message::Strlen(k) => match db.read(k).await {
+                Ok(entry) => Message::Text(entry.map_or(0, |e| e.value.len()).to_string().into()),
+                Err(e) => storage_error(e),
+            },
+            Message::MemoryUsage(k) => memory_usage(&*kd.read().await, k, db.now()),
 
-                        current.write_entry(&entry).unwrap()
-                    }
-                    Err(e) => {
-                        todo!()
-                    }
+            Message::MGet(keys, consistent) => {
+                let entries = match consistent {
+                    true => db.read_consistent(keys).await,
+                    false => db.read_many(keys).await,
                 };
 
-                let data = KeyData::new(current.id, offset);
-                kd.write().await.insert(k, data);
+                match entries {
+                    Ok(entries) => values(keys, entries),
+                    Err(e) => storage_error(e),
+                }
+            }
+            Message::MSet(pairs) => mset(db, user, pairs).await,
+            Message::Batch(messages) => batch(db, user, messages).await,
+            Message::Scan(cursor, prefix) => scan(&*kd.read().await, cursor, prefix, db.now()),
+            Message::Range(start, end, limit, values) => {
+                let (keys, cursor) = range(&*kd.read().await, start, end, *limit, db.now());
+                if !*values {
+                    return Message::Keys(cursor, keys);
+                }
+
+                match db.read_many(&keys).await {
+                    Ok(entries) => with_values(cursor, entries),
+                    Err(e) => storage_error(e),
+                }
+            }
+            Message::CompactionEstimate => {
+                let compactor = Compactor::new(m.clone(), kd.clone());
+                match compactor.estimate().await {
+                    Ok(e) => Message::Text(
+                        format!(
+                            "pages_scanned:{} pages_reclaimable:{} live_bytes:{} dead_bytes:{} bytes_reclaimable:{}",
+                            e.pages_scanned,
+                            e.pages_reclaimable,
+                            e.live_bytes,
+                            e.dead_bytes,
+                            e.bytes_reclaimable
+                        )
+                        .into(),
+                    ),
+                    Err(e) => Message::Error("ERR".into(), e.to_string().into()),
+                }
+            }
+            Message::PageFill => {
+                let fill = m.fill();
+                let mut text = format!("pages:{} wasted_bytes:{}", fill.pages, fill.wasted_bytes);
+                for (i, n) in fill.buckets.iter().enumerate() {
+                    text.push_str(&format!(" fill_{}:{}", i * 10, n));
+                }
+
+                Message::Text(text.into())
+            }
+            Message::Validate(prefix, rule) => {
+                if !user.admin {
+                    return Message::error(ADMIN_ONLY);
+                }
+
+                let rule = match std::str::from_utf8(rule).map(str::parse::<Rule>) {
+                    Ok(Ok(rule)) => rule,
+                    Ok(Err(e)) => return Message::Error("ERR".into(), e.to_string().into()),
+                    Err(e) => return Message::Error("ERR".into(), e.to_string().into()),
+                };
+                db.validators().add(prefix, rule);
 
                 Message::Success
             }
-            Message::Delete(k) => {
-                let mut current = m.get_current().await;
-
-                let entry = Entry::new(k, &[], EntryType::Delete);
-                if let Err(e) = current.write_entry(&entry) {
-                    if e == PageError::NotEnoughSpace {
-                        if let Err(e) = m.replace_current(&mut current).await {
-                            todo!()
-                        }
-                        current.write_entry(&entry).unwrap();
-                    } else {
-                        todo!()
+            Message::Unvalidate(prefix) => {
+                if !user.admin {
+                    return Message::error(ADMIN_ONLY);
+                }
+
+                db.validators().remove(prefix);
+                Message::Success
+            }
+            Message::Validators => {
+                let rules: Vec<_> = db
+                    .validators()
+                    .list()
+                    .into_iter()
+                    .map(|(prefix, rule)| format!("{} {}", String::from_utf8_lossy(&prefix), rule))
+                    .collect();
+
+                Message::Text(rules.join(", ").into())
+            }
+            Message::Health => {
+                let alarms: Vec<_> = db.alarms().iter().map(Alarm::name).collect();
+
+                match alarms.is_empty() {
+                    true => Message::Text("status:ok".into()),
+                    false => {
+                        Message::Text(format!("status:warn alarms:{}", alarms.join(",")).into())
+                    }
+                }
+            }
+            Message::Info => info(db, None).await,
+            Message::DbSize => db_size(&*kd.read().await, db.now()),
+            Message::FlushDb => flush_db(db, user).await,
+            Message::ExpiryForecast => {
+                match expiry::forecast(m, kd, &expiry::FORECAST_HORIZONS).await {
+                    Ok(forecast) => {
+                        let text: Vec<_> = forecast
+                            .iter()
+                            .map(|f| {
+                                let within = horizon(f.within);
+                                format!("keys_{0}:{1} bytes_{0}:{2}", within, f.keys, f.bytes)
+                            })
+                            .collect();
+
+                        Message::Text(text.join(" ").into())
                     }
+                    Err(e) => storage_error(e),
+                }
+            }
+            Message::BootstrapStats => {
+                let r = db.bootstrap_report();
+
+                Message::Text(
+                    format!(
+                        "pages:{} entries:{} tombstones:{} expired:{} corrupt:{} torn:{} discarded_bytes:{} keys:{} checkpoint:{} duration_ms:{}",
+                        r.pages,
+                        r.entries,
+                        r.tombstones,
+                        r.expired,
+                        r.corrupt,
+                        r.torn,
+                        r.discarded_bytes,
+                        r.keys,
+                        u8::from(r.checkpoint),
+                        r.duration.as_millis()
+                    )
+                    .into(),
+                )
+            }
+            Message::ExportKeys(file, meta) => {
+                let Some(file) = export_file(file) else {
+                    return Message::error(INVALID_EXPORT_FILE);
                 };
 
-                kd.write().await.remove(k);
+                match db.export_keys(file, *meta).await {
+                    Ok(n) => Message::Text(format!("exported {} keys", n).into()),
+                    Err(e) => Message::Error("ERR".into(), e.to_string().into()),
+                }
+            }
+            Message::ExportSstable(file) => {
+                let Some(file) = export_file(file) else {
+                    return Message::error(INVALID_EXPORT_FILE);
+                };
 
-                Message::Success
+                match db.export_sstable(file).await {
+                    Ok(n) => Message::Text(format!("exported {} keys", n).into()),
+                    Err(e) => Message::Error("ERR".into(), e.to_string().into()),
+                }
             }
-            Message::Get(k) => {
-                let kd = kd.read().await;
-                let Some(data) = kd.get(k) else { return Message::None };
+            Message::Snapshot(dir) => {
+                let Some(dir) = export_file(dir) else {
+                    return Message::error(INVALID_SNAPSHOT_DIR);
+                };
+
+                match db.snapshot(dir).await {
+                    Ok(stats) => Message::Text(
+                        format!("snapshot {} pages {} keys", stats.pages, stats.keys).into(),
+                    ),
+                    Err(e) => Message::Error("ERR".into(), e.to_string().into()),
+                }
+            }
+
+            // Switching databases, streaming notifications or changes and transactions are
+            // connection state, and pub/sub channels belong to the server, so the server handles
+            // them. Quoted requests are unwrapped as they're read
+            Message::Use(_)
+            | Message::Quoted(_, _)
+            | Message::Select(_)
+            | Message::Notifications
+            | Message::Begin
+            | Message::Commit
+            | Message::Discard
+            | Message::Watch(_, _)
+            | Message::Subscribe(_)
+            | Message::Publish(_, _)
+            | Message::SubscribeOplog(_)
+            | Message::ConfigGet(_)
+            | Message::ConfigSet(_, _)
+            | Message::KeysMatching(_)
+            | Message::Result(_, _)
+            | Message::Values(_)
+            | Message::Keys(_, _)
+            | Message::Text(_)
+            | Message::Success
+            | Message::NotFound
+            | Message::Error(_, _)
+            | Message::None => Message::None,
+            Message::Invalid(_, e) => Message::error(*e),
+        }
+    }
+
+    /// Answers a read from a transaction's view of the keyspace instead of the live one. Returns
+    /// `None` for messages that don't read keys, which are answered as usual.
+    pub async fn exec_in(&self, txn: &ReadTxn) -> Option<Message> {
+        let (kd, now) = (txn.keys(), txn.now());
+
+        let res = match self {
+            Message::Get(k) => match txn.read(k).await {
+                Ok(Some(entry)) => Message::Result(entry.key.into(), entry.value.into()),
+                Ok(None) => Message::NotFound,
+                Err(e) => storage_error(e),
+            },
+            Message::Exists(k) => {
+                let exists = kd.get(k).is_some_and(|data| !data.is_expired(now));
 
-                // TODO: return error if replacer couldn't replace
-                let Some(page) = m.fetch_page(data.page_id).await else { return Message::None };
-                let page_w = page.read().await;
-                // TODO: return error page could not have held entry
-                let Some(entry) = page_w.read_entry(data.offset as usize) else { return Message::None };
+                Message::Text(if exists { "1" } else { "0" }.into())
+            }
+            Message::Strlen(k) => match txn.read(k).await {
+                Ok(entry) => Message::Text(entry.map_or(0, |e| e.value.len()).to_string().into()),
+                Err(e) => storage_error(e),
+            },
+            Message::MemoryUsage(k) => memory_usage(kd, k, now),
+            Message::Ttl(k) => ttl(kd, k, now),
+            // Every read in a transaction is consistent
+            Message::MGet(keys, _) => match txn.read_many(keys).await {
+                Ok(entries) => values(keys, entries),
+                Err(e) => storage_error(e),
+            },
+            Message::Scan(cursor, prefix) => scan(kd, cursor, prefix, now),
+            Message::Range(start, end, limit, values) => {
+                let (keys, cursor) = range(kd, start, end, *limit, now);
+                if !*values {
+                    return Some(Message::Keys(cursor, keys));
+                }
 
-                Message::Result(entry.key.into(), entry.value.into())
+                match txn.read_many(&keys).await {
+                    Ok(entries) => with_values(cursor, entries),
+                    Err(e) => storage_error(e),
+                }
             }
+            Message::DbSize => db_size(kd, now),
+            _ => return None,
+        };
+
+        Some(res)
+    }
+
+    /// Rejects a request carrying a key or value longer than `limits` allow. A `SetRange` is
+    /// checked by the length the value would grow to.
+    pub fn check_limits(&self, limits: &Limits) -> Result<(), Message> {
+        let key = |k: &Bytes| match k.len() > limits.max_key_len {
+            true => Err(Message::error(KEY_TOO_LONG)),
+            false => Ok(()),
+        };
+        let value = |len: u64| match len > limits.max_value_len as u64 {
+            true => Err(Message::error(VALUE_TOO_LONG)),
+            false => Ok(()),
+        };
 
-            Message::Result(_, _) | Message::Success | Message::Ignore(_) | Message::None => {
-                Message::None
+        match self {
+            Message::Insert(k, v)
+            | Message::InsertEx(k, v, _)
+            | Message::Append(k, v)
+            | Message::GetSet(k, v)
+            | Message::SetNx(k, v) => {
+                key(k)?;
+                value(v.len() as u64)
+            }
+            Message::SetRange(k, offset, v) => {
+                key(k)?;
+                value(offset.saturating_add(v.len() as u64))
+            }
+            Message::Merge(_, k, operand) => {
+                key(k)?;
+                value(operand.len() as u64)
+            }
+            Message::Delete(k)
+            | Message::Del(k)
+            | Message::Unlink(k)
+            | Message::GetDel(k)
+            | Message::Get(k)
+            | Message::Exists(k)
+            | Message::Strlen(k)
+            | Message::MemoryUsage(k)
+            | Message::Expire(k, _)
+            | Message::ExpireAt(k, _)
+            | Message::Persist(k)
+            | Message::Ttl(k) => key(k),
+            Message::MGet(keys, _) => keys.iter().try_for_each(key),
+            Message::Range(start, end, _, _) => {
+                key(start)?;
+                key(end)
             }
+            Message::MSet(pairs) => pairs.iter().try_for_each(|(k, v)| {
+                key(k)?;
+                value(v.len() as u64)
+            }),
+            Message::Batch(messages) => messages.iter().try_for_each(|m| m.check_limits(limits)),
+            Message::Fenced(_, message) | Message::Grouped(_, message) => {
+                message.check_limits(limits)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Message::Insert(_, _)
+                | Message::InsertEx(_, _, _)
+                | Message::Delete(_)
+                | Message::Del(_)
+                | Message::Unlink(_)
+                | Message::SetRange(_, _, _)
+                | Message::Merge(_, _, _)
+                | Message::Append(_, _)
+                | Message::GetSet(_, _)
+                | Message::SetNx(_, _)
+                | Message::GetDel(_)
+                | Message::Expire(_, _)
+                | Message::ExpireAt(_, _)
+                | Message::Persist(_)
+                | Message::MSet(_)
+                | Message::FlushDb
+                | Message::Batch(_)
+                | Message::Fenced(_, _)
+                | Message::Grouped(_, _)
+        )
+    }
+
+    /// Unwraps a request read in the quoted form.
+    pub fn unquoted(self) -> Message {
+        match self {
+            Message::Quoted(_, message) => *message,
+            message => message,
         }
     }
 
     pub fn parse(buf: &[u8]) -> Option<Self> {
         let mut buf = Cursor::new(buf);
 
-        if buf.get_ref()[..].starts_with(b"\n") {
-            return Some(Message::Ignore(1));
+        if buf.get_ref()[..].starts_with(b"multi\n") {
+            return parse_batch(buf.get_ref());
         }
 
-        // check for "get " first
-        if buf.remaining() <= 4 {
+        if let Some(message) = parse_quoted(buf.get_ref()) {
+            return Some(message);
+        }
+
+        if buf.get_ref()[..].starts_with(COMPACTION_ESTIMATE) {
+            return Some(Message::CompactionEstimate);
+        }
+        if COMPACTION_ESTIMATE.starts_with(buf.get_ref()) {
             return None;
         }
 
-        let maybe_get = &buf.get_ref()[0..3];
-        if maybe_get == b"get" {
-            buf.advance(4);
-            let Some(key) = read_until(&buf, b'\n') else { return None };
+        if buf.get_ref()[..].starts_with(PAGE_FILL) {
+            return Some(Message::PageFill);
+        }
+        if PAGE_FILL.starts_with(buf.get_ref()) {
+            return None;
+        }
 
-            return Some(Message::Get(key.into()));
+        if buf.get_ref()[..].starts_with(EXPIRY_FORECAST) {
+            return Some(Message::ExpiryForecast);
+        }
+        if EXPIRY_FORECAST.starts_with(buf.get_ref()) {
+            return None;
         }
 
-        // check for "insert " or "delete "
-        if buf.remaining() < 7 {
+        if buf.get_ref()[..].starts_with(STATS_BOOTSTRAP) {
+            return Some(Message::BootstrapStats);
+        }
+        if STATS_BOOTSTRAP.starts_with(buf.get_ref()) {
             return None;
         }
-        let maybe_insert_or_delete = &buf.get_ref()[0..6];
-        match maybe_insert_or_delete {
-            b"insert" => {
-                buf.advance(7);
-                let Some(key) = read_until(&buf, b' ') else { return None };
-                buf.advance(key.len() + 1);
-                let Some(value) = read_until(&buf, b'\n') else { return None };
 
-                Some(Message::Insert(key, value))
+        if buf.get_ref()[..].starts_with(HEALTH) {
+            return Some(Message::Health);
+        }
+        if HEALTH.starts_with(buf.get_ref()) {
+            return None;
+        }
+
+        if buf.get_ref()[..].starts_with(INFO) {
+            return Some(Message::Info);
+        }
+        if INFO.starts_with(buf.get_ref()) {
+            return None;
+        }
+
+        if buf.get_ref()[..].starts_with(DBSIZE) {
+            return Some(Message::DbSize);
+        }
+        if DBSIZE.starts_with(buf.get_ref()) {
+            return None;
+        }
+
+        if buf.get_ref()[..].starts_with(FLUSHDB) {
+            return Some(Message::FlushDb);
+        }
+        if FLUSHDB.starts_with(buf.get_ref()) {
+            return None;
+        }
+
+        if buf.get_ref()[..].starts_with(VALIDATORS) {
+            return Some(Message::Validators);
+        }
+        if VALIDATORS.starts_with(buf.get_ref()) {
+            return None;
+        }
+
+        if buf.get_ref()[..].starts_with(NOTIFICATIONS) {
+            return Some(Message::Notifications);
+        }
+        if NOTIFICATIONS.starts_with(buf.get_ref()) {
+            return None;
+        }
+
+        if buf.get_ref()[..].starts_with(BEGIN) {
+            return Some(Message::Begin);
+        }
+        if BEGIN.starts_with(buf.get_ref()) {
+            return None;
+        }
+
+        if buf.get_ref()[..].starts_with(COMMIT) {
+            return Some(Message::Commit);
+        }
+        if COMMIT.starts_with(buf.get_ref()) {
+            return None;
+        }
+
+        if buf.get_ref()[..].starts_with(DISCARD) {
+            return Some(Message::Discard);
+        }
+        if DISCARD.starts_with(buf.get_ref()) {
+            return None;
+        }
+
+        if buf.get_ref()[..].starts_with(b"export-keys ") {
+            buf.advance(12);
+            let line = read_until(&buf, b'\n')?;
+
+            return match line.strip_suffix(b" meta") {
+                Some(file) => Some(Message::ExportKeys(line.slice(..file.len()), true)),
+                None => Some(Message::ExportKeys(line, false)),
+            };
+        }
+
+        if buf.get_ref()[..].starts_with(b"export-sstable ") {
+            buf.advance(15);
+            let file = read_until(&buf, b'\n')?;
+
+            return Some(Message::ExportSstable(file));
+        }
+
+        if buf.get_ref()[..].starts_with(b"snapshot ") {
+            buf.advance(9);
+            let dir = read_until(&buf, b'\n')?;
+
+            return Some(Message::Snapshot(dir));
+        }
+
+        if buf.get_ref()[..].starts_with(b"fence ") {
+            buf.advance(6);
+            let token = read_until(&buf, b' ')?;
+            if token.contains(&b'\n') {
+                return reject(buf.get_ref(), WRONG_ARGUMENTS);
             }
-            b"delete" => {
-                buf.advance(7);
-                let Some(key) = read_until(&buf, b'\n') else { return None };
+            let rest = &buf.get_ref()[6 + token.len() + 1..];
+            let message = Message::parse(rest)?;
+            let len = 6 + token.len() + 1 + message.len();
+
+            // Message::len recomputes the token's digits, so only accept its canonical form
+            let token = std::str::from_utf8(&token)
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .filter(|t| t.to_string().as_bytes() == token);
+
+            return match (token, message) {
+                (None, _) => Some(Message::Invalid(len, INVALID_FENCE_TOKEN)),
+                (_, Message::Invalid(_, e)) => Some(Message::Invalid(len, e)),
+                (Some(token), Message::Quoted(_, message))
+                    if matches!(
+                        *message,
+                        Message::Insert(_, _) | Message::InsertEx(_, _, _) | Message::Delete(_)
+                    ) =>
+                {
+                    Some(Message::Quoted(
+                        len,
+                        Box::new(Message::Fenced(token, message)),
+                    ))
+                }
+                (
+                    Some(token),
+                    message @ (Message::Insert(_, _)
+                    | Message::InsertEx(_, _, _)
+                    | Message::Grouped(_, _)
+                    | Message::Delete(_)),
+                ) => Some(Message::Fenced(token, Box::new(message))),
+                _ => Some(Message::Invalid(len, FENCED_WRITES_ONLY)),
+            };
+        }
 
-                return Some(Message::Delete(key.into()));
+        if buf.get_ref()[..].starts_with(b"mget ") || buf.get_ref()[..].starts_with(MGET_CONSISTENT)
+        {
+            let consistent = buf.get_ref()[..].starts_with(MGET_CONSISTENT);
+            buf.advance(if consistent { MGET_CONSISTENT.len() } else { 5 });
+            let line = read_until(&buf, b'\n')?;
+            if line.is_empty() {
+                return reject(buf.get_ref(), WRONG_ARGUMENTS);
             }
-            _ => {
-                return Some(Message::Ignore(buf.get_ref().len()));
+
+            return Some(Message::MGet(split_words(&line), consistent));
+        }
+
+        if buf.get_ref()[..].starts_with(b"mset ") {
+            buf.advance(5);
+            let line = read_until(&buf, b'\n')?;
+            let words = split_words(&line);
+            if line.is_empty() || !words.len().is_multiple_of(2) {
+                return reject(buf.get_ref(), WRONG_ARGUMENTS);
             }
+
+            let pairs = words
+                .chunks(2)
+                .map(|kv| (kv[0].clone(), kv[1].clone()))
+                .collect();
+            return Some(Message::MSet(pairs));
         }
-    }
 
-    pub fn len(&self) -> usize {
-        match self {
-            Message::Insert(k, v) => 9 + k.len() + v.len(),
-            Message::Delete(k) => 7 + k.len(),
-            Message::Get(k) => 5 + k.len(),
+        if buf.get_ref()[..].starts_with(b"select ") {
+            buf.advance(7);
+            let index = read_until(&buf, b'\n')?;
 
-            Message::Result(k, v) => k.len() + v.len() + 1,
-            Message::Success => 8,
-            Message::Ignore(l) => *l,
-            Message::None => 0,
+            // Message::len recomputes the digits, so only accept the canonical form
+            return match std::str::from_utf8(&index)
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .filter(|n| n.to_string().as_bytes() == index)
+            {
+                Some(n) => Some(Message::Select(n)),
+                None => reject(buf.get_ref(), INVALID_DB_INDEX),
+            };
         }
-    }
-}
 
-fn read_until(cursor: &Cursor<&[u8]>, c: u8) -> Option<Bytes> {
-    let start = cursor.position() as usize;
-    let end = cursor.get_ref().len();
+        if buf.get_ref()[..].starts_with(b"validate ") {
+            buf.advance(9);
+            let prefix = read_until(&buf, b' ')?;
+            buf.advance(prefix.len() + 1);
+            let rule = read_until(&buf, b'\n')?;
 
-    for i in start..end {
-        if cursor.get_ref()[i] == c {
-            let ret = BytesMut::from(&cursor.get_ref()[start..i]);
-            let ret = Bytes::from(ret);
-            return Some(ret);
+            return Some(Message::Validate(prefix, rule));
         }
-    }
 
-    None
-}
+        if buf.get_ref()[..].starts_with(b"subscribe ") {
+            buf.advance(10);
+            let channel = read_until(&buf, b'\n')?;
 
-impl Into<Bytes> for Message {
-    fn into(self) -> Bytes {
-        match self {
-            Message::Insert(_, _)
-            | Message::Delete(_)
-            | Message::Get(_)
-            | Message::Ignore(_)
-            | Message::None => Bytes::new(),
+            return Some(Message::Subscribe(channel));
+        }
 
-            Message::Result(k, v) => {
-                let len = k.len() + v.len() + 2;
-                let mut dst = BytesMut::zeroed(len);
+        if buf.get_ref()[..].starts_with(b"subscribe-oplog ") {
+            buf.advance(16);
+            let seq = read_until(&buf, b'\n')?;
+
+            // Message::len recomputes the digits, so only accept the canonical form
+            return match std::str::from_utf8(&seq)
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .filter(|n| n.to_string().as_bytes() == seq)
+            {
+                Some(n) => Some(Message::SubscribeOplog(n)),
+                None => reject(buf.get_ref(), INVALID_OPLOG_POSITION),
+            };
+        }
 
-                crate::put_bytes!(dst, k, 0, k.len());
-                dst[k.len()] = b' ';
-                crate::put_bytes!(dst, v, k.len() + 1, v.len());
-                dst[len - 1] = b'\n';
+        if buf.get_ref()[..].starts_with(b"publish ") {
+            buf.advance(8);
+            let channel = read_until(&buf, b' ')?;
+            buf.advance(channel.len() + 1);
+            let payload = read_until(&buf, b'\n')?;
 
-                dst.into()
+            return Some(Message::Publish(channel, payload));
+        }
+
+        if buf.get_ref()[..].starts_with(b"config get ") {
+            buf.advance(11);
+            let name = read_until(&buf, b'\n')?;
+
+            return Some(Message::ConfigGet(name));
+        }
+
+        if buf.get_ref()[..].starts_with(b"config set ") {
+            buf.advance(11);
+            let name = read_until(&buf, b' ')?;
+            buf.advance(name.len() + 1);
+            let value = read_until(&buf, b'\n')?;
+
+            return Some(Message::ConfigSet(name, value));
+        }
+
+        if buf.get_ref()[..].starts_with(b"watch ") {
+            buf.advance(6);
+            let prefix = buf.chunk().starts_with(PREFIX_OPTION);
+            if prefix {
+                buf.advance(PREFIX_OPTION.len());
             }
-            Message::Success => Bytes::from("Success\n"),
+            let k = read_until(&buf, b'\n')?;
+
+            return Some(Message::Watch(k, prefix));
+        }
+
+        if buf.get_ref()[..].starts_with(b"unvalidate ") {
+            buf.advance(11);
+            let prefix = read_until(&buf, b'\n')?;
+
+            return Some(Message::Unvalidate(prefix));
+        }
+
+        if buf.get_ref()[..].starts_with(b"unlink ") {
+            buf.advance(7);
+            let key = read_until(&buf, b'\n')?;
+
+            return Some(Message::Unlink(key));
+        }
+
+        if buf.get_ref()[..].starts_with(b"setrange ") {
+            buf.advance(9);
+            let line = read_until(&buf, b'\n')?;
+
+            let mut parts = line.splitn(3, |b| *b == b' ');
+            let (Some(k), Some(offset), Some(v)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Some(Message::Invalid(9 + line.len() + 1, WRONG_ARGUMENTS));
+            };
+            // Message::len recomputes the offset's digits, so only accept its canonical form
+            let offset = std::str::from_utf8(offset)
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .filter(|o| o.to_string().as_bytes() == offset);
+
+            return match offset {
+                Some(offset) => Some(Message::SetRange(
+                    Bytes::copy_from_slice(k),
+                    offset,
+                    Bytes::copy_from_slice(v),
+                )),
+                None => Some(Message::Invalid(9 + line.len() + 1, INVALID_OFFSET)),
+            };
+        }
+
+        if buf.get_ref()[..].starts_with(b"merge ") {
+            buf.advance(6);
+            let op = read_until(&buf, b' ')?;
+            buf.advance(op.len() + 1);
+            let k = read_until(&buf, b' ')?;
+            buf.advance(k.len() + 1);
+            let operand = read_until(&buf, b'\n')?;
+
+            return Some(Message::Merge(op, k, operand));
+        }
+
+        for (command, len) in [(&b"append "[..], 7), (b"getset ", 7), (b"setnx ", 6)] {
+            if !buf.get_ref()[..].starts_with(command) {
+                continue;
+            }
+            buf.advance(len);
+            let k = read_until(&buf, b' ')?;
+            buf.advance(k.len() + 1);
+            let v = read_until(&buf, b'\n')?;
+
+            return Some(match command {
+                b"append " => Message::Append(k, v),
+                b"getset " => Message::GetSet(k, v),
+                _ => Message::SetNx(k, v),
+            });
+        }
+
+        for (command, len) in [(&b"expireat "[..], 9), (b"expire ", 7)] {
+            if !buf.get_ref()[..].starts_with(command) {
+                continue;
+            }
+            buf.advance(len);
+            let k = read_until(&buf, b' ')?;
+            buf.advance(k.len() + 1);
+            let n = read_until(&buf, b'\n')?;
+
+            // Message::len recomputes the digits, so only accept the canonical form
+            let Some(n) = std::str::from_utf8(&n)
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .filter(|s| s.to_string().as_bytes() == n)
+            else {
+                return reject(buf.get_ref(), INVALID_EXPIRE_TIME);
+            };
+
+            return Some(match command {
+                b"expireat " => Message::ExpireAt(k, n),
+                _ => Message::Expire(k, n),
+            });
+        }
+
+        if buf.get_ref()[..].starts_with(b"persist ") {
+            buf.advance(8);
+            let key = read_until(&buf, b'\n')?;
+
+            return Some(Message::Persist(key));
+        }
+
+        if buf.get_ref()[..].starts_with(b"ttl ") {
+            buf.advance(4);
+            let key = read_until(&buf, b'\n')?;
+
+            return Some(Message::Ttl(key));
+        }
+
+        if buf.get_ref()[..].starts_with(b"getdel ") {
+            buf.advance(7);
+            let key = read_until(&buf, b'\n')?;
+
+            return Some(Message::GetDel(key));
+        }
+
+        if buf.get_ref()[..].starts_with(b"exists ") {
+            buf.advance(7);
+            let key = read_until(&buf, b'\n')?;
+
+            return Some(Message::Exists(key));
+        }
+
+        if buf.get_ref()[..].starts_with(b"strlen ") {
+            buf.advance(7);
+            let key = read_until(&buf, b'\n')?;
+
+            return Some(Message::Strlen(key));
+        }
+
+        if buf.get_ref()[..].starts_with(MEMORY_USAGE) {
+            buf.advance(MEMORY_USAGE.len());
+            let key = read_until(&buf, b'\n')?;
+
+            return Some(Message::MemoryUsage(key));
+        }
+
+        if buf.get_ref()[..].starts_with(b"keys ") {
+            buf.advance(5);
+            let pattern = read_until(&buf, b'\n')?;
+
+            return Some(Message::KeysMatching(pattern));
+        }
+
+        if buf.get_ref()[..].starts_with(b"range ") {
+            buf.advance(6);
+            let values = buf.chunk().starts_with(VALUES_OPTION);
+            if values {
+                buf.advance(VALUES_OPTION.len());
+            }
+            let line = read_until(&buf, b'\n')?;
+
+            let mut words = split_words(&line).into_iter();
+            let (Some(start), Some(end)) = (words.next(), words.next()) else {
+                return reject(buf.get_ref(), WRONG_ARGUMENTS);
+            };
+            // Message::len recomputes the digits, so only accept the canonical form
+            let limit = match words.next() {
+                Some(limit) => match std::str::from_utf8(&limit)
+                    .ok()
+                    .and_then(|s| s.parse::<usize>().ok())
+                    .filter(|n| n.to_string().as_bytes() == limit)
+                {
+                    Some(n) => Some(n),
+                    None => return reject(buf.get_ref(), INVALID_LIMIT),
+                },
+                None => None,
+            };
+            if words.next().is_some() {
+                return reject(buf.get_ref(), WRONG_ARGUMENTS);
+            }
+
+            return Some(Message::Range(start, end, limit, values));
+        }
+
+        if buf.get_ref()[..].starts_with(b"scan ") {
+            buf.advance(5);
+            let line = read_until(&buf, b'\n')?;
+
+            return match line.iter().position(|b| *b == b' ') {
+                Some(i) => Some(Message::Scan(line.slice(..i), Some(line.slice(i + 1..)))),
+                None => Some(Message::Scan(line, None)),
+            };
+        }
+
+        // check for "get " and "use " first
+        if buf.remaining() <= 4 {
+            return reject(buf.get_ref(), UNKNOWN_COMMAND);
+        }
+
+        match &buf.get_ref()[0..3] {
+            b"get" => {
+                buf.advance(4);
+                let key = read_until(&buf, b'\n')?;
+
+                return Some(Message::Get(key));
+            }
+            b"use" => {
+                buf.advance(4);
+                let name = read_until(&buf, b'\n')?;
+
+                return Some(Message::Use(name));
+            }
+            _ => {}
+        }
+
+        // check for "insert " or "delete "
+        if buf.remaining() < 7 {
+            return reject(buf.get_ref(), UNKNOWN_COMMAND);
+        }
+        let maybe_insert_or_delete = &buf.get_ref()[0..6];
+        match maybe_insert_or_delete {
+            b"insert" => {
+                buf.advance(7);
+                let group = match buf.chunk().starts_with(GROUP_OPTION) {
+                    true => {
+                        buf.advance(GROUP_OPTION.len());
+                        let group = read_until(&buf, b' ')?;
+                        buf.advance(group.len() + 1);
+                        Some(group)
+                    }
+                    false => None,
+                };
+                let key = read_until(&buf, b' ')?;
+                buf.advance(key.len() + 1);
+                let value = read_until(&buf, b'\n')?;
+
+                let insert = match split_expiry(&value) {
+                    Some((value, Some(secs))) => Message::InsertEx(key, value, secs),
+                    Some((_, None)) => return reject(buf.get_ref(), INVALID_EXPIRE_TIME),
+                    None => Message::Insert(key, value),
+                };
+                match group {
+                    Some(group) if group.is_empty() => reject(buf.get_ref(), EMPTY_GROUP),
+                    Some(group) => Some(Message::Grouped(group, Box::new(insert))),
+                    None => Some(insert),
+                }
+            }
+            b"delete" => {
+                buf.advance(7);
+                let key = read_until(&buf, b'\n')?;
+
+                Some(Message::Delete(key))
+            }
+            _ => reject(buf.get_ref(), UNKNOWN_COMMAND),
+        }
+    }
+
+    /// Encodes a write as a line protocol request, the inverse of `parse`. Keys and values that
+    /// can't be sent as they are are sent in the quoted form. Returns `None` if the write can't
+    /// be represented, e.g. a grouped insert of a key containing a space.
+    pub fn request(&self) -> Option<Bytes> {
+        let mut dst = BytesMut::new();
+
+        match self {
+            Message::Insert(k, v) if needs_quoted_insert(k, v) => {
+                dst.extend_from_slice(b"insert ");
+                quote::put(&mut dst, k);
+                dst.extend_from_slice(b" ");
+                quote::put(&mut dst, v);
+            }
+            Message::Insert(k, v) => {
+                dst.extend_from_slice(b"insert ");
+                dst.extend_from_slice(k);
+                dst.extend_from_slice(b" ");
+                dst.extend_from_slice(v);
+            }
+            Message::InsertEx(k, v, secs) => {
+                let insert = Message::Insert(k.clone(), v.clone()).request()?;
+
+                dst.extend_from_slice(&insert[..insert.len() - 1]);
+                dst.extend_from_slice(format!(" EX {}", secs).as_bytes());
+            }
+            Message::Delete(k) => {
+                dst.extend_from_slice(b"delete ");
+                quote::put_word(&mut dst, k);
+            }
+            Message::MSet(pairs) => {
+                dst.extend_from_slice(b"mset");
+                for (k, v) in pairs {
+                    dst.extend_from_slice(b" ");
+                    quote::put_word(&mut dst, k);
+                    dst.extend_from_slice(b" ");
+                    quote::put_word(&mut dst, v);
+                }
+            }
+            Message::Unlink(k) => {
+                dst.extend_from_slice(b"unlink ");
+                quote::put_word(&mut dst, k);
+            }
+            Message::SetRange(k, offset, v) => {
+                if k.contains(&b' ') || k.contains(&b'\n') || v.contains(&b'\n') {
+                    return None;
+                }
+
+                dst.extend_from_slice(b"setrange ");
+                dst.extend_from_slice(k);
+                dst.extend_from_slice(format!(" {} ", offset).as_bytes());
+                dst.extend_from_slice(v);
+            }
+            Message::Merge(op, k, operand) => {
+                if [op, k]
+                    .iter()
+                    .any(|b| b.contains(&b' ') || b.contains(&b'\n'))
+                    || operand.contains(&b'\n')
+                {
+                    return None;
+                }
+
+                dst.extend_from_slice(b"merge ");
+                dst.extend_from_slice(op);
+                dst.extend_from_slice(b" ");
+                dst.extend_from_slice(k);
+                dst.extend_from_slice(b" ");
+                dst.extend_from_slice(operand);
+            }
+            Message::Append(k, v) | Message::GetSet(k, v) | Message::SetNx(k, v) => {
+                if k.contains(&b' ') || k.contains(&b'\n') || v.contains(&b'\n') {
+                    return None;
+                }
+
+                dst.extend_from_slice(match self {
+                    Message::Append(_, _) => b"append ",
+                    Message::GetSet(_, _) => b"getset ",
+                    _ => b"setnx ",
+                });
+                dst.extend_from_slice(k);
+                dst.extend_from_slice(b" ");
+                dst.extend_from_slice(v);
+            }
+            Message::GetDel(k) => {
+                if k.contains(&b'\n') {
+                    return None;
+                }
+
+                dst.extend_from_slice(b"getdel ");
+                dst.extend_from_slice(k);
+            }
+            Message::Expire(k, n) | Message::ExpireAt(k, n) => {
+                if k.contains(&b' ') || k.contains(&b'\n') {
+                    return None;
+                }
+
+                dst.extend_from_slice(match self {
+                    Message::Expire(_, _) => b"expire ",
+                    _ => b"expireat ",
+                });
+                dst.extend_from_slice(k);
+                dst.extend_from_slice(format!(" {}", n).as_bytes());
+            }
+            Message::Persist(k) => {
+                if k.contains(&b'\n') {
+                    return None;
+                }
+
+                dst.extend_from_slice(b"persist ");
+                dst.extend_from_slice(k);
+            }
+            Message::Fenced(token, message) => {
+                let mut write = message.request()?;
+                write.truncate(write.len() - 1);
+
+                dst.extend_from_slice(format!("fence {} ", token).as_bytes());
+                dst.extend_from_slice(&write);
+            }
+            Message::Grouped(group, message) => {
+                if group.is_empty() || group.contains(&b' ') || group.contains(&b'\n') {
+                    return None;
+                }
+                // The quoted form can't carry a group
+                match &**message {
+                    Message::Insert(k, v) | Message::InsertEx(k, v, _)
+                        if !needs_quoted_insert(k, v) => {}
+                    _ => return None,
+                }
+                let insert = message.request()?;
+
+                dst.extend_from_slice(b"insert --group ");
+                dst.extend_from_slice(group);
+                dst.extend_from_slice(b" ");
+                dst.extend_from_slice(&insert[7..insert.len() - 1]);
+            }
+            Message::Use(name) => {
+                if name.contains(&b'\n') {
+                    return None;
+                }
+
+                dst.extend_from_slice(b"use ");
+                dst.extend_from_slice(name);
+            }
+            Message::Batch(messages) => {
+                dst.extend_from_slice(b"multi\n");
+                for message in messages {
+                    dst.extend_from_slice(&message.request()?);
+                }
+                dst.extend_from_slice(b"exec");
+            }
+            _ => return None,
+        }
+        dst.extend_from_slice(b"\n");
+
+        Some(dst.into())
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Message::Insert(k, v) => 9 + k.len() + v.len(),
+            Message::InsertEx(k, v, secs) => 13 + k.len() + v.len() + secs.to_string().len(),
+            Message::Delete(k) | Message::Del(k) => 8 + k.len(),
+            Message::Unlink(k) => 8 + k.len(),
+            Message::SetRange(k, offset, v) => 12 + k.len() + offset.to_string().len() + v.len(),
+            Message::Merge(op, k, operand) => 6 + op.len() + 1 + k.len() + 1 + operand.len() + 1,
+            Message::Append(k, v) | Message::GetSet(k, v) => 7 + k.len() + 1 + v.len() + 1,
+            Message::SetNx(k, v) => 6 + k.len() + 1 + v.len() + 1,
+            Message::GetDel(k) => 7 + k.len() + 1,
+            Message::Expire(k, secs) => 7 + k.len() + 1 + secs.to_string().len() + 1,
+            Message::ExpireAt(k, ts) => 9 + k.len() + 1 + ts.to_string().len() + 1,
+            Message::Persist(k) => 8 + k.len() + 1,
+            Message::Ttl(k) => 4 + k.len() + 1,
+            Message::Get(k) => 5 + k.len(),
+            Message::Exists(k) | Message::Strlen(k) => 8 + k.len(),
+            Message::MemoryUsage(k) => MEMORY_USAGE.len() + k.len() + 1,
+            Message::Use(name) => 5 + name.len(),
+            Message::Select(n) => 8 + n.to_string().len(),
+            Message::DbSize => DBSIZE.len(),
+            Message::FlushDb => FLUSHDB.len(),
+            Message::Batch(messages) => 11 + messages.iter().map(Message::len).sum::<usize>(),
+            Message::Scan(cursor, prefix) => {
+                6 + cursor.len() + prefix.as_ref().map_or(0, |p| p.len() + 1)
+            }
+            Message::KeysMatching(pattern) => 6 + pattern.len(),
+            Message::Range(start, end, limit, values) => {
+                6 + if *values { VALUES_OPTION.len() } else { 0 }
+                    + start.len()
+                    + 1
+                    + end.len()
+                    + limit.map_or(0, |n| n.to_string().len() + 1)
+                    + 1
+            }
+
+            Message::MGet(keys, consistent) => {
+                let prefix = if *consistent {
+                    MGET_CONSISTENT.len()
+                } else {
+                    5
+                };
+                prefix + keys.iter().map(|k| k.len() + 1).sum::<usize>()
+            }
+            Message::MSet(pairs) => {
+                5 + pairs
+                    .iter()
+                    .map(|(k, v)| k.len() + v.len() + 2)
+                    .sum::<usize>()
+            }
+
+            Message::Result(k, v) => k.len() + v.len() + 1,
+            Message::Values(values) => values
+                .iter()
+                .map(|(k, v)| k.len() + v.as_ref().map_or(0, |v| v.len() + 1) + 1)
+                .sum(),
+            Message::CompactionEstimate => COMPACTION_ESTIMATE.len(),
+            Message::PageFill => PAGE_FILL.len(),
+            Message::Notifications => NOTIFICATIONS.len(),
+            Message::Begin => BEGIN.len(),
+            Message::Commit => COMMIT.len(),
+            Message::Discard => DISCARD.len(),
+            Message::Watch(k, prefix) => {
+                6 + if *prefix { PREFIX_OPTION.len() } else { 0 } + k.len() + 1
+            }
+            Message::Subscribe(channel) => 10 + channel.len() + 1,
+            Message::Publish(channel, payload) => 8 + channel.len() + 1 + payload.len() + 1,
+            Message::SubscribeOplog(seq) => 17 + seq.to_string().len(),
+            Message::ConfigGet(name) => 11 + name.len() + 1,
+            Message::ConfigSet(name, value) => 11 + name.len() + 1 + value.len() + 1,
+            Message::BootstrapStats => STATS_BOOTSTRAP.len(),
+            Message::Health => HEALTH.len(),
+            Message::Info => INFO.len(),
+            Message::ExpiryForecast => EXPIRY_FORECAST.len(),
+            Message::Validate(prefix, rule) => 11 + prefix.len() + rule.len(),
+            Message::Unvalidate(prefix) => 12 + prefix.len(),
+            Message::Validators => VALIDATORS.len(),
+            Message::ExportKeys(file, meta) => 13 + file.len() + if *meta { 5 } else { 0 },
+            Message::ExportSstable(file) => 16 + file.len(),
+            Message::Snapshot(dir) => 10 + dir.len(),
+            Message::Fenced(token, message) => 7 + token.to_string().len() + message.len(),
+            Message::Grouped(group, message) => 9 + group.len() + message.len(),
+            Message::Quoted(len, _) => *len,
+
+            Message::Keys(cursor, keys) => {
+                cursor.len() + keys.iter().map(|k| k.len() + 1).sum::<usize>() + 1
+            }
+            Message::Text(t) => t.len() + 1,
+            Message::Success => 8,
+            Message::NotFound => 9,
+            Message::Error(code, text) => 8 + code.len() + text.len(),
+            Message::Invalid(l, _) => *l,
+            Message::None => 0,
+        }
+    }
+
+    /// Parses a binary protocol frame from the start of `buf`, returning the request id, the
+    /// message and the number of bytes consumed. Returns `Ok(None)` if more data is needed.
+    ///
+    /// Keys and values are length prefixed so they can hold any bytes. A frame that can't be
+    /// executed is returned as a `Message::Error` to answer it with.
+    pub fn parse_frame(buf: &[u8]) -> io::Result<Option<(u32, Message, usize)>> {
+        let Some(Frame {
+            opcode,
+            id,
+            key,
+            value,
+            len,
+        }) = Frame::read(buf)?
+        else {
+            return Ok(None);
+        };
+
+        let message = match opcode {
+            OP_GET => Message::Get(key),
+            OP_SET => Message::Insert(key, value),
+            OP_SETEX if value.len() >= 8 => {
+                let secs = (&value[..8]).get_u64();
+                Message::InsertEx(key, value.slice(8..), secs)
+            }
+            OP_SETEX => Message::error(INVALID_SETEX),
+            OP_DEL => Message::Delete(key),
+            OP_USE => Message::Use(key),
+            _ => Message::error(UNKNOWN_OPCODE),
+        };
+
+        Ok(Some((id, message, len)))
+    }
+
+    /// Parses a response frame written by `Message::frame`, the client side of `parse_frame`.
+    pub fn parse_reply_frame(buf: &[u8]) -> io::Result<Option<(u32, Message, usize)>> {
+        let Some(Frame {
+            opcode,
+            id,
+            key,
+            value,
+            len,
+        }) = Frame::read(buf)?
+        else {
+            return Ok(None);
+        };
+
+        let message = match opcode {
+            OP_OK => Message::Success,
+            OP_VALUE if key.is_empty() => Message::Text(value),
+            OP_VALUE => Message::Result(key, value),
+            OP_NOT_FOUND => Message::NotFound,
+            OP_ERROR => Message::Error(key, value),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown response opcode {:#x}", opcode),
+                ))
+            }
+        };
+
+        Ok(Some((id, message, len)))
+    }
+
+    /// Encodes a request as a binary protocol frame with id `id`, the client side of
+    /// `parse_frame`. `None` for requests the binary protocol doesn't carry.
+    pub fn request_frame(&self, id: u32) -> Option<Bytes> {
+        let frame = match self {
+            Message::Get(k) => put_frame(OP_GET, id, k, b""),
+            Message::Insert(k, v) => put_frame(OP_SET, id, k, v),
+            Message::InsertEx(k, v, secs) => {
+                let mut value = BytesMut::with_capacity(8 + v.len());
+                value.put_u64(*secs);
+                value.put_slice(v);
+
+                put_frame(OP_SETEX, id, k, &value)
+            }
+            Message::Delete(k) => put_frame(OP_DEL, id, k, b""),
+            Message::Use(db) => put_frame(OP_USE, id, db, b""),
+            _ => return None,
+        };
+
+        Some(frame)
+    }
+
+    /// Encodes a response as a binary protocol frame answering request `id`.
+    pub fn frame(self, id: u32) -> Bytes {
+        let (opcode, key, value) = match self {
+            Message::Result(k, v) => (OP_VALUE, k, v),
+            Message::Text(t) => (OP_VALUE, Bytes::new(), t),
+            Message::Success => (OP_OK, Bytes::new(), Bytes::new()),
+            Message::NotFound => (OP_NOT_FOUND, Bytes::new(), Bytes::new()),
+            Message::Error(code, text) => (OP_ERROR, code, text),
+
+            // Not the response to a binary request
+            Message::Insert(_, _)
+            | Message::InsertEx(_, _, _)
+            | Message::Delete(_)
+            | Message::Del(_)
+            | Message::Unlink(_)
+            | Message::SetRange(_, _, _)
+            | Message::Merge(_, _, _)
+            | Message::Append(_, _)
+            | Message::GetSet(_, _)
+            | Message::SetNx(_, _)
+            | Message::GetDel(_)
+            | Message::Expire(_, _)
+            | Message::ExpireAt(_, _)
+            | Message::Persist(_)
+            | Message::Ttl(_)
+            | Message::Get(_)
+            | Message::Exists(_)
+            | Message::Strlen(_)
+            | Message::MemoryUsage(_)
+            | Message::MGet(_, _)
+            | Message::MSet(_)
+            | Message::Use(_)
+            | Message::Batch(_)
+            | Message::Scan(_, _)
+            | Message::Range(_, _, _, _)
+            | Message::KeysMatching(_)
+            | Message::CompactionEstimate
+            | Message::PageFill
+            | Message::BootstrapStats
+            | Message::Health
+            | Message::Info
+            | Message::ExpiryForecast
+            | Message::Select(_)
+            | Message::DbSize
+            | Message::FlushDb
+            | Message::Validate(_, _)
+            | Message::Unvalidate(_)
+            | Message::Validators
+            | Message::ExportKeys(_, _)
+            | Message::ExportSstable(_)
+            | Message::Snapshot(_)
+            | Message::Notifications
+            | Message::Begin
+            | Message::Commit
+            | Message::Discard
+            | Message::Watch(_, _)
+            | Message::Subscribe(_)
+            | Message::Publish(_, _)
+            | Message::SubscribeOplog(_)
+            | Message::ConfigGet(_)
+            | Message::ConfigSet(_, _)
+            | Message::Fenced(_, _)
+            | Message::Grouped(_, _)
+            | Message::Quoted(_, _)
+            | Message::Values(_)
+            | Message::Keys(_, _)
+            | Message::Invalid(_, _)
+            | Message::None => return Bytes::new(),
+        };
+
+        put_frame(opcode, id, &key, &value)
+    }
+}
+
+struct Frame {
+    opcode: u8,
+    id: u32,
+    key: Bytes,
+    value: Bytes,
+    /// Bytes the frame takes up, header included.
+    len: usize,
+}
+
+impl Frame {
+    /// Reads the frame at the start of `buf`. Returns `Ok(None)` if more data is needed.
+    fn read(buf: &[u8]) -> io::Result<Option<Self>> {
+        if buf.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let mut src = buf;
+        if src.get_u8() != FRAME_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected frame magic",
+            ));
+        }
+        let opcode = src.get_u8();
+        let id = src.get_u32();
+        let key_len = src.get_u32() as usize;
+        let value_len = src.get_u32() as usize;
+
+        if key_len + value_len > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame of {} bytes is too large", key_len + value_len),
+            ));
+        }
+        let len = FRAME_HEADER_LEN + key_len + value_len;
+        if buf.len() < len {
+            return Ok(None);
+        }
+
+        let key = Bytes::copy_from_slice(&src[..key_len]);
+        let value = Bytes::copy_from_slice(&src[key_len..key_len + value_len]);
+
+        Ok(Some(Self {
+            opcode,
+            id,
+            key,
+            value,
+            len,
+        }))
+    }
+}
+
+fn put_frame(opcode: u8, id: u32, key: &[u8], value: &[u8]) -> Bytes {
+    let mut dst = BytesMut::with_capacity(FRAME_HEADER_LEN + key.len() + value.len());
+    dst.put_u8(FRAME_MAGIC);
+    dst.put_u8(opcode);
+    dst.put_u32(id);
+    dst.put_u32(key.len() as u32);
+    dst.put_u32(value.len() as u32);
+    dst.put_slice(key);
+    dst.put_slice(value);
+
+    dst.into()
+}
+
+async fn insert(
+    db: &Db,
+    user: &User,
+    k: &[u8],
+    v: &[u8],
+    expires: Option<u64>,
+    fence: Option<u64>,
+    group: Option<u32>,
+) -> Message {
+    if let Err(e) = validate(db, k, v) {
+        return e;
+    }
+    // Compressed before taking the current page so other writers don't wait on it
+    let mut entry = db.entry(k, v, EntryType::Put);
+
+    let _permit = db.insert_permit().await;
+    let (m, kd) = (&db.pc, &db.kd);
+    let mut current = m.get_current().await;
+    let fence = match check(kd, k, user, fence, db.now()).await {
+        Ok(f) => f,
+        Err(e) => return e,
+    };
+
+    entry = entry
+        .with_owner(user.uid)
+        .with_fence(fence)
+        .with_group(group);
+    entry.expires = expires;
+    let offset = match m.write_entry(&mut current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error("ERR".into(), e.to_string().into()),
+    };
+
+    let data = KeyData::new(current.id, offset)
+        .with_expiry(expires)
+        .with_owner(user.uid)
+        .with_fence(fence)
+        .with_len(entry.len());
+    kd.write().await.insert(k, data);
+    drop(current);
+
+    m.commit().await;
+    db.write_behind([entry]).await;
+
+    Message::Success
+}
+
+/// Runs an insert under the locality group named `group`.
+async fn grouped(
+    db: &Db,
+    user: &User,
+    group: &[u8],
+    message: &Message,
+    fence: Option<u64>,
+) -> Message {
+    let group = Some(log::group_id(group));
+
+    match message {
+        Message::Insert(k, v) => insert(db, user, k, v, None, fence, group).await,
+        Message::InsertEx(k, v, secs) => {
+            insert(
+                db,
+                user,
+                k,
+                v,
+                Some(db.now().saturating_add(*secs)),
+                fence,
+                group,
+            )
+            .await
+        }
+        _ => Message::error(GROUPED_WRITES_ONLY),
+    }
+}
+
+/// Deletes `k`, returning whether it was live beforehand. That's read from the `KeyDir` while
+/// holding the current page, so it can't change before the delete lands.
+async fn delete(db: &Db, user: &User, k: &[u8], fence: Option<u64>) -> Result<bool, Message> {
+    let (m, kd) = (&db.pc, &db.kd);
+    let mut current = m.get_current().await;
+    let now = db.now();
+    check(kd, k, user, fence, now).await?;
+    let live = kd
+        .read()
+        .await
+        .get(k)
+        .is_some_and(|data| !data.is_expired(now));
+
+    let entry = db.entry(k, &[], EntryType::Delete).with_fence(fence);
+    if let Err(e) = m.write_entry(&mut current, &entry).await {
+        return Err(Message::Error("ERR".into(), e.to_string().into()));
+    }
+
+    kd.write().await.remove(k);
+    drop(current);
+
+    m.commit().await;
+    db.write_behind([entry]).await;
+
+    Ok(live)
+}
+
+/// Writes every pair to one page while holding the current page, then makes them visible in one
+/// go and commits once. Pairs that don't fit in a page are rejected, so an error never leaves some
+/// of them set.
+async fn mset(db: &Db, user: &User, pairs: &[(Bytes, Bytes)]) -> Message {
+    for (k, v) in pairs {
+        if let Err(e) = validate(db, k, v) {
+            return e;
+        }
+    }
+    let compressed: Vec<_> = pairs
+        .iter()
+        .map(|(k, v)| db.entry(k, v, EntryType::Put))
+        .collect();
+    if compressed.iter().map(Entry::len).sum::<usize>() > PAGE_CAPACITY {
+        return Message::error(MSET_TOO_LARGE);
+    }
+
+    let _permit = db.insert_permit().await;
+    let (m, kd) = (&db.pc, &db.kd);
+    let mut current = m.get_current().await;
+
+    let mut entries = Vec::with_capacity(pairs.len());
+    for entry in compressed {
+        match check(kd, &entry.key, user, None, db.now()).await {
+            Ok(fence) => entries.push(entry.with_owner(user.uid).with_fence(fence)),
+            Err(e) => return e,
+        }
+    }
+
+    let offsets = match m.write_entries(&mut current, &entries).await {
+        Ok(offsets) => offsets,
+        Err(e) => return Message::Error("ERR".into(), e.to_string().into()),
+    };
+
+    let mut kd = kd.write().await;
+    for (entry, offset) in entries.iter().zip(offsets) {
+        let data = KeyData::new(current.id, offset)
+            .with_owner(entry.owner)
+            .with_fence(entry.fence)
+            .with_len(entry.len());
+        kd.insert(&entry.key, data);
+    }
+    drop(kd);
+    drop(current);
+
+    m.commit().await;
+    db.write_behind(entries).await;
+
+    Message::Success
+}
+
+/// Reads the value of `k` and writes it back patched while holding the current page, so no
+/// other write to the key can land in between.
+async fn set_range(db: &Db, user: &User, k: &[u8], offset: u64, patch: &[u8]) -> Message {
+    // The entry has to fit in a page, which also stops an offset allocating a huge value
+    if offset > PAGE_CAPACITY as u64 || offset as usize + patch.len() > PAGE_CAPACITY {
+        return Message::error(VALUE_TOO_LARGE);
+    }
+    let end = offset as usize + patch.len();
+
+    let _permit = db.insert_permit().await;
+    let (m, kd) = (&db.pc, &db.kd);
+    let mut current = m.get_current().await;
+    let fence = match check(kd, k, user, None, db.now()).await {
+        Ok(f) => f,
+        Err(e) => return e,
+    };
+    let (mut v, expires) = match db.read_holding(&current, k).await {
+        Ok(Some(entry)) => (entry.value.to_vec(), entry.expires),
+        Ok(None) => (Vec::new(), None),
+        Err(e) => return storage_error(e),
+    };
+
+    if v.len() < end {
+        v.resize(end, 0);
+    }
+    v[offset as usize..end].copy_from_slice(patch);
+    if let Err(e) = validate(db, k, &v) {
+        return e;
+    }
+
+    let mut entry = db
+        .entry(k, &v, EntryType::Put)
+        .with_owner(user.uid)
+        .with_fence(fence);
+    entry.expires = expires;
+    let offset = match m.write_entry(&mut current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error("ERR".into(), e.to_string().into()),
+    };
+
+    let data = KeyData::new(current.id, offset)
+        .with_expiry(expires)
+        .with_owner(user.uid)
+        .with_fence(fence)
+        .with_len(entry.len());
+    kd.write().await.insert(k, data);
+    drop(current);
+
+    m.commit().await;
+    db.write_behind([entry]).await;
+
+    Message::Text(v.len().to_string().into())
+}
+
+async fn merge(db: &Db, user: &User, op: &[u8], k: &[u8], operand: &[u8]) -> Message {
+    let op = match String::from_utf8_lossy(op).parse::<MergeOp>() {
+        Ok(op) => op,
+        Err(e) => return Message::Error("ERR".into(), e.to_string().into()),
+    };
+
+    match merge_value(db, user, op, k, operand).await {
+        Ok(v) => Message::Result(Bytes::copy_from_slice(k), v.into()),
+        Err(e) => e,
+    }
+}
+
+async fn append(db: &Db, user: &User, k: &[u8], v: &[u8]) -> Message {
+    match merge_value(db, user, MergeOp::Append, k, v).await {
+        Ok(v) => Message::Text(v.len().to_string().into()),
+        Err(e) => e,
+    }
+}
+
+/// Merges `operand` into the value of `k` under the current page, so no write lands in between.
+/// Returns the new value.
+async fn merge_value(
+    db: &Db,
+    user: &User,
+    op: MergeOp,
+    k: &[u8],
+    operand: &[u8],
+) -> Result<Vec<u8>, Message> {
+    let _permit = db.insert_permit().await;
+    let (m, kd) = (&db.pc, &db.kd);
+    let mut current = m.get_current().await;
+    let fence = check(kd, k, user, None, db.now()).await?;
+    let (v, expires) = match db.read_holding(&current, k).await {
+        Ok(Some(entry)) => (op.apply(Some(&entry.value), operand), entry.expires),
+        Ok(None) => (op.apply(None, operand), None),
+        Err(e) => return Err(storage_error(e)),
+    };
+    let v = v.map_err(|e| Message::Error("ERR".into(), e.to_string().into()))?;
+    validate(db, k, &v)?;
+
+    let mut entry = db
+        .entry(k, &v, EntryType::Put)
+        .with_owner(user.uid)
+        .with_fence(fence);
+    entry.expires = expires;
+    if entry.len() > PAGE_CAPACITY {
+        return Err(Message::error(VALUE_TOO_LARGE));
+    }
+    let offset = m
+        .write_entry(&mut current, &entry)
+        .await
+        .map_err(|e| Message::Error("ERR".into(), e.to_string().into()))?;
+
+    let data = KeyData::new(current.id, offset)
+        .with_expiry(expires)
+        .with_owner(user.uid)
+        .with_fence(fence)
+        .with_len(entry.len());
+    kd.write().await.insert(k, data);
+    drop(current);
+
+    m.commit().await;
+    db.write_behind([entry]).await;
+
+    Ok(v)
+}
+
+async fn get_set(db: &Db, user: &User, k: &[u8], v: &[u8]) -> Message {
+    if let Err(e) = validate(db, k, v) {
+        return e;
+    }
+    let entry = db.entry(k, v, EntryType::Put).with_owner(user.uid);
+
+    match swap(db, user, entry, |_| true).await {
+        Ok(Some(prev)) => Message::Result(prev.key.into(), prev.value.into()),
+        Ok(None) => Message::NotFound,
+        Err(e) => e,
+    }
+}
+
+async fn set_nx(db: &Db, user: &User, k: &[u8], v: &[u8]) -> Message {
+    if let Err(e) = validate(db, k, v) {
+        return e;
+    }
+    let entry = db.entry(k, v, EntryType::Put).with_owner(user.uid);
+
+    match swap(db, user, entry, |prev| prev.is_none()).await {
+        Ok(Some(_)) => Message::Text("0".into()),
+        Ok(None) => Message::Text("1".into()),
+        Err(e) => e,
+    }
+}
+
+async fn get_del(db: &Db, user: &User, k: &[u8]) -> Message {
+    let entry = db.entry(k, &[], EntryType::Delete);
+
+    match swap(db, user, entry, |prev| prev.is_some()).await {
+        Ok(Some(prev)) => Message::Result(prev.key.into(), prev.value.into()),
+        Ok(None) => Message::NotFound,
+        Err(e) => e,
+    }
+}
+
+/// Rewrites the entry for `k` with a new expiry under the current page, keeping its value, so the
+/// expiry survives a restart. Removing the expiry of a key that doesn't have one writes nothing.
+async fn set_expiry(db: &Db, user: &User, k: &[u8], expires: Option<u64>) -> Message {
+    let _permit = db.insert_permit().await;
+    let (m, kd) = (&db.pc, &db.kd);
+    let mut current = m.get_current().await;
+    let fence = match check(kd, k, user, None, db.now()).await {
+        Ok(f) => f,
+        Err(e) => return e,
+    };
+    let prev = match db.read_holding(&current, k).await {
+        Ok(Some(prev)) if expires.is_some() || prev.expires.is_some() => prev,
+        Ok(_) => return Message::Text("0".into()),
+        Err(e) => return storage_error(e),
+    };
+
+    let mut entry = db
+        .entry(k, &prev.value, EntryType::Put)
+        .with_owner(user.uid)
+        .with_fence(fence);
+    entry.expires = expires;
+    let offset = match m.write_entry(&mut current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error("ERR".into(), e.to_string().into()),
+    };
+
+    let data = KeyData::new(current.id, offset)
+        .with_expiry(expires)
+        .with_owner(user.uid)
+        .with_fence(fence)
+        .with_len(entry.len());
+    kd.write().await.insert(k, data);
+    drop(current);
+
+    m.commit().await;
+    db.write_behind([entry]).await;
+
+    Message::Text("1".into())
+}
+
+/// Reads the entry for `entry.key` and, if `replace` accepts it, writes `entry` over it while
+/// holding the current page, so no other write lands in between. Returns the entry it read.
+async fn swap(
+    db: &Db,
+    user: &User,
+    entry: Entry,
+    replace: impl FnOnce(Option<&Entry>) -> bool,
+) -> Result<Option<Entry>, Message> {
+    let _permit = db.insert_permit().await;
+    let (m, kd) = (&db.pc, &db.kd);
+    let mut current = m.get_current().await;
+    let fence = check(kd, &entry.key, user, None, db.now()).await?;
+    let prev = db
+        .read_holding(&current, &entry.key)
+        .await
+        .map_err(storage_error)?;
+    if !replace(prev.as_ref()) {
+        return Ok(prev);
+    }
+
+    let entry = entry.with_fence(fence);
+    let offset = m
+        .write_entry(&mut current, &entry)
+        .await
+        .map_err(|e| Message::Error("ERR".into(), e.to_string().into()))?;
+
+    match entry.t {
+        EntryType::Put => {
+            let data = KeyData::new(current.id, offset)
+                .with_expiry(entry.expires)
+                .with_owner(entry.owner)
+                .with_fence(fence)
+                .with_len(entry.len());
+            kd.write().await.insert(&entry.key, data);
+        }
+        EntryType::Delete => {
+            kd.write().await.remove(&entry.key);
+        }
+    }
+    drop(current);
+
+    m.commit().await;
+    db.write_behind([entry]).await;
+
+    Ok(prev)
+}
+
+/// Removes `k` from the `KeyDir` and leaves writing its tombstone to the background, so it
+/// doesn't wait on a page write or fsync.
+async fn unlink(db: &Db, user: &User, k: &[u8]) -> Message {
+    let (m, kd) = (&db.pc, &db.kd);
+    let current = m.get_current().await;
+    if let Err(e) = check(kd, k, user, None, db.now()).await {
+        return e;
+    }
+
+    let removed = kd.write().await.remove(k);
+    drop(current);
+
+    if removed.is_some() {
+        db.unlink_later(k).await;
+        db.write_behind([db.entry(k, &[], EntryType::Delete)]).await;
+    }
+
+    Message::Success
+}
+
+/// Checks a value against the database's validators, see `Validators`.
+fn validate(db: &Db, k: &[u8], v: &[u8]) -> Result<(), Message> {
+    db.validators()
+        .check(k, v)
+        .map_err(|e| Message::Error("VALIDATION".into(), e.to_string().into()))
+}
+
+/// Checks `user` may overwrite or delete `k` with a write carrying `fence`, returning the fencing
+/// token the key keeps afterwards: writes without one keep the last token seen so stale holders
+/// are still rejected. Writers have to hold the current page so the owner and token can't change
+/// before their write lands.
+async fn check(
+    kd: &RwLock<KeyDir>,
+    k: &[u8],
+    user: &User,
+    fence: Option<u64>,
+    now: u64,
+) -> Result<Option<u64>, Message> {
+    let kd = kd.read().await;
+    let Some(data) = kd.get(k).filter(|data| !data.is_expired(now)) else {
+        return Ok(fence);
+    };
+
+    if !user.may_modify(data.owner) {
+        return Err(Message::error(NOPERM));
+    }
+
+    match (fence, data.fence) {
+        (Some(token), Some(last)) if token < last => Err(Message::error(FENCED)),
+        (Some(token), _) => Ok(Some(token)),
+        (None, last) => Ok(last),
+    }
+}
+
+/// Writes a tombstone for every key while holding the current page, then empties the `KeyDir`
+/// under a single lock. Fails without deleting anything if the user can't modify one of the keys.
+/// The tombstones span pages, so if a write fails the ones before it are already in the log and
+/// are replayed on restart. Their keys are removed too, so the keys read the same before and
+/// after a restart, and the error is returned.
+async fn flush_db(db: &Db, user: &User) -> Message {
+    let _permit = db.insert_permit().await;
+    let (m, kd) = (&db.pc, &db.kd);
+    let mut current = m.get_current().await;
+
+    let now = db.now();
+    let mut entries = Vec::new();
+    for (k, data) in kd.read().await.iter() {
+        if !data.is_expired(now) && !user.may_modify(data.owner) {
+            return Message::error(NOPERM);
+        }
+        entries.push(db.entry(k, &[], EntryType::Delete));
+    }
+
+    let mut res = Message::Success;
+    for (i, entry) in entries.iter().enumerate() {
+        if let Err(e) = m.write_entry(&mut current, entry).await {
+            res = Message::Error("ERR".into(), e.to_string().into());
+            entries.truncate(i);
+            break;
+        }
+    }
+
+    let mut kd = kd.write().await;
+    for entry in &entries {
+        kd.remove(&entry.key);
+    }
+    drop(kd);
+    drop(current);
+
+    m.commit().await;
+    db.write_behind(entries).await;
+
+    res
+}
+
+/// Replies to a read that failed, keeping the server up when the disk fails.
+fn storage_error(e: StorageError) -> Message {
+    match e {
+        StorageError::Corrupt => Message::error(CORRUPT),
+        StorageError::Io(e) => Message::Error("ERR".into(), e.to_string().into()),
+    }
+}
+
+/// Labels a forecast horizon, e.g. `5m` or `6h`.
+fn horizon(within: Duration) -> String {
+    match within.as_secs() {
+        secs if secs % 3600 == 0 => format!("{}h", secs / 3600),
+        secs if secs % 60 == 0 => format!("{}m", secs / 60),
+        secs => format!("{}s", secs),
+    }
+}
+
+/// Writes every entry of the batch to the same page while holding the current page, then applies
+/// them to the `KeyDir` under a single lock so readers see all of the batch or none of it.
+async fn batch(db: &Db, user: &User, messages: &[Message]) -> Message {
+    let entries = match batch_entries(db, user, messages) {
+        Ok(entries) => entries,
+        Err(e) => return e,
+    };
+
+    commit_batch(user, vec![(db, entries)]).await
+}
+
+/// Applies a batch whose writes go to several databases: the ones before the first `Use` go to
+/// `selected`, the rest to the database last switched to. Every database's current page is taken
+/// in name order, so batches over the same databases can't deadlock, and nothing is written
+/// unless the writes to all of them pass their checks. Each database's writes land on a single
+/// page of its own file, so they become visible together, but a crash can keep the writes to some
+/// of the databases and not the others.
+pub async fn batch_across(
+    dbs: &HashMap<Bytes, Db>,
+    selected: &Bytes,
+    user: &User,
+    messages: &[Message],
+) -> Message {
+    let mut writes: BTreeMap<&Bytes, Vec<Message>> = BTreeMap::new();
+    let mut name = selected;
+    for message in messages {
+        match message {
+            Message::Use(next) => match dbs.get_key_value(next) {
+                Some((next, _)) => name = next,
+                None => return Message::error(UNKNOWN_DATABASE),
+            },
+            message => writes.entry(name).or_default().push(message.clone()),
+        }
+    }
+
+    let mut batches = Vec::with_capacity(writes.len());
+    for (name, messages) in &writes {
+        let db = &dbs[*name];
+        match batch_entries(db, user, messages) {
+            Ok(entries) => batches.push((db, entries)),
+            Err(e) => return e,
+        }
+    }
+
+    commit_batch(user, batches).await
+}
+
+/// Builds the entries for a batch's writes to `db`, checking values against its validators.
+fn batch_entries(db: &Db, user: &User, messages: &[Message]) -> Result<Vec<Entry>, Message> {
+    let now = db.now();
+    let mut entries = Vec::with_capacity(messages.len());
+    for message in messages {
+        if let Message::Insert(k, v) | Message::InsertEx(k, v, _) = message {
+            validate(db, k, v)?;
+        }
+
+        let entry = match message {
+            Message::Insert(k, v) => db.entry(k, v, EntryType::Put).with_owner(user.uid),
+            Message::InsertEx(k, v, secs) => db
+                .entry(k, v, EntryType::Put)
+                .with_expiry(now.saturating_add(*secs))
+                .with_owner(user.uid),
+            Message::Delete(k) => db.entry(k, &[], EntryType::Delete),
+            Message::Invalid(_, e) => return Err(Message::error(*e)),
+            _ => return Err(Message::error(BATCH_WRITES_ONLY)),
+        };
+        entries.push(entry);
+    }
+
+    if entries.iter().map(Entry::len).sum::<usize>() > PAGE_CAPACITY {
+        return Err(Message::error(BATCH_TOO_LARGE));
+    }
+
+    Ok(entries)
+}
+
+/// Writes each database's entries to its current page, taking them in the order given, then
+/// applies them to every `KeyDir` at once.
+async fn commit_batch(user: &User, mut batches: Vec<(&Db, Vec<Entry>)>) -> Message {
+    let mut held = Vec::with_capacity(batches.len());
+    for (db, _) in &batches {
+        let permit = db.insert_permit().await;
+        held.push((permit, db.pc.get_current().await));
+    }
+
+    for (db, entries) in &mut batches {
+        for entry in entries.iter_mut() {
+            match check(&db.kd, &entry.key, user, None, db.now()).await {
+                Ok(fence) => entry.fence = fence,
+                Err(e) => return e,
+            }
+        }
+    }
+
+    let mut offsets = Vec::with_capacity(batches.len());
+    for ((db, entries), (_, current)) in batches.iter().zip(&mut held) {
+        match db.pc.write_entries(current, entries).await {
+            Ok(o) => offsets.push(o),
+            Err(e) => return Message::Error("ERR".into(), e.to_string().into()),
+        }
+    }
+
+    let mut kds = Vec::with_capacity(batches.len());
+    for (db, _) in &batches {
+        kds.push(db.kd.write().await);
+    }
+    for (((kd, (_, entries)), (_, current)), offsets) in
+        kds.iter_mut().zip(&batches).zip(&held).zip(offsets)
+    {
+        for (entry, offset) in entries.iter().zip(offsets) {
+            match entry.t {
+                EntryType::Put => {
+                    let data = KeyData::new(current.id, offset)
+                        .with_expiry(entry.expires)
+                        .with_owner(entry.owner)
+                        .with_fence(entry.fence)
+                        .with_len(entry.len());
+                    kd.insert(&entry.key, data);
+                }
+                EntryType::Delete => {
+                    kd.remove(&entry.key);
+                }
+            }
+        }
+    }
+    drop(kds);
+    drop(held);
+
+    for (db, entries) in batches {
+        db.pc.commit().await;
+        db.write_behind(entries).await;
+    }
+
+    Message::Success
+}
+
+/// Parses the writes between `multi` and `exec`. Anything else inside the batch is kept too, so
+/// running the batch answers it with an error and writes nothing.
+fn parse_batch(buf: &[u8]) -> Option<Message> {
+    let mut messages = Vec::new();
+    let mut quoted = false;
+
+    let mut pos = 6;
+    loop {
+        let rest = &buf[pos..];
+        if rest.starts_with(b"exec\n") {
+            let batch = Message::Batch(messages);
+
+            return match quoted {
+                true => Some(Message::Quoted(pos + 5, Box::new(batch))),
+                false => Some(batch),
+            };
+        }
+
+        let message = Message::parse(rest)?;
+        pos += message.len();
+        quoted |= matches!(message, Message::Quoted(_, _));
+
+        messages.push(message.unquoted());
+    }
+}
+
+/// Parses a request in the quoted form, see `quote`. Returns `None` if `buf` doesn't start with
+/// a whole line in that form, so the rest of `parse` reads it as before. Only lines where an
+/// argument starts with a quote are in the quoted form, an insert's value only at its start.
+fn parse_quoted(buf: &[u8]) -> Option<Message> {
+    let end = buf.iter().position(|b| *b == b'\n')?;
+    let line = &buf[..end];
+    let (command, args) = QUOTED_COMMANDS
+        .iter()
+        .find_map(|c| line.strip_prefix(*c).map(|args| (*c, args)))?;
+
+    let quoted = match command {
+        b"insert " => match args.iter().position(|b| *b == b' ') {
+            _ if args.starts_with(b"\"") => true,
+            Some(i) => args[i + 1..].starts_with(b"\""),
+            None => false,
+        },
+        b"mget " | MGET_CONSISTENT | b"mset " => {
+            args.split(|b| *b == b' ').any(|w| w.starts_with(b"\""))
+        }
+        _ => args.starts_with(b"\""),
+    };
+    if !quoted {
+        return None;
+    }
+
+    let len = end + 1;
+    let Some(words) = quote::split(args) else {
+        return Some(Message::Invalid(len, INVALID_QUOTING));
+    };
+
+    let message = match (command, words.as_slice()) {
+        (b"insert ", [k, v]) => Message::Insert(k.clone(), v.clone()),
+        (b"insert ", [k, v, ex, secs]) if &ex[..] == b"EX" => match parse_secs(secs) {
+            Some(secs) => Message::InsertEx(k.clone(), v.clone(), secs),
+            None => return Some(Message::Invalid(len, INVALID_EXPIRE_TIME)),
+        },
+        (b"get ", [k]) => Message::Get(k.clone()),
+        (b"delete ", [k]) => Message::Delete(k.clone()),
+        (b"unlink ", [k]) => Message::Unlink(k.clone()),
+        (b"exists ", [k]) => Message::Exists(k.clone()),
+        (b"strlen ", [k]) => Message::Strlen(k.clone()),
+        (MEMORY_USAGE, [k]) => Message::MemoryUsage(k.clone()),
+        (b"mget ", keys) if !keys.is_empty() => Message::MGet(keys.to_vec(), false),
+        (MGET_CONSISTENT, keys) if !keys.is_empty() => Message::MGet(keys.to_vec(), true),
+        (b"mset ", words) if !words.is_empty() && words.len().is_multiple_of(2) => Message::MSet(
+            words
+                .chunks(2)
+                .map(|kv| (kv[0].clone(), kv[1].clone()))
+                .collect(),
+        ),
+        _ => return Some(Message::Invalid(len, WRONG_ARGUMENTS)),
+    };
+
+    Some(Message::Quoted(len, Box::new(message)))
+}
+
+/// Rejects the line at the start of `buf`, to be answered with `error`. Returns `None` if the
+/// line isn't all there yet.
+fn reject(buf: &[u8], error: (&'static str, &'static str)) -> Option<Message> {
+    let end = buf.iter().position(|b| *b == b'\n')?;
+
+    Some(Message::Invalid(end + 1, error))
+}
+
+/// Whether an insert has to be sent in the quoted form to be read back as itself. A key that
+/// starts with `--` would be parsed as an option, and a value that looks like it ends in an
+/// expiry as one.
+fn needs_quoted_insert(k: &[u8], v: &Bytes) -> bool {
+    quote::needs_quotes(k)
+        || quote::needs_quotes_at_end(v)
+        || k.starts_with(b"--")
+        || split_expiry(v).is_some()
+}
+
+/// Clients only get to choose a file name for exports, not where on the server it is written.
+fn export_file(file: &[u8]) -> Option<&str> {
+    match std::str::from_utf8(file) {
+        Ok(f) if !f.is_empty() && !f.contains('/') && f != "." && f != ".." => Some(f),
+        _ => None,
+    }
+}
+
+/// Splits a trailing ` EX <secs>` off an insert value, with `None` for seconds that aren't a
+/// valid expiry. A value that should end in ` EX ` and a word has to be sent quoted.
+fn split_expiry(value: &Bytes) -> Option<(Bytes, Option<u64>)> {
+    let i = value.windows(4).rposition(|w| w == b" EX ")?;
+    let secs = &value[i + 4..];
+    if secs.is_empty() || secs.contains(&b' ') {
+        return None;
+    }
+
+    Some((value.slice(..i), parse_secs(secs)))
+}
+
+/// Parses the seconds of an expiry.
+fn parse_secs(digits: &[u8]) -> Option<u64> {
+    let secs: u64 = std::str::from_utf8(digits).ok()?.parse().ok()?;
+
+    // Message::len recomputes the expiry from secs, so only accept its canonical form
+    (secs.to_string().as_bytes() == digits).then_some(secs)
+}
+
+fn values(keys: &[Bytes], entries: Vec<Option<Entry>>) -> Message {
+    Message::Values(
+        keys.iter()
+            .cloned()
+            .zip(entries.into_iter().map(|e| e.map(|e| e.value.freeze())))
+            .collect(),
+    )
+}
+
+fn scan(kd: &KeyDir, cursor: &Bytes, prefix: &Option<Bytes>, now: u64) -> Message {
+    let after = match &cursor[..] {
+        SCAN_START => None,
+        cursor => match unhex(cursor) {
+            Some(k) => Some(k),
+            None => return Message::error(INVALID_CURSOR),
+        },
+    };
+    let prefix = prefix.as_deref().unwrap_or_default();
+
+    let keys = kd.scan(after.as_deref(), prefix, SCAN_COUNT, now);
+    let cursor = match keys.last() {
+        Some(k) if keys.len() == SCAN_COUNT => hex(k),
+        _ => Bytes::from_static(SCAN_START),
+    };
+
+    Message::Keys(cursor, keys.into_iter().map(Bytes::from).collect())
+}
+
+/// Reads the keys for a `Range`, with the cursor to reply with: the hex encoded key the range
+/// continues from if it has more than `limit` keys, otherwise the start cursor.
+fn range(
+    kd: &KeyDir,
+    start: &[u8],
+    end: &[u8],
+    limit: Option<usize>,
+    now: u64,
+) -> (Vec<Bytes>, Bytes) {
+    let limit = limit.unwrap_or(SCAN_COUNT);
+    let mut keys: Vec<_> = kd
+        .range(start, end, limit.saturating_add(1), now)
+        .into_iter()
+        .map(Bytes::from)
+        .collect();
+    let cursor = match keys.len() > limit {
+        true => hex(&keys.pop().expect("should have more than limit keys")),
+        false => Bytes::from_static(SCAN_START),
+    };
+
+    (keys, cursor)
+}
+
+/// A `Range` reply with each key followed by its value, leaving out keys deleted since they were
+/// listed.
+fn with_values(cursor: Bytes, entries: Vec<Option<Entry>>) -> Message {
+    let words = entries
+        .into_iter()
+        .flatten()
+        .flat_map(|e| [e.key.freeze(), e.value.freeze()])
+        .collect();
+
+    Message::Keys(cursor, words)
+}
+
+fn ttl(kd: &KeyDir, k: &[u8], now: u64) -> Message {
+    let ttl = match kd.get(k).filter(|data| !data.is_expired(now)) {
+        Some(KeyData {
+            expires: Some(expires),
+            ..
+        }) => i64::try_from(expires - now).unwrap_or(i64::MAX),
+        Some(_) => -1,
+        None => -2,
+    };
+
+    Message::Text(ttl.to_string().into())
+}
+
+fn memory_usage(kd: &KeyDir, k: &[u8], now: u64) -> Message {
+    match kd.memory_usage(k, now) {
+        Some(n) => Message::Text(n.to_string().into()),
+        None => Message::NotFound,
+    }
+}
+
+fn db_size(kd: &KeyDir, now: u64) -> Message {
+    let n = kd.iter().filter(|(_, data)| !data.is_expired(now)).count();
+
+    Message::Text(n.to_string().into())
+}
+
+/// Server wide stats for `info`, which a database doesn't know about.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ServerInfo {
+    pub uptime: Duration,
+    pub connections: u64,
+    pub rejected: u64,
+}
+
+/// Builds the reply to an `Info`, with a server section if `server` is set.
+pub async fn info(db: &Db, server: Option<ServerInfo>) -> Message {
+    let mut fields = Vec::new();
+    if let Some(server) = server {
+        fields.push(("server.uptime_secs", server.uptime.as_secs()));
+        fields.push(("server.connections", server.connections));
+        fields.push(("server.rejected", server.rejected));
+    }
+
+    let (keys, key_bytes, entry_bytes) = {
+        let kd = db.kd.read().await;
+        (kd.len(), kd.key_bytes(), kd.entry_bytes())
+    };
+    // Pages written so far, and the space they take up once reclaimed pages are given back
+    let (file_bytes, disk_bytes) = match tokio::try_join!(db.pc.pages(), db.pc.disk_bytes()) {
+        Ok((pages, disk_bytes)) => (pages as u64 * PAGE_SIZE as u64, disk_bytes),
+        Err(e) => return Message::Error("ERR".into(), e.to_string().into()),
+    };
+    fields.push(("keyspace.keys", keys as u64));
+    fields.push(("keyspace.key_bytes", key_bytes as u64));
+    fields.push(("keyspace.entry_bytes", entry_bytes as u64));
+    fields.push(("keyspace.file_bytes", file_bytes));
+    fields.push(("keyspace.disk_bytes", disk_bytes));
+
+    let cache = db.pc.stats();
+    fields.push(("cache.frames", db.pc.frames().await as u64));
+    fields.push(("cache.hits", cache.hits));
+    fields.push(("cache.misses", cache.misses));
+    fields.push(("cache.evictions", cache.evictions));
+    fields.push(("cache.prefetched", cache.prefetched));
+    if let Some(values) = db.value_cache_stats() {
+        fields.push(("value_cache.keys", values.keys as u64));
+        fields.push(("value_cache.hits", values.hits));
+        fields.push(("value_cache.misses", values.misses));
+    }
+
+    // 0 if compaction hasn't run yet
+    fields.push(("compaction.last_run", cache.last_compaction.unwrap_or(0)));
+    fields.push(("compaction.pages_reclaimed", cache.pages_reclaimed));
+
+    let text: Vec<_> = fields
+        .iter()
+        .map(|(name, n)| format!("{}:{}", name, n))
+        .collect();
+
+    Message::Text(text.join(" ").into())
+}
+
+/// Reads the next chunk of keys matching `pattern` after `after` for a `KeysMatching`. Returns
+/// the chunk as a `Keys` reply, and the key to continue after if there may be more.
+pub async fn keys_matching(
+    db: &Db,
+    pattern: &[u8],
+    after: Option<&[u8]>,
+) -> (Message, Option<Bytes>) {
+    matching(&*db.kd.read().await, pattern, after, db.now())
+}
+
+/// Like `keys_matching`, from a transaction's view of the keyspace.
+pub fn keys_matching_in(
+    txn: &ReadTxn,
+    pattern: &[u8],
+    after: Option<&[u8]>,
+) -> (Message, Option<Bytes>) {
+    matching(txn.keys(), pattern, after, txn.now())
+}
+
+fn matching(
+    kd: &KeyDir,
+    pattern: &[u8],
+    after: Option<&[u8]>,
+    now: u64,
+) -> (Message, Option<Bytes>) {
+    let keys = kd.scan_glob(after, pattern, SCAN_COUNT, now);
+    let next = match keys.last() {
+        Some(k) if keys.len() == SCAN_COUNT => Some(Bytes::copy_from_slice(k)),
+        _ => None,
+    };
+    let cursor = match &next {
+        Some(k) => hex(k),
+        None => Bytes::from_static(SCAN_START),
+    };
+
+    (
+        Message::Keys(cursor, keys.into_iter().map(Bytes::from).collect()),
+        next,
+    )
+}
+
+fn hex(b: &[u8]) -> Bytes {
+    b.iter()
+        .flat_map(|b| [b >> 4, b & 0xf])
+        .map(|n| b"0123456789abcdef"[n as usize])
+        .collect()
+}
+
+fn unhex(b: &[u8]) -> Option<Vec<u8>> {
+    if !b.len().is_multiple_of(2) {
+        return None;
+    }
+
+    b.chunks(2)
+        .map(|c| u8::from_str_radix(std::str::from_utf8(c).ok()?, 16).ok())
+        .collect()
+}
+
+fn split_words(line: &Bytes) -> Vec<Bytes> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    for (i, b) in line.iter().enumerate() {
+        if *b == b' ' {
+            words.push(line.slice(start..i));
+            start = i + 1;
+        }
+    }
+    words.push(line.slice(start..));
+
+    words
+}
+
+fn read_until(cursor: &Cursor<&[u8]>, c: u8) -> Option<Bytes> {
+    let start = cursor.position() as usize;
+    let end = cursor.get_ref().len();
+
+    for i in start..end {
+        if cursor.get_ref()[i] == c {
+            let ret = BytesMut::from(&cursor.get_ref()[start..i]);
+            let ret = Bytes::from(ret);
+            return Some(ret);
+        }
+    }
+
+    None
+}
+
+impl From<Message> for Bytes {
+    fn from(m: Message) -> Bytes {
+        match m {
+            Message::Insert(_, _)
+            | Message::InsertEx(_, _, _)
+            | Message::Delete(_)
+            | Message::Del(_)
+            | Message::Unlink(_)
+            | Message::SetRange(_, _, _)
+            | Message::Merge(_, _, _)
+            | Message::Append(_, _)
+            | Message::GetSet(_, _)
+            | Message::SetNx(_, _)
+            | Message::GetDel(_)
+            | Message::Expire(_, _)
+            | Message::ExpireAt(_, _)
+            | Message::Persist(_)
+            | Message::Ttl(_)
+            | Message::Get(_)
+            | Message::Exists(_)
+            | Message::Strlen(_)
+            | Message::MemoryUsage(_)
+            | Message::MGet(_, _)
+            | Message::MSet(_)
+            | Message::Use(_)
+            | Message::Batch(_)
+            | Message::Scan(_, _)
+            | Message::Range(_, _, _, _)
+            | Message::KeysMatching(_)
+            | Message::CompactionEstimate
+            | Message::PageFill
+            | Message::BootstrapStats
+            | Message::Health
+            | Message::Info
+            | Message::ExpiryForecast
+            | Message::Select(_)
+            | Message::DbSize
+            | Message::FlushDb
+            | Message::Validate(_, _)
+            | Message::Unvalidate(_)
+            | Message::Validators
+            | Message::ExportKeys(_, _)
+            | Message::ExportSstable(_)
+            | Message::Snapshot(_)
+            | Message::Notifications
+            | Message::Begin
+            | Message::Commit
+            | Message::Discard
+            | Message::Watch(_, _)
+            | Message::Subscribe(_)
+            | Message::Publish(_, _)
+            | Message::SubscribeOplog(_)
+            | Message::ConfigGet(_)
+            | Message::ConfigSet(_, _)
+            | Message::Fenced(_, _)
+            | Message::Grouped(_, _)
+            | Message::Quoted(_, _)
+            | Message::Invalid(_, _)
+            | Message::None => Bytes::new(),
+
+            // Keys and values are only quoted if they'd be ambiguous otherwise, see `quote`
+            Message::Result(k, v) => {
+                let mut dst = BytesMut::with_capacity(k.len() + v.len() + 2);
+                quote::put_word(&mut dst, &k);
+                dst.extend_from_slice(b" ");
+                quote::put_at_end(&mut dst, &v);
+                dst.extend_from_slice(b"\n");
+
+                dst.into()
+            }
+            Message::Values(values) => {
+                let mut dst = BytesMut::new();
+                for (k, v) in values {
+                    quote::put_word(&mut dst, &k);
+                    if let Some(v) = v {
+                        dst.extend_from_slice(b" ");
+                        quote::put_at_end(&mut dst, &v);
+                    }
+                    dst.extend_from_slice(b"\n");
+                }
+
+                dst.into()
+            }
+            Message::Keys(cursor, keys) => {
+                let mut dst = BytesMut::new();
+                dst.extend_from_slice(&cursor);
+                for k in keys {
+                    dst.extend_from_slice(b" ");
+                    quote::put_word(&mut dst, &k);
+                }
+                dst.extend_from_slice(b"\n");
+
+                dst.into()
+            }
+            Message::Text(t) => {
+                let mut dst = BytesMut::with_capacity(t.len() + 1);
+                dst.extend_from_slice(&t);
+                dst.extend_from_slice(b"\n");
+
+                dst.into()
+            }
+            Message::Success => Bytes::from("Success\n"),
+            Message::NotFound => Bytes::from("NotFound\n"),
+            Message::Error(code, text) => {
+                let mut dst = BytesMut::with_capacity(8 + code.len() + text.len());
+                dst.extend_from_slice(b"Error ");
+                dst.extend_from_slice(&code);
+                dst.extend_from_slice(b" ");
+                dst.extend_from_slice(&text);
+                dst.extend_from_slice(b"\n");
+
+                dst.into()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, io, time::Duration};
+
+    use bytes::{BufMut, Bytes};
+
+    use crate::{
+        serverv2::{
+            auth::User,
+            message::{
+                batch_across, keys_matching, Limits, Message, ADMIN_ONLY, BATCH_WRITES_ONLY,
+                EMPTY_GROUP, FENCED_WRITES_ONLY, FRAME_MAGIC, INVALID_DB_INDEX,
+                INVALID_EXPIRE_TIME, INVALID_FENCE_TOKEN, INVALID_LIMIT, INVALID_OFFSET,
+                INVALID_OPLOG_POSITION, INVALID_QUOTING, KEY_TOO_LONG, MSET_TOO_LARGE, NOPERM,
+                OP_DEL, OP_ERROR, OP_GET, OP_NOT_FOUND, OP_SET, OP_SETEX, OP_VALUE, SCAN_COUNT,
+                UNKNOWN_COMMAND, UNKNOWN_DATABASE, VALUE_TOO_LARGE, VALUE_TOO_LONG,
+                WRONG_ARGUMENTS,
+            },
+        },
+        storagev2::{
+            clock::TestClock,
+            compaction::Compactor,
+            db::{Db, Options},
+            events::Change,
+            log::{self, Entry, EntryType},
+            page::PAGE_SIZE,
+            page_manager::GROUP_ROOM,
+            test::CleanUp,
+            txn::ReadTxn,
+        },
+    };
+
+    #[test]
+    fn test_request() {
+        let messages = [
+            Message::Insert("key1".into(), "value 1".into()),
+            Message::InsertEx("key1".into(), "value1".into(), 10),
+            Message::InsertEx("key1".into(), "value 1".into(), 10),
+            Message::Delete("key1".into()),
+            Message::Unlink("key1".into()),
+            Message::MSet(vec![
+                ("key1".into(), "value1".into()),
+                ("key2".into(), "value2".into()),
+            ]),
+            Message::Fenced(
+                7,
+                Box::new(Message::InsertEx("key1".into(), "value1".into(), 10)),
+            ),
+            Message::Grouped(
+                "g1".into(),
+                Box::new(Message::InsertEx("key1".into(), "value1".into(), 10)),
+            ),
+            Message::Fenced(
+                7,
+                Box::new(Message::Grouped(
+                    "g1".into(),
+                    Box::new(Message::Insert("key1".into(), "value1".into())),
+                )),
+            ),
+            Message::Batch(vec![
+                Message::Insert("key1".into(), "value1".into()),
+                Message::Delete("key2".into()),
+            ]),
+            // Sent in the quoted form
+            Message::Insert("key 1".into(), "value1".into()),
+            Message::Insert("--group".into(), "value1".into()),
+            Message::Insert("key1".into(), "value1 EX 10".into()),
+            Message::Insert("key1".into(), "value1 EX ten".into()),
+            Message::InsertEx("key\n1".into(), "\"value\"\r\n1".into(), 10),
+            Message::InsertEx("key1".into(), "\"value1".into(), 10),
+            Message::Delete("".into()),
+            Message::Unlink("\"key1\"".into()),
+            Message::MSet(vec![("key 1".into(), "value1".into())]),
+            Message::Fenced(7, Box::new(Message::Delete("key 1".into()))),
+            Message::Batch(vec![
+                Message::Insert("key1".into(), "value\n1".into()),
+                Message::Delete("key2".into()),
+            ]),
+        ];
+        for message in messages {
+            let req = message.request().expect("should encode");
+            let got = Message::parse(&req);
+            assert!(
+                got.clone().map(Message::unquoted).as_ref() == Some(&message),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                message,
+                got
+            );
+            let len = got.map_or(0, |m| m.len());
+            assert!(len == req.len(), "Got: {}", len);
+        }
+
+        let unrepresentable = [
+            Message::Grouped(
+                "g1".into(),
+                Box::new(Message::Insert("key 1".into(), "value1".into())),
+            ),
+            Message::Grouped("g 1".into(), Box::new(Message::Delete("key1".into()))),
+            Message::Get("key1".into()),
+        ];
+        for message in unrepresentable {
+            assert!(message.request().is_none(), "Got: {:?}", message.request());
+        }
+    }
+
+    #[test]
+    fn test_parse_expiry() {
+        let cases = [
+            (
+                &b"insert key1 value1 EX 10\n"[..],
+                Message::InsertEx("key1".into(), "value1".into(), 10),
+            ),
+            (
+                b"insert key1 value 1 EX 10\n",
+                Message::InsertEx("key1".into(), "value 1".into(), 10),
+            ),
+            (
+                b"insert --group g1 key1 value1 EX 10\n",
+                Message::Grouped(
+                    "g1".into(),
+                    Box::new(Message::InsertEx("key1".into(), "value1".into(), 10)),
+                ),
+            ),
+            (
+                b"insert \"key 1\" value1 EX 10\n",
+                Message::InsertEx("key 1".into(), "value1".into(), 10),
+            ),
+            // Only a last word after EX is an expiry
+            (
+                b"insert key1 value1 EX 10 s\n",
+                Message::Insert("key1".into(), "value1 EX 10 s".into()),
+            ),
+            (
+                b"insert key1 \"value1 EX 10\"\n",
+                Message::Insert("key1".into(), "value1 EX 10".into()),
+            ),
+            (
+                b"insert key1 value1 EX 010\n",
+                Message::Invalid(26, INVALID_EXPIRE_TIME),
+            ),
+            (
+                b"insert key1 value1 EX ten\n",
+                Message::Invalid(26, INVALID_EXPIRE_TIME),
+            ),
+            (
+                b"insert \"key1\" value1 EX ten\n",
+                Message::Invalid(28, INVALID_EXPIRE_TIME),
+            ),
+        ];
+        for (buf, expected) in cases {
+            let got = Message::parse(buf);
+            let len = got.as_ref().map_or(0, |m| m.len());
+            assert!(len == buf.len(), "Got: {}", len);
+
+            let got = got.map(Message::unquoted);
+            assert!(
+                got.as_ref() == Some(&expected),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_batch() -> io::Result<()> {
+        const DB_FILE: &str = "./test_batch.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+
+        let buf = b"multi\ninsert key1 value1\ninsert key2 value2\ndelete key1\nexec\nget key2\n";
+        let batch = Message::parse(buf).expect("should parse batch");
+        assert!(batch.len() == 61, "Got: {}", batch.len());
+        assert!(
+            batch
+                == Message::Batch(vec![
+                    Message::Insert("key1".into(), "value1".into()),
+                    Message::Insert("key2".into(), "value2".into()),
+                    Message::Delete("key1".into()),
+                ])
+        );
+
+        // Incomplete until exec arrives
+        assert!(Message::parse(&buf[..50]).is_none());
+
+        assert!(batch.exec(&db, &User::default()).await == Message::Success);
+
+        let cases = [
+            (
+                &b"multi\ninsert key3 value3\nget key2\nexec\nget key2\n"[..],
+                BATCH_WRITES_ONLY,
+            ),
+            (
+                b"multi\ninsert key3 value3\nfoo\nexec\nget key2\n",
+                UNKNOWN_COMMAND,
+            ),
+        ];
+        for (buf, e) in cases {
+            let batch = Message::parse(buf).expect("should parse batch");
+            assert!(batch.len() == buf.len() - 9, "Got: {}", batch.len());
+
+            let got = batch.exec(&db, &User::default()).await;
+            let expected = Message::error(e);
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        let cases = [
+            ("key1", Message::NotFound),
+            ("key2", Message::Result("key2".into(), "value2".into())),
+            ("key3", Message::NotFound),
+        ];
+        for (k, expected) in cases {
+            let got = Message::Get(k.into()).exec(&db, &User::default()).await;
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_batch_across() -> io::Result<()> {
+        const MAIN_FILE: &str = "./test_batch_across_main.db";
+        const OTHER_FILE: &str = "./test_batch_across_other.db";
+        let _cu_main = CleanUp::file(MAIN_FILE);
+        let _cu_other = CleanUp::file(OTHER_FILE);
+        let dbs = HashMap::from([
+            (
+                Bytes::from("main"),
+                Db::open(MAIN_FILE, Options::default()).await?,
+            ),
+            (
+                Bytes::from("other"),
+                Db::open(OTHER_FILE, Options::default()).await?,
+            ),
+        ]);
+        let (main, other) = (&dbs[&b"main"[..]], &dbs[&b"other"[..]]);
+        let selected = Bytes::from("main");
+        let alice = User {
+            uid: Some(1),
+            admin: false,
+        };
+
+        let buf = b"multi\ninsert key1 value1\nuse other\ninsert key2 value2\nexec\n";
+        let batch = Message::parse(buf).expect("should parse batch");
+        assert!(batch.len() == buf.len(), "Got: {}", batch.len());
+        let Message::Batch(messages) = &batch else {
+            panic!("Got: {:?}", batch);
+        };
+        let got = batch_across(&dbs, &selected, &alice, messages).await;
+        assert!(got == Message::Success, "Got: {:?}", got);
+
+        let cases = [
+            (main, "key1", true),
+            (main, "key2", false),
+            (other, "key1", false),
+            (other, "key2", true),
+        ];
+        for (db, k, expected) in cases {
+            let got = db.get(k.as_bytes()).await?.is_some();
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        // A write to one database failing its checks leaves the other untouched
+        let messages = [
+            Message::Insert("key3".into(), "value3".into()),
+            Message::Use("other".into()),
+            Message::Delete("key2".into()),
+        ];
+        let got = batch_across(&dbs, &selected, &User::default(), &messages).await;
+        assert!(got == Message::error(NOPERM), "Got: {:?}", got);
+        assert!(main.get(b"key3").await?.is_none());
+        assert!(other.get(b"key2").await?.is_some());
+
+        let messages = [Message::Use("missing".into())];
+        let got = batch_across(&dbs, &selected, &alice, &messages).await;
+        assert!(got == Message::error(UNKNOWN_DATABASE), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mget() -> io::Result<()> {
+        const DB_FILE: &str = "./test_mget.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let user = User::default();
+
+        let buf = b"mset key1 value1 key2 value2 key3 value3\nmget key1 key4 key3\n";
+        let mset = Message::parse(buf).expect("should parse mset");
+        let got = mset.exec(&db, &user).await;
+        assert!(got == Message::Success, "Got: {:?}", got);
+
+        let mget = Message::parse(&buf[mset.len()..]).expect("should parse mget");
+        assert!(mget.len() == 20, "Got: {}", mget.len());
+        let got = mget.exec(&db, &user).await;
+        let expected = Message::Values(vec![
+            ("key1".into(), Some("value1".into())),
+            ("key4".into(), None),
+            ("key3".into(), Some("value3".into())),
+        ]);
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        let got = Bytes::from(got);
+        assert!(
+            &got[..] == b"key1 value1\nkey4\nkey3 value3\n",
+            "Got: {:?}",
+            got
+        );
+
+        for buf in [&b"mget \nget key1\n"[..], b"mset key1\nget key1\n"] {
+            let got = Message::parse(buf);
+            let expected = Message::Invalid(buf.len() - 9, WRONG_ARGUMENTS);
+            assert!(
+                got.as_ref() == Some(&expected),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        // An mset that doesn't fit in a page sets nothing
+        let pairs = (0..PAGE_SIZE)
+            .map(|i| (format!("big{}", i).into(), "value".into()))
+            .collect();
+        let got = Message::MSet(pairs).exec(&db, &user).await;
+        assert!(got == Message::error(MSET_TOO_LARGE), "Got: {:?}", got);
+        let got = Message::Get("big0".into()).exec(&db, &user).await;
+        assert!(got == Message::NotFound, "Got: {:?}", got);
+
+        // Consistent reads never see one half of an mset without the other
+        let mset = Message::MSet(vec![
+            ("key1".into(), "value".into()),
+            ("key2".into(), "value".into()),
+        ]);
+        mset.exec(&db, &user).await;
+        let writer = {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for i in 0..200 {
+                    let v = Bytes::from(format!("value{}", i));
+                    let mset = Message::MSet(vec![("key1".into(), v.clone()), ("key2".into(), v)]);
+                    mset.exec(&db, &User::default()).await;
+                }
+            })
+        };
+        let mget = Message::parse(b"mget-consistent key1 key2\n").expect("should parse mget");
+        assert!(mget.len() == 26, "Got: {}", mget.len());
+        while !writer.is_finished() {
+            let got = mget.exec(&db, &user).await;
+            let Message::Values(values) = &got else {
+                panic!("Got: {:?}", got);
+            };
+            assert!(values[0].1 == values[1].1, "Got: {:?}", got);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unlink() -> io::Result<()> {
+        const DB_FILE: &str = "./test_unlink.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let user = User::default();
+
+        let messages = [
+            Message::Insert("key1".into(), "value1".into()),
+            Message::Insert("key2".into(), "value2".into()),
+            Message::Unlink("key1".into()),
+            Message::Unlink("key2".into()),
+            // Set again before its tombstone is written, the tombstone has to be skipped
+            Message::Insert("key2".into(), "value3".into()),
+            Message::Unlink("key3".into()),
+        ];
+        for message in messages {
+            let got = message.exec(&db, &user).await;
+            assert!(got == Message::Success, "{:?} got: {:?}", message, got);
+        }
+        let got = Message::Get("key1".into()).exec(&db, &user).await;
+        assert!(got == Message::NotFound, "Got: {:?}", got);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        db.flush().await?;
+
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        for (k, expected) in [("key1", None), ("key2", Some("value3")), ("key3", None)] {
+            let got = db.get(k.as_bytes()).await?;
+            let got = got.as_ref().map(|entry| &entry.value[..]);
+            assert!(
+                got == expected.map(str::as_bytes),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_del() -> io::Result<()> {
+        const DB_FILE: &str = "./test_del.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let user = User::default();
+
+        let cases = [
+            (
+                Message::Insert("key1".into(), "value1".into()),
+                Message::Success,
+            ),
+            (Message::Del("key1".into()), Message::Text("1".into())),
+            (Message::Del("key1".into()), Message::Text("0".into())),
+            (Message::Get("key1".into()), Message::NotFound),
+        ];
+        for (message, expected) in cases {
+            let got = message.exec(&db, &user).await;
+            assert!(
+                got == expected,
+                "\nMessage: {:?}\nExpected: {:?}\nGot: {:?}\n",
+                message,
+                expected,
+                got
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_exists_strlen() -> io::Result<()> {
+        const DB_FILE: &str = "./test_exists_strlen.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let user = User::default();
+
+        let got = Message::Insert("key1".into(), "value1".into())
+            .exec(&db, &user)
+            .await;
+        assert!(got == Message::Success, "Got: {:?}", got);
+
+        let buf = b"exists key1\nexists key2\nstrlen key1\nstrlen key2\n";
+        let expected = ["1", "0", "6", "0"];
+        let mut offset = 0;
+        for expected in expected {
+            let message = Message::parse(&buf[offset..]).expect("should parse");
+            offset += message.len();
+
+            let got = message.exec(&db, &user).await;
+            assert!(
+                got == Message::Text(expected.into()),
+                "{:?}\nExpected: {:?}\nGot: {:?}\n",
+                message,
+                expected,
+                got
+            );
+        }
+        assert!(offset == buf.len(), "Got: {}", offset);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_info() -> io::Result<()> {
+        const DB_FILE: &str = "./test_info.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let options = Options {
+            page_cache_size: 1,
+            ..Default::default()
+        };
+        let db = Db::open(DB_FILE, options).await?;
+        let user = User::default();
+
+        for i in 0..20 {
+            let k = format!("key{:02}", i);
+            let got = Message::Insert(k.into(), "value".repeat(8).into())
+                .exec(&db, &user)
+                .await;
+            assert!(got == Message::Success, "Got: {:?}", got);
+        }
+        // Keys from two pages that aren't current, so one evicts the other
+        for k in ["key00", "key10", "key00"] {
+            let got = Message::Get(k.into()).exec(&db, &user).await;
+            assert!(matches!(got, Message::Result(_, _)), "Got: {:?}", got);
+        }
+        for i in 0..10 {
+            let got = Message::Delete(format!("key{:02}", i).into())
+                .exec(&db, &user)
+                .await;
+            assert!(got == Message::Success, "Got: {:?}", got);
+        }
+        Compactor::new(db.pc.clone(), db.kd.clone())
+            .compact()
+            .await?;
+
+        let Message::Text(text) = Message::Info.exec(&db, &user).await else {
+            panic!("expected text");
+        };
+        let text = String::from_utf8_lossy(&text);
+        let fields: HashMap<_, u64> = text
+            .split(' ')
+            .filter_map(|field| field.split_once(':'))
+            .map(|(name, n)| (name, n.parse().expect("should be a number")))
+            .collect();
+
+        // Only the server knows about connections
+        assert!(!fields.contains_key("server.uptime_secs"), "Got: {}", text);
+        let expected = [
+            ("keyspace.keys", 10),
+            ("keyspace.key_bytes", 50),
+            ("cache.frames", 1),
+            ("cache.misses", 3),
+            ("cache.evictions", 2),
+        ];
+        for (name, n) in expected {
+            let got = fields.get(name);
+            assert!(
+                got == Some(&n),
+                "{}\nExpected: {:?}\nGot: {:?}\n",
+                name,
+                n,
+                got
+            );
+        }
+        assert!(fields["compaction.last_run"] > 0, "Got: {}", text);
+        assert!(fields["compaction.pages_reclaimed"] > 0, "Got: {}", text);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_value_cache() -> io::Result<()> {
+        const DB_FILE: &str = "./test_value_cache.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let options = Options {
+            value_cache_size: 16,
+            ..Default::default()
+        };
+        let db = Db::open(DB_FILE, options).await?;
+        let user = User::default();
+
+        let requests = [
+            (
+                Message::Insert("key1".into(), "value1".into()),
+                Message::Success,
+            ),
+            (
+                Message::Get("key1".into()),
+                Message::Result("key1".into(), "value1".into()),
+            ),
+            (
+                Message::Get("key1".into()),
+                Message::Result("key1".into(), "value1".into()),
+            ),
+            (
+                Message::Insert("key1".into(), "value2".into()),
+                Message::Success,
+            ),
+            (
+                Message::Get("key1".into()),
+                Message::Result("key1".into(), "value2".into()),
+            ),
+            (Message::Delete("key1".into()), Message::Success),
+            (Message::Get("key1".into()), Message::NotFound),
+        ];
+        for (request, expected) in requests {
+            let got = request.exec(&db, &user).await;
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        let Message::Text(text) = Message::Info.exec(&db, &user).await else {
+            panic!("expected text");
+        };
+        let text = String::from_utf8_lossy(&text);
+        for field in [
+            "value_cache.keys:1",
+            "value_cache.hits:1",
+            "value_cache.misses:2",
+        ] {
+            assert!(
+                text.split(' ').any(|f| f == field),
+                "{}\nGot: {}",
+                field,
+                text
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_memory_usage() -> io::Result<()> {
+        const DB_FILE: &str = "./test_memory_usage.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let user = User::default();
+
+        let value = "value".repeat(8);
+        Message::Insert("key1".into(), value.clone().into())
+            .exec(&db, &user)
+            .await;
+        Message::Insert("key2".into(), "v".into())
+            .exec(&db, &user)
+            .await;
+
+        let buf = b"memory usage key1\n";
+        let message = Message::parse(buf).expect("should parse memory usage");
+        assert!(message.len() == buf.len(), "Got: {}", message.len());
+        let Message::Text(got) = message.exec(&db, &user).await else {
+            panic!("expected text");
+        };
+        let got: usize = String::from_utf8_lossy(&got)
+            .parse()
+            .expect("should be a number");
+        let entry = Entry::new(b"key1", value.as_bytes(), EntryType::Put);
+        assert!(got > entry.len(), "Got: {}", got);
+
+        // Entries are counted as they're overwritten and deleted
+        let entry_bytes = db.kd.read().await.entry_bytes();
+        assert!(entry_bytes > entry.len(), "Got: {}", entry_bytes);
+        Message::Insert("key1".into(), "v".into())
+            .exec(&db, &user)
+            .await;
+        Message::Delete("key2".into()).exec(&db, &user).await;
+        let expected = Entry::new(b"key1", b"v", EntryType::Put).len();
+        let got = db.kd.read().await.entry_bytes();
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        let got = Message::MemoryUsage("key2".into()).exec(&db, &user).await;
+        assert!(got == Message::NotFound, "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_read_txn() -> io::Result<()> {
+        const DB_FILE: &str = "./test_read_txn.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let user = User::default();
+
+        let value = "value".repeat(8);
+        for i in 0..10 {
+            let got = Message::Insert(format!("key{}", i).into(), value.clone().into())
+                .exec(&db, &user)
+                .await;
+            assert!(got == Message::Success, "Got: {:?}", got);
+        }
+
+        let txn = ReadTxn::begin(&db).await;
+        let got = Message::Delete("key0".into()).exec(&db, &user).await;
+        assert!(got == Message::Success, "Got: {:?}", got);
+        for i in 1..10 {
+            let got = Message::Insert(format!("key{}", i).into(), "new".into())
+                .exec(&db, &user)
+                .await;
+            assert!(got == Message::Success, "Got: {:?}", got);
+        }
+
+        // Page 0 is dead, but kept on disk for the transaction
+        let stats = Compactor::new(db.pc.clone(), db.kd.clone())
+            .compact()
+            .await?;
+        assert!(stats.pages_reclaimed > 0, "Got: {:?}", stats);
+        assert!(db.pc.is_deferred(0));
+
+        let reads = [
+            (
+                Message::Get("key0".into()),
+                Message::Result("key0".into(), value.clone().into()),
+            ),
+            (Message::Exists("key0".into()), Message::Text("1".into())),
+            (Message::Strlen("key9".into()), Message::Text("40".into())),
+            (Message::DbSize, Message::Text("10".into())),
+        ];
+        for (message, expected) in reads {
+            let got = message.exec_in(&txn).await;
+            assert!(
+                got.as_ref() == Some(&expected),
+                "{:?}\nExpected: {:?}\nGot: {:?}\n",
+                message,
+                expected,
+                got
+            );
+        }
+        assert!(Message::Health.exec_in(&txn).await.is_none());
+
+        // Outside the transaction the writes are visible
+        let got = Message::Get("key1".into()).exec(&db, &user).await;
+        assert!(
+            got == Message::Result("key1".into(), "new".into()),
+            "Got: {:?}",
+            got
+        );
+
+        txn.end().await;
+        assert!(!db.pc.is_deferred(0));
+        assert!(db.pc.read_page(0).await?.read_entry(0) == Ok(None));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_watch() -> io::Result<()> {
+        const DB_FILE: &str = "./test_watch.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let user = User::default();
+
+        let buf = b"watch key1\nwatch --prefix key\n";
+        let expected = [
+            Message::Watch("key1".into(), false),
+            Message::Watch("key".into(), true),
+        ];
+        let mut offset = 0;
+        for expected in expected {
+            let got = Message::parse(&buf[offset..]);
+            assert!(got.as_ref() == Some(&expected), "Got: {:?}", got);
+            offset += expected.len();
+        }
+        assert!(offset == buf.len(), "Got: {}", offset);
+
+        let mut rx = db.watch();
+        let writes = [
+            Message::Insert("key1".into(), "value1".into()),
+            Message::Delete("key1".into()),
+            Message::Batch(vec![
+                Message::Insert("key2".into(), "value2".into()),
+                Message::Delete("key3".into()),
+            ]),
+            // Rejected writes aren't published
+            Message::SetRange("key4".into(), PAGE_SIZE as u64, "value".into()),
+        ];
+        for message in writes {
+            message.exec(&db, &user).await;
+        }
+
+        let expected = [
+            Change::Set("key1".into()),
+            Change::Deleted("key1".into()),
+            Change::Set("key2".into()),
+            Change::Deleted("key3".into()),
+        ];
+        for expected in expected {
+            let got = rx.try_recv();
+            assert!(
+                got.as_ref() == Ok(&expected),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+        assert!(rx.try_recv().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pubsub() {
+        let buf = b"subscribe news\npublish news hello world\n";
+        let expected = [
+            Message::Subscribe("news".into()),
+            Message::Publish("news".into(), "hello world".into()),
+        ];
+        let mut offset = 0;
+        for expected in expected {
+            let got = Message::parse(&buf[offset..]);
+            assert!(
+                got.as_ref() == Some(&expected),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+            offset += expected.len();
+        }
+        assert!(offset == buf.len(), "Got: {}", offset);
+
+        // Incomplete
+        assert!(Message::parse(b"publish news").is_none());
+    }
+
+    #[test]
+    fn test_config() {
+        let buf = b"config get durability\nconfig set max_key_len 512\n";
+        let expected = [
+            Message::ConfigGet("durability".into()),
+            Message::ConfigSet("max_key_len".into(), "512".into()),
+        ];
+        let mut offset = 0;
+        for expected in expected {
+            let got = Message::parse(&buf[offset..]);
+            assert!(
+                got.as_ref() == Some(&expected),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+            offset += expected.len();
+        }
+        assert!(offset == buf.len(), "Got: {}", offset);
+    }
+
+    #[test]
+    fn test_subscribe_oplog() {
+        let buf = b"subscribe-oplog 4096\n";
+        let got = Message::parse(buf);
+        assert!(got == Some(Message::SubscribeOplog(4096)), "Got: {:?}", got);
+        assert!(got.is_some_and(|m| m.len() == buf.len()));
+
+        let buf = b"subscribe-oplog 007\nget key1\n";
+        let got = Message::parse(buf);
+        let expected = Message::Invalid(20, INVALID_OPLOG_POSITION);
+        assert!(
+            got.as_ref() == Some(&expected),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_setrange() -> io::Result<()> {
+        const DB_FILE: &str = "./test_setrange.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let user = User::default();
+
+        let got = Message::InsertEx("key1".into(), "hello world".into(), 60)
+            .exec(&db, &user)
+            .await;
+        assert!(got == Message::Success, "Got: {:?}", got);
+
+        let buf = b"setrange key1 6 there\nsetrange key2 2 a b\n";
+        let first = Message::parse(buf).expect("should parse setrange");
+        let second = Message::parse(&buf[first.len()..]).expect("should parse setrange");
+        assert!(
+            first.len() + second.len() == buf.len(),
+            "Got: {} {}",
+            first.len(),
+            second.len()
+        );
+        assert!(
+            second.request().as_deref() == Some(&buf[first.len()..]),
+            "Got: {:?}",
+            second.request()
+        );
+
+        let cases = [
+            (first, "11", "key1", &b"hello there"[..]),
+            // Unset keys are empty, and short values are padded
+            (second, "5", "key2", b"\0\0a b"),
+        ];
+        for (message, expected_len, k, expected) in cases {
+            let got = message.exec(&db, &user).await;
+            assert!(got == Message::Text(expected_len.into()), "Got: {:?}", got);
+
+            let got = Message::Get(k.into()).exec(&db, &user).await;
+            let expected = Message::Result(k.into(), Bytes::copy_from_slice(expected));
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        // The expiry is kept
+        let expires = db
+            .kd
+            .read()
+            .await
+            .get(b"key1")
+            .and_then(|data| data.expires);
+        assert!(expires.is_some(), "Got: {:?}", expires);
+
+        let got = Message::SetRange("key1".into(), u64::MAX, "x".into())
+            .exec(&db, &user)
+            .await;
+        assert!(got == Message::error(VALUE_TOO_LARGE), "Got: {:?}", got);
+        let cases = [
+            (&b"setrange key1 06 x\n"[..], INVALID_OFFSET),
+            (b"setrange key1 6\n", WRONG_ARGUMENTS),
+        ];
+        for (buf, e) in cases {
+            let got = Message::parse(buf);
+            let expected = Message::Invalid(buf.len(), e);
+            assert!(
+                got.as_ref() == Some(&expected),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_merge() -> io::Result<()> {
+        const DB_FILE: &str = "./test_merge.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let user = User::default();
+
+        let buf = b"merge add counter 5\nmerge add counter -2\nmerge append log a b\n";
+        let mut offset = 0;
+        let mut got = Vec::new();
+        while offset < buf.len() {
+            let message = Message::parse(&buf[offset..]).expect("should parse merge");
+            assert!(
+                message.request().as_deref() == Some(&buf[offset..offset + message.len()]),
+                "Got: {:?}",
+                message.request()
+            );
+            offset += message.len();
+            got.push(message.exec(&db, &user).await);
+        }
+        let expected = [
+            Message::Result("counter".into(), "5".into()),
+            Message::Result("counter".into(), "3".into()),
+            Message::Result("log".into(), "a b".into()),
+        ];
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        // The expiry is kept
+        Message::InsertEx("doc".into(), r#"{"a":1}"#.into(), 60)
+            .exec(&db, &user)
+            .await;
+        let got = Message::Merge("json-patch".into(), "doc".into(), r#"{"b":2}"#.into())
+            .exec(&db, &user)
+            .await;
+        let expected = Message::Result("doc".into(), r#"{"a":1,"b":2}"#.into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        let expires = db.kd.read().await.get(b"doc").and_then(|data| data.expires);
+        assert!(expires.is_some(), "Got: {:?}", expires);
+
+        // Failed merges leave the value alone
+        for (op, k) in [("add", "log"), ("max", "counter")] {
+            let got = Message::Merge(op.into(), k.into(), "1".into())
+                .exec(&db, &user)
+                .await;
+            assert!(matches!(got, Message::Error(_, _)), "Got: {:?}", got);
+        }
+        let got = Message::Get("counter".into()).exec(&db, &user).await;
+        assert!(
+            got == Message::Result("counter".into(), "3".into()),
+            "Got: {:?}",
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_swap() -> io::Result<()> {
+        const DB_FILE: &str = "./test_swap.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let user = User::default();
+
+        let buf = b"setnx key1 a\nsetnx key1 b\nappend key1 bc\ngetset key1 d\n\
+            getdel key1\ngetdel key1\nappend key2 x y\n";
+        let mut offset = 0;
+        let mut got = Vec::new();
+        while offset < buf.len() {
+            let message = Message::parse(&buf[offset..]).expect("should parse");
+            assert!(
+                message.request().as_deref() == Some(&buf[offset..offset + message.len()]),
+                "Got: {:?}",
+                message.request()
+            );
+            offset += message.len();
+            got.push(message.exec(&db, &user).await);
+        }
+        let expected = [
+            Message::Text("1".into()),
+            Message::Text("0".into()),
+            Message::Text("3".into()),
+            Message::Result("key1".into(), "abc".into()),
+            Message::Result("key1".into(), "d".into()),
+            Message::NotFound,
+            Message::Text("3".into()),
+        ];
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        // The tombstone is written, so the delete survives a restart
+        db.flush().await?;
+        drop(db);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let got = Message::Get("key1".into()).exec(&db, &user).await;
+        assert!(got == Message::NotFound, "Got: {:?}", got);
+
+        // Appending keeps the expiry, setting a new value drops it
+        Message::InsertEx("key3".into(), "a".into(), 60)
+            .exec(&db, &user)
+            .await;
+        Message::Append("key3".into(), "b".into())
+            .exec(&db, &user)
+            .await;
+        let expires = db
+            .kd
+            .read()
+            .await
+            .get(b"key3")
+            .and_then(|data| data.expires);
+        assert!(expires.is_some(), "Got: {:?}", expires);
+        Message::GetSet("key3".into(), "c".into())
+            .exec(&db, &user)
+            .await;
+        let expires = db
+            .kd
+            .read()
+            .await
+            .get(b"key3")
+            .and_then(|data| data.expires);
+        assert!(expires.is_none(), "Got: {:?}", expires);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_expire() -> io::Result<()> {
+        const DB_FILE: &str = "./test_expire.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let clock = TestClock::new(1_000);
+        let db = Db::open_with_clock(DB_FILE, Options::default(), clock.clone()).await?;
+        let user = User::default();
+
+        Message::Insert("key1".into(), "value1".into())
+            .exec(&db, &user)
+            .await;
+        let buf = b"ttl key1\nexpire key1 100\nttl key1\npersist key1\npersist key1\n\
+            ttl key1\nexpireat key1 1500\nexpire key2 10\nttl key2\nexpire key1 010\n\
+            expireat key1 soon\nttl key1\n";
+        let mut offset = 0;
+        let mut got = Vec::new();
+        while offset < buf.len() {
+            let message = Message::parse(&buf[offset..]).expect("should parse");
+            offset += message.len();
+            got.push(message.exec(&db, &user).await);
+        }
+        let mut expected = ["-1", "1", "100", "1", "0", "-1", "1", "0", "-2"]
+            .map(|t| Message::Text(t.into()))
+            .to_vec();
+        expected.extend([
+            Message::error(INVALID_EXPIRE_TIME),
+            Message::error(INVALID_EXPIRE_TIME),
+            Message::Text("500".into()),
+        ]);
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        // The expiry is written with the entry
+        db.flush().await?;
+        drop(db);
+        let db = Db::open_with_clock(DB_FILE, Options::default(), clock.clone()).await?;
+        let got = Message::Ttl("key1".into()).exec(&db, &user).await;
+        assert!(got == Message::Text("500".into()), "Got: {:?}", got);
+
+        clock.advance(Duration::from_secs(500));
+        let got = Message::Ttl("key1".into()).exec(&db, &user).await;
+        assert!(got == Message::Text("-2".into()), "Got: {:?}", got);
+
+        // Expiries too far out to represent never expire rather than wrapping around
+        for message in [
+            Message::InsertEx("key3".into(), "value3".into(), u64::MAX),
+            Message::Batch(vec![Message::InsertEx(
+                "key4".into(),
+                "value4".into(),
+                u64::MAX,
+            )]),
+        ] {
+            let got = message.exec(&db, &user).await;
+            assert!(got == Message::Success, "Got: {:?}", got);
+        }
+        for k in ["key3", "key4"] {
+            let got = Message::Ttl(k.into()).exec(&db, &user).await;
+            let expected = Message::Text(i64::MAX.to_string().into());
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_range() -> io::Result<()> {
+        const DB_FILE: &str = "./test_range.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let options = Options {
+            ordered_index: true,
+            ..Default::default()
+        };
+        let db = Db::open(DB_FILE, options).await?;
+        let user = User::default();
+
+        let pairs = ["a", "b", "c", "d"]
+            .map(|k| (Bytes::from(k), Bytes::from(format!("{}1", k))))
+            .to_vec();
+        Message::MSet(pairs).exec(&db, &user).await;
+
+        let buf = b"range b d\nrange --values a z 2\nrange c a\n";
+        let mut offset = 0;
+        let mut got = Vec::new();
+        while offset < buf.len() {
+            let message = Message::parse(&buf[offset..]).expect("should parse range");
+            offset += message.len();
+            got.push(message.exec(&db, &user).await);
+        }
+        let expected = [
+            Message::Keys("0".into(), vec!["b".into(), "c".into()]),
+            Message::Keys(
+                "63".into(),
+                vec!["a".into(), "a1".into(), "b".into(), "b1".into()],
+            ),
+            Message::Keys("0".into(), vec![]),
+        ];
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        let cases = [
+            (&b"range a\nget a\n"[..], WRONG_ARGUMENTS),
+            (b"range a b 01\nget a\n", INVALID_LIMIT),
+            (b"range a b 1 2\nget a\n", WRONG_ARGUMENTS),
+        ];
+        for (buf, e) in cases {
+            let got = Message::parse(buf);
+            let expected = Message::Invalid(buf.len() - 6, e);
+            assert!(
+                got.as_ref() == Some(&expected),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_keys_matching() -> io::Result<()> {
+        const DB_FILE: &str = "./test_keys_matching.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let user = User::default();
+
+        let n = SCAN_COUNT + 5;
+        let mut pairs: Vec<_> = (0..n)
+            .map(|i| (format!("user:{}:name", i).into(), "v".into()))
+            .collect();
+        pairs.push(("user:x:age".into(), "v".into()));
+        pairs.push(("order:1:name".into(), "v".into()));
+        for (k, v) in pairs {
+            let got = Message::Insert(k, v).exec(&db, &user).await;
+            assert!(got == Message::Success, "Got: {:?}", got);
+        }
+
+        let message = Message::parse(b"keys user:[0-9]*:name\n").expect("should parse keys");
+        assert!(message.len() == 22, "Got: {}", message.len());
+        let Message::KeysMatching(pattern) = message else {
+            panic!("Got: {:?}", message);
+        };
+
+        let mut keys = Vec::new();
+        let mut chunks = 0;
+        let mut after = None;
+        loop {
+            let (chunk, next) = keys_matching(&db, &pattern, after.as_deref()).await;
+            let Message::Keys(cursor, chunk) = chunk else {
+                panic!("Got: {:?}", chunk);
+            };
+            keys.extend(chunk);
+            chunks += 1;
+
+            if next.is_none() {
+                assert!(&cursor[..] == b"0", "Got: {:?}", cursor);
+                break;
+            }
+            after = next;
+        }
+
+        assert!(chunks == 2, "Got: {}", chunks);
+        assert!(keys.len() == n, "Got: {}", keys.len());
+        assert!(keys.windows(2).all(|w| w[0] < w[1]), "Got: {:?}", keys);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_validate() -> io::Result<()> {
+        const DB_FILE: &str = "./test_validate.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let admin = User {
+            uid: Some(0),
+            admin: true,
+        };
+        let user = User::default();
+
+        let line = b"validate user: regex ^[a-z]+$\n";
+        let validate = Message::parse(line).expect("should parse");
+        assert!(validate.len() == line.len(), "Got: {}", validate.len());
+
+        let got = validate.exec(&db, &user).await;
+        assert!(got == Message::error(ADMIN_ONLY), "Got: {:?}", got);
+        let got = validate.exec(&db, &admin).await;
+        assert!(got == Message::Success, "Got: {:?}", got);
+
+        let cases = [
+            (Message::Insert("user:1".into(), "abc".into()), true),
+            (Message::Insert("user:1".into(), "ABC".into()), false),
+            (Message::MSet(vec![("user:2".into(), "1".into())]), false),
+            (
+                Message::Batch(vec![Message::InsertEx("user:3".into(), "!".into(), 10)]),
+                false,
+            ),
+            (Message::Insert("other".into(), "ABC".into()), true),
+        ];
+        for (message, expected) in cases {
+            let got = message.exec(&db, &user).await;
+            let ok = got == Message::Success;
+            assert!(ok == expected, "{:?} got: {:?}", message, got);
+        }
+
+        let got = Message::Validators.exec(&db, &user).await;
+        let expected = Message::Text("user: regex ^[a-z]+$".into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        let got = Message::Unvalidate("user:".into()).exec(&db, &admin).await;
+        assert!(got == Message::Success, "Got: {:?}", got);
+        let got = Message::Insert("user:1".into(), "ABC".into())
+            .exec(&db, &user)
+            .await;
+        assert!(got == Message::Success, "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_flush_db() -> io::Result<()> {
+        const DB_FILE: &str = "./test_flush_db.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let user = User::default();
+
+        for line in [&b"select 1\n"[..], b"dbsize\n", b"flushdb\n"] {
+            let message = Message::parse(line).expect("should parse");
+            assert!(
+                message.len() == line.len(),
+                "{:?} got: {}",
+                message,
+                message.len()
+            );
+        }
+        for buf in [&b"select 01\nget key1\n"[..], b"select one\nget key1\n"] {
+            let got = Message::parse(buf);
+            let expected = Message::Invalid(buf.len() - 9, INVALID_DB_INDEX);
+            assert!(
+                got.as_ref() == Some(&expected),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        for i in 0..3 {
+            let k = format!("key{}", i);
+            Message::Insert(k.into(), "value".into())
+                .exec(&db, &user)
+                .await;
+        }
+        let got = Message::DbSize.exec(&db, &user).await;
+        assert!(got == Message::Text("3".into()), "Got: {:?}", got);
+
+        let owner = User {
+            uid: Some(1),
+            admin: false,
+        };
+        Message::Insert("owned".into(), "value".into())
+            .exec(&db, &owner)
+            .await;
+        let got = Message::FlushDb.exec(&db, &user).await;
+        assert!(got == Message::error(NOPERM), "Got: {:?}", got);
+
+        let got = Message::FlushDb.exec(&db, &owner).await;
+        assert!(got == Message::Success, "Got: {:?}", got);
+        let got = Message::DbSize.exec(&db, &user).await;
+        assert!(got == Message::Text("0".into()), "Got: {:?}", got);
+
+        db.flush().await?;
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let got = Message::DbSize.exec(&db, &user).await;
+        assert!(got == Message::Text("0".into()), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_owner() -> io::Result<()> {
+        const DB_FILE: &str = "./test_owner.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+
+        let alice = User {
+            uid: Some(1),
+            admin: false,
+        };
+        let bob = User {
+            uid: Some(2),
+            admin: false,
+        };
+        let admin = User {
+            uid: Some(0),
+            admin: true,
+        };
+        let anonymous = User::default();
+
+        let insert = Message::Insert("key1".into(), "value1".into());
+        let delete = Message::Delete("key1".into());
+        let cases = [
+            (&insert, alice, true),
+            (&insert, bob, false),
+            (&delete, anonymous, false),
+            (&insert, alice, true),
+            (&delete, admin, true),
+            (&insert, bob, true),
+        ];
+        for (message, user, allowed) in cases {
+            let got = message.exec(&db, &user).await;
+            assert!(
+                (got == Message::Success) == allowed,
+                "\n{:?} as {:?}\nGot: {:?}\n",
+                message,
+                user,
+                got
+            );
+        }
+
+        // The owner survives a restart
+        db.flush().await?;
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let got = db.kd.read().await.get(b"key1").map(|data| data.owner);
+        assert!(got == Some(Some(2)), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_limits() -> io::Result<()> {
+        const DB_FILE: &str = "./test_limits.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+
+        let limits = Limits {
+            max_key_len: 4,
+            max_value_len: 8,
+        };
+        let long_key = Err(Message::error(KEY_TOO_LONG));
+        let long_value = Err(Message::error(VALUE_TOO_LONG));
+        let cases = [
+            (Message::Insert("key1".into(), "value1".into()), Ok(())),
+            (
+                Message::Insert("key12".into(), "value1".into()),
+                long_key.clone(),
+            ),
+            (
+                Message::Insert("key1".into(), "value1234".into()),
+                long_value.clone(),
+            ),
+            (Message::Get("key12".into()), long_key.clone()),
+            (Message::SetRange("key1".into(), 4, "v".into()), Ok(())),
+            (
+                Message::SetRange("key1".into(), u64::MAX, "v".into()),
+                long_value.clone(),
+            ),
+            (
+                Message::Fenced(
+                    1,
+                    Box::new(Message::Insert("key1".into(), "value1234".into())),
+                ),
+                long_value,
+            ),
+            (
+                Message::Batch(vec![
+                    Message::Delete("key1".into()),
+                    Message::Delete("key12".into()),
+                ]),
+                long_key,
+            ),
+        ];
+        for (message, expected) in cases {
+            let got = message.check_limits(&limits);
+            assert!(
+                got == expected,
+                "{:?}\nExpected: {:?}\nGot: {:?}\n",
+                message,
+                expected,
+                got
+            );
+        }
+
+        // The default limits always leave room for the entry in a page
+        assert!(Limits::default().fits_page());
+
+        // An entry too large for a page is an error rather than a crash
+        let value = Bytes::from(vec![b'v'; PAGE_SIZE]);
+        let got = Message::Insert("key1".into(), value)
+            .exec(&db, &User::default())
+            .await;
+        assert!(matches!(got, Message::Error(_, _)), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_grouped() -> io::Result<()> {
+        const DB_FILE: &str = "./test_grouped.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let user = User::default();
+
+        let got = Message::parse(b"insert --group g1 key1 value1\n");
+        let expected = Message::Grouped(
+            "g1".into(),
+            Box::new(Message::Insert("key1".into(), "value1".into())),
+        );
+        assert!(
+            got.as_ref() == Some(&expected),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        let got = Message::parse(b"insert --group  key1 value1\nget key1\n");
+        let expected = Message::Invalid(28, EMPTY_GROUP);
+        assert!(
+            got.as_ref() == Some(&expected),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        // Leave less than GROUP_ROOM on the current page
+        let mut i = 0;
+        while db.pc.get_current().await.remaining() >= GROUP_ROOM {
+            let k = format!("key{}", i);
+            Message::Insert(k.into(), "value".into())
+                .exec(&db, &user)
+                .await;
+            i += 1;
+        }
+        let page_id = db.pc.get_current().await.id;
+
+        // The group starts on a new page, where the rest of it follows
+        for k in ["g1:a", "g1:b"] {
+            let message = Message::Grouped(
+                "g1".into(),
+                Box::new(Message::Insert(k.into(), "value".into())),
+            );
+            let got = message.exec(&db, &user).await;
+            assert!(got == Message::Success, "Got: {:?}", got);
+        }
+        let kd = db.kd.read().await;
+        let (a, b) = (kd.get(b"g1:a").unwrap(), kd.get(b"g1:b").unwrap());
+        assert!(
+            a.page_id == page_id + 1 && b.page_id == a.page_id,
+            "\na: {:?}\nb: {:?}\n",
+            a,
+            b
+        );
+
+        let entry = db.pc.fetch_entry(b.page_id, b.offset).await?.unwrap();
+        assert!(
+            entry.group == Some(log::group_id(b"g1")),
+            "Got: {:?}",
+            entry
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_quoted() -> io::Result<()> {
+        const DB_FILE: &str = "./test_quoted.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let user = User::default();
+
+        let buf = b"insert \"my key\" \"line 1\\nline 2\"\nget \"my key\"\n";
+        let insert = Message::parse(buf).expect("should parse insert");
+        assert!(insert.len() == 33, "Got: {}", insert.len());
+        let insert = insert.unquoted();
+        assert!(
+            insert == Message::Insert("my key".into(), "line 1\nline 2".into()),
+            "Got: {:?}",
+            insert
+        );
+        assert!(insert.exec(&db, &user).await == Message::Success);
+
+        let get = Message::parse(&buf[33..]).expect("should parse get");
+        let got = Bytes::from(get.unquoted().exec(&db, &user).await);
+        let expected = Bytes::from("\"my key\" \"line 1\\nline 2\"\n");
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        // A quote elsewhere in a value is kept as it was
+        let got = Message::parse(b"insert key1 say \"hi\"\n");
+        let expected = Message::Insert("key1".into(), "say \"hi\"".into());
+        assert!(
+            got.as_ref() == Some(&expected),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        let cases = [
+            (&b"get \"unclosed\n"[..], INVALID_QUOTING),
+            (b"get \"key\" extra\n", WRONG_ARGUMENTS),
+            (b"insert \"key\" value with spaces\n", WRONG_ARGUMENTS),
+            (b"mset \"key\"\n", WRONG_ARGUMENTS),
+        ];
+        for (buf, e) in cases {
+            let got = Message::parse(buf);
+            let expected = Message::Invalid(buf.len(), e);
+            assert!(
+                got.as_ref() == Some(&expected),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fence() -> io::Result<()> {
+        const DB_FILE: &str = "./test_fence.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+
+        let fenced = |token, message| Message::Fenced(token, Box::new(message));
+        let insert = Message::Insert("key1".into(), "value1".into());
+        let delete = Message::Delete("key1".into());
+        let cases = [
+            (fenced(5, insert.clone()), true),
+            (fenced(4, insert.clone()), false),
+            (fenced(5, insert.clone()), true),
+            // Writes without a token keep the last one
+            (insert.clone(), true),
+            (fenced(4, delete.clone()), false),
+            (fenced(6, delete.clone()), true),
+            (fenced(1, insert.clone()), true),
+            (fenced(8, insert.clone()), true),
+        ];
+        for (message, allowed) in cases {
+            let got = message.exec(&db, &User::default()).await;
+            assert!(
+                (got == Message::Success) == allowed,
+                "\n{:?}\nGot: {:?}\n",
+                message,
+                got
+            );
+        }
+
+        let got = Message::parse(b"fence 8 delete key1\n");
+        assert!(got == Some(fenced(8, delete)), "Got: {:?}", got);
+        let cases = [
+            (&b"fence 8 get key1\n"[..], FENCED_WRITES_ONLY),
+            (b"fence 08 delete key1\n", INVALID_FENCE_TOKEN),
+            (b"fence 8 foo\n", UNKNOWN_COMMAND),
+            (b"fence 8\n", WRONG_ARGUMENTS),
+        ];
+        for (buf, e) in cases {
+            let mut pipelined = buf.to_vec();
+            pipelined.extend_from_slice(b"get key1\n");
+            let got = Message::parse(&pipelined);
+            let expected = Message::Invalid(buf.len(), e);
+            assert!(
+                got.as_ref() == Some(&expected),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        // The token survives a restart
+        db.flush().await?;
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let got = db.kd.read().await.get(b"key1").map(|data| data.fence);
+        assert!(got == Some(Some(8)), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    fn frame(opcode: u8, id: u32, k: &[u8], v: &[u8]) -> Vec<u8> {
+        let mut dst = vec![FRAME_MAGIC, opcode];
+        dst.put_u32(id);
+        dst.put_u32(k.len() as u32);
+        dst.put_u32(v.len() as u32);
+        dst.put_slice(k);
+        dst.put_slice(v);
+
+        dst
+    }
+
+    #[test]
+    fn test_frame() {
+        let mut setex = 10u64.to_be_bytes().to_vec();
+        setex.extend_from_slice(b"value\n1");
+
+        // Pipelined, with keys and values the line protocol can't carry
+        let mut buf = Vec::new();
+        buf.extend(frame(OP_SET, 1, b"key 1", b"value 1"));
+        buf.extend(frame(OP_SETEX, 2, b"key\n2", &setex));
+        buf.extend(frame(OP_GET, 3, b"key 1", b""));
+        buf.extend(frame(OP_DEL, 4, b"key 1", b""));
+        buf.extend(frame(0x7f, 5, b"", b""));
+
+        let expected = [
+            (1, Message::Insert("key 1".into(), "value 1".into())),
+            (2, Message::InsertEx("key\n2".into(), "value\n1".into(), 10)),
+            (3, Message::Get("key 1".into())),
+            (4, Message::Delete("key 1".into())),
+            (5, Message::Error("ERR".into(), "unknown opcode".into())),
+        ];
+        let mut pos = 0;
+        for (id, message) in expected {
+            let got = Message::parse_frame(&buf[pos..]).expect("should be valid");
+            let (got_id, got, n) = got.expect("should be complete");
+            assert!(
+                got_id == id && got == message,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                (id, message),
+                (got_id, got)
+            );
+            pos += n;
+        }
+        assert!(pos == buf.len(), "Got: {}", pos);
+
+        // Incomplete until the whole frame arrives
+        let get = frame(OP_GET, 3, b"key 1", b"");
+        for i in 0..get.len() {
+            let got = Message::parse_frame(&get[..i]).expect("should be valid");
+            assert!(got.is_none(), "Got: {:?}", got);
+        }
+        assert!(Message::parse_frame(b"*1\r\n$4\r\nPING\r\n").is_err());
+
+        let got = Message::Result("key 1".into(), "value 1".into()).frame(3);
+        let expected = frame(OP_VALUE, 3, b"key 1", b"value 1");
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        let got = Message::NotFound.frame(4);
+        let expected = frame(OP_NOT_FOUND, 4, b"", b"");
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        let got = Message::error(NOPERM).frame(5);
+        let expected = frame(OP_ERROR, 5, b"NOPERM", b"key is owned by another user");
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        // Clients encode requests and parse replies with the same framing
+        let got = Message::InsertEx("key\n2".into(), "value\n1".into(), 10).request_frame(2);
+        let expected = frame(OP_SETEX, 2, b"key\n2", &setex);
+        assert!(
+            got.as_deref() == Some(&expected[..]),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        assert!(Message::DbSize.request_frame(3).is_none());
+        for (reply, id) in [
+            (Message::Result("key 1".into(), "value 1".into()), 3),
+            (Message::Text("3".into()), 4),
+            (Message::Success, 5),
+            (Message::error(NOPERM), 6),
+        ] {
+            let got =
+                Message::parse_reply_frame(&reply.clone().frame(id)).expect("should be valid");
+            let (got_id, got, _) = got.expect("should be complete");
+            assert!(
+                got_id == id && got == reply,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                (id, reply),
+                (got_id, got)
+            );
+        }
+
+        // Every reply in the line protocol is a line, not found included
+        for (message, expected) in [
+            (Message::NotFound, &b"NotFound\n"[..]),
+            (
+                Message::error(NOPERM),
+                b"Error NOPERM key is owned by another user\n",
+            ),
+        ] {
+            let len = message.len();
+            let got = Bytes::from(message);
+            assert!(got == expected && got.len() == len, "Got: {:?}", got);
         }
     }
 }
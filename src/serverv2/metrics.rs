@@ -0,0 +1,202 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering::*},
+    time::Duration,
+};
+
+use tokio::net::UdpSocket;
+
+use crate::{
+    serverv2::{message::Message, shadow::Outcome},
+    storagev2::db::Db,
+};
+
+pub const STATSD_PREFIX: &str = "hash_db";
+
+/// Server wide counters, shared by every connection.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    connections: AtomicU64,
+    rejected: AtomicU64,
+    commands: AtomicU64,
+    errors: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    shadow_writes: AtomicU64,
+    shadow_divergences: AtomicU64,
+    shadow_errors: AtomicU64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Snapshot {
+    pub connections: u64,
+    /// Connections turned away at `max_clients`.
+    pub rejected: u64,
+    pub commands: u64,
+    pub errors: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub shadow_writes: u64,
+    pub shadow_divergences: u64,
+    pub shadow_errors: u64,
+    /// GETs waiting to fetch pages and inserts waiting for their turn, across databases.
+    pub fetches_queued: u64,
+    pub inserts_queued: u64,
+}
+
+impl Metrics {
+    pub fn connected(&self) {
+        self.connections.fetch_add(1, Relaxed);
+    }
+
+    pub fn disconnected(&self) {
+        self.connections.fetch_sub(1, Relaxed);
+    }
+
+    pub fn rejected(&self) {
+        self.rejected.fetch_add(1, Relaxed);
+    }
+
+    /// Records a command and the response it was answered with.
+    pub fn record(&self, message: &Message, res: &Message) {
+        self.commands.fetch_add(1, Relaxed);
+
+        match (message, res) {
+            (_, Message::Error(_, _)) => self.errors.fetch_add(1, Relaxed),
+            (Message::Get(_), Message::Result(_, _)) => self.hits.fetch_add(1, Relaxed),
+            (Message::Get(_), Message::NotFound) => self.misses.fetch_add(1, Relaxed),
+            _ => 0,
+        };
+    }
+
+    /// Records a write forwarded to the shadow target.
+    pub fn shadowed(&self, outcome: Outcome) {
+        self.shadow_writes.fetch_add(1, Relaxed);
+
+        match outcome {
+            Outcome::Matched => 0,
+            Outcome::Diverged => self.shadow_divergences.fetch_add(1, Relaxed),
+            Outcome::Failed => self.shadow_errors.fetch_add(1, Relaxed),
+        };
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            connections: self.connections.load(Relaxed),
+            rejected: self.rejected.load(Relaxed),
+            commands: self.commands.load(Relaxed),
+            errors: self.errors.load(Relaxed),
+            hits: self.hits.load(Relaxed),
+            misses: self.misses.load(Relaxed),
+            shadow_writes: self.shadow_writes.load(Relaxed),
+            shadow_divergences: self.shadow_divergences.load(Relaxed),
+            shadow_errors: self.shadow_errors.load(Relaxed),
+            ..Default::default()
+        }
+    }
+}
+
+impl Snapshot {
+    /// Adds how many reads and writes are queued on `dbs`.
+    pub fn with_queued<'a>(mut self, dbs: impl IntoIterator<Item = &'a Db>) -> Self {
+        for db in dbs {
+            let queued = db.queued();
+            self.fetches_queued += queued.fetches as u64;
+            self.inserts_queued += queued.inserts as u64;
+        }
+
+        self
+    }
+
+    /// Encodes the change since `prev` as statsd lines: counters are sent as deltas, connections
+    /// and queues as gauges.
+    pub fn statsd(&self, prev: &Snapshot) -> String {
+        let counters = [
+            ("commands", self.commands - prev.commands),
+            ("errors", self.errors - prev.errors),
+            ("hits", self.hits - prev.hits),
+            ("misses", self.misses - prev.misses),
+            ("shadow.writes", self.shadow_writes - prev.shadow_writes),
+            (
+                "shadow.divergences",
+                self.shadow_divergences - prev.shadow_divergences,
+            ),
+            ("shadow.errors", self.shadow_errors - prev.shadow_errors),
+            ("connections.rejected", self.rejected - prev.rejected),
+        ];
+
+        let gauges = [
+            ("connections", self.connections),
+            ("queued.fetches", self.fetches_queued),
+            ("queued.inserts", self.inserts_queued),
+        ];
+
+        let mut dst = String::new();
+        for (name, n) in gauges {
+            let _ = writeln!(dst, "{}.{}:{}|g", STATSD_PREFIX, name, n);
+        }
+        for (name, n) in counters {
+            let _ = writeln!(dst, "{}.{}:{}|c", STATSD_PREFIX, name, n);
+        }
+
+        dst
+    }
+}
+
+/// Pushes metrics to a statsd `endpoint` every `interval`, for deployments without a scraper.
+pub async fn push_statsd(metrics: &Metrics, dbs: &[Db], endpoint: &str, interval: Duration) {
+    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(s) => s,
+        Err(e) => return eprintln!("error: could not bind statsd socket: {}", e),
+    };
+    if let Err(e) = socket.connect(endpoint).await {
+        return eprintln!(
+            "error: could not resolve statsd endpoint {}: {}",
+            endpoint, e
+        );
+    }
+
+    let mut interval = tokio::time::interval(interval);
+    let mut prev = Snapshot::default();
+    loop {
+        interval.tick().await;
+
+        let snapshot = metrics.snapshot().with_queued(dbs);
+        if let Err(e) = socket.send(snapshot.statsd(&prev).as_bytes()).await {
+            eprintln!("error: could not push metrics: {}", e);
+        }
+        prev = snapshot;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::serverv2::{message::Message, metrics::Metrics};
+
+    #[test]
+    fn test_statsd() {
+        let metrics = Metrics::default();
+        metrics.connected();
+
+        let get = Message::Get("key1".into());
+        metrics.record(&get, &Message::Result("key1".into(), "value1".into()));
+        metrics.record(&get, &Message::NotFound);
+        let prev = metrics.snapshot();
+
+        metrics.record(&get, &Message::NotFound);
+        metrics.rejected();
+        metrics.record(
+            &Message::Delete("key1".into()),
+            &Message::Error("ERR".into(), "".into()),
+        );
+
+        let got = metrics.snapshot().statsd(&prev);
+        let expected = "hash_db.connections:1|g\nhash_db.queued.fetches:0|g\nhash_db.queued.inserts:0|g\nhash_db.commands:2|c\nhash_db.errors:1|c\nhash_db.hits:0|c\nhash_db.misses:1|c\nhash_db.shadow.writes:0|c\nhash_db.shadow.divergences:0|c\nhash_db.shadow.errors:0|c\nhash_db.connections.rejected:1|c\n";
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+    }
+}
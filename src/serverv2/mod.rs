@@ -1,3 +1,12 @@
+pub mod auth;
+pub mod config;
 pub mod connection;
 pub mod message;
+pub mod metrics;
+pub mod quote;
+pub mod raft;
+pub mod resp;
 pub mod server;
+pub mod shadow;
+pub mod tls;
+pub mod trace;
@@ -0,0 +1,168 @@
+//! The quoted form of the line protocol, for keys and values holding spaces or newlines. Words
+//! are separated by spaces and are either bare, or in double quotes with `\"`, `\\`, `\n`, `\r`,
+//! `\t`, `\0` and `\xHH` escapes. Quoted words can't hold a raw newline, it always ends the line.
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// Splits a line into words, unescaping quoted ones. `None` if a quote isn't closed or is
+/// followed by anything but a space, or an escape isn't one of the above.
+pub fn split(line: &[u8]) -> Option<Vec<Bytes>> {
+    let mut words = Vec::new();
+
+    let mut i = 0;
+    while i < line.len() {
+        match line[i] {
+            b' ' => i += 1,
+            b'"' => {
+                i += 1;
+                let mut word = BytesMut::new();
+                loop {
+                    match *line.get(i)? {
+                        b'"' => break,
+                        b'\\' => {
+                            let (b, n) = unescape(line.get(i + 1..)?)?;
+                            word.put_u8(b);
+                            i += 1 + n;
+                        }
+                        b => {
+                            word.put_u8(b);
+                            i += 1;
+                        }
+                    }
+                }
+
+                i += 1;
+                if line.get(i).is_some_and(|b| *b != b' ') {
+                    return None;
+                }
+                words.push(word.freeze());
+            }
+            _ => {
+                let end = line[i..]
+                    .iter()
+                    .position(|b| *b == b' ')
+                    .map_or(line.len(), |n| i + n);
+                words.push(Bytes::copy_from_slice(&line[i..end]));
+                i = end;
+            }
+        }
+    }
+
+    Some(words)
+}
+
+/// Whether `b` has to be quoted to be read back as a single word.
+pub fn needs_quotes(b: &[u8]) -> bool {
+    b.is_empty() || b[0] == b'"' || b.iter().any(|b| matches!(b, b' ' | b'\n' | b'\r'))
+}
+
+/// Whether `b` has to be quoted to be read back as the rest of a line.
+pub fn needs_quotes_at_end(b: &[u8]) -> bool {
+    b.first() == Some(&b'"') || b.iter().any(|b| matches!(b, b'\n' | b'\r'))
+}
+
+/// Writes `b` in quotes, escaping what has to be.
+pub fn put(dst: &mut BytesMut, b: &[u8]) {
+    dst.put_u8(b'"');
+    for b in b {
+        match b {
+            b'"' => dst.put_slice(b"\\\""),
+            b'\\' => dst.put_slice(b"\\\\"),
+            b'\n' => dst.put_slice(b"\\n"),
+            b'\r' => dst.put_slice(b"\\r"),
+            b'\t' => dst.put_slice(b"\\t"),
+            0 => dst.put_slice(b"\\0"),
+            b if b.is_ascii_control() => dst.put_slice(format!("\\x{:02x}", b).as_bytes()),
+            b => dst.put_u8(*b),
+        }
+    }
+    dst.put_u8(b'"');
+}
+
+/// Writes `b` as a word, only quoted if it has to be.
+pub fn put_word(dst: &mut BytesMut, b: &[u8]) {
+    match needs_quotes(b) {
+        true => put(dst, b),
+        false => dst.put_slice(b),
+    }
+}
+
+/// Writes `b` as the rest of a line, only quoted if it has to be.
+pub fn put_at_end(dst: &mut BytesMut, b: &[u8]) {
+    match needs_quotes_at_end(b) {
+        true => put(dst, b),
+        false => dst.put_slice(b),
+    }
+}
+
+/// Reads the escape after a backslash, returning the byte it stands for and its length.
+fn unescape(src: &[u8]) -> Option<(u8, usize)> {
+    let b = match src.first()? {
+        b'"' => b'"',
+        b'\\' => b'\\',
+        b'n' => b'\n',
+        b'r' => b'\r',
+        b't' => b'\t',
+        b'0' => 0,
+        b'x' => {
+            let hex = src.get(1..3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let b = u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?;
+
+            return Some((b, 3));
+        }
+        _ => return None,
+    };
+
+    Some((b, 1))
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{Bytes, BytesMut};
+
+    use crate::serverv2::quote::{put, split};
+
+    #[test]
+    fn test_quote() {
+        let cases: [(&[u8], &[&[u8]]); 4] = [
+            (b"get key", &[b"get", b"key"]),
+            (b"get \"my key\"", &[b"get", b"my key"]),
+            (
+                b"insert \"a\\nb\" \"say \\\"hi\\\"\\\\\"",
+                &[b"insert", b"a\nb", b"say \"hi\"\\"],
+            ),
+            (b"insert \"\\x00\\xff\" \"\"", &[b"insert", b"\0\xff", b""]),
+        ];
+        for (line, expected) in cases {
+            let got = split(line);
+            let expected: Vec<Bytes> = expected.iter().map(|w| Bytes::copy_from_slice(w)).collect();
+            assert!(
+                got.as_ref() == Some(&expected),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        let invalid: [&[u8]; 4] = [
+            b"get \"unclosed",
+            b"get \"a\"b",
+            b"get \"\\q\"",
+            b"get \"\\x+f\"",
+        ];
+        for line in invalid {
+            let got = split(line);
+            assert!(got.is_none(), "Got: {:?}", got);
+        }
+
+        // Anything quoted reads back as itself
+        let word: Vec<u8> = (0..=255).collect();
+        let mut dst = BytesMut::new();
+        put(&mut dst, &word);
+        let got = split(&dst);
+        assert!(got == Some(vec![Bytes::from(word)]), "Got: {:?}", got);
+    }
+}
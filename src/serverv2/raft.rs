@@ -0,0 +1,253 @@
+//! Glue for running a `Db` as the state machine of a raft group, e.g. under openraft. Commands
+//! travel through the raft log as line protocol requests, see `Message::request` and `decode`.
+
+use std::{future::Future, io};
+
+use bytes::Bytes;
+use tokio::sync::Mutex;
+
+use crate::{
+    serverv2::{auth::User, message::Message},
+    storagev2::{db::Db, dump::Format},
+};
+
+/// The key the index of the last applied log entry is kept under, written in the same batch as
+/// the entry's writes so the two can't disagree after a crash. It shows up in scans like any
+/// other key.
+pub const APPLIED_KEY: &[u8] = b"\0raft/applied";
+
+/// The state machine side of a raft implementation, shaped after openraft's
+/// `RaftStateMachine`: committed entries are applied in log order, and snapshots bring new or
+/// lagging members up to date.
+pub trait StateMachine {
+    type Command;
+    type Response;
+
+    /// Applies the committed entry at `index`. Entries at or before the last applied index have
+    /// already been applied and are skipped.
+    fn apply(
+        &self,
+        index: u64,
+        command: Self::Command,
+    ) -> impl Future<Output = io::Result<Self::Response>> + Send;
+
+    /// The index of the last applied entry, `None` if nothing has been applied yet.
+    fn applied(&self) -> impl Future<Output = Option<u64>> + Send;
+
+    fn snapshot(&self) -> impl Future<Output = io::Result<Snapshot>> + Send;
+
+    /// Replaces everything in the state machine with `snapshot`.
+    fn restore(&self, snapshot: &Snapshot) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub applied: Option<u64>,
+    /// Every live key, written by `Db::export_to`.
+    pub data: Bytes,
+}
+
+/// A `Db` applying inserts, deletes and batches of them from a raft log. Expiries are relative
+/// to when each member applies the entry, so they can differ slightly between members.
+pub struct RaftDb {
+    db: Db,
+    applied: Mutex<Option<u64>>,
+}
+
+impl RaftDb {
+    pub async fn new(db: Db) -> io::Result<Self> {
+        let applied = read_applied(&db).await?;
+
+        Ok(Self {
+            db,
+            applied: Mutex::new(applied),
+        })
+    }
+
+    /// The database, for serving reads. Writes to it directly bypass the raft log.
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+}
+
+impl StateMachine for RaftDb {
+    type Command = Message;
+    /// `Success`, or the `Error` the write was rejected with. A rejected write is still applied,
+    /// as a no-op, so every member agrees on it.
+    type Response = Message;
+
+    async fn apply(&self, index: u64, command: Message) -> io::Result<Message> {
+        let mut applied = self.applied.lock().await;
+        if applied.is_some_and(|a| index <= a) {
+            return Ok(Message::Success);
+        }
+
+        let mut writes = match command {
+            Message::Batch(messages) => messages,
+            message => vec![message],
+        };
+        writes.push(applied_entry(index));
+
+        let reply = Message::Batch(writes).exec(&self.db, &MEMBER).await;
+        if let Message::Error(_, _) = reply {
+            // Still move past the entry, so it isn't retried on restart
+            if let Message::Error(code, text) = Message::Batch(vec![applied_entry(index)])
+                .exec(&self.db, &MEMBER)
+                .await
+            {
+                return Err(io::Error::other(format!(
+                    "could not record applied index {} - {} {}",
+                    index,
+                    String::from_utf8_lossy(&code),
+                    String::from_utf8_lossy(&text)
+                )));
+            }
+        }
+        *applied = Some(index);
+
+        Ok(reply)
+    }
+
+    async fn applied(&self) -> Option<u64> {
+        *self.applied.lock().await
+    }
+
+    async fn snapshot(&self) -> io::Result<Snapshot> {
+        // Holding the lock keeps entries from being applied while exporting
+        let applied = self.applied.lock().await;
+        let mut data = Vec::new();
+        self.db.export_to(&mut data, Format::Json).await?;
+
+        Ok(Snapshot {
+            applied: *applied,
+            data: data.into(),
+        })
+    }
+
+    /// Not atomic, a crash part way through leaves the database empty or partly loaded. Raft
+    /// sends the snapshot again on restart since nothing is recorded as applied until the end.
+    async fn restore(&self, snapshot: &Snapshot) -> io::Result<()> {
+        let mut applied = self.applied.lock().await;
+
+        if let Message::Error(_, text) = Message::FlushDb.exec(&self.db, &MEMBER).await {
+            return Err(io::Error::other(String::from_utf8_lossy(&text).to_string()));
+        }
+        *applied = None;
+
+        self.db.import_from(&snapshot.data[..]).await?;
+        if read_applied(&self.db).await? != snapshot.applied {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "snapshot data does not match its applied index",
+            ));
+        }
+        *applied = snapshot.applied;
+
+        Ok(())
+    }
+}
+
+/// Decodes a command written to the log with `Message::request`.
+pub fn decode(buf: &[u8]) -> Option<Message> {
+    match Message::parse(buf)? {
+        message if message.len() == buf.len() => Some(message.unquoted()),
+        _ => None,
+    }
+}
+
+/// Members own every key, so a restore can clear anything that was written.
+const MEMBER: User = User {
+    uid: None,
+    admin: true,
+};
+
+fn applied_entry(index: u64) -> Message {
+    Message::Insert(Bytes::from_static(APPLIED_KEY), index.to_string().into())
+}
+
+async fn read_applied(db: &Db) -> io::Result<Option<u64>> {
+    let Some(entry) = db.read(APPLIED_KEY).await? else {
+        return Ok(None);
+    };
+
+    match std::str::from_utf8(&entry.value)
+        .ok()
+        .and_then(|s| s.parse().ok())
+    {
+        Some(index) => Ok(Some(index)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "applied index is not a number",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::{
+        serverv2::{
+            message::Message,
+            raft::{decode, RaftDb, StateMachine},
+        },
+        storagev2::{
+            db::{Db, Options},
+            test::CleanUp,
+        },
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_raft() -> io::Result<()> {
+        const DB_FILE: &str = "./test_raft.db";
+        const RESTORED_FILE: &str = "./test_raft_restored.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let _cu_restored = CleanUp::file(RESTORED_FILE);
+
+        let leader = RaftDb::new(Db::open(DB_FILE, Options::default()).await?).await?;
+        let commands = [
+            Message::Insert("key1".into(), "value1".into()),
+            Message::Batch(vec![
+                Message::Insert("key 2".into(), "value2".into()),
+                Message::Delete("key1".into()),
+            ]),
+        ];
+        for (index, command) in (1..).zip(commands) {
+            let req = command.request().expect("should encode");
+            let command = decode(&req).expect("should decode");
+            let got = leader.apply(index, command).await?;
+            assert!(got == Message::Success, "Got: {:?}", got);
+        }
+
+        // Already applied
+        leader
+            .apply(1, Message::Insert("key1".into(), "value1".into()))
+            .await?;
+        assert!(leader.db().read(b"key1").await?.is_none());
+
+        // A rejected write still counts as applied
+        let got = leader.apply(3, Message::FlushDb).await?;
+        assert!(matches!(got, Message::Error(_, _)), "Got: {:?}", got);
+        assert!(leader.applied().await == Some(3));
+
+        let snapshot = leader.snapshot().await?;
+        assert!(snapshot.applied == Some(3), "Got: {:?}", snapshot.applied);
+
+        let follower = RaftDb::new(Db::open(RESTORED_FILE, Options::default()).await?).await?;
+        follower
+            .apply(1, Message::Insert("stale".into(), "value".into()))
+            .await?;
+        follower.restore(&snapshot).await?;
+        assert!(follower.applied().await == Some(3));
+        assert!(follower.db().read(b"stale").await?.is_none());
+        let got = follower.db().read(b"key 2").await?.map(|e| e.value);
+        assert!(got.as_deref() == Some(&b"value2"[..]), "Got: {:?}", got);
+
+        // The applied index survives a restart
+        drop(follower);
+        let follower = RaftDb::new(Db::open(RESTORED_FILE, Options::default()).await?).await?;
+        assert!(follower.applied().await == Some(3));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,615 @@
+use std::io;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::serverv2::message::{Message, MAX_FRAME_LEN};
+
+/// Where `BGSAVE` without arguments puts the snapshot.
+const BGSAVE_DIR: &str = "snapshot";
+
+/// Most arguments a request can have. The count is sent ahead of them, so it's checked before
+/// anything is allocated for them.
+const MAX_ARGS: usize = 16 * 1024;
+
+/// Requests are buffered whole before they're parsed, like frames.
+const MAX_REQUEST_LEN: usize = 16 * MAX_FRAME_LEN;
+
+/// Longest an array or bulk string header can be, CRLF included. A length takes at most 20
+/// digits, so anything past this is never going to parse.
+const MAX_HEADER_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Version {
+    Resp2,
+    Resp3,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Message(Message),
+    /// A request Redis replies to with a number of keys, e.g. `DEL` or `EXISTS`. Executed as the
+    /// message, whose reply is then encoded with `encode_count`.
+    Counted(Message),
+    Hello(Option<Bytes>),
+    Ping(Option<Bytes>),
+    Unknown(Bytes),
+}
+
+/// Parses a single RESP array of bulk strings from the start of `buf`, returning the
+/// arguments and the number of bytes consumed. Returns `Ok(None)` if more data is needed, and
+/// an `InvalidData` error for requests over `MAX_ARGS` arguments or `MAX_REQUEST_LEN` bytes.
+pub fn parse(buf: &[u8]) -> io::Result<Option<(Vec<Bytes>, usize)>> {
+    let mut pos = 0;
+
+    let Some(n) = read_header(buf, &mut pos, b'*')? else {
+        return Ok(None);
+    };
+    if n > MAX_ARGS {
+        return Err(invalid("too many arguments"));
+    }
+    let mut args = Vec::with_capacity(n);
+    for _ in 0..n {
+        let Some(len) = read_header(buf, &mut pos, b'$')? else {
+            return Ok(None);
+        };
+        let end = match pos.checked_add(len).and_then(|end| end.checked_add(2)) {
+            Some(end) if len <= MAX_FRAME_LEN && end <= MAX_REQUEST_LEN => end,
+            _ => return Err(invalid("request too long")),
+        };
+        if buf.len() < end {
+            return Ok(None);
+        }
+
+        if &buf[pos + len..end] != b"\r\n" {
+            return Err(invalid("expected CRLF after bulk string"));
+        }
+
+        args.push(Bytes::copy_from_slice(&buf[pos..pos + len]));
+        pos = end;
+    }
+
+    Ok(Some((args, pos)))
+}
+
+fn read_header(buf: &[u8], pos: &mut usize, prefix: u8) -> io::Result<Option<usize>> {
+    let Some(&first) = buf.get(*pos) else {
+        return Ok(None);
+    };
+    if first != prefix {
+        return Err(invalid("unexpected RESP type"));
+    }
+
+    let header = &buf[*pos..buf.len().min(*pos + MAX_HEADER_LEN)];
+    let Some(end) = header.windows(2).position(|w| w == b"\r\n") else {
+        return match header.len() {
+            MAX_HEADER_LEN => Err(invalid("RESP header too long")),
+            _ => Ok(None),
+        };
+    };
+    let n = std::str::from_utf8(&buf[*pos + 1..*pos + end])
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("invalid RESP length"))?;
+    *pos += end + 2;
+
+    Ok(Some(n))
+}
+
+fn invalid(e: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Maps a parsed RESP request onto the equivalent `Message`, or a command handled by the
+/// connection itself.
+pub fn command(mut args: Vec<Bytes>) -> Command {
+    if args.is_empty() {
+        return Command::Unknown(Bytes::new());
+    }
+
+    let name = args.remove(0).to_ascii_uppercase();
+    let mut args = args.into_iter();
+    match (&name[..], args.len()) {
+        (b"GET", 1) => Command::Message(Message::Get(args.next().unwrap())),
+        (b"EXISTS", 1) => Command::Counted(Message::Exists(args.next().unwrap())),
+        (b"STRLEN", 1) => Command::Message(Message::Strlen(args.next().unwrap())),
+        (b"MEMORY", 2) if args.as_slice()[0].eq_ignore_ascii_case(b"USAGE") => {
+            Command::Message(Message::MemoryUsage(args.nth(1).unwrap()))
+        }
+        (b"MGET", 1..) => Command::Message(Message::MGet(args.collect(), false)),
+        (b"MGET-CONSISTENT", 1..) => Command::Message(Message::MGet(args.collect(), true)),
+        (b"MSET", n) if n > 0 && n.is_multiple_of(2) => {
+            let mut pairs = Vec::with_capacity(n / 2);
+            while let (Some(k), Some(v)) = (args.next(), args.next()) {
+                pairs.push((k, v));
+            }
+
+            Command::Message(Message::MSet(pairs))
+        }
+        (b"SET", 2) => {
+            let k = args.next().unwrap();
+            let v = args.next().unwrap();
+
+            Command::Message(Message::Insert(k, v))
+        }
+        (b"SET", 4) => {
+            let k = args.next().unwrap();
+            let v = args.next().unwrap();
+            let option = args.next().unwrap();
+            let secs = std::str::from_utf8(&args.next().unwrap())
+                .ok()
+                .and_then(|s| s.parse().ok());
+
+            match secs {
+                Some(secs) if option.eq_ignore_ascii_case(b"EX") => {
+                    Command::Message(Message::InsertEx(k, v, secs))
+                }
+                _ => Command::Unknown(name.into()),
+            }
+        }
+        (b"DEL", 1) => Command::Counted(Message::Del(args.next().unwrap())),
+        (b"UNLINK", 1) => Command::Message(Message::Unlink(args.next().unwrap())),
+        (b"SETRANGE", 3) => {
+            let (k, offset, v) = (
+                args.next().unwrap(),
+                args.next().unwrap(),
+                args.next().unwrap(),
+            );
+            match std::str::from_utf8(&offset)
+                .ok()
+                .and_then(|s| s.parse().ok())
+            {
+                Some(offset) => Command::Message(Message::SetRange(k, offset, v)),
+                None => Command::Unknown(name.into()),
+            }
+        }
+        (b"MERGE", 3) => {
+            let (op, k, operand) = (
+                args.next().unwrap(),
+                args.next().unwrap(),
+                args.next().unwrap(),
+            );
+            Command::Message(Message::Merge(op, k, operand))
+        }
+        (b"APPEND", 2) => {
+            let (k, v) = (args.next().unwrap(), args.next().unwrap());
+            Command::Message(Message::Append(k, v))
+        }
+        (b"GETSET", 2) => {
+            let (k, v) = (args.next().unwrap(), args.next().unwrap());
+            Command::Message(Message::GetSet(k, v))
+        }
+        (b"SETNX", 2) => {
+            let (k, v) = (args.next().unwrap(), args.next().unwrap());
+            Command::Message(Message::SetNx(k, v))
+        }
+        (b"GETDEL", 1) => Command::Message(Message::GetDel(args.next().unwrap())),
+        (b"EXPIRE" | b"EXPIREAT", 2) => {
+            let (k, n) = (args.next().unwrap(), args.next().unwrap());
+            match std::str::from_utf8(&n).ok().and_then(|s| s.parse().ok()) {
+                Some(n) if &name[..] == b"EXPIRE" => Command::Message(Message::Expire(k, n)),
+                Some(n) => Command::Message(Message::ExpireAt(k, n)),
+                None => Command::Unknown(name.into()),
+            }
+        }
+        (b"PERSIST", 1) => Command::Message(Message::Persist(args.next().unwrap())),
+        (b"TTL", 1) => Command::Message(Message::Ttl(args.next().unwrap())),
+        (b"USE", 1) => Command::Message(Message::Use(args.next().unwrap())),
+        (b"SELECT", 1) => match std::str::from_utf8(&args.next().unwrap())
+            .ok()
+            .and_then(|s| s.parse().ok())
+        {
+            Some(n) => Command::Message(Message::Select(n)),
+            None => Command::Unknown(name.into()),
+        },
+        (b"DBSIZE", 0) => Command::Message(Message::DbSize),
+        (b"HEALTH", 0) => Command::Message(Message::Health),
+        (b"INFO", 0) => Command::Message(Message::Info),
+        (b"FLUSHDB", 0) => Command::Message(Message::FlushDb),
+        (b"COMPACTION", 1) if args.as_slice()[0].eq_ignore_ascii_case(b"ESTIMATE") => {
+            Command::Message(Message::CompactionEstimate)
+        }
+        (b"PAGE", 1) if args.as_slice()[0].eq_ignore_ascii_case(b"FILL") => {
+            Command::Message(Message::PageFill)
+        }
+        (b"EXPIRYFORECAST", 0) => Command::Message(Message::ExpiryForecast),
+        (b"STATS", 1) if args.as_slice()[0].eq_ignore_ascii_case(b"BOOTSTRAP") => {
+            Command::Message(Message::BootstrapStats)
+        }
+        (b"EXPORT-KEYS", 1) => Command::Message(Message::ExportKeys(args.next().unwrap(), false)),
+        (b"EXPORT-KEYS", 2) => {
+            let file = args.next().unwrap();
+            match args.next().unwrap().eq_ignore_ascii_case(b"META") {
+                true => Command::Message(Message::ExportKeys(file, true)),
+                false => Command::Unknown(name.into()),
+            }
+        }
+        (b"EXPORT-SSTABLE", 1) => Command::Message(Message::ExportSstable(args.next().unwrap())),
+        (b"SNAPSHOT", 1) => Command::Message(Message::Snapshot(args.next().unwrap())),
+        (b"VALIDATE", 2..) => {
+            let prefix = args.next().unwrap();
+            let rule = args.collect::<Vec<_>>().join(&b' ');
+
+            Command::Message(Message::Validate(prefix, rule.into()))
+        }
+        (b"UNVALIDATE", 1) => Command::Message(Message::Unvalidate(args.next().unwrap())),
+        (b"VALIDATORS", 0) => Command::Message(Message::Validators),
+        (b"NOTIFICATIONS", 0) => Command::Message(Message::Notifications),
+        (b"BEGIN", 0) => Command::Message(Message::Begin),
+        (b"COMMIT", 0) => Command::Message(Message::Commit),
+        (b"DISCARD", 0) => Command::Message(Message::Discard),
+        (b"SUBSCRIBE", 1) => Command::Message(Message::Subscribe(args.next().unwrap())),
+        (b"PUBLISH", 2) => {
+            let channel = args.next().unwrap();
+            Command::Message(Message::Publish(channel, args.next().unwrap()))
+        }
+        (b"SUBSCRIBE-OPLOG", 1) => match std::str::from_utf8(&args.next().unwrap())
+            .ok()
+            .and_then(|s| s.parse().ok())
+        {
+            Some(seq) => Command::Message(Message::SubscribeOplog(seq)),
+            None => Command::Unknown(name.into()),
+        },
+        (b"WATCH", 1) => Command::Message(Message::Watch(args.next().unwrap(), false)),
+        (b"WATCH", 2) if args.as_slice()[0].eq_ignore_ascii_case(b"PREFIX") => {
+            Command::Message(Message::Watch(args.nth(1).unwrap(), true))
+        }
+        (b"CONFIG", 2) if args.as_slice()[0].eq_ignore_ascii_case(b"GET") => {
+            Command::Message(Message::ConfigGet(args.nth(1).unwrap()))
+        }
+        (b"CONFIG", 3) if args.as_slice()[0].eq_ignore_ascii_case(b"SET") => {
+            let name = args.nth(1).unwrap();
+            Command::Message(Message::ConfigSet(name, args.next().unwrap()))
+        }
+        (b"BGSAVE", 0) => Command::Message(Message::Snapshot(BGSAVE_DIR.into())),
+        (b"BGSAVE", 1) => Command::Message(Message::Snapshot(args.next().unwrap())),
+        (b"SCAN", 1) => Command::Message(Message::Scan(args.next().unwrap(), None)),
+        (b"RANGE", 2..=4) => {
+            let mut args: Vec<_> = args.collect();
+            let values = args
+                .last()
+                .is_some_and(|arg| arg.eq_ignore_ascii_case(b"WITHVALUES"));
+            if values {
+                args.pop();
+            }
+
+            let mut args = args.into_iter();
+            let (Some(start), Some(end)) = (args.next(), args.next()) else {
+                return Command::Unknown(name.into());
+            };
+            let limit = match args.next() {
+                Some(limit) => match std::str::from_utf8(&limit)
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                {
+                    Some(n) => Some(n),
+                    None => return Command::Unknown(name.into()),
+                },
+                None => None,
+            };
+            if args.next().is_some() {
+                return Command::Unknown(name.into());
+            }
+
+            Command::Message(Message::Range(start, end, limit, values))
+        }
+        (b"SCAN", 3) => {
+            let cursor = args.next().unwrap();
+            let option = args.next().unwrap();
+            let pattern = args.next().unwrap();
+
+            // Only prefix patterns are supported
+            match pattern.strip_suffix(b"*") {
+                Some(prefix)
+                    if option.eq_ignore_ascii_case(b"MATCH")
+                        && !prefix
+                            .iter()
+                            .any(|b| matches!(b, b'*' | b'?' | b'[' | b'\\')) =>
+                {
+                    let prefix = pattern.slice(..prefix.len());
+                    Command::Message(Message::Scan(cursor, Some(prefix)))
+                }
+                _ => Command::Unknown(name.into()),
+            }
+        }
+        (b"FENCE", 3..) => {
+            let token = std::str::from_utf8(&args.next().unwrap())
+                .ok()
+                .and_then(|s| s.parse().ok());
+
+            match (token, command(args.collect())) {
+                (
+                    Some(token),
+                    Command::Message(
+                        message @ (Message::Insert(_, _)
+                        | Message::InsertEx(_, _, _)
+                        | Message::Grouped(_, _)),
+                    ),
+                ) => Command::Message(Message::Fenced(token, Box::new(message))),
+                (Some(token), Command::Counted(Message::Del(k))) => {
+                    Command::Message(Message::Fenced(token, Box::new(Message::Delete(k))))
+                }
+                _ => Command::Unknown(name.into()),
+            }
+        }
+        (b"GROUP", 3..) => {
+            let group = args.next().unwrap();
+
+            match command(args.collect()) {
+                Command::Message(
+                    message @ (Message::Insert(_, _) | Message::InsertEx(_, _, _)),
+                ) if !group.is_empty() => {
+                    Command::Message(Message::Grouped(group, Box::new(message)))
+                }
+                _ => Command::Unknown(name.into()),
+            }
+        }
+        (b"HELLO", _) => Command::Hello(args.next()),
+        (b"PING", 0 | 1) => Command::Ping(args.next()),
+        _ => Command::Unknown(name.into()),
+    }
+}
+
+pub fn encode(m: Message, version: Version) -> Bytes {
+    let mut dst = BytesMut::new();
+
+    match m {
+        Message::Result(_, v) | Message::Text(v) => put_bulk(&mut dst, &v),
+        Message::Keys(cursor, keys) => {
+            dst.put_slice(b"*2\r\n");
+            put_bulk(&mut dst, &cursor);
+            dst.put_slice(format!("*{}\r\n", keys.len()).as_bytes());
+            for k in keys {
+                put_bulk(&mut dst, &k);
+            }
+        }
+        Message::Values(values) => {
+            dst.put_slice(format!("*{}\r\n", values.len()).as_bytes());
+            for (_, v) in values {
+                match v {
+                    Some(v) => put_bulk(&mut dst, &v),
+                    None => match version {
+                        Version::Resp2 => dst.put_slice(b"$-1\r\n"),
+                        Version::Resp3 => dst.put_slice(b"_\r\n"),
+                    },
+                }
+            }
+        }
+        Message::Success => dst.put_slice(b"+OK\r\n"),
+        Message::Error(code, text) => {
+            dst.put_u8(b'-');
+            dst.put_slice(&code);
+            dst.put_u8(b' ');
+            dst.put_slice(&text);
+            dst.put_slice(b"\r\n");
+        }
+        Message::NotFound => match version {
+            Version::Resp2 => dst.put_slice(b"$-1\r\n"),
+            Version::Resp3 => dst.put_slice(b"_\r\n"),
+        },
+        Message::Insert(_, _)
+        | Message::InsertEx(_, _, _)
+        | Message::Delete(_)
+        | Message::Del(_)
+        | Message::Unlink(_)
+        | Message::SetRange(_, _, _)
+        | Message::Merge(_, _, _)
+        | Message::Append(_, _)
+        | Message::GetSet(_, _)
+        | Message::SetNx(_, _)
+        | Message::GetDel(_)
+        | Message::Expire(_, _)
+        | Message::ExpireAt(_, _)
+        | Message::Persist(_)
+        | Message::Ttl(_)
+        | Message::Get(_)
+        | Message::Exists(_)
+        | Message::Strlen(_)
+        | Message::MemoryUsage(_)
+        | Message::MGet(_, _)
+        | Message::MSet(_)
+        | Message::Use(_)
+        | Message::Batch(_)
+        | Message::Scan(_, _)
+        | Message::Range(_, _, _, _)
+        | Message::KeysMatching(_)
+        | Message::CompactionEstimate
+        | Message::PageFill
+        | Message::BootstrapStats
+        | Message::Health
+        | Message::Info
+        | Message::ExpiryForecast
+        | Message::Select(_)
+        | Message::DbSize
+        | Message::FlushDb
+        | Message::Validate(_, _)
+        | Message::Unvalidate(_)
+        | Message::Validators
+        | Message::ExportKeys(_, _)
+        | Message::ExportSstable(_)
+        | Message::Snapshot(_)
+        | Message::Notifications
+        | Message::Begin
+        | Message::Commit
+        | Message::Discard
+        | Message::Watch(_, _)
+        | Message::Subscribe(_)
+        | Message::Publish(_, _)
+        | Message::SubscribeOplog(_)
+        | Message::ConfigGet(_)
+        | Message::ConfigSet(_, _)
+        | Message::Fenced(_, _)
+        | Message::Grouped(_, _)
+        | Message::Quoted(_, _)
+        | Message::Invalid(_, _)
+        | Message::None => {}
+    }
+
+    dst.into()
+}
+
+/// Encodes the reply to a `Command::Counted` request as the number of keys it removed or found.
+pub fn encode_count(m: Message, version: Version) -> Bytes {
+    match m {
+        Message::Result(_, _) => Bytes::from(":1\r\n"),
+        Message::NotFound => Bytes::from(":0\r\n"),
+        Message::Text(n) => Bytes::from(format!(":{}\r\n", String::from_utf8_lossy(&n))),
+        m => encode(m, version),
+    }
+}
+
+pub fn encode_hello(version: Version) -> Bytes {
+    let fields: [(&[u8], &[u8]); 3] = [
+        (b"server", b"hash_db"),
+        (b"version", env!("CARGO_PKG_VERSION").as_bytes()),
+        (
+            b"proto",
+            if version == Version::Resp3 {
+                b"3"
+            } else {
+                b"2"
+            },
+        ),
+    ];
+
+    let mut dst = BytesMut::new();
+    match version {
+        Version::Resp2 => dst.put_slice(format!("*{}\r\n", fields.len() * 2).as_bytes()),
+        Version::Resp3 => dst.put_slice(format!("%{}\r\n", fields.len()).as_bytes()),
+    }
+    for (k, v) in fields {
+        put_bulk(&mut dst, k);
+        put_bulk(&mut dst, v);
+    }
+
+    dst.into()
+}
+
+pub fn encode_pong(payload: Option<Bytes>) -> Bytes {
+    match payload {
+        Some(p) => {
+            let mut dst = BytesMut::new();
+            put_bulk(&mut dst, &p);
+
+            dst.into()
+        }
+        None => Bytes::from("+PONG\r\n"),
+    }
+}
+
+pub fn encode_error(e: &str) -> Bytes {
+    Bytes::from(format!("-{}\r\n", e))
+}
+
+fn put_bulk(dst: &mut BytesMut, b: &[u8]) {
+    dst.put_slice(format!("${}\r\n", b.len()).as_bytes());
+    dst.put_slice(b);
+    dst.put_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use crate::serverv2::{
+        message::{Message, MAX_FRAME_LEN},
+        resp::{command, encode, encode_count, parse, Command, Version, MAX_ARGS, MAX_HEADER_LEN},
+    };
+
+    #[test]
+    fn test_parse() {
+        let buf = b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n*2\r\n$3\r\nget";
+
+        let (args, n) = parse(buf).unwrap().expect("should parse first command");
+        assert!(n == 35, "Got: {}", n);
+        assert!(command(args) == Command::Message(Message::Insert("key1".into(), "value1".into())));
+
+        let rest = parse(&buf[n..]).unwrap();
+        assert!(rest.is_none(), "Got: {:?}", rest);
+
+        assert!(parse(b"+OK\r\n").is_err());
+
+        let too_many = format!("*{}\r\n", MAX_ARGS + 1);
+        let too_long = format!("*1\r\n${}\r\n", MAX_FRAME_LEN + 1);
+        let overflow = format!("*1\r\n${}\r\n", usize::MAX);
+        let no_crlf = format!("*{}", "1".repeat(MAX_HEADER_LEN));
+        for buf in [too_many, too_long, overflow, no_crlf] {
+            let got = parse(buf.as_bytes()).map_err(|e| e.kind());
+            assert!(
+                got == Err(std::io::ErrorKind::InvalidData),
+                "\nBuf: {:?}\nGot: {:?}\n",
+                buf,
+                got
+            );
+        }
+    }
+
+    #[test]
+    fn test_del() {
+        let got = command(vec!["DEL".into(), "key1".into()]);
+        let expected = Command::Counted(Message::Del("key1".into()));
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        let got = command(vec!["EXISTS".into(), "key1".into()]);
+        let expected = Command::Counted(Message::Exists("key1".into()));
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        let cases = [
+            (Message::Result("key1".into(), "value1".into()), ":1\r\n"),
+            (Message::NotFound, ":0\r\n"),
+            (Message::Text("1".into()), ":1\r\n"),
+            (Message::Text("0".into()), ":0\r\n"),
+            (
+                Message::Error("NOPERM".into(), "not yours".into()),
+                "-NOPERM not yours\r\n",
+            ),
+        ];
+        for (m, expected) in cases {
+            let got = encode_count(m, Version::Resp2);
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let cases = [
+            (
+                Message::Result("k".into(), "value".into()),
+                Version::Resp2,
+                Bytes::from("$5\r\nvalue\r\n"),
+            ),
+            (Message::Success, Version::Resp2, Bytes::from("+OK\r\n")),
+            (Message::NotFound, Version::Resp2, Bytes::from("$-1\r\n")),
+            (Message::NotFound, Version::Resp3, Bytes::from("_\r\n")),
+            (
+                Message::Keys("0".into(), vec!["k1".into(), "k2".into()]),
+                Version::Resp2,
+                Bytes::from("*2\r\n$1\r\n0\r\n*2\r\n$2\r\nk1\r\n$2\r\nk2\r\n"),
+            ),
+        ];
+
+        for (m, version, expected) in cases {
+            let got = encode(m, version);
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+    }
+}
@@ -1,83 +1,1006 @@
-use std::{io, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+    os::unix::fs::FileTypeExt,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering::*},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use crate::{
-    serverv2::{connection::Connection, message::Message},
+    serverv2::{
+        auth::{PeerAuth, User},
+        config::{Args, Config, Tunables},
+        connection::Connection,
+        message::{self, Message, ServerInfo, UNKNOWN_DATABASE},
+        metrics::{self, Metrics},
+        quote,
+        shadow::Shadow,
+        tls,
+        trace::{Span, Tracer},
+    },
     storagev2::{
-        disk::Disk,
-        key_dir::{self, KeyDir},
-        page_manager::PageCache,
+        db::Db,
+        events::{Change, Event},
+        log::EntryType,
+        oplog::{Op, Oplog},
+        txn::ReadTxn,
     },
 };
+use bytes::{Bytes, BytesMut};
 use tokio::{
-    io::{BufReader, BufWriter},
-    net::{TcpListener, TcpStream},
-    signal,
-    sync::RwLock,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    signal::{self, unix::SignalKind},
+    sync::{
+        broadcast::{self, error::RecvError},
+        watch, OwnedSemaphorePermit, Semaphore,
+    },
 };
+use tokio_rustls::TlsAcceptor;
+
+const INVALID_DB_INDEX: (&str, &str) = ("ERR", "DB index is out of range");
+const TXN_OPEN: (&str, &str) = ("ERR", "a transaction is already open");
+const NO_TXN: (&str, &str) = ("ERR", "no transaction is open");
+const TXN_WRITES_ONLY: (&str, &str) = (
+    "ERR",
+    "only inserts and deletes can be queued in a transaction",
+);
+const TXN_SWITCH: (&str, &str) = ("ERR", "cannot switch databases in a transaction");
+
+type Databases = Arc<HashMap<Bytes, Db>>;
+
+const CONFIG_ADMIN_ONLY: (&str, &str) = ("NOPERM", "only admins can change the configuration");
+const UNKNOWN_CONFIG: (&str, &str) = ("ERR", "unknown config parameter");
+const SHUTDOWN_IN_PROGRESS: (&str, &str) = ("SHUTDOWN_IN_PROGRESS", "server is shutting down");
+
+const NOAUTH: &[u8] = b"Error NOAUTH peer is not allowed to connect\n";
+
+const MAX_CLIENTS: &[u8] = b"Error ERR max clients reached\n";
+
+/// Payloads buffered for each subscriber of a channel. A subscriber that falls further behind
+/// misses the oldest ones and is told how many it missed.
+const CHANNEL_QUEUE_SIZE: usize = 1024;
+
+/// Entries an oplog subscriber reads from the log at a time.
+const OPLOG_BATCH: usize = 256;
+
+/// How often a caught up oplog subscriber looks for writes no change was sent for, e.g. entries
+/// moved by compaction.
+const OPLOG_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Pub/sub channels by name. A channel is created by its first subscriber and dropped with its
+/// last, publishing to a channel without subscribers goes nowhere.
+type Channels = Arc<std::sync::Mutex<HashMap<Bytes, broadcast::Sender<Bytes>>>>;
 
-const DB_FILE: &str = "main.db";
+/// State shared by every connection.
+#[derive(Clone)]
+struct Shared {
+    config: Arc<Config>,
+    /// The config's current `Tunables`, which may have changed since it was loaded.
+    tunables: Arc<watch::Sender<Tunables>>,
+    dbs: Databases,
+    shutdown: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+    tracer: Option<Tracer>,
+    shadow: Option<Shadow>,
+    /// One permit per connection, up to `max_clients`.
+    clients: Arc<Semaphore>,
+    started: Instant,
+    channels: Channels,
+}
+
+/// The databases and the state shared by their connections, without any listeners. `run` serves
+/// it on the configured sockets, `serve` on anything else, e.g. a simulated network.
+#[derive(Clone)]
+pub struct Server {
+    shared: Shared,
+}
 
-pub async fn run() {
-    let disk = Disk::new(DB_FILE).await.expect("Failed to open db file");
-    let (kd, latest, latest_id) = key_dir::bootstrap(&disk).await;
-    let kd = Arc::new(RwLock::new(kd));
+impl Server {
+    pub async fn open(config: Config) -> io::Result<Self> {
+        let mut dbs = HashMap::new();
+        for database in &config.databases {
+            let db = Db::open(&database.path, config.db_options()).await?;
+            dbs.insert(Bytes::from(database.name.clone()), db);
+        }
 
-    let m = PageCache::new(disk, 2, latest, latest_id);
+        let metrics = Arc::new(Metrics::default());
+        let shared = Shared {
+            config: Arc::new(config.clone()),
+            tunables: Arc::new(watch::channel(config.tunables()).0),
+            dbs: Arc::new(dbs),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            metrics: metrics.clone(),
+            tracer: config.otlp_endpoint.clone().map(Tracer::new),
+            shadow: config
+                .shadow_target
+                .clone()
+                .map(|target| Shadow::new(target, metrics)),
+            clients: Arc::new(Semaphore::new(config.max_clients)),
+            started: Instant::now(),
+            channels: Channels::default(),
+        };
+
+        if let Some(endpoint) = config.statsd_endpoint.clone() {
+            let metrics = shared.metrics.clone();
+            let dbs: Vec<_> = shared.dbs.values().cloned().collect();
+            let interval = Duration::from_secs(config.statsd_interval_secs);
+            tokio::spawn(
+                async move { metrics::push_statsd(&metrics, &dbs, &endpoint, interval).await },
+            );
+        }
 
-    let listener = TcpListener::bind("0.0.0.0:4444")
+        Ok(Self { shared })
+    }
+
+    /// Serves a connection until it closes, like one accepted on the TCP listener.
+    pub async fn serve<R, W>(&self, r: R, mut w: W)
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        match admit(&self.shared) {
+            Some(permit) => accept(r, w, self.shared.clone(), User::default(), permit).await,
+            None => {
+                let _ = w.write_all(MAX_CLIENTS).await;
+            }
+        }
+    }
+}
+
+/// Serves `config` until a shutdown signal. On SIGHUP the config is loaded again from `args`, and
+/// its `Tunables` applied.
+pub async fn run(config: Config, args: Args) {
+    let shared = Server::open(config.clone())
+        .await
+        .expect("Failed to open db file")
+        .shared;
+
+    if let Some(path) = &config.import_fast {
+        let db = &shared.dbs[config.databases[0].name.as_bytes()];
+        let n = import(db, path).await.expect("Failed to import");
+        eprintln!("imported {} keys from {}", n, path.display());
+    }
+
+    let listener = TcpListener::bind(&config.bind)
         .await
         .expect("Could not bind");
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::acceptor(cert, key).expect("Could not load TLS")),
+        _ => None,
+    };
+
+    if let Some(path) = &config.unix_socket {
+        remove_stale_socket(Path::new(path)).expect("Could not bind unix socket");
+        let listener = UnixListener::bind(path).expect("Could not bind unix socket");
+        let auth = PeerAuth::new(&config.unix_allowed_uids, &config.unix_allowed_gids)
+            .with_admins(&config.unix_admin_uids);
 
-    let mut _m = m.clone();
+        tokio::spawn(run_unix(listener, auth, shared.clone()));
+    }
+
+    tokio::spawn(reload_on_hangup(args, shared.clone()));
+
+    let _shared = shared.clone();
     tokio::spawn(async move {
         if let Err(e) = signal::ctrl_c().await {
             eprintln!("signal error: {}", e);
         }
 
-        _m.flush_current().await;
+        let timeout = Duration::from_secs(_shared.config.shutdown_drain_timeout_secs);
+        eprintln!("shutting down, draining connections for {:?}", timeout);
+        _shared.shutdown.store(true, SeqCst);
+        tokio::time::sleep(timeout).await;
+
+        for db in _shared.dbs.values() {
+            if let Err(e) = db.shutdown().await {
+                eprintln!("error: could not flush - {}", e);
+            }
+        }
         std::process::exit(0);
     });
 
     loop {
         match listener.accept().await {
-            Ok((stream, addr)) => {
-                tokio::spawn(accept(stream, addr, m.clone(), kd.clone()));
+            Ok((stream, _addr)) => {
+                let Some(permit) = admit(&shared) else {
+                    // TLS clients are just closed, the reply would fail their handshake anyway
+                    if let (None, Ok(mut stream)) = (&tls, stream.into_std()) {
+                        let _ = stream.write(MAX_CLIENTS);
+                    }
+                    continue;
+                };
+
+                match &tls {
+                    Some(tls) => {
+                        tokio::spawn(accept_tls(stream, tls.clone(), shared.clone(), permit));
+                    }
+                    None => {
+                        let (r, w) = stream.into_split();
+                        tokio::spawn(accept(r, w, shared.clone(), User::default(), permit));
+                    }
+                }
+            }
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+}
+
+async fn reload_on_hangup(args: Args, shared: Shared) {
+    let mut hangup = match signal::unix::signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => return eprintln!("signal error: {}", e),
+    };
+
+    while hangup.recv().await.is_some() {
+        let reloaded = Config::load(args.clone()).map(|config| config.tunables());
+        match reconfigure(&shared, |_| reloaded) {
+            Ok(tunables) => eprintln!("reloaded configuration: {:?}", tunables),
+            Err(e) => eprintln!("error: could not reload configuration - {}", e),
+        }
+    }
+}
+
+/// Applies the tunables `f` returns to every database, and to connections from their next
+/// request on. Changes are made one at a time, so concurrent ones aren't lost.
+fn reconfigure(
+    shared: &Shared,
+    f: impl FnOnce(&Tunables) -> io::Result<Tunables>,
+) -> io::Result<Tunables> {
+    let mut res = Err(io::Error::other("not reconfigured"));
+    shared
+        .tunables
+        .send_if_modified(|tunables| match f(tunables) {
+            Ok(next) => {
+                for db in shared.dbs.values() {
+                    db.set_durability(next.durability);
+                    db.set_compaction_interval(next.compaction_interval());
+                }
+                *tunables = next;
+                res = Ok(next);
+                true
+            }
+            Err(e) => {
+                res = Err(e);
+                false
+            }
+        });
+
+    res
+}
+
+/// Completes the TLS handshake before serving the connection like any other.
+async fn accept_tls(
+    stream: TcpStream,
+    tls: TlsAcceptor,
+    shared: Shared,
+    permit: OwnedSemaphorePermit,
+) {
+    match tls.accept(stream).await {
+        Ok(stream) => {
+            let (r, w) = tokio::io::split(stream);
+            accept(r, w, shared, User::default(), permit).await
+        }
+        Err(e) => eprintln!("error: TLS handshake failed: {}", e),
+    }
+}
+
+/// Bulk loads a file of `key\tvalue` lines. Later lines win over earlier ones for the same key.
+async fn import(db: &Db, path: &Path) -> io::Result<usize> {
+    let data = tokio::fs::read(path).await?;
+
+    let mut entries = BTreeMap::new();
+    for (i, line) in data.split(|b| *b == b'\n').enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+
+        let Some(tab) = line.iter().position(|b| *b == b'\t') else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {} has no tab", i + 1),
+            ));
+        };
+        entries.insert(line[..tab].to_vec(), line[tab + 1..].to_vec());
+    }
+
+    db.bulk_load(entries).await
+}
+
+/// Removes a socket file left behind by a previous run, which would make bind fail. Anything
+/// else at `path`, or a socket another server is still listening on, is left alone.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and isn't a socket", path.display()),
+        ));
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("another server is listening on {}", path.display()),
+        ));
+    }
+
+    std::fs::remove_file(path)
+}
+
+async fn run_unix(listener: UnixListener, auth: PeerAuth, shared: Shared) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _addr)) => {
+                let user = match authorize(&stream, &auth) {
+                    Ok(user) => user,
+                    Err(e) => {
+                        eprintln!("error: rejected unix socket client: {}", e);
+                        let (_, mut w) = stream.into_split();
+                        let _ = w.write_all(NOAUTH).await;
+                        continue;
+                    }
+                };
+
+                let Some(permit) = admit(&shared) else {
+                    if let Ok(mut stream) = stream.into_std() {
+                        let _ = stream.write(MAX_CLIENTS);
+                    }
+                    continue;
+                };
+
+                let (r, w) = stream.into_split();
+                tokio::spawn(accept(r, w, shared.clone(), user, permit));
             }
             Err(e) => eprintln!("error: {}", e),
         }
     }
 }
 
-async fn accept(stream: TcpStream, addr: SocketAddr, pc: PageCache, kd: Arc<RwLock<KeyDir>>) {
-    if let Err(e) = accept_loop(stream, addr, pc, kd).await {
+fn authorize(stream: &UnixStream, auth: &PeerAuth) -> io::Result<User> {
+    let cred = stream.peer_cred()?;
+    if !auth.allows(&cred) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("uid {} gid {} not allowed", cred.uid(), cred.gid()),
+        ));
+    }
+
+    Ok(auth.user(&cred))
+}
+
+/// Takes a connection slot, `None` if `max_clients` are already connected. Rejected clients are
+/// told with a write straight to the non-blocking socket, so one that isn't reading can't hold
+/// up accepting others.
+fn admit(shared: &Shared) -> Option<OwnedSemaphorePermit> {
+    match shared.clients.clone().try_acquire_owned() {
+        Ok(permit) => Some(permit),
+        Err(_) => {
+            shared.metrics.rejected();
+            None
+        }
+    }
+}
+
+/// Serves a connection, releasing its slot in `max_clients` once it closes.
+async fn accept<R, W>(r: R, w: W, shared: Shared, user: User, _permit: OwnedSemaphorePermit)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    shared.metrics.connected();
+    let span = shared.tracer.as_ref().map(|_| Span::root("connection"));
+
+    if let Err(e) = accept_loop(r, w, &shared, user, span.as_ref()).await {
         match e.kind() {
             io::ErrorKind::ConnectionReset => {}
             e => eprintln!("error: {}", e),
         }
     }
+
+    shared.metrics.disconnected();
+    if let (Some(tracer), Some(span)) = (&shared.tracer, span) {
+        tracer.record(span);
+    }
 }
 
-async fn accept_loop(
-    stream: TcpStream,
-    _addr: SocketAddr,
-    pc: PageCache,
-    kd: Arc<RwLock<KeyDir>>,
-) -> io::Result<()> {
-    let (reader, writer) = stream.into_split();
-    let reader = BufReader::new(reader);
-    let writer = BufWriter::new(writer);
+async fn accept_loop<R, W>(
+    r: R,
+    w: W,
+    shared: &Shared,
+    user: User,
+    span: Option<&Span>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let reader = BufReader::new(r);
+    let writer = BufWriter::new(w);
 
-    let mut conn = Connection::new(reader, writer);
+    let mut tunables = shared.tunables.subscribe();
+    let mut conn = Connection::new(reader, writer).with_limits(tunables.borrow().limits());
+    let default = shared.config.databases[0].name.as_bytes();
+    let mut db = shared.dbs[default].clone();
+    let mut selected = Bytes::copy_from_slice(default);
+    let mut shadowed = true;
+    let mut txn: Option<ReadTxn> = None;
+    // Writes queued in the open transaction
+    let mut queued = Vec::new();
 
     loop {
+        if tunables.has_changed().unwrap_or(false) {
+            conn.set_limits(tunables.borrow_and_update().limits());
+        }
+
         let message = match conn.read().await? {
-            Some(m) if m == Message::None => continue,
+            Some(Message::None) => continue,
             Some(m) => m,
             None => continue,
         };
 
-        let res = message.exec(&pc, &kd).await;
+        let command = match (&shared.tracer, span) {
+            (Some(tracer), Some(span)) => Some(tracer.command(span, &message)),
+            _ => None,
+        };
+
+        let commits = matches!(message, Message::Commit) && !queued.is_empty();
+        let draining = shared.shutdown.load(SeqCst)
+            && (message.is_write() || commits || !shared.config.reads_during_shutdown);
+        let mut committed = None;
+        let res = match (draining, &message) {
+            (true, _) => Message::error(SHUTDOWN_IN_PROGRESS),
+            (false, Message::Begin) => match txn {
+                Some(_) => Message::error(TXN_OPEN),
+                None => {
+                    txn = Some(ReadTxn::begin(&db).await);
+                    Message::Success
+                }
+            },
+            (false, Message::Commit) => match txn.take() {
+                Some(txn) => {
+                    txn.end().await;
+                    match queued.is_empty() {
+                        true => Message::Success,
+                        false => {
+                            // Applied like a batch, to a single page, so all or none of it lands
+                            let batch = Message::Batch(std::mem::take(&mut queued));
+                            let res = batch.exec(&db, &user).await;
+                            committed = Some(batch);
+                            res
+                        }
+                    }
+                }
+                None => Message::error(NO_TXN),
+            },
+            (false, Message::Discard) => match txn.take() {
+                Some(txn) => {
+                    txn.end().await;
+                    queued.clear();
+                    Message::Success
+                }
+                None => Message::error(NO_TXN),
+            },
+            (false, message) if txn.is_some() && message.is_write() => match message {
+                Message::Insert(_, _) | Message::InsertEx(_, _, _) | Message::Delete(_) => {
+                    queued.push(message.clone());
+                    Message::Text("queued".into())
+                }
+                _ => Message::error(TXN_WRITES_ONLY),
+            },
+            (false, Message::Use(_) | Message::Select(_)) if txn.is_some() => {
+                Message::error(TXN_SWITCH)
+            }
+            (false, Message::Use(name)) => match shared.dbs.get(name) {
+                Some(next) => {
+                    db = next.clone();
+                    selected = name.clone();
+                    shadowed = name == default;
+                    Message::Success
+                }
+                None => Message::error(UNKNOWN_DATABASE),
+            },
+            (false, Message::Select(n)) => match shared.config.databases.get(*n as usize) {
+                Some(database) => {
+                    selected = Bytes::from(database.name.clone());
+                    db = shared.dbs[&selected].clone();
+                    shadowed = *n == 0;
+                    Message::Success
+                }
+                None => Message::error(INVALID_DB_INDEX),
+            },
+            (false, Message::Notifications) => {
+                let rx = db.subscribe();
+                shared.metrics.record(&message, &Message::Success);
+                conn.write(Message::Success).await?;
+
+                return notify(conn, rx).await;
+            }
+            (false, Message::Subscribe(channel)) => {
+                shared.metrics.record(&message, &Message::Success);
+                conn.write(Message::Success).await?;
+
+                return subscribe(conn, &shared.channels, channel.clone()).await;
+            }
+            (false, Message::Publish(channel, payload)) => {
+                let n = publish(&shared.channels, channel, payload.clone());
+                Message::Text(n.to_string().into())
+            }
+            (false, Message::SubscribeOplog(seq)) => {
+                let db = db.clone();
+                shared.metrics.record(&message, &Message::Success);
+                conn.write(Message::Success).await?;
+
+                return oplog(conn, &db, *seq).await;
+            }
+            (false, Message::Watch(k, prefix)) => {
+                let rx = db.watch();
+                shared.metrics.record(&message, &Message::Success);
+                conn.write(Message::Success).await?;
+
+                return watch(conn, rx, k, *prefix).await;
+            }
+            (false, Message::ConfigGet(name)) => {
+                match shared.tunables.borrow().get(&String::from_utf8_lossy(name)) {
+                    Some(value) => Message::Text(value.into()),
+                    None => Message::error(UNKNOWN_CONFIG),
+                }
+            }
+            (false, Message::ConfigSet(_, _)) if !user.admin => Message::error(CONFIG_ADMIN_ONLY),
+            (false, Message::ConfigSet(name, value)) => {
+                let (name, value) = (
+                    String::from_utf8_lossy(name),
+                    String::from_utf8_lossy(value),
+                );
+                match reconfigure(shared, |tunables| tunables.set(&name, &value)) {
+                    Ok(_) => Message::Success,
+                    Err(e) => Message::Error("ERR".into(), e.to_string().into()),
+                }
+            }
+            (false, Message::Info) => {
+                let metrics = shared.metrics.snapshot();
+                let server = ServerInfo {
+                    uptime: shared.started.elapsed(),
+                    connections: metrics.connections,
+                    rejected: metrics.rejected,
+                };
+
+                message::info(&db, Some(server)).await
+            }
+            (false, Message::KeysMatching(pattern)) => {
+                keys(&mut conn, &db, txn.as_ref(), pattern).await?
+            }
+            (false, Message::Batch(messages))
+                if messages.iter().any(|m| matches!(m, Message::Use(_))) =>
+            {
+                message::batch_across(&shared.dbs, &selected, &user, messages).await
+            }
+            (false, message) => match &txn {
+                Some(txn) => match message.exec_in(txn).await {
+                    Some(res) => res,
+                    None => message.exec(&db, &user).await,
+                },
+                None => message.exec(&db, &user).await,
+            },
+        };
+        shared.metrics.record(&message, &res);
+        if let (Some(tracer), Some(command)) = (&shared.tracer, command) {
+            tracer.record(command);
+        }
+        // Writes queued in a transaction are forwarded as the batch they're committed in
+        let write = match committed {
+            Some(batch) => Some(batch),
+            None => Some(message).filter(|m| m.is_write() && txn.is_none()),
+        };
+        if let Some(shadow) = shared.shadow.as_ref().filter(|_| shadowed && !draining) {
+            if let Some(write) = write {
+                shadow.forward(write, res.clone());
+            }
+        }
 
         conn.write(res).await?;
     }
 }
+
+/// Writes an `expired <key>` or `alarm <name> raised|cleared <value> <threshold>` line for every
+/// event until the client disconnects. Keys are quoted if they have to be. Anything the client
+/// sends in the meantime is ignored.
+async fn notify<R, W>(
+    mut conn: Connection<R, W>,
+    mut rx: broadcast::Receiver<Event>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let text = tokio::select! {
+            event = rx.recv() => match event {
+                Ok(Event::Expired(k)) => expired_line(&k),
+                Ok(Event::Alarm { alarm, raised, value, threshold }) => {
+                    let state = if raised { "raised" } else { "cleared" };
+                    format!("alarm {} {} {} {}", alarm.name(), state, value, threshold).into()
+                }
+                Err(RecvError::Lagged(n)) => format!("lagged {}", n).into(),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            read = conn.read() => {
+                read?;
+                continue;
+            }
+        };
+
+        conn.write(Message::Text(text)).await?;
+    }
+}
+
+fn expired_line(k: &[u8]) -> Bytes {
+    let mut dst = BytesMut::from(&b"expired "[..]);
+    quote::put_word(&mut dst, k);
+
+    dst.freeze()
+}
+
+/// Writes every chunk of the keys matching `pattern` but the last, which is returned to be
+/// written as the reply. Each chunk is a `Keys` reply, so a `<cursor> <key>...` line, and only the
+/// last has the start cursor `0`, which is how clients know the listing is over. The last chunk
+/// can be empty.
+async fn keys<R, W>(
+    conn: &mut Connection<R, W>,
+    db: &Db,
+    txn: Option<&ReadTxn>,
+    pattern: &[u8],
+) -> io::Result<Message>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // A chunk at a time, so the KeyDir isn't held while the connection is written to, yielding
+    // between chunks so listing a large keyspace doesn't hold the worker
+    let mut after = None;
+    loop {
+        let (keys, next) = match txn {
+            Some(txn) => message::keys_matching_in(txn, pattern, after.as_deref()),
+            None => message::keys_matching(db, pattern, after.as_deref()).await,
+        };
+        if next.is_none() {
+            return Ok(keys);
+        }
+        conn.write(keys).await?;
+        after = next;
+
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Writes a `set <key>` or `del <key>` line for every change clients make to `k`, or to keys
+/// starting with it if it's a `prefix`, until the client disconnects. Keys are quoted if they
+/// have to be. Anything the client sends in the meantime is ignored.
+async fn watch<R, W>(
+    mut conn: Connection<R, W>,
+    mut rx: broadcast::Receiver<Change>,
+    k: &[u8],
+    prefix: bool,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let watched = |key: &[u8]| match prefix {
+        true => key.starts_with(k),
+        false => key == k,
+    };
+
+    loop {
+        let text = tokio::select! {
+            change = rx.recv() => match change {
+                Ok(change) if !watched(change.key()) => continue,
+                Ok(change) => change_line(&change),
+                // The client has to assume any watched key changed
+                Err(RecvError::Lagged(n)) => format!("lagged {}", n).into(),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            read = conn.read() => {
+                read?;
+                continue;
+            }
+        };
+
+        conn.write(Message::Text(text)).await?;
+    }
+}
+
+fn change_line(change: &Change) -> Bytes {
+    let mut dst = BytesMut::new();
+    let key = match change {
+        Change::Set(key) => {
+            dst.extend_from_slice(b"set ");
+            key
+        }
+        Change::Deleted(key) => {
+            dst.extend_from_slice(b"del ");
+            key
+        }
+    };
+    quote::put_word(&mut dst, key);
+
+    dst.freeze()
+}
+
+/// Writes a `<seq> set <key> <value>` or `<seq> del <key>` line for every entry in the log from
+/// `seq` on, then for each one written after until the client disconnects, see `Oplog`. Keys
+/// and values are quoted if they have to be. Anything the client sends in the meantime is
+/// ignored.
+async fn oplog<R, W>(mut conn: Connection<R, W>, db: &Db, seq: u64) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // Subscribed first, so a write between reading the log and waiting isn't missed
+    let mut rx = db.watch();
+    let mut oplog = Oplog::new(db.pc.clone(), seq);
+
+    loop {
+        let ops = match oplog.read(OPLOG_BATCH).await {
+            Ok(ops) => ops,
+            Err(e) => {
+                conn.write(Message::Error("ERR".into(), e.to_string().into()))
+                    .await?;
+                return Ok(());
+            }
+        };
+        if !ops.is_empty() {
+            for op in &ops {
+                conn.write(Message::Text(op_line(op))).await?;
+            }
+            continue;
+        }
+
+        tokio::select! {
+            change = rx.recv() => if let Err(RecvError::Closed) = change {
+                return Ok(());
+            },
+            _ = tokio::time::sleep(OPLOG_POLL_INTERVAL) => {}
+            read = conn.read() => {
+                read?;
+            }
+        }
+    }
+}
+
+fn op_line(op: &Op) -> Bytes {
+    let mut dst = BytesMut::new();
+    dst.extend_from_slice(op.seq.to_string().as_bytes());
+    match op.entry.t {
+        EntryType::Put => {
+            dst.extend_from_slice(b" set ");
+            quote::put_word(&mut dst, &op.entry.key);
+            dst.extend_from_slice(b" ");
+            quote::put_at_end(&mut dst, &op.entry.value);
+        }
+        EntryType::Delete => {
+            dst.extend_from_slice(b" del ");
+            quote::put_word(&mut dst, &op.entry.key);
+        }
+    }
+
+    dst.freeze()
+}
+
+/// Sends `payload` to the subscribers of `channel`. Returns how many there were.
+fn publish(channels: &Channels, channel: &[u8], payload: Bytes) -> usize {
+    let channels = channels.lock().expect("channels lock poisoned");
+
+    match channels.get(channel) {
+        // Only fails if the last subscriber left since it was checked
+        Some(tx) => tx.send(payload).unwrap_or(0),
+        None => 0,
+    }
+}
+
+/// Writes a `message <channel> <payload>` line for everything published to `channel` until the
+/// client disconnects, dropping the channel if it was the last subscriber. The channel and
+/// payload are quoted if they have to be. Anything the client sends in the meantime is ignored.
+async fn subscribe<R, W>(
+    mut conn: Connection<R, W>,
+    channels: &Channels,
+    channel: Bytes,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut rx = channels
+        .lock()
+        .expect("channels lock poisoned")
+        .entry(channel.clone())
+        .or_insert_with(|| broadcast::channel(CHANNEL_QUEUE_SIZE).0)
+        .subscribe();
+
+    let res = async {
+        loop {
+            let text = tokio::select! {
+                payload = rx.recv() => match payload {
+                    Ok(payload) => message_line(&channel, &payload),
+                    Err(RecvError::Lagged(n)) => format!("lagged {}", n).into(),
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                read = conn.read() => {
+                    read?;
+                    continue;
+                }
+            };
+
+            conn.write(Message::Text(text)).await?;
+        }
+    }
+    .await;
+    drop(rx);
+
+    let mut channels = channels.lock().expect("channels lock poisoned");
+    if channels
+        .get(&channel)
+        .is_some_and(|tx| tx.receiver_count() == 0)
+    {
+        channels.remove(&channel);
+    }
+
+    res
+}
+
+fn message_line(channel: &[u8], payload: &[u8]) -> Bytes {
+    let mut dst = BytesMut::from(&b"message "[..]);
+    quote::put_word(&mut dst, channel);
+    dst.extend_from_slice(b" ");
+    quote::put_at_end(&mut dst, payload);
+
+    dst.freeze()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, path::Path};
+
+    use crate::{
+        serverv2::{
+            auth::User,
+            connection::Connection,
+            message::{Message, SCAN_COUNT},
+            server::{change_line, expired_line, keys, message_line, remove_stale_socket},
+        },
+        storagev2::{
+            db::{Db, Options},
+            events::Change,
+            test::CleanUp,
+        },
+    };
+
+    #[test]
+    fn test_remove_stale_socket() -> io::Result<()> {
+        const SOCKET: &str = "./test_remove_stale_socket.sock";
+        let path = Path::new(SOCKET);
+        let _cu = CleanUp::file(SOCKET);
+
+        // Still listened on
+        let listener = std::os::unix::net::UnixListener::bind(path)?;
+        let got = remove_stale_socket(path).map_err(|e| e.kind());
+        assert!(got == Err(io::ErrorKind::AddrInUse), "Got: {:?}", got);
+
+        drop(listener);
+        remove_stale_socket(path)?;
+        assert!(!path.exists());
+        remove_stale_socket(path)?;
+
+        std::fs::write(path, b"not a socket")?;
+        let got = remove_stale_socket(path).map_err(|e| e.kind());
+        assert!(got == Err(io::ErrorKind::AlreadyExists), "Got: {:?}", got);
+        assert!(path.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_keys() -> io::Result<()> {
+        const DB_FILE: &str = "./test_server_keys.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+
+        // With exactly a chunk of keys, the last chunk is an empty one
+        for n in [SCAN_COUNT / 2, SCAN_COUNT] {
+            for i in 0..n {
+                let k = format!("key{}:{:03}", n, i);
+                let got = Message::Insert(k.into(), "v".into())
+                    .exec(&db, &User::default())
+                    .await;
+                assert!(got == Message::Success, "Got: {:?}", got);
+            }
+
+            let mut out = Vec::new();
+            let mut conn = Connection::new(&b""[..], &mut out);
+            let last = keys(&mut conn, &db, None, format!("key{}:*", n).as_bytes()).await?;
+            conn.write(last).await?;
+            drop(conn);
+
+            let out = String::from_utf8(out).expect("should be UTF-8");
+            let lines: Vec<_> = out.lines().collect();
+            assert!(lines.len() == n / SCAN_COUNT + 1, "Got: {:?}", lines);
+            let cursors: Vec<_> = lines.iter().map(|l| l.split(' ').next()).collect();
+            let got = lines
+                .iter()
+                .map(|l| l.split(' ').count() - 1)
+                .sum::<usize>();
+            assert!(got == n, "\nExpected: {:?}\nGot: {:?}\n", n, got);
+            assert!(
+                cursors.last() == Some(&Some("0"))
+                    && cursors[..cursors.len() - 1].iter().all(|c| c != &Some("0")),
+                "Got: {:?}",
+                cursors
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_line() {
+        let cases = [
+            (Change::Set("key1".into()), "set key1"),
+            (Change::Deleted("key1".into()), "del key1"),
+            (Change::Set("key 1\n".into()), "set \"key 1\\n\""),
+            (Change::Deleted("".into()), "del \"\""),
+        ];
+        for (change, expected) in cases {
+            let got = change_line(&change);
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+    }
+
+    #[test]
+    fn test_expired_line() {
+        let cases = [
+            ("key1", "expired key1"),
+            ("key 1\n", "expired \"key 1\\n\""),
+            ("", "expired \"\""),
+        ];
+        for (k, expected) in cases {
+            let got = expired_line(k.as_bytes());
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+    }
+
+    #[test]
+    fn test_message_line() {
+        let cases = [
+            ("news", "hello world", "message news hello world"),
+            ("news", "line 1\nline 2", "message news \"line 1\\nline 2\""),
+            ("news 1", "hi", "message \"news 1\" hi"),
+            ("news", "\"hi\"", "message news \"\\\"hi\\\"\""),
+        ];
+        for (channel, payload, expected) in cases {
+            let got = message_line(channel.as_bytes(), payload.as_bytes());
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+    }
+}
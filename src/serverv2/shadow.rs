@@ -0,0 +1,88 @@
+use std::{io, sync::Arc};
+
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::mpsc,
+};
+
+use crate::serverv2::{message::Message, metrics::Metrics};
+
+/// Writes buffered for the shadow target. Writes are dropped and counted as errors rather than
+/// slowing down the primary when the target can't keep up.
+const QUEUE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Matched,
+    Diverged,
+    Failed,
+}
+
+/// Forwards writes that were applied locally to a second hash_db, e.g. one running a new engine
+/// during a migration, and counts where its responses diverge from ours.
+#[derive(Clone)]
+pub struct Shadow {
+    tx: mpsc::Sender<(Message, Message)>,
+    metrics: Arc<Metrics>,
+}
+
+impl Shadow {
+    pub fn new(target: String, metrics: Arc<Metrics>) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(run(target, rx, metrics.clone()));
+
+        Self { tx, metrics }
+    }
+
+    /// Queues `message` for the target along with the response it got locally.
+    pub fn forward(&self, message: Message, res: Message) {
+        if self.tx.try_send((message, res)).is_err() {
+            self.metrics.shadowed(Outcome::Failed);
+        }
+    }
+}
+
+async fn run(target: String, mut rx: mpsc::Receiver<(Message, Message)>, metrics: Arc<Metrics>) {
+    let mut conn = None;
+
+    while let Some((message, res)) = rx.recv().await {
+        let Some(req) = message.request() else {
+            metrics.shadowed(Outcome::Failed);
+            continue;
+        };
+
+        let outcome = match send(&mut conn, &target, &req).await {
+            Ok(remote) if remote == Bytes::from(res) => Outcome::Matched,
+            Ok(_) => Outcome::Diverged,
+            Err(e) => {
+                eprintln!("error: could not forward write to {}: {}", target, e);
+                conn = None;
+                Outcome::Failed
+            }
+        };
+        metrics.shadowed(outcome);
+    }
+}
+
+/// Sends `req` to the target, connecting first if needed, and reads back the response line.
+async fn send(
+    conn: &mut Option<BufReader<TcpStream>>,
+    target: &str,
+    req: &[u8],
+) -> io::Result<Vec<u8>> {
+    let stream = match conn {
+        Some(stream) => stream,
+        None => conn.insert(BufReader::new(TcpStream::connect(target).await?)),
+    };
+
+    stream.get_mut().write_all(req).await?;
+
+    let mut res = Vec::new();
+    if 0 == stream.read_until(b'\n', &mut res).await? {
+        return Err(io::Error::from(io::ErrorKind::ConnectionReset));
+    }
+
+    Ok(res)
+}
@@ -0,0 +1,101 @@
+use std::{io, path::Path, sync::Arc};
+
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+/// Builds an acceptor that terminates TLS with the PEM certificate chain in `cert` and the
+/// private key in `key`.
+pub fn acceptor(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(cert, e))?;
+    if certs.is_empty() {
+        return Err(invalid(cert, "no certificates found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| invalid(key, e))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn invalid(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}: {}", path.display(), e),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, path::Path, sync::Arc};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{
+        rustls::{
+            pki_types::{pem::PemObject, CertificateDer, ServerName},
+            ClientConfig, RootCertStore,
+        },
+        TlsConnector,
+    };
+
+    use crate::serverv2::tls::acceptor;
+
+    const CERT_FILE: &str = "src/serverv2/fixtures/cert.pem";
+    const KEY_FILE: &str = "src/serverv2/fixtures/key.pem";
+
+    #[tokio::test]
+    async fn test_acceptor() -> io::Result<()> {
+        let tls = acceptor(Path::new(CERT_FILE), Path::new(KEY_FILE))?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = tls.accept(stream).await?;
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(&buf).await?;
+            stream.shutdown().await?;
+
+            io::Result::Ok(())
+        });
+
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(CERT_FILE).expect("should read cert") {
+            roots
+                .add(cert.expect("should parse cert"))
+                .expect("should add root");
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        let name = ServerName::try_from("localhost").expect("valid name");
+
+        let mut stream = connector
+            .connect(name, TcpStream::connect(addr).await?)
+            .await?;
+        stream.write_all(b"ping").await?;
+        let mut got = Vec::new();
+        stream.read_to_end(&mut got).await?;
+        assert!(got == b"ping", "Got: {:?}", got);
+
+        server.await.expect("server task panicked")?;
+
+        assert!(acceptor(Path::new(KEY_FILE), Path::new(KEY_FILE)).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    collections::hash_map::{DefaultHasher, RandomState},
+    fmt::Write,
+    hash::{BuildHasher, Hash, Hasher},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+};
+
+use crate::serverv2::message::Message;
+
+pub const SERVICE_NAME: &str = "hash_db";
+
+/// Spans buffered for the exporter. Spans are dropped rather than slowing down commands when the
+/// collector can't keep up.
+const QUEUE_SIZE: usize = 4096;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// OTLP span kinds
+const KIND_SERVER: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_id: Option<[u8; 8]>,
+    pub name: &'static str,
+    pub start: u64,
+    pub end: u64,
+    pub attributes: Vec<(&'static str, String)>,
+}
+
+impl Span {
+    pub fn root(name: &'static str) -> Self {
+        let mut trace_id = [0; 16];
+        trace_id[..8].copy_from_slice(&random_id());
+        trace_id[8..].copy_from_slice(&random_id());
+
+        Self {
+            trace_id,
+            span_id: random_id(),
+            parent_id: None,
+            name,
+            start: now_nanos(),
+            end: 0,
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn child(&self, name: &'static str) -> Self {
+        Self {
+            trace_id: self.trace_id,
+            span_id: random_id(),
+            parent_id: Some(self.span_id),
+            name,
+            start: now_nanos(),
+            end: 0,
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn with_attribute(mut self, k: &'static str, v: impl Into<String>) -> Self {
+        self.attributes.push((k, v.into()));
+        self
+    }
+
+    pub fn end(mut self) -> Self {
+        self.end = now_nanos();
+        self
+    }
+
+    /// Encodes the span as OTLP JSON. Attribute values are generated by the server, never
+    /// client input, so they don't need escaping.
+    fn json(&self, dst: &mut String) {
+        let _ = write!(
+            dst,
+            r#"{{"traceId":"{}","spanId":"{}","#,
+            hex(&self.trace_id),
+            hex(&self.span_id)
+        );
+        if let Some(parent) = self.parent_id {
+            let _ = write!(dst, r#""parentSpanId":"{}","#, hex(&parent));
+        }
+        let _ = write!(
+            dst,
+            r#""name":"{}","kind":{},"startTimeUnixNano":"{}","endTimeUnixNano":"{}","attributes":["#,
+            self.name, KIND_SERVER, self.start, self.end
+        );
+        for (i, (k, v)) in self.attributes.iter().enumerate() {
+            if i > 0 {
+                dst.push(',');
+            }
+            let _ = write!(
+                dst,
+                r#"{{"key":"{}","value":{{"stringValue":"{}"}}}}"#,
+                k, v
+            );
+        }
+        dst.push_str("]}");
+    }
+}
+
+/// Records spans for connections and commands and exports them to an OTLP/HTTP collector.
+#[derive(Clone)]
+pub struct Tracer(mpsc::Sender<Span>);
+
+impl Tracer {
+    /// Starts exporting to `endpoint`, the host and port of an OTLP/HTTP collector.
+    pub fn new(endpoint: String) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(export(endpoint, rx));
+
+        Self(tx)
+    }
+
+    /// Starts a span for `message` under the connection span. Keys are hashed so traces don't
+    /// leak data.
+    pub fn command(&self, connection: &Span, message: &Message) -> Span {
+        let (name, key) = match message {
+            Message::Insert(k, _) | Message::InsertEx(k, _, _) => ("SET", Some(k)),
+            Message::Delete(k) | Message::Del(k) => ("DEL", Some(k)),
+            Message::Unlink(k) => ("UNLINK", Some(k)),
+            Message::SetRange(k, _, _) => ("SETRANGE", Some(k)),
+            Message::Merge(_, k, _) => ("MERGE", Some(k)),
+            Message::Append(k, _) => ("APPEND", Some(k)),
+            Message::GetSet(k, _) => ("GETSET", Some(k)),
+            Message::SetNx(k, _) => ("SETNX", Some(k)),
+            Message::GetDel(k) => ("GETDEL", Some(k)),
+            Message::Expire(k, _) => ("EXPIRE", Some(k)),
+            Message::ExpireAt(k, _) => ("EXPIREAT", Some(k)),
+            Message::Persist(k) => ("PERSIST", Some(k)),
+            Message::Ttl(k) => ("TTL", Some(k)),
+            Message::Get(k) => ("GET", Some(k)),
+            Message::Exists(k) => ("EXISTS", Some(k)),
+            Message::Strlen(k) => ("STRLEN", Some(k)),
+            Message::MemoryUsage(k) => ("MEMORY", Some(k)),
+            Message::MGet(_, _) => ("MGET", None),
+            Message::MSet(_) => ("MSET", None),
+            Message::Use(_) => ("USE", None),
+            Message::Select(_) => ("SELECT", None),
+            Message::DbSize => ("DBSIZE", None),
+            Message::FlushDb => ("FLUSHDB", None),
+            Message::Batch(_) => ("BATCH", None),
+            Message::Scan(_, _) => ("SCAN", None),
+            Message::Range(_, _, _, _) => ("RANGE", None),
+            Message::KeysMatching(_) => ("KEYS", None),
+            Message::CompactionEstimate => ("COMPACTION", None),
+            Message::PageFill => ("PAGE", None),
+            Message::BootstrapStats => ("STATS", None),
+            Message::Health => ("HEALTH", None),
+            Message::Info => ("INFO", None),
+            Message::ExpiryForecast => ("EXPIRYFORECAST", None),
+            Message::Validate(_, _) => ("VALIDATE", None),
+            Message::Unvalidate(_) => ("UNVALIDATE", None),
+            Message::Validators => ("VALIDATORS", None),
+            Message::ExportKeys(_, _) => ("EXPORT-KEYS", None),
+            Message::ExportSstable(_) => ("EXPORT-SSTABLE", None),
+            Message::Snapshot(_) => ("SNAPSHOT", None),
+            Message::Notifications => ("NOTIFICATIONS", None),
+            Message::Begin => ("BEGIN", None),
+            Message::Commit => ("COMMIT", None),
+            Message::Discard => ("DISCARD", None),
+            Message::Watch(k, _) => ("WATCH", Some(k)),
+            Message::Subscribe(_) => ("SUBSCRIBE", None),
+            Message::Publish(_, _) => ("PUBLISH", None),
+            Message::SubscribeOplog(_) => ("SUBSCRIBE-OPLOG", None),
+            Message::ConfigGet(_) | Message::ConfigSet(_, _) => ("CONFIG", None),
+            Message::Fenced(_, message)
+            | Message::Grouped(_, message)
+            | Message::Quoted(_, message) => return self.command(connection, message),
+            _ => ("UNKNOWN", None),
+        };
+
+        let span = connection
+            .child(name)
+            .with_attribute("db.system", SERVICE_NAME)
+            .with_attribute("db.operation", name);
+        match key {
+            Some(k) => span.with_attribute("db.key.hash", format!("{:016x}", hash_key(k))),
+            None => span,
+        }
+    }
+
+    pub fn record(&self, span: Span) {
+        let _ = self.0.try_send(span.end());
+    }
+}
+
+async fn export(endpoint: String, mut rx: mpsc::Receiver<Span>) {
+    let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+    let mut spans = Vec::new();
+
+    loop {
+        tokio::select! {
+            span = rx.recv() => match span {
+                Some(span) => spans.push(span),
+                None => return,
+            },
+            _ = interval.tick() => {
+                if spans.is_empty() {
+                    continue;
+                }
+
+                if let Err(e) = post(&endpoint, &encode(&spans)).await {
+                    eprintln!("error: could not export {} spans: {}", spans.len(), e);
+                }
+                spans.clear();
+            }
+        }
+    }
+}
+
+pub fn encode(spans: &[Span]) -> String {
+    let mut dst = format!(
+        r#"{{"resourceSpans":[{{"resource":{{"attributes":[{{"key":"service.name","value":{{"stringValue":"{}"}}}}]}},"scopeSpans":[{{"scope":{{"name":"{}"}},"spans":["#,
+        SERVICE_NAME, SERVICE_NAME
+    );
+    for (i, span) in spans.iter().enumerate() {
+        if i > 0 {
+            dst.push(',');
+        }
+        span.json(&mut dst);
+    }
+    dst.push_str("]}]}]}");
+
+    dst
+}
+
+async fn post(endpoint: &str, body: &str) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(endpoint).await?;
+
+    let req = format!(
+        "POST /v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        endpoint,
+        body.len(),
+        body
+    );
+    stream.write_all(req.as_bytes()).await?;
+
+    let mut res = Vec::new();
+    stream.read_to_end(&mut res).await?;
+    match res.get(9..12) {
+        Some(status) if status.starts_with(b"2") => Ok(()),
+        _ => Err(std::io::Error::other(format!(
+            "collector responded {:?}",
+            String::from_utf8_lossy(res.split(|b| *b == b'\r').next().unwrap_or_default())
+        ))),
+    }
+}
+
+fn hash_key(k: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    k.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn random_id() -> [u8; 8] {
+    RandomState::new().hash_one(now_nanos()).to_be_bytes()
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_nanos() as u64
+}
+
+fn hex(b: &[u8]) -> String {
+    b.iter()
+        .fold(String::with_capacity(b.len() * 2), |mut s, b| {
+            let _ = write!(s, "{:02x}", b);
+            s
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::serverv2::trace::{encode, Span};
+
+    #[test]
+    fn test_encode() {
+        let connection = Span {
+            trace_id: [1; 16],
+            span_id: [2; 8],
+            parent_id: None,
+            name: "connection",
+            start: 10,
+            end: 20,
+            attributes: Vec::new(),
+        };
+        let command = Span {
+            span_id: [3; 8],
+            parent_id: Some([2; 8]),
+            name: "GET",
+            start: 11,
+            end: 12,
+            attributes: vec![("db.operation", "GET".into())],
+            ..connection.clone()
+        };
+
+        let got = encode(&[command]);
+        let expected = concat!(
+            r#"{"resourceSpans":[{"resource":{"attributes":[{"key":"service.name","value":{"stringValue":"hash_db"}}]},"#,
+            r#""scopeSpans":[{"scope":{"name":"hash_db"},"spans":[{"traceId":"01010101010101010101010101010101","#,
+            r#""spanId":"0303030303030303","parentSpanId":"0202020202020202","name":"GET","kind":2,"#,
+            r#""startTimeUnixNano":"11","endTimeUnixNano":"12","#,
+            r#""attributes":[{"key":"db.operation","value":{"stringValue":"GET"}}]}]}]}]}"#,
+        );
+        assert!(got == expected, "\nExpected: {}\nGot: {}\n", expected, got);
+    }
+}
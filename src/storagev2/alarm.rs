@@ -0,0 +1,273 @@
+//! Thresholds on how large the database has grown, checked in the background so operators hear
+//! about it before the disk fills or the `KeyDir` outgrows memory.
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::RwLock;
+
+use crate::storagev2::{
+    compaction::Compactor,
+    events::{Event, Events},
+    key_dir::KeyDir,
+    page_manager::PageCache,
+};
+
+pub const ALARM_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Checks between dead ratio checks, which read every page like a compaction estimate does.
+const DEAD_RATIO_EVERY: usize = 6;
+
+/// Levels past which an alarm is raised. Unset ones are never checked.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    pub max_keys: Option<usize>,
+    pub max_disk_bytes: Option<u64>,
+    /// Fraction of the bytes in the data file that belong to overwritten, deleted or expired
+    /// entries.
+    pub max_dead_ratio: Option<f64>,
+}
+
+impl Thresholds {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alarm {
+    Keys,
+    DiskBytes,
+    DeadRatio,
+}
+
+impl Alarm {
+    pub const ALL: [Alarm; 3] = [Alarm::Keys, Alarm::DiskBytes, Alarm::DeadRatio];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Alarm::Keys => "keys",
+            Alarm::DiskBytes => "disk_bytes",
+            Alarm::DeadRatio => "dead_ratio",
+        }
+    }
+
+    fn bit(&self) -> u8 {
+        1 << *self as u8
+    }
+}
+
+/// The alarms currently raised.
+#[derive(Debug, Default)]
+pub struct Alarms(AtomicU8);
+
+impl Alarms {
+    pub fn raised(&self) -> Vec<Alarm> {
+        let raised = self.0.load(Ordering::Relaxed);
+
+        Alarm::ALL
+            .into_iter()
+            .filter(|alarm| raised & alarm.bit() != 0)
+            .collect()
+    }
+
+    /// Raises or clears `alarm`, returns whether that changed anything.
+    fn set(&self, alarm: Alarm, raised: bool) -> bool {
+        let prev = match raised {
+            true => self.0.fetch_or(alarm.bit(), Ordering::Relaxed),
+            false => self.0.fetch_and(!alarm.bit(), Ordering::Relaxed),
+        };
+
+        (prev & alarm.bit() != 0) != raised
+    }
+}
+
+pub async fn run(
+    m: PageCache,
+    kd: Arc<RwLock<KeyDir>>,
+    thresholds: Thresholds,
+    alarms: Arc<Alarms>,
+    events: Events,
+) {
+    let mut interval = tokio::time::interval(ALARM_INTERVAL);
+
+    for i in 0.. {
+        interval.tick().await;
+
+        let dead_ratio = i % DEAD_RATIO_EVERY == 0;
+        if let Err(e) = check(&m, &kd, &thresholds, dead_ratio, &alarms, &events).await {
+            eprintln!("error: could not check alarms: {}", e);
+        }
+    }
+}
+
+/// Measures the database against `thresholds`, raising alarms that are crossed and clearing ones
+/// that no longer are. Each change is logged and published as an `Event::Alarm`. The dead ratio
+/// alarm is left as it is unless `dead_ratio` is set.
+pub async fn check(
+    m: &PageCache,
+    kd: &Arc<RwLock<KeyDir>>,
+    thresholds: &Thresholds,
+    dead_ratio: bool,
+    alarms: &Alarms,
+    events: &Events,
+) -> io::Result<()> {
+    if let Some(max) = thresholds.max_keys {
+        let keys = kd.read().await.len();
+        update(alarms, events, Alarm::Keys, keys as f64, max as f64);
+    }
+
+    if let Some(max) = thresholds.max_disk_bytes {
+        let bytes = m.disk_bytes().await?;
+        update(alarms, events, Alarm::DiskBytes, bytes as f64, max as f64);
+    }
+
+    if let Some(max) = thresholds.max_dead_ratio.filter(|_| dead_ratio) {
+        let estimate = Compactor::new(m.clone(), kd.clone()).estimate().await?;
+        let total = estimate.live_bytes + estimate.dead_bytes;
+        let ratio = match total {
+            0 => 0.0,
+            total => estimate.dead_bytes as f64 / total as f64,
+        };
+        update(alarms, events, Alarm::DeadRatio, ratio, max);
+    }
+
+    Ok(())
+}
+
+fn update(alarms: &Alarms, events: &Events, alarm: Alarm, value: f64, threshold: f64) {
+    let raised = value > threshold;
+    if !alarms.set(alarm, raised) {
+        return;
+    }
+
+    let state = if raised { "raised" } else { "cleared" };
+    eprintln!(
+        "warning: alarm={} state={} value={} threshold={}",
+        alarm.name(),
+        state,
+        value,
+        threshold
+    );
+
+    // Fails only when nobody is subscribed
+    let _ = events.send(Event::Alarm {
+        alarm,
+        raised,
+        value,
+        threshold,
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, sync::Arc};
+
+    use tokio::sync::RwLock;
+
+    use crate::storagev2::{
+        alarm::{check, Alarm, Alarms, Thresholds},
+        events::{self, Event},
+        key_dir::bootstrap_with_report,
+        page::PAGE_SIZE,
+        page_manager::{PageCache, DEFAULT_READ_SIZE},
+        replacer::Policy,
+        testing::Fixture,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_alarms() -> io::Result<()> {
+        const DB_FILE: &str = "./test_alarms.db";
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .put(b"key1", b"value1")
+            .put(b"key2", b"value2")
+            .next_page()
+            .put(b"key1", b"value3")
+            .build()
+            .await?;
+
+        let (kd, latest, latest_id, _) = bootstrap_with_report(&disk, 0).await?;
+        let kd = Arc::new(RwLock::new(kd));
+        let m = PageCache::new(
+            disk,
+            Policy::default(),
+            DEFAULT_READ_SIZE,
+            latest,
+            latest_id,
+        );
+        let events = events::channel();
+        let mut rx = events.subscribe();
+        let alarms = Alarms::default();
+
+        let thresholds = Thresholds {
+            max_keys: Some(1),
+            max_disk_bytes: Some(PAGE_SIZE as u64 * 1024),
+            max_dead_ratio: Some(0.01),
+        };
+        check(&m, &kd, &thresholds, true, &alarms, &events).await?;
+
+        let expected = vec![Alarm::Keys, Alarm::DeadRatio];
+        let got = alarms.raised();
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        let got = rx.try_recv().ok();
+        assert!(
+            matches!(
+                got,
+                Some(Event::Alarm {
+                    alarm: Alarm::Keys,
+                    raised: true,
+                    ..
+                })
+            ),
+            "Got: {:?}",
+            got
+        );
+
+        // Staying over a threshold doesn't raise the alarm again
+        let _ = rx.try_recv();
+        check(&m, &kd, &thresholds, true, &alarms, &events).await?;
+        assert!(rx.try_recv().is_err());
+
+        // Nor does skipping the dead ratio clear it
+        let thresholds = Thresholds {
+            max_keys: Some(2),
+            ..thresholds
+        };
+        check(&m, &kd, &thresholds, false, &alarms, &events).await?;
+
+        let expected = vec![Alarm::DeadRatio];
+        let got = alarms.raised();
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        let got = rx.try_recv().ok();
+        assert!(
+            matches!(
+                got,
+                Some(Event::Alarm {
+                    alarm: Alarm::Keys,
+                    raised: false,
+                    ..
+                })
+            ),
+            "Got: {:?}",
+            got
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::{fs::File, io::AsyncWriteExt};
+
+use crate::storagev2::{
+    disk::Disk,
+    hint::{self, HintIndex},
+    key_dir::{BootstrapReport, KeyDir},
+    mmap::MappedFile,
+    page::{Page, PageID, PageInner, PageTrailer, PAGE_SIZE},
+};
+
+/// Identifies a checkpoint file and its layout.
+const MAGIC: &[u8; 4] = b"HDC2";
+
+/// Extension added to the data file's name for its checkpoint.
+pub const CHECKPOINT_EXTENSION: &str = "checkpoint";
+
+/// What a graceful shutdown leaves for the next startup: the `KeyDir`, where the data file ended
+/// and the pages that were cached. It's only valid for the data file exactly as it was left.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    /// Length of the data file in pages.
+    pub pages: u32,
+    /// Checksum of the last page, which every write after the checkpoint goes into.
+    pub last_page_crc: u32,
+    /// Pages that were cached, to read back into the cache on startup.
+    pub hot: Vec<PageID>,
+    pub kd: KeyDir,
+}
+
+impl Checkpoint {
+    /// Encoded as: magic | pages u32 | last_page_crc u32 | hot_len u32 | [page_id u32] |
+    /// keydir_len u64 | keydir | crc u32, with the crc over everything before it. The `KeyDir` is
+    /// a hint, see `hint::encode`.
+    pub fn encode(&self) -> BytesMut {
+        let kd = hint::encode(&self.kd);
+
+        let mut dst = BytesMut::new();
+        dst.put_slice(MAGIC);
+        dst.put_u32(self.pages);
+        dst.put_u32(self.last_page_crc);
+        dst.put_u32(self.hot.len() as u32);
+        for page_id in &self.hot {
+            dst.put_u32(*page_id);
+        }
+        dst.put_u64(kd.len() as u64);
+        dst.put_slice(&kd);
+
+        let crc = crc32fast::hash(&dst);
+        dst.put_u32(crc);
+
+        dst
+    }
+
+    pub fn decode(src: &[u8]) -> io::Result<Self> {
+        if src.len() < MAGIC.len() + 4 || &src[..MAGIC.len()] != MAGIC {
+            return Err(corrupt("bad magic"));
+        }
+        let (body, mut crc) = src.split_at(src.len() - 4);
+        if crc32fast::hash(body) != crc.get_u32() {
+            return Err(corrupt("checksum mismatch"));
+        }
+
+        let mut src = &body[MAGIC.len()..];
+        let pages = take(&mut src, 4)?.get_u32();
+        let last_page_crc = take(&mut src, 4)?.get_u32();
+        let hot_len = take(&mut src, 4)?.get_u32() as usize;
+        let hot = (0..hot_len)
+            .map(|_| Ok(take(&mut src, 4)?.get_u32()))
+            .collect::<io::Result<_>>()?;
+        let kd_len = take(&mut src, 8)?.get_u64() as usize;
+        let kd = HintIndex::new(take(&mut src, kd_len)?)?.to_key_dir()?;
+        if !src.is_empty() {
+            return Err(corrupt("trailing bytes"));
+        }
+
+        Ok(Self {
+            pages,
+            last_page_crc,
+            hot,
+            kd,
+        })
+    }
+
+    /// Whether the data file is still the one the checkpoint was taken of.
+    pub async fn matches(&self, disk: &Disk) -> io::Result<bool> {
+        let len = disk.len().await?;
+        if len != self.pages as usize * PAGE_SIZE {
+            return Ok(false);
+        }
+
+        Ok(last_page_crc(disk, self.pages).await? == self.last_page_crc)
+    }
+}
+
+/// Checksum of the last of `pages` pages on disk, 0 for an empty file.
+pub async fn last_page_crc(disk: &Disk, pages: u32) -> io::Result<u32> {
+    match pages.checked_sub(1) {
+        Some(last) => Ok(crc32fast::hash(&disk.read_page(last).await?)),
+        None => Ok(0),
+    }
+}
+
+/// Where the checkpoint for the data file `file` lives.
+pub fn path(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".");
+    path.push(CHECKPOINT_EXTENSION);
+
+    PathBuf::from(path)
+}
+
+/// Writes `checkpoint` to `path` atomically, so a crash midway leaves no checkpoint rather than a
+/// torn one.
+pub async fn write(path: &Path, checkpoint: &Checkpoint) -> io::Result<()> {
+    crate::fail_point!("checkpoint::write");
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    let mut w = File::create(&tmp).await?;
+    w.write_all(&checkpoint.encode()).await?;
+    w.sync_all().await?;
+    crate::fail_point!("checkpoint::rename");
+    tokio::fs::rename(&tmp, path).await
+}
+
+/// Takes the checkpoint at `path` if it's intact and was taken of `disk` as it is now. The file is
+/// removed either way, a checkpoint is only good for the startup right after it was written. It's
+/// memory mapped rather than read in, so it's never held in memory next to the `KeyDir` built from
+/// it.
+pub async fn take_valid(path: &Path, disk: &Disk) -> Option<Checkpoint> {
+    let data = match MappedFile::open(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            eprintln!(
+                "error: could not read checkpoint {} - {}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    };
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        eprintln!(
+            "error: could not remove checkpoint {} - {}",
+            path.display(),
+            e
+        );
+    }
+
+    let checkpoint = match Checkpoint::decode(data.bytes()) {
+        Ok(checkpoint) => checkpoint,
+        Err(e) => {
+            eprintln!("error: {}, falling back to a full bootstrap", e);
+            return None;
+        }
+    };
+    match checkpoint.matches(disk).await {
+        Ok(true) => Some(checkpoint),
+        Ok(false) => {
+            eprintln!("error: stale checkpoint, falling back to a full bootstrap");
+            None
+        }
+        Err(e) => {
+            eprintln!("error: {}, falling back to a full bootstrap", e);
+            None
+        }
+    }
+}
+
+/// Picks up from `checkpoint` instead of scanning the data file, returning what
+/// `bootstrap_with_report` would have: the last page is the one to carry on writing to.
+pub async fn restore(
+    disk: &Disk,
+    checkpoint: Checkpoint,
+) -> io::Result<(KeyDir, Page, PageID, BootstrapReport)> {
+    let start = Instant::now();
+
+    let latest_id = checkpoint.pages.saturating_sub(1);
+    let page = Page::default();
+    if checkpoint.pages > 0 {
+        let data = disk.read_page(latest_id).await?;
+        // The last page was written last, so later writes carry on from its LSN
+        if let Ok(Some(trailer)) = PageTrailer::check(&data) {
+            disk.observe_lsn(trailer.lsn);
+        }
+        *page.write().await = PageInner::from_bytes(latest_id, data);
+    }
+
+    let report = BootstrapReport {
+        pages: checkpoint.pages as usize,
+        keys: checkpoint.kd.len(),
+        checkpoint: true,
+        duration: start.elapsed(),
+        ..Default::default()
+    };
+
+    Ok((checkpoint.kd, page, latest_id, report))
+}
+
+fn take<'a>(src: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if src.len() < n {
+        return Err(corrupt("truncated"));
+    }
+
+    let (b, rest) = src.split_at(n);
+    *src = rest;
+
+    Ok(b)
+}
+
+fn corrupt(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt checkpoint: {}", reason),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, path::Path};
+
+    use crate::storagev2::{
+        checkpoint::{self, last_page_crc, Checkpoint},
+        disk::Disk,
+        key_dir::bootstrap,
+        page::PAGE_SIZE,
+        testing::Fixture,
+    };
+
+    #[tokio::test]
+    async fn test_checkpoint() -> io::Result<()> {
+        const DB_FILE: &str = "./test_checkpoint.db";
+        const CHECKPOINT_FILE: &str = "./test_checkpoint.db.checkpoint";
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .put(b"key1", b"value1")
+            .put(b"key2", b"value2")
+            .build()
+            .await?;
+        let (kd, _, _) = bootstrap(&disk).await?;
+
+        let pages = (disk.len().await? / PAGE_SIZE) as u32;
+        let expected = Checkpoint {
+            pages,
+            last_page_crc: last_page_crc(&disk, pages).await?,
+            hot: vec![0],
+            kd,
+        };
+        let path = checkpoint::path(Path::new(DB_FILE));
+        assert!(path == Path::new(CHECKPOINT_FILE), "Got: {:?}", path);
+
+        let encoded = expected.encode();
+        let got = Checkpoint::decode(&encoded)?;
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        let mut corrupt = encoded.clone();
+        corrupt[6] ^= 1;
+        assert!(Checkpoint::decode(&corrupt).is_err());
+        assert!(Checkpoint::decode(&encoded[..encoded.len() - 1]).is_err());
+
+        // Taken once, and only while it matches the data file
+        checkpoint::write(&path, &expected).await?;
+        let got = checkpoint::take_valid(&path, &disk).await;
+        assert!(got.as_ref() == Some(&expected), "Got: {:?}", got);
+        assert!(checkpoint::take_valid(&path, &disk).await.is_none());
+
+        checkpoint::write(&path, &expected).await?;
+        let other = Disk::new(DB_FILE).await?;
+        other.write_page(pages, &[1; PAGE_SIZE]).await?;
+        assert!(checkpoint::take_valid(&path, &other).await.is_none());
+        assert!(!path.exists(), "stale checkpoint should be removed");
+
+        Ok(())
+    }
+}
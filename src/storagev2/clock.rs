@@ -0,0 +1,55 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering::*},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::storagev2::log;
+
+/// Where a database reads the time from, in seconds since the epoch, for entry timestamps and
+/// expiry.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The wall clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        log::now()
+    }
+}
+
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// A clock that only moves when told to, so tests of time based features don't sleep.
+#[derive(Debug, Default)]
+pub struct TestClock(AtomicU64);
+
+impl TestClock {
+    pub fn new(now: u64) -> Arc<Self> {
+        Arc::new(Self(AtomicU64::new(now)))
+    }
+
+    pub fn set(&self, now: u64) {
+        self.0.store(now, SeqCst);
+    }
+
+    pub fn advance(&self, d: Duration) {
+        self.0.fetch_add(d.as_secs(), SeqCst);
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> u64 {
+        self.0.load(SeqCst)
+    }
+}
@@ -0,0 +1,577 @@
+use std::{collections::HashMap, io, sync::Arc, time::Duration};
+
+use tokio::sync::{watch, RwLock};
+
+use crate::storagev2::{
+    key_dir::{KeyData, KeyDir},
+    log::{Entry, EntryType},
+    page::{PageCodec, PageID, PageInner, PAGE_CAPACITY},
+    page_manager::PageCache,
+};
+
+pub const COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Fraction of a page that has to be dead before it is worth rewriting.
+pub const MIN_DEAD_RATIO: f64 = 0.5;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CompactionStats {
+    pub pages_scanned: usize,
+    pub pages_reclaimed: usize,
+    pub entries_moved: usize,
+    pub bytes_reclaimed: usize,
+    pub pages_compressed: usize,
+    pub segments_deleted: usize,
+}
+
+/// What a compaction would do right now, without rewriting anything.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CompactionEstimate {
+    pub pages_scanned: usize,
+    pub pages_reclaimable: usize,
+    pub live_bytes: usize,
+    pub dead_bytes: usize,
+    pub bytes_reclaimable: usize,
+}
+
+/// Rewrites the live entries of mostly dead pages into the current page and reclaims them.
+///
+/// A tombstone can only be dropped if nothing older could still resurrect its key on bootstrap:
+/// either the key has been written again since, or every older page has been reclaimed.
+/// Otherwise it is carried forward with the live entries.
+///
+/// With a codec set, cold pages that are kept are rewritten compressed, see `PageCache::compress_page`.
+///
+/// Segments every page of which has been reclaimed are deleted, see `segment`.
+pub struct Compactor {
+    m: PageCache,
+    kd: Arc<RwLock<KeyDir>>,
+    // Every page below this one has been reclaimed
+    low: PageID,
+    codec: Option<PageCodec>,
+}
+
+impl Compactor {
+    pub fn new(m: PageCache, kd: Arc<RwLock<KeyDir>>) -> Self {
+        Self {
+            m,
+            kd,
+            low: 0,
+            codec: None,
+        }
+    }
+
+    pub fn with_page_codec(mut self, codec: Option<PageCodec>) -> Self {
+        self.codec = codec;
+
+        self
+    }
+
+    /// Compacts every `interval`, which can be changed while it runs. A change restarts the wait.
+    pub async fn run(mut self, mut interval: watch::Receiver<Duration>) {
+        loop {
+            let period = *interval.borrow_and_update();
+            tokio::select! {
+                _ = tokio::time::sleep(period) => {}
+                Ok(()) = interval.changed() => continue,
+            }
+
+            match self.compact().await {
+                Ok(stats)
+                    if stats.pages_reclaimed > 0
+                        || stats.pages_compressed > 0
+                        || stats.segments_deleted > 0 =>
+                {
+                    eprintln!("compaction: {:?}", stats)
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("error: compaction failed: {}", e),
+            }
+        }
+    }
+
+    pub async fn compact(&mut self) -> io::Result<CompactionStats> {
+        let mut stats = CompactionStats::default();
+
+        // Pages kept for read transactions that have since ended
+        let reclaiming = self.m.reclaiming().await;
+        self.m.reclaim_deferred().await?;
+        drop(reclaiming);
+
+        // Pages in deleted segments were all reclaimed
+        self.low = self.low.max(self.m.first_page());
+        let current_id = self.m.get_current().await.id;
+        for page_id in self.low..current_id {
+            // Already reclaimed, and only kept on disk for a read transaction
+            if self.m.is_deferred(page_id) {
+                continue;
+            }
+
+            let page = self.m.read_page(page_id).await?;
+            stats.pages_scanned += 1;
+
+            let (total, dead) = self.dead_bytes(&page).await;
+            if total == 0 {
+                if page_id == self.low {
+                    self.low += 1;
+                }
+                continue;
+            }
+
+            if (dead as f64 / total as f64) < MIN_DEAD_RATIO {
+                if let Some(codec) = self.codec {
+                    if self.m.compress_page(page_id, codec).await? {
+                        stats.pages_compressed += 1;
+                    }
+                }
+                continue;
+            }
+
+            stats.entries_moved += self.rewrite(&page).await?;
+
+            // Moved entries have to be on disk before the only other copy is gone
+            crate::fail_point!("compaction::flush");
+            self.m.flush_current().await?;
+            self.m.sync().await?;
+            let reclaiming = self.m.reclaiming().await;
+            crate::fail_point!("compaction::reclaim");
+            self.m.reclaim_page(page_id).await?;
+            drop(reclaiming);
+
+            stats.pages_reclaimed += 1;
+            stats.bytes_reclaimed += dead;
+            // Tombstones can't be dropped until a deferred page is actually zeroed
+            if page_id == self.low && !self.m.is_deferred(page_id) {
+                self.low += 1;
+            }
+
+            tokio::task::yield_now().await;
+        }
+        stats.segments_deleted = self.m.drop_segments(self.low).await?;
+        self.m.compacted(stats.pages_reclaimed);
+
+        Ok(stats)
+    }
+
+    /// Scans the pages a compaction would, without rewriting or reclaiming any of them.
+    pub async fn estimate(&self) -> io::Result<CompactionEstimate> {
+        let mut estimate = CompactionEstimate::default();
+
+        let current_id = self.m.get_current().await.id;
+        for page_id in self.low..current_id {
+            if self.m.is_deferred(page_id) {
+                continue;
+            }
+
+            let page = self.m.read_page(page_id).await?;
+            estimate.pages_scanned += 1;
+
+            let (total, dead) = self.dead_bytes(&page).await;
+            estimate.live_bytes += total - dead;
+            estimate.dead_bytes += dead;
+
+            if total > 0 && (dead as f64 / total as f64) >= MIN_DEAD_RATIO {
+                estimate.pages_reclaimable += 1;
+                estimate.bytes_reclaimable += dead;
+            }
+
+            tokio::task::yield_now().await;
+        }
+
+        Ok(estimate)
+    }
+
+    /// Returns the total and dead bytes in `page`, an estimate as writers may still be running.
+    async fn dead_bytes(&self, page: &PageInner) -> (usize, usize) {
+        let now = self.m.now();
+        let kd = self.kd.read().await;
+
+        let (mut total, mut dead) = (0, 0);
+        for (offset, entry) in entries(page) {
+            total += entry.len();
+            if !self.keep(&kd, page.id, offset, &entry, now) {
+                dead += entry.len();
+            }
+        }
+
+        (total, dead)
+    }
+
+    /// Moves the live entries of `page` to the current page. The entries of a locality group are
+    /// moved together, onto a fresh page if they would otherwise straddle two.
+    async fn rewrite(&self, page: &PageInner) -> io::Result<usize> {
+        let now = self.m.now();
+
+        let mut moved = 0;
+        for run in runs(entries(page)) {
+            // Writers take the current page before the KeyDir, so holding it keeps the KeyDir
+            // stable for this run
+            let mut current = self.m.get_current().await;
+            let run: Vec<_> = {
+                let kd = self.kd.read().await;
+                run.into_iter()
+                    .filter(|(offset, entry)| self.keep(&kd, page.id, *offset, entry, now))
+                    .collect()
+            };
+
+            let len: usize = run.iter().map(|(_, entry)| entry.len()).sum();
+            if run.len() > 1 && len > current.remaining() && len <= PAGE_CAPACITY {
+                self.m.replace_current(&mut current).await?;
+            }
+
+            for (_, entry) in run {
+                let entry = Entry {
+                    checksum: true,
+                    ..entry
+                };
+                let new_offset = self.m.write_entry(&mut current, &entry).await?;
+
+                if entry.t == EntryType::Put {
+                    let data = KeyData::new(current.id, new_offset)
+                        .with_expiry(entry.expires)
+                        .with_owner(entry.owner)
+                        .with_fence(entry.fence)
+                        .with_len(entry.len());
+                    self.kd.write().await.insert(&entry.key, data);
+                }
+
+                moved += 1;
+            }
+        }
+
+        Ok(moved)
+    }
+
+    fn keep(&self, kd: &KeyDir, page_id: PageID, offset: u64, entry: &Entry, now: u64) -> bool {
+        match entry.t {
+            EntryType::Put => {
+                !entry.is_expired(now)
+                    && kd
+                        .get(&entry.key)
+                        .is_some_and(|d| d.page_id == page_id && d.offset == offset)
+            }
+            EntryType::Delete => kd.get(&entry.key).is_none() && page_id != self.low,
+        }
+    }
+}
+
+fn entries(page: &PageInner) -> Vec<(u64, Entry)> {
+    let mut ret = Vec::new();
+
+    let mut offset = 0;
+    while let Ok(Some(entry)) = page.read_entry(offset) {
+        let len = entry.len();
+        ret.push((offset as u64, entry));
+        offset += len;
+    }
+
+    ret
+}
+
+/// Splits a page's entries into runs that are moved together: all the entries of a locality
+/// group, placed where its first entry was, and every other entry on its own. Entries keep their
+/// order within a run. Reordering runs is safe as only one entry per key is ever kept.
+fn runs(entries: Vec<(u64, Entry)>) -> Vec<Vec<(u64, Entry)>> {
+    let mut runs: Vec<Vec<_>> = Vec::new();
+    let mut groups: HashMap<u32, usize> = HashMap::new();
+    for (offset, entry) in entries {
+        match entry.group {
+            Some(group) => match groups.get(&group) {
+                Some(&i) => runs[i].push((offset, entry)),
+                None => {
+                    groups.insert(group, runs.len());
+                    runs.push(vec![(offset, entry)]);
+                }
+            },
+            None => runs.push(vec![(offset, entry)]),
+        }
+    }
+
+    runs
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, sync::Arc};
+
+    use tokio::sync::RwLock;
+
+    use crate::storagev2::{
+        compaction::Compactor,
+        disk::Disk,
+        key_dir::{bootstrap, KeyDir},
+        log::{self, Entry, EntryType},
+        page::{self, PageCodec},
+        page_manager::{PageCache, DEFAULT_READ_SIZE},
+        replacer::Policy,
+        testing::Fixture,
+    };
+
+    async fn get(m: &PageCache, kd: &KeyDir, k: &[u8]) -> Option<Vec<u8>> {
+        let data = kd.get(k)?;
+        let entry = m.fetch_entry(data.page_id, data.offset).await.unwrap()?;
+
+        Some(entry.value.to_vec())
+    }
+
+    async fn reopen(file: &str) -> io::Result<(PageCache, KeyDir)> {
+        let disk = Disk::new(file).await?;
+        let (kd, latest, latest_id) = bootstrap(&disk).await?;
+
+        Ok((
+            PageCache::new(
+                disk,
+                Policy::default(),
+                DEFAULT_READ_SIZE,
+                latest,
+                latest_id,
+            ),
+            kd,
+        ))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compact() -> io::Result<()> {
+        const DB_FILE: &str = "./test_compact.db";
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .put(b"key1", b"value1")
+            .put(b"key2", b"value2")
+            .put(b"key3", b"value3")
+            .put(b"key4", b"value4")
+            .put(b"key5", b"value5")
+            .put(b"key6", b"value6")
+            .next_page()
+            .put(b"key1", b"newva1")
+            .put(b"key2", b"newva2")
+            .put(b"key3", b"newva3")
+            .put(b"key4", b"newva4")
+            .delete(b"key5")
+            .next_page()
+            .put(b"key7", b"value7")
+            .build()
+            .await?;
+
+        let (kd, latest, latest_id) = bootstrap(&disk).await?;
+        let kd = Arc::new(RwLock::new(kd));
+        let m = PageCache::new(
+            disk,
+            Policy::default(),
+            DEFAULT_READ_SIZE,
+            latest,
+            latest_id,
+        );
+
+        let mut compactor = Compactor::new(m.clone(), kd.clone());
+        let estimate = compactor.estimate().await?;
+        let stats = compactor.compact().await?;
+        assert!(stats.pages_reclaimed == 1, "Got: {:?}", stats);
+        assert!(stats.entries_moved == 1, "Got: {:?}", stats);
+        assert!(
+            estimate.pages_reclaimable == stats.pages_reclaimed
+                && estimate.bytes_reclaimable == stats.bytes_reclaimed,
+            "\nEstimate: {:?}\nStats: {:?}\n",
+            estimate,
+            stats
+        );
+
+        {
+            let kd = kd.read().await;
+            let key6 = kd.get(b"key6").unwrap();
+            assert!(key6.page_id == 2, "Got: {:?}", key6);
+            assert!(get(&m, &kd, b"key6").await.as_deref() == Some(&b"value6"[..]));
+            assert!(get(&m, &kd, b"key1").await.as_deref() == Some(&b"newva1"[..]));
+        }
+
+        assert!(m.read_page(0).await?.read_entry(0) == Ok(None));
+        m.flush_current().await?;
+
+        let (m, kd) = reopen(DB_FILE).await?;
+        let expected: [(&[u8], Option<&[u8]>); 7] = [
+            (b"key1", Some(b"newva1")),
+            (b"key2", Some(b"newva2")),
+            (b"key3", Some(b"newva3")),
+            (b"key4", Some(b"newva4")),
+            (b"key5", None),
+            (b"key6", Some(b"value6")),
+            (b"key7", Some(b"value7")),
+        ];
+        for (k, v) in expected {
+            let got = get(&m, &kd, k).await;
+            assert!(got.as_deref() == v, "\nExpected: {:?}\nGot: {:?}\n", v, got);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compact_keeps_tombstone() -> io::Result<()> {
+        const DB_FILE: &str = "./test_compact_keeps_tombstone.db";
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .put(b"key1", b"value1")
+            .put(b"key2", b"value2")
+            .put(b"key3", b"value3")
+            .put(b"key4", b"value4")
+            .put(b"key5", b"value5")
+            .put(b"key6", b"value6")
+            .next_page()
+            .put(b"tmp1", b"value1")
+            .put(b"tmp2", b"value2")
+            .put(b"tmp3", b"value3")
+            .put(b"tmp4", b"value4")
+            .delete(b"key6")
+            .next_page()
+            .put(b"tmp1", b"newva1")
+            .put(b"tmp2", b"newva2")
+            .put(b"tmp3", b"newva3")
+            .put(b"tmp4", b"newva4")
+            .build()
+            .await?;
+
+        let (kd, latest, latest_id) = bootstrap(&disk).await?;
+        let kd = Arc::new(RwLock::new(kd));
+        let m = PageCache::new(
+            disk,
+            Policy::default(),
+            DEFAULT_READ_SIZE,
+            latest,
+            latest_id,
+        );
+
+        // Page 0 is mostly live so it stays, which means the key6 tombstone on page 1 has to
+        // survive page 1 being reclaimed
+        let stats = Compactor::new(m.clone(), kd.clone()).compact().await?;
+        assert!(stats.pages_reclaimed == 1, "Got: {:?}", stats);
+        assert!(m.read_page(1).await?.read_entry(0) == Ok(None));
+        m.flush_current().await?;
+
+        let (m, kd) = reopen(DB_FILE).await?;
+        assert!(get(&m, &kd, b"key6").await.is_none());
+        assert!(get(&m, &kd, b"key5").await.as_deref() == Some(&b"value5"[..]));
+        assert!(get(&m, &kd, b"tmp1").await.as_deref() == Some(&b"newva1"[..]));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compact_keeps_groups() -> io::Result<()> {
+        const DB_FILE: &str = "./test_compact_keeps_groups.db";
+        let (g1, g2) = (log::group_id(b"g1"), log::group_id(b"g2"));
+        let grouped = |k: &[u8], group| Entry::new(k, b"v", EntryType::Put).with_group(Some(group));
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .entry(grouped(b"a1", g1))
+            .put(b"x", &[b'x'; 30])
+            .entry(grouped(b"b1", g2))
+            .put(b"y", &[b'y'; 30])
+            .entry(grouped(b"a2", g1))
+            .next_page()
+            .put(b"x", b"x")
+            .put(b"y", b"y")
+            .build()
+            .await?;
+
+        let (kd, latest, latest_id) = bootstrap(&disk).await?;
+        let kd = Arc::new(RwLock::new(kd));
+        let m = PageCache::new(
+            disk,
+            Policy::default(),
+            DEFAULT_READ_SIZE,
+            latest,
+            latest_id,
+        );
+
+        let stats = Compactor::new(m.clone(), kd.clone()).compact().await?;
+        assert!(stats.entries_moved == 3, "Got: {:?}", stats);
+
+        // Group g1 is moved together even though b1 was between its entries
+        let kd = kd.read().await;
+        let (a1, a2, b1) = (
+            kd.get(b"a1").unwrap(),
+            kd.get(b"a2").unwrap(),
+            kd.get(b"b1").unwrap(),
+        );
+        let a1_len = grouped(b"a1", g1).len() as u64;
+        assert!(
+            a1.page_id == a2.page_id && a2.offset == a1.offset + a1_len && b1.offset > a2.offset,
+            "\na1: {:?}\na2: {:?}\nb1: {:?}\n",
+            a1,
+            a2,
+            b1
+        );
+
+        let entry = m.fetch_entry(a2.page_id, a2.offset).await?.unwrap();
+        assert!(entry.group == Some(g1), "Got: {:?}", entry);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compact_compresses_cold_pages() -> io::Result<()> {
+        const DB_FILE: &str = "./test_compact_compresses_cold_pages.db";
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .put(b"key1", b"value1")
+            .put(b"key2", b"value2")
+            .next_page()
+            .put(b"key3", b"value3")
+            .next_page()
+            .put(b"key4", b"value4")
+            .build()
+            .await?;
+
+        let (kd, latest, latest_id) = bootstrap(&disk).await?;
+        let kd = Arc::new(RwLock::new(kd));
+        let m = PageCache::new(
+            disk,
+            Policy::default(),
+            DEFAULT_READ_SIZE,
+            latest,
+            latest_id,
+        );
+
+        // Page 1 is hot, so only page 0 is compressed
+        assert!(m.fetch_page(1).await?.is_some());
+        let mut compactor =
+            Compactor::new(m.clone(), kd.clone()).with_page_codec(Some(PageCodec::Lz4));
+        let stats = compactor.compact().await?;
+        assert!(stats.pages_compressed == 1, "Got: {:?}", stats);
+        assert!(stats.pages_reclaimed == 0, "Got: {:?}", stats);
+
+        let stats = compactor.compact().await?;
+        assert!(stats.pages_compressed == 0, "Got: {:?}", stats);
+
+        let disk = Disk::new(DB_FILE).await?;
+        assert!(page::is_compressed(&disk.read_stored_page(0).await?));
+        assert!(!page::is_compressed(&disk.read_stored_page(1).await?));
+
+        {
+            let kd = kd.read().await;
+            assert!(get(&m, &kd, b"key1").await.as_deref() == Some(&b"value1"[..]));
+            assert!(get(&m, &kd, b"key3").await.as_deref() == Some(&b"value3"[..]));
+        }
+        m.flush_current().await?;
+
+        let (m, kd) = reopen(DB_FILE).await?;
+        let expected: [(&[u8], &[u8]); 4] = [
+            (b"key1", b"value1"),
+            (b"key2", b"value2"),
+            (b"key3", b"value3"),
+            (b"key4", b"value4"),
+        ];
+        for (k, v) in expected {
+            let got = get(&m, &kd, k).await;
+            assert!(
+                got.as_deref() == Some(v),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                v,
+                got
+            );
+        }
+
+        // A damaged compressed page is an error rather than garbage entries
+        let mut stored = disk.read_stored_page(0).await?;
+        stored[1] = 0xff;
+        disk.write_page(0, &stored).await?;
+        assert!(disk.read_page(0).await.is_err());
+
+        Ok(())
+    }
+}
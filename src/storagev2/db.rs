@@ -0,0 +1,1826 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::{broadcast, mpsc, watch, RwLock, SemaphorePermit},
+};
+
+use crate::storagev2::{
+    alarm::{self, Alarm, Alarms, Thresholds},
+    checkpoint::{self, Checkpoint},
+    clock::{self, SharedClock},
+    compaction::{self, Compactor},
+    disk::{Backend, Disk, Durability},
+    dump::{self, Format, Record},
+    error::StorageError,
+    events::{self, Change, Changes, Event, Events},
+    expiry,
+    key_dir::{self, BootstrapReport, KeyData, KeyDir},
+    limit::{self, Limit},
+    log::{Entry, EntryType},
+    page::{PageCodec, PageID, PageInner, PAGE_CAPACITY, PAGE_SIZE},
+    page_manager::{self, EpochPin, PageCache},
+    pressure::{self, Pressure},
+    replacer::Policy,
+    segment,
+    sstable::SsTableWriter,
+    validate::Validators,
+    value_cache::{ValueCache, ValueCacheStats},
+};
+
+/// Writes queued for the sink. Writers wait once the sink falls this far behind.
+pub const SINK_QUEUE_SIZE: usize = 65536;
+
+/// Most writes handed to the sink at once.
+pub const SINK_BATCH_SIZE: usize = 256;
+
+/// Backoff between attempts to hand a batch to the sink, doubling up to the max.
+const SINK_RETRY_MIN: Duration = Duration::from_millis(100);
+const SINK_RETRY_MAX: Duration = Duration::from_secs(10);
+
+/// Tombstones queued for the background writer by `unlink_later`. Unlinking waits once it falls
+/// this far behind.
+pub const UNLINK_QUEUE_SIZE: usize = 65536;
+
+/// Most tombstones written between commits.
+pub const UNLINK_BATCH_SIZE: usize = 256;
+
+/// Pages a bulk load keeps open at once. An entry that doesn't fit the newest page can still go
+/// into the tail of an older one, so small entries fill the space a large one left behind.
+pub const BULK_LOAD_OPEN_PAGES: usize = 4;
+
+/// Entries a size class packing bulk load buffers, by size, before placing them. The larger the
+/// window the better the fit, at the cost of holding more pages in memory.
+pub const PACKING_WINDOW: usize = 16 * PAGE_SIZE;
+
+/// Entries are grouped by size in classes of `PAGE_SIZE / SIZE_CLASSES` bytes.
+pub const SIZE_CLASSES: usize = 16;
+
+/// How a bulk load places entries into pages.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Packing {
+    /// Each entry goes into the first open page it fits, in the order it was given.
+    FirstFit,
+    /// Entries are buffered, see `PACKING_WINDOW`, and placed largest size class first, so the
+    /// space large entries leave at the end of pages is filled by small ones.
+    #[default]
+    SizeClass,
+}
+
+/// Files a snapshot directory holds: a copy of the data file and the serialized `KeyDir`.
+pub const SNAPSHOT_DATA_FILE: &str = "data.db";
+pub const SNAPSHOT_KEYDIR_FILE: &str = "keydir";
+
+/// Pages copied into a snapshot between yields back to the executor.
+const SNAPSHOT_YIELD_EVERY: u32 = 64;
+
+/// What a snapshot copied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SnapshotStats {
+    pub pages: u32,
+    pub keys: usize,
+}
+
+/// Settings for opening a `Db`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    pub durability: Durability,
+    pub backend: Backend,
+    /// Pages that aren't cached are read straight from a mapping of the data file, leaving the
+    /// page cache to hot pages.
+    pub mmap_reads: bool,
+    /// Pages in each segment file of a new database, see `segment`.
+    pub segment_pages: PageID,
+    /// Number of pages cached besides the current one.
+    pub page_cache_size: usize,
+    /// Pages read into the cache after one that missed, for sequential reads, see
+    /// `PageCache::with_read_ahead`. 0 turns it off.
+    pub read_ahead_pages: usize,
+    /// How the page cache picks a page to evict.
+    pub replacer: Policy,
+    pub compaction_interval: Duration,
+    pub packing: Packing,
+    /// GETs that can fetch pages from disk at once, so a burst of reads can't churn the whole
+    /// page cache. Reads served from cached pages don't count.
+    pub max_fetches: usize,
+    /// Inserts that can be in progress at once, independently of reads.
+    pub max_inserts: usize,
+    /// Values longer than this many bytes are stored compressed, see `Entry::with_compression`.
+    pub compression_threshold: Option<usize>,
+    /// Pages that are kept by compaction and aren't cached are rewritten compressed with this
+    /// codec, trading CPU on reads for disk footprint.
+    pub page_codec: Option<PageCodec>,
+    /// Keep the keys in order as well, for range queries and scans that don't visit every key,
+    /// see `KeyDir::with_ordered_index`.
+    pub ordered_index: bool,
+    /// Sizes past which alarms are raised, see `alarm::check`.
+    pub thresholds: Thresholds,
+    /// Levels past which the page cache is shrunk, see `pressure::check`.
+    pub pressure: Pressure,
+    /// Keys whose entries are kept decoded for reads, see `ValueCache`. 0 turns it off.
+    pub value_cache_size: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            durability: Durability::default(),
+            backend: Backend::default(),
+            mmap_reads: false,
+            segment_pages: segment::DEFAULT_SEGMENT_PAGES,
+            page_cache_size: page_manager::DEFAULT_READ_SIZE,
+            read_ahead_pages: 0,
+            replacer: Policy::default(),
+            compaction_interval: compaction::COMPACTION_INTERVAL,
+            packing: Packing::default(),
+            max_fetches: limit::DEFAULT_MAX_FETCHES,
+            max_inserts: limit::DEFAULT_MAX_INSERTS,
+            compression_threshold: None,
+            page_codec: None,
+            ordered_index: false,
+            thresholds: Thresholds::default(),
+            pressure: Pressure::default(),
+            value_cache_size: 0,
+        }
+    }
+}
+
+/// Reads and writes waiting for their turn, see `Options::max_fetches` and
+/// `Options::max_inserts`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Queued {
+    pub fetches: usize,
+    pub inserts: usize,
+}
+
+/// A value fetched by a `Loader`, cached for `ttl` if set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loaded {
+    pub value: Vec<u8>,
+    pub ttl: Option<Duration>,
+}
+
+pub type LoadFuture = Pin<Box<dyn Future<Output = io::Result<Option<Loaded>>> + Send>>;
+
+/// Fetches a key missing from the database from another data source.
+pub type Loader = Arc<dyn Fn(&[u8]) -> LoadFuture + Send + Sync>;
+
+pub type SinkFuture = Pin<Box<dyn Future<Output = io::Result<()>> + Send>>;
+
+/// Receives batches of writes, puts and deletes in the order they were applied, after they are
+/// durable locally. A batch that fails is retried until it succeeds.
+pub type Sink = Arc<dyn Fn(Arc<[Entry]>) -> SinkFuture + Send + Sync>;
+
+/// Called with the data file's path before it's read, e.g. to fetch it from a backup. An error
+/// fails the open.
+pub type BeforeBootstrap = Arc<dyn Fn(&Path) -> io::Result<()> + Send + Sync>;
+
+/// Called with what bootstrapping found, before the background tasks start.
+pub type AfterBootstrap = Arc<dyn Fn(&BootstrapReport) + Send + Sync>;
+
+/// Called by `Db::shutdown` before the checkpoint is written, e.g. to stop feeding writes.
+pub type BeforeShutdown = Arc<dyn Fn() + Send + Sync>;
+
+/// Lifecycle hooks for embedders, see `DbBuilder`.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    pub before_bootstrap: Option<BeforeBootstrap>,
+    pub after_bootstrap: Option<AfterBootstrap>,
+    pub before_shutdown: Option<BeforeShutdown>,
+}
+
+/// Configures and opens a `Db` in one place, see `Options` for what each setting does. The page
+/// size is fixed when the crate is built, see `PAGE_SIZE`.
+pub struct DbBuilder {
+    path: PathBuf,
+    options: Options,
+    clock: SharedClock,
+    hooks: Hooks,
+    loader: Option<Loader>,
+    sink: Option<Sink>,
+}
+
+impl DbBuilder {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            options: Options::default(),
+            clock: clock::system(),
+            hooks: Hooks::default(),
+            loader: None,
+            sink: None,
+        }
+    }
+
+    /// Replaces every setting at once, e.g. with ones read from a config file.
+    pub fn with_options(mut self, options: Options) -> Self {
+        self.options = options;
+
+        self
+    }
+
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.options.durability = durability;
+
+        self
+    }
+
+    pub fn with_page_cache_size(mut self, size: usize) -> Self {
+        self.options.page_cache_size = size;
+
+        self
+    }
+
+    pub fn with_read_ahead(mut self, pages: usize) -> Self {
+        self.options.read_ahead_pages = pages;
+
+        self
+    }
+
+    pub fn with_replacer(mut self, replacer: Policy) -> Self {
+        self.options.replacer = replacer;
+
+        self
+    }
+
+    pub fn with_compaction_interval(mut self, interval: Duration) -> Self {
+        self.options.compaction_interval = interval;
+
+        self
+    }
+
+    pub fn with_page_codec(mut self, codec: Option<PageCodec>) -> Self {
+        self.options.page_codec = codec;
+
+        self
+    }
+
+    pub fn with_ordered_index(mut self, ordered_index: bool) -> Self {
+        self.options.ordered_index = ordered_index;
+
+        self
+    }
+
+    pub fn with_value_cache_size(mut self, size: usize) -> Self {
+        self.options.value_cache_size = size;
+
+        self
+    }
+
+    pub fn with_segment_pages(mut self, segment_pages: PageID) -> Self {
+        self.options.segment_pages = segment_pages;
+
+        self
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+
+        self
+    }
+
+    pub fn with_loader(mut self, loader: Loader) -> Self {
+        self.loader = Some(loader);
+
+        self
+    }
+
+    pub fn with_sink(mut self, sink: Sink) -> Self {
+        self.sink = Some(sink);
+
+        self
+    }
+
+    pub fn before_bootstrap(
+        mut self,
+        f: impl Fn(&Path) -> io::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.before_bootstrap = Some(Arc::new(f));
+
+        self
+    }
+
+    pub fn after_bootstrap(mut self, f: impl Fn(&BootstrapReport) + Send + Sync + 'static) -> Self {
+        self.hooks.after_bootstrap = Some(Arc::new(f));
+
+        self
+    }
+
+    pub fn before_shutdown(mut self, f: impl Fn() + Send + Sync + 'static) -> Self {
+        self.hooks.before_shutdown = Some(Arc::new(f));
+
+        self
+    }
+
+    pub async fn open(self) -> io::Result<Db> {
+        let mut db = Db::open_with_hooks(self.path, self.options, self.clock, self.hooks).await?;
+        if let Some(loader) = self.loader {
+            db = db.with_loader(loader);
+        }
+        if let Some(sink) = self.sink {
+            db = db.with_sink(sink);
+        }
+
+        Ok(db)
+    }
+}
+
+/// A single database file with its own page cache, `KeyDir` and background tasks.
+#[derive(Clone)]
+pub struct Db {
+    pub pc: PageCache,
+    pub kd: Arc<RwLock<KeyDir>>,
+    loader: Option<Loader>,
+    sink: Option<mpsc::Sender<Entry>>,
+    packing: Packing,
+    events: Events,
+    changes: Changes,
+    alarms: Arc<Alarms>,
+    unlinked: mpsc::Sender<BytesMut>,
+    report: BootstrapReport,
+    fetches: Arc<Limit>,
+    inserts: Arc<Limit>,
+    validators: Arc<Validators>,
+    compression_threshold: Option<usize>,
+    compaction_interval: Arc<watch::Sender<Duration>>,
+    values: Option<Arc<ValueCache>>,
+    checkpoint: PathBuf,
+    /// Taken with the last checkpoint, so compaction can't zero pages its `KeyDir` points into.
+    checkpointed: Arc<StdMutex<Option<EpochPin>>>,
+    before_shutdown: Option<BeforeShutdown>,
+}
+
+impl Db {
+    pub async fn open(file: impl AsRef<Path>, options: Options) -> io::Result<Self> {
+        Self::open_with_clock(file, options, clock::system()).await
+    }
+
+    /// Opens the database reading the time from `clock`, see `Clock`.
+    pub async fn open_with_clock(
+        file: impl AsRef<Path>,
+        options: Options,
+        clock: SharedClock,
+    ) -> io::Result<Self> {
+        Self::open_with_hooks(file, options, clock, Hooks::default()).await
+    }
+
+    async fn open_with_hooks(
+        file: impl AsRef<Path>,
+        options: Options,
+        clock: SharedClock,
+        hooks: Hooks,
+    ) -> io::Result<Self> {
+        if let Some(f) = &hooks.before_bootstrap {
+            f(file.as_ref())?;
+        }
+
+        let checkpoint_file = checkpoint::path(file.as_ref());
+        let disk = Disk::new(file)
+            .await?
+            .with_segment_pages(options.segment_pages)?
+            .with_durability(options.durability)
+            .with_backend(options.backend)?
+            .with_mmap_reads(options.mmap_reads)?;
+        let (hot, (kd, latest, latest_id, report)) =
+            match checkpoint::take_valid(&checkpoint_file, &disk).await {
+                Some(checkpoint) => (
+                    checkpoint.hot.clone(),
+                    checkpoint::restore(&disk, checkpoint).await?,
+                ),
+                None => (
+                    Vec::new(),
+                    key_dir::bootstrap_with_report(&disk, clock.now()).await?,
+                ),
+            };
+        eprintln!("bootstrap: {:?}", report);
+        if let Some(f) = &hooks.after_bootstrap {
+            f(&report);
+        }
+        let kd = if options.ordered_index {
+            kd.with_ordered_index()
+        } else {
+            kd
+        };
+        let kd = Arc::new(RwLock::new(kd));
+
+        let pc = PageCache::new(
+            disk,
+            options.replacer,
+            options.page_cache_size,
+            latest,
+            latest_id,
+        )
+        .with_clock(clock)
+        .with_read_ahead(options.read_ahead_pages);
+        pc.warm(&hot).await?;
+
+        let events = events::channel();
+        tokio::spawn(expiry::run(pc.clone(), kd.clone(), events.clone()));
+        let (unlinked, rx) = mpsc::channel(UNLINK_QUEUE_SIZE);
+        tokio::spawn(run_unlinker(pc.clone(), kd.clone(), rx));
+        let (compaction_interval, rx) = watch::channel(options.compaction_interval);
+        tokio::spawn(
+            Compactor::new(pc.clone(), kd.clone())
+                .with_page_codec(options.page_codec)
+                .run(rx),
+        );
+        tokio::spawn(pc.clone().run_dirty_flusher());
+        if options.read_ahead_pages > 0 {
+            tokio::spawn(pc.clone().run_read_ahead());
+        }
+        let alarms = Arc::new(Alarms::default());
+        if !options.thresholds.is_empty() {
+            tokio::spawn(alarm::run(
+                pc.clone(),
+                kd.clone(),
+                options.thresholds,
+                alarms.clone(),
+                events.clone(),
+            ));
+        }
+        if !options.pressure.is_empty() {
+            tokio::spawn(pressure::run(pc.clone(), options.pressure));
+        }
+        // Both run regardless of the durability, it can be changed while open
+        tokio::spawn(pc.clone().run_committer());
+        tokio::spawn(pc.clone().run_flusher());
+
+        Ok(Self {
+            pc,
+            kd,
+            loader: None,
+            sink: None,
+            packing: options.packing,
+            events,
+            changes: events::changes(),
+            alarms,
+            unlinked,
+            report,
+            fetches: Arc::new(Limit::new(options.max_fetches)),
+            inserts: Arc::new(Limit::new(options.max_inserts)),
+            validators: Arc::new(Validators::default()),
+            compression_threshold: options.compression_threshold,
+            compaction_interval: Arc::new(compaction_interval),
+            values: (options.value_cache_size > 0)
+                .then(|| Arc::new(ValueCache::new(options.value_cache_size))),
+            checkpoint: checkpoint_file,
+            checkpointed: Arc::default(),
+            before_shutdown: hooks.before_shutdown,
+        })
+    }
+
+    /// What bootstrapping the data file found when the database was opened.
+    pub fn bootstrap_report(&self) -> BootstrapReport {
+        self.report
+    }
+
+    /// Changes how often the background compactor runs, see `Options::compaction_interval`.
+    pub fn set_compaction_interval(&self, interval: Duration) {
+        self.compaction_interval.send_replace(interval);
+    }
+
+    /// Changes when writes are fsynced from the next write on, see `Options::durability`.
+    pub fn set_durability(&self, durability: Durability) {
+        self.pc.set_durability(durability)
+    }
+
+    /// Lookups in the value cache, `None` if it's turned off, see `Options::value_cache_size`.
+    pub fn value_cache_stats(&self) -> Option<ValueCacheStats> {
+        self.values.as_ref().map(|values| values.stats())
+    }
+
+    /// Waits for a turn to insert, see `Options::max_inserts`. Take it before the current page.
+    pub async fn insert_permit(&self) -> SemaphorePermit<'_> {
+        self.inserts.acquire().await
+    }
+
+    /// The current time in seconds since the epoch, see `Clock`.
+    pub fn now(&self) -> u64 {
+        self.pc.now()
+    }
+
+    /// Creates an entry stamped with the database's clock, compressed if it's a put with a
+    /// value over the configured threshold.
+    pub fn entry(&self, k: &[u8], v: &[u8], t: EntryType) -> Entry {
+        self.compress(Entry::new(k, v, t).with_time(self.now()))
+    }
+
+    /// Compresses the entry's value if it's over the configured threshold. Call it on new entries
+    /// before writing them.
+    pub fn compress(&self, entry: Entry) -> Entry {
+        match self.compression_threshold {
+            Some(threshold) => entry.with_compression(threshold),
+            None => entry,
+        }
+    }
+
+    /// Rules values inserted through the server have to pass.
+    pub fn validators(&self) -> &Validators {
+        &self.validators
+    }
+
+    pub fn queued(&self) -> Queued {
+        Queued {
+            fetches: self.fetches.queued(),
+            inserts: self.inserts.queued(),
+        }
+    }
+
+    /// Alarms raised by the last check against `Options::thresholds`.
+    pub fn alarms(&self) -> Vec<Alarm> {
+        self.alarms.raised()
+    }
+
+    /// Receives keys expiring and alarms changing from now on, see `Event`.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Receives every key clients set or delete from now on, see `write_behind`.
+    pub fn watch(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
+    }
+
+    /// Makes `get` read through to `loader` on a miss, caching what it returns. Concurrent misses
+    /// on the same key each call the loader.
+    pub fn with_loader(mut self, loader: Loader) -> Self {
+        self.loader = Some(loader);
+
+        self
+    }
+
+    /// Hands every write passed to `write_behind` to `sink` in the background, so the database
+    /// can front a slower system.
+    pub fn with_sink(mut self, sink: Sink) -> Self {
+        let (tx, rx) = mpsc::channel(SINK_QUEUE_SIZE);
+        tokio::spawn(run_sink(sink, rx));
+        self.sink = Some(tx);
+
+        self
+    }
+
+    /// Queues a tombstone for `k`, which the caller has already removed from the `KeyDir`, to be
+    /// written in the background. Until it is written the key comes back after a restart.
+    pub async fn unlink_later(&self, k: &[u8]) {
+        if self.unlinked.send(BytesMut::from(k)).await.is_err() {
+            eprintln!("error: unlinker stopped");
+        }
+    }
+
+    /// Queues writes that were applied and committed for the sink, if there is one, and tells
+    /// watchers about them.
+    pub async fn write_behind(&self, entries: impl IntoIterator<Item = Entry>) {
+        let watched = self.changes.receiver_count() > 0;
+        let mut sink = self.sink.as_ref();
+        if sink.is_none() && !watched {
+            return;
+        }
+
+        for entry in entries {
+            if watched {
+                let k = Bytes::copy_from_slice(&entry.key);
+                // Fails only once every watcher is gone
+                let _ = self.changes.send(match entry.t {
+                    EntryType::Put => Change::Set(k),
+                    EntryType::Delete => Change::Deleted(k),
+                });
+            }
+
+            if let Some(tx) = sink {
+                if tx.send(entry).await.is_err() {
+                    eprintln!("error: sink stopped");
+                    sink = None;
+                }
+            }
+        }
+    }
+
+    /// Writes every live key with its value and expiry to `w`. Returns the number of keys
+    /// written.
+    pub async fn export_to(&self, w: impl io::Write, format: Format) -> io::Result<usize> {
+        let mut w = dump::Writer::new(w, format)?;
+        let mut scan = self.pc.scan();
+        let mut written = 0;
+        for (k, data) in self.sorted_keys().await {
+            let entry = match scan.fetch_entry(data.page_id, data.offset).await {
+                Ok(Some(entry)) if entry.key == k => Some(entry),
+                Ok(_) => self.read(&k).await?,
+                Err(e) => return Err(e.into()),
+            };
+
+            if let Some(entry) = entry {
+                w.write(&Record {
+                    key: entry.key.to_vec(),
+                    value: entry.value.to_vec(),
+                    expires: entry.expires,
+                })?;
+                written += 1;
+            }
+        }
+        w.finish()?;
+
+        Ok(written)
+    }
+
+    /// Loads records written by `export_to`, in either format, with `load_entries`. Later records
+    /// for a key win and ones that have already expired are skipped. Returns the number of keys
+    /// loaded.
+    pub async fn import_from(&self, r: impl io::Read) -> io::Result<usize> {
+        let now = self.now();
+        let mut records = BTreeMap::new();
+        for record in dump::read_all(r)? {
+            records.insert(record.key, (record.value, record.expires));
+        }
+
+        let entries = records
+            .into_iter()
+            .filter(|(_, (_, expires))| !expires.is_some_and(|e| e <= now))
+            .map(|(k, (v, expires))| {
+                let entry = Entry::new(&k, &v, EntryType::Put).with_time(now);
+                match expires {
+                    Some(expires) => entry.with_expiry(expires),
+                    None => entry,
+                }
+            });
+
+        self.load_entries(entries).await
+    }
+
+    /// Writes the live dataset to `file` as an `SsTable`. The keys are snapshotted from the
+    /// `KeyDir` up front and their entries read afterwards, so writers are only held up while the
+    /// keys are copied. Entries compaction reclaimed in the meantime are read again where they
+    /// moved to, and keys deleted in the meantime are left out. Returns the number of keys written.
+    pub async fn export_sstable(&self, file: impl AsRef<Path>) -> io::Result<u64> {
+        let keys = self.sorted_keys().await;
+
+        let mut w = SsTableWriter::create(file).await?;
+        let mut scan = self.pc.scan();
+        for (k, data) in keys {
+            let entry = match scan.fetch_entry(data.page_id, data.offset).await {
+                Ok(Some(entry)) if entry.key == k => Some(entry),
+                Ok(_) => self.read(&k).await?,
+                Err(e) => return Err(e.into()),
+            };
+
+            if let Some(entry) = entry {
+                w.add(&entry.key, &entry.value).await?;
+            }
+        }
+
+        w.finish().await
+    }
+
+    /// Copies the data file and the `KeyDir` into `dir`. Only the current page is copied while
+    /// holding it, the pages before it are never written to again and are read from disk
+    /// afterwards, with compaction holding off reclaiming them until the copy is done. Writers
+    /// carry on in the meantime, the snapshot has everything acknowledged before it started.
+    pub async fn snapshot(&self, dir: impl AsRef<Path>) -> io::Result<SnapshotStats> {
+        let dir = dir.as_ref();
+        tokio::fs::create_dir_all(dir).await?;
+
+        let _paused = self.pc.pause_reclaims().await;
+        let (kd, last) = {
+            let current = self.pc.get_current().await;
+            let kd = self.kd.read().await.clone();
+
+            (kd, PageInner::from_bytes(current.id, current.data))
+        };
+
+        let file = dir.join(SNAPSHOT_DATA_FILE);
+        match tokio::fs::remove_file(&file).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        segment::remove(&file)?;
+        let disk = Disk::new(file).await?;
+        for page_id in 0..last.id {
+            let page = self.pc.read_page(page_id).await?;
+            disk.write_page(page_id, &page.data).await?;
+
+            if page_id % SNAPSHOT_YIELD_EVERY == 0 {
+                tokio::task::yield_now().await;
+            }
+        }
+        disk.write_page(last.id, &last.data).await?;
+        disk.sync().await?;
+
+        let mut w = File::create(dir.join(SNAPSHOT_KEYDIR_FILE)).await?;
+        w.write_all(&kd.encode()).await?;
+        w.sync_all().await?;
+
+        Ok(SnapshotStats {
+            pages: last.id + 1,
+            keys: kd.len(),
+        })
+    }
+
+    /// Live keys in order, with where their entries were when the `KeyDir` was read.
+    async fn sorted_keys(&self) -> Vec<(BytesMut, KeyData)> {
+        let now = self.now();
+        let mut keys: Vec<_> = self
+            .kd
+            .read()
+            .await
+            .iter()
+            .filter(|(_, data)| !data.is_expired(now))
+            .map(|(k, data)| (k.clone(), *data))
+            .collect();
+        keys.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        keys
+    }
+
+    /// Loads `entries`, sorted by key with no duplicates, straight into freshly allocated pages,
+    /// bypassing the page cache, and adds them to the `KeyDir` in one go at the end. Keys are
+    /// unique, so entries can be packed into any open page in any order, see `Packing`. Writers
+    /// wait for the whole load. Returns the number of entries loaded.
+    pub async fn bulk_load<K, V>(
+        &self,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> io::Result<usize>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let now = self.now();
+        let entries = entries
+            .into_iter()
+            .map(move |(k, v)| Entry::new(k.as_ref(), v.as_ref(), EntryType::Put).with_time(now));
+
+        self.load_entries(entries).await
+    }
+
+    /// Like `bulk_load`, for puts that can carry an expiry.
+    pub async fn load_entries(
+        &self,
+        entries: impl IntoIterator<Item = Entry>,
+    ) -> io::Result<usize> {
+        let mut current = self.pc.get_current().await;
+
+        let mut pages = Vec::new();
+        let mut loaded = Vec::new();
+        if let Err(e) = self.write_sorted(entries, &mut pages, &mut loaded).await {
+            // Nothing points into the pages yet, they would only be picked up on restart
+            for page_id in pages {
+                if let Err(e) = self.pc.reclaim_page(page_id).await {
+                    eprintln!("error: could not reclaim page {} - {}", page_id, e);
+                }
+            }
+            return Err(e);
+        }
+
+        // The current page has to come after the loaded pages so later writes win on bootstrap
+        self.pc.replace_current(&mut current).await?;
+        self.pc.sync().await?;
+
+        let mut kd = self.kd.write().await;
+        for (k, data) in &loaded {
+            kd.insert(k, *data);
+        }
+
+        Ok(loaded.len())
+    }
+
+    async fn write_sorted(
+        &self,
+        entries: impl IntoIterator<Item = Entry>,
+        pages: &mut Vec<PageID>,
+        loaded: &mut Vec<(Vec<u8>, KeyData)>,
+    ) -> io::Result<()> {
+        let mut open: Vec<PageInner> = Vec::with_capacity(BULK_LOAD_OPEN_PAGES);
+        let mut window = Vec::new();
+        let mut window_len = 0;
+        let mut last: Option<Vec<u8>> = None;
+
+        for entry in entries {
+            let entry = self.compress(entry);
+            if last.as_deref().is_some_and(|last| &entry.key[..] <= last) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "bulk loaded keys must be sorted and unique",
+                ));
+            }
+            last = Some(entry.key.to_vec());
+
+            if entry.len() > PAGE_CAPACITY {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "entry larger than page",
+                ));
+            }
+
+            match self.packing {
+                Packing::FirstFit => self.place(&mut open, entry, pages, loaded).await?,
+                Packing::SizeClass => {
+                    window_len += entry.len();
+                    window.push(entry);
+                    if window_len >= PACKING_WINDOW {
+                        self.place_window(&mut open, &mut window, pages, loaded)
+                            .await?;
+                        window_len = 0;
+                    }
+                }
+            }
+        }
+        self.place_window(&mut open, &mut window, pages, loaded)
+            .await?;
+        for page in &open {
+            self.pc.write_page(page).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Places the buffered entries largest size class first. Pages opened for the window are
+    /// kept until it is placed, then all but the `BULK_LOAD_OPEN_PAGES` with the most room left
+    /// are written.
+    async fn place_window(
+        &self,
+        open: &mut Vec<PageInner>,
+        window: &mut Vec<Entry>,
+        pages: &mut Vec<PageID>,
+        loaded: &mut Vec<(Vec<u8>, KeyData)>,
+    ) -> io::Result<()> {
+        // Stable, so entries in the same class stay in key order
+        window.sort_by_key(|entry| std::cmp::Reverse(size_class(entry.len())));
+        for entry in window.drain(..) {
+            match open.iter().position(|p| p.remaining() >= entry.len()) {
+                Some(i) => Self::write_loaded(&mut open[i], entry, loaded),
+                None => {
+                    let mut page = PageInner::new(self.pc.inc_id());
+                    pages.push(page.id);
+                    Self::write_loaded(&mut page, entry, loaded);
+                    open.push(page);
+                }
+            }
+        }
+
+        open.sort_by_key(|p| std::cmp::Reverse(p.remaining()));
+        for page in open.drain(BULK_LOAD_OPEN_PAGES.min(open.len())..) {
+            self.pc.write_page(&page).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn place(
+        &self,
+        open: &mut Vec<PageInner>,
+        entry: Entry,
+        pages: &mut Vec<PageID>,
+        loaded: &mut Vec<(Vec<u8>, KeyData)>,
+    ) -> io::Result<()> {
+        let i = match open.iter().position(|p| p.remaining() >= entry.len()) {
+            Some(i) => i,
+            None => {
+                if open.len() == BULK_LOAD_OPEN_PAGES {
+                    self.pc.write_page(&open.remove(0)).await?;
+                }
+                open.push(PageInner::new(self.pc.inc_id()));
+                pages.push(open[open.len() - 1].id);
+
+                open.len() - 1
+            }
+        };
+
+        Self::write_loaded(&mut open[i], entry, loaded);
+
+        Ok(())
+    }
+
+    fn write_loaded(page: &mut PageInner, entry: Entry, loaded: &mut Vec<(Vec<u8>, KeyData)>) {
+        let offset = page.write_entry(&entry).expect("entry should fit");
+        let data = KeyData::new(page.id, offset)
+            .with_expiry(entry.expires)
+            .with_len(entry.len());
+        loaded.push((entry.key.to_vec(), data));
+    }
+
+    /// Reads the live entry for `k`.
+    pub async fn read(&self, k: &[u8]) -> Result<Option<Entry>, StorageError> {
+        loop {
+            // Don't hold the KeyDir lock while waiting on the page: writers take the current page
+            // before the KeyDir
+            let Some(data) = self.kd.read().await.get(k).copied() else {
+                return Ok(None);
+            };
+            if data.is_expired(self.now()) {
+                return Ok(None);
+            }
+            if let Some(entry) = self.values.as_ref().and_then(|values| values.get(k, &data)) {
+                return Ok(Some(entry));
+            }
+
+            let _permit = self.fetch_permit(data.page_id).await;
+            // TODO: return error if replacer couldn't replace or page could not have held entry
+            match self.pc.fetch_entry(data.page_id, data.offset).await? {
+                Some(entry) if entry.key == k => {
+                    if let Some(values) = &self.values {
+                        values.insert(k, data, &entry);
+                    }
+                    return Ok(Some(entry));
+                }
+                // Compaction can move the entry and reclaim its page after the KeyDir was read
+                _ if self.kd.read().await.get(k) != Some(&data) => continue,
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    /// Reads the live entry for `k` while the caller holds the current page, so it can't be
+    /// overwritten, or moved by compaction, before the caller writes over it.
+    pub async fn read_holding(
+        &self,
+        current: &PageInner,
+        k: &[u8],
+    ) -> Result<Option<Entry>, StorageError> {
+        let Some(data) = self.kd.read().await.get(k).copied() else {
+            return Ok(None);
+        };
+        if data.is_expired(self.now()) {
+            return Ok(None);
+        }
+
+        let entry = if data.page_id == current.id {
+            current.read_entry(data.offset as usize)?
+        } else {
+            let _permit = self.fetch_permit(data.page_id).await;
+            self.pc.fetch_entry(data.page_id, data.offset).await?
+        };
+
+        Ok(entry.filter(|entry| entry.key == k))
+    }
+
+    /// Waits for a turn to fetch `page_id` if it has to be read from disk, see
+    /// `Options::max_fetches`.
+    pub async fn fetch_permit(&self, page_id: PageID) -> Option<SemaphorePermit<'_>> {
+        if self.pc.is_cached(page_id).await {
+            return None;
+        }
+
+        Some(self.fetches.acquire().await)
+    }
+
+    /// Reads the live entries for `keys`, looking them all up under one `KeyDir` lock and
+    /// fetching each page they are on once.
+    pub async fn read_many<K: AsRef<[u8]>>(
+        &self,
+        keys: &[K],
+    ) -> Result<Vec<Option<Entry>>, StorageError> {
+        let pages = self.locate(&*self.kd.read().await, keys);
+
+        let mut entries: Vec<_> = keys.iter().map(|_| None).collect();
+        for (page_id, found) in pages {
+            let offsets: Vec<_> = found.iter().map(|(_, offset)| *offset).collect();
+            let permit = self.fetch_permit(page_id).await;
+            let fetched = self.pc.fetch_entries(page_id, &offsets).await?;
+            drop(permit);
+
+            for ((i, _), entry) in found.into_iter().zip(fetched) {
+                let k = keys[i].as_ref();
+                entries[i] = match entry {
+                    Some(entry) if entry.key == k => Some(entry),
+                    // Moved by compaction or deleted since the KeyDir was read
+                    _ => self.read(k).await?,
+                };
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Reads the live entries for `keys` as they all were at one point, unlike `read_many` which
+    /// can return some from before a concurrent write and some from after it. Writers, and
+    /// compaction moving entries, are held off by holding the current page until every entry has
+    /// been read, so the snapshot lasts as long as reading the pages does.
+    pub async fn read_consistent<K: AsRef<[u8]>>(
+        &self,
+        keys: &[K],
+    ) -> Result<Vec<Option<Entry>>, StorageError> {
+        let current = self.pc.get_current().await;
+        let pages = self.locate(&*self.kd.read().await, keys);
+
+        let mut entries: Vec<_> = keys.iter().map(|_| None).collect();
+        for (page_id, found) in pages {
+            let offsets: Vec<_> = found.iter().map(|(_, offset)| *offset).collect();
+            let fetched = if page_id == current.id {
+                offsets
+                    .iter()
+                    .map(|offset| current.read_entry(*offset as usize))
+                    .collect::<Result<_, _>>()?
+            } else {
+                let _permit = self.fetch_permit(page_id).await;
+                self.pc.fetch_entries(page_id, &offsets).await?
+            };
+
+            for ((i, _), entry) in found.into_iter().zip(fetched) {
+                // Nothing can have moved the entry since the KeyDir was read
+                entries[i] = entry.filter(|entry| entry.key == keys[i].as_ref());
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Groups the live `keys` found in `kd` by the page their entry is on, with each one's index
+    /// in `keys` and offset in the page.
+    fn locate<K: AsRef<[u8]>>(
+        &self,
+        kd: &KeyDir,
+        keys: &[K],
+    ) -> BTreeMap<PageID, Vec<(usize, u64)>> {
+        let now = self.now();
+        let mut pages: BTreeMap<PageID, Vec<(usize, u64)>> = BTreeMap::new();
+        for (i, k) in keys.iter().enumerate() {
+            match kd.get(k.as_ref()) {
+                Some(data) if !data.is_expired(now) => pages
+                    .entry(data.page_id)
+                    .or_default()
+                    .push((i, data.offset)),
+                _ => {}
+            }
+        }
+
+        pages
+    }
+
+    /// Reads the live entry for `k`, loading and caching it with the loader, if there is one, on
+    /// a miss.
+    pub async fn get(&self, k: &[u8]) -> io::Result<Option<Entry>> {
+        if let Some(entry) = self.read(k).await? {
+            return Ok(Some(entry));
+        }
+        let Some(loader) = &self.loader else {
+            return Ok(None);
+        };
+        let Some(loaded) = loader(k).await? else {
+            return Ok(None);
+        };
+
+        let mut entry = self.entry(k, &loaded.value, EntryType::Put);
+        if let Some(ttl) = loaded.ttl {
+            entry = entry.with_expiry(self.now().saturating_add(ttl.as_secs()));
+        }
+
+        let mut current = self.pc.get_current().await;
+        // A write that landed while loading is newer than what was loaded
+        if self
+            .kd
+            .read()
+            .await
+            .get(k)
+            .is_some_and(|data| !data.is_expired(self.now()))
+        {
+            drop(current);
+            return Ok(self.read(k).await?);
+        }
+
+        let offset = self.pc.write_entry(&mut current, &entry).await?;
+        let data = KeyData::new(current.id, offset)
+            .with_expiry(entry.expires)
+            .with_len(entry.len());
+        self.kd.write().await.insert(k, data);
+
+        Ok(Some(entry))
+    }
+
+    pub async fn flush(&self) -> io::Result<()> {
+        self.pc.flush_current().await
+    }
+
+    /// Runs the `before_shutdown` hook, then writes a checkpoint so the next open doesn't have to
+    /// read every page. If it can't be written the current page is flushed instead.
+    pub async fn shutdown(&self) -> io::Result<()> {
+        if let Some(f) = &self.before_shutdown {
+            f();
+        }
+
+        if let Err(e) = self.checkpoint().await {
+            eprintln!("error: could not write checkpoint - {}", e);
+            return self.flush().await;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the current page and writes a checkpoint next to the data file, so the next open
+    /// can load the `KeyDir` and warm the page cache instead of scanning the data file. Call it on
+    /// graceful shutdown once writes have stopped: any write afterwards leaves the checkpoint
+    /// stale and the next open falls back to a full bootstrap. Reclaiming a page doesn't change
+    /// the data file's length or last page, so pages compaction reclaims afterwards are kept on
+    /// disk for as long as the database is open.
+    pub async fn checkpoint(&self) -> io::Result<()> {
+        let _paused = self.pc.pause_reclaims().await;
+        let pin = self.pc.pin_epoch();
+        self.checkpointed
+            .lock()
+            .expect("checkpointed lock poisoned")
+            .replace(pin);
+        let current = self.pc.get_current().await;
+        self.pc.sync_current(&current).await?;
+
+        let pages = self.pc.pages().await?;
+        let last_page_crc = match pages.checked_sub(1) {
+            Some(last) => crc32fast::hash(&self.pc.read_page(last).await?.data),
+            None => 0,
+        };
+        let checkpoint = Checkpoint {
+            pages,
+            last_page_crc,
+            hot: self.pc.cached_pages().await,
+            kd: self.kd.read().await.clone(),
+        };
+
+        checkpoint::write(&self.checkpoint, &checkpoint).await
+    }
+
+    /// Writes the live keys to `file` in sorted order, one per line. With `meta` each key is
+    /// followed by a tab separated value size and write time, which means reading every entry.
+    /// Returns the number of keys written.
+    pub async fn export_keys(&self, file: impl AsRef<Path>, meta: bool) -> io::Result<usize> {
+        let keys = self.sorted_keys().await;
+
+        let mut w = BufWriter::new(File::create(file).await?);
+        let mut scan = self.pc.scan();
+        let mut written = 0;
+        for (k, data) in keys {
+            if !meta {
+                w.write_all(&k).await?;
+                w.write_all(b"\n").await?;
+                written += 1;
+                continue;
+            }
+
+            // Entries can be moved by compaction in the meantime, keys that can't be read are
+            // left out
+            let entry = match scan.fetch_entry(data.page_id, data.offset).await {
+                Ok(Some(entry)) if entry.key == k => entry,
+                _ => continue,
+            };
+
+            w.write_all(&k).await?;
+            w.write_all(format!("\t{}\t{}\n", entry.value.len(), entry.time).as_bytes())
+                .await?;
+            written += 1;
+        }
+        w.flush().await?;
+
+        Ok(written)
+    }
+}
+
+async fn run_sink(sink: Sink, mut rx: mpsc::Receiver<Entry>) {
+    while let Some(entry) = rx.recv().await {
+        let mut batch = vec![entry];
+        while batch.len() < SINK_BATCH_SIZE {
+            match rx.try_recv() {
+                Ok(entry) => batch.push(entry),
+                Err(_) => break,
+            }
+        }
+        let entries: Arc<[Entry]> = batch.into();
+
+        let mut backoff = SINK_RETRY_MIN;
+        while let Err(e) = sink(entries.clone()).await {
+            eprintln!(
+                "error: sink failed {} writes, retrying in {:?}: {}",
+                entries.len(),
+                backoff,
+                e
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(SINK_RETRY_MAX);
+        }
+    }
+}
+
+/// Writes the tombstones queued by `Db::unlink_later`, committing once per batch.
+async fn run_unlinker(pc: PageCache, kd: Arc<RwLock<KeyDir>>, mut rx: mpsc::Receiver<BytesMut>) {
+    while let Some(k) = rx.recv().await {
+        let mut batch = vec![k];
+        while batch.len() < UNLINK_BATCH_SIZE {
+            match rx.try_recv() {
+                Ok(k) => batch.push(k),
+                Err(_) => break,
+            }
+        }
+
+        let mut current = pc.get_current().await;
+        for k in batch {
+            // A tombstone written after the key was set again would delete it on restart
+            if kd.read().await.get(&k).is_some() {
+                continue;
+            }
+
+            let entry = Entry::new(&k, &[], EntryType::Delete).with_time(pc.now());
+            if let Err(e) = pc.write_entry(&mut current, &entry).await {
+                eprintln!("error: could not write tombstone for unlinked key: {}", e);
+            }
+        }
+        drop(current);
+
+        pc.commit().await;
+    }
+}
+
+fn size_class(len: usize) -> usize {
+    len * SIZE_CLASSES / PAGE_SIZE
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering::*},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use crate::storagev2::{
+        clock::TestClock,
+        db::{Db, DbBuilder, Loaded, Options, Packing, SNAPSHOT_DATA_FILE, SNAPSHOT_KEYDIR_FILE},
+        dump::Format,
+        key_dir::KeyDir,
+        log::{Entry, EntryType},
+        test::CleanUp,
+        testing::Fixture,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export_keys() -> io::Result<()> {
+        const DB_FILE: &str = "./test_export_keys.db";
+        const EXPORT_FILE: &str = "./test_export_keys.txt";
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .put(b"key2", b"value2")
+            .put(b"key1", b"value1")
+            .put(b"key3", b"value3")
+            .delete(b"key3")
+            .entry(Entry::new(b"key4", b"value4", EntryType::Put).with_expiry(1))
+            .put(b"key0", b"v0")
+            .build()
+            .await?;
+        drop(disk);
+        let _cu_export = CleanUp::file(EXPORT_FILE);
+
+        let db = Db::open(DB_FILE, Options::default()).await?;
+
+        let n = db.export_keys(EXPORT_FILE, false).await?;
+        let got = std::fs::read_to_string(EXPORT_FILE)?;
+        let expected = "key0\nkey1\nkey2\n";
+        assert!(n == 3, "Got: {}", n);
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        db.export_keys(EXPORT_FILE, true).await?;
+        let got = std::fs::read_to_string(EXPORT_FILE)?;
+        let sizes: Vec<_> = got
+            .lines()
+            .map(|l| l.split('\t').take(2).collect::<Vec<_>>().join(" "))
+            .collect();
+        let expected = ["key0 2", "key1 6", "key2 6"];
+        assert!(
+            sizes == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            sizes
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_loader() -> io::Result<()> {
+        const DB_FILE: &str = "./test_loader.db";
+        let _cu = CleanUp::file(DB_FILE);
+
+        let loads = Arc::new(AtomicUsize::new(0));
+        let _loads = loads.clone();
+        let db = Db::open(DB_FILE, Options::default())
+            .await?
+            .with_loader(Arc::new(move |k| {
+                _loads.fetch_add(1, SeqCst);
+                let loaded = match k {
+                    b"missing" => None,
+                    k => Some(Loaded {
+                        value: [k, b"-loaded"].concat(),
+                        ttl: Some(Duration::from_secs(60)),
+                    }),
+                };
+
+                Box::pin(async move { Ok(loaded) })
+            }));
+
+        let cases = [
+            (&b"key1"[..], Some(&b"key1-loaded"[..]), 1),
+            // Cached by the first get
+            (b"key1", Some(b"key1-loaded"), 1),
+            (b"missing", None, 2),
+            (b"missing", None, 3),
+        ];
+        for (k, expected, expected_loads) in cases {
+            let got = db.get(k).await?;
+            let got = got.as_ref().map(|entry| &entry.value[..]);
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+            let got_loads = loads.load(SeqCst);
+            assert!(got_loads == expected_loads, "Got: {}", got_loads);
+        }
+
+        let got = db
+            .kd
+            .read()
+            .await
+            .get(b"key1")
+            .and_then(|data| data.expires);
+        assert!(got.is_some(), "loaded value should expire");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sink() -> io::Result<()> {
+        const DB_FILE: &str = "./test_sink.db";
+        let _cu = CleanUp::file(DB_FILE);
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let sunk = Arc::new(Mutex::new(Vec::new()));
+        let (_attempts, _sunk) = (attempts.clone(), sunk.clone());
+        let db = Db::open(DB_FILE, Options::default())
+            .await?
+            .with_sink(Arc::new(move |entries| {
+                // The first attempt fails and has to be retried
+                if _attempts.fetch_add(1, SeqCst) == 0 {
+                    return Box::pin(async { Err(io::Error::other("unavailable")) });
+                }
+
+                let mut sunk = _sunk.lock().unwrap();
+                sunk.extend(entries.iter().map(|e| (e.t, e.key.to_vec())));
+                Box::pin(async { Ok(()) })
+            }));
+
+        db.write_behind([
+            Entry::new(b"key1", b"value1", EntryType::Put),
+            Entry::new(b"key2", b"value2", EntryType::Put),
+        ])
+        .await;
+        db.write_behind([Entry::new(b"key1", b"", EntryType::Delete)])
+            .await;
+
+        let expected = vec![
+            (EntryType::Put, b"key1".to_vec()),
+            (EntryType::Put, b"key2".to_vec()),
+            (EntryType::Delete, b"key1".to_vec()),
+        ];
+        for _ in 0..50 {
+            if sunk.lock().unwrap().len() == expected.len() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let got = sunk.lock().unwrap().clone();
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_snapshot() -> io::Result<()> {
+        const DB_FILE: &str = "./test_snapshot.db";
+        const SNAPSHOT_DIR: &str = "./test_snapshot";
+        let mut fixture = Fixture::new(DB_FILE);
+        for i in 0..50 {
+            fixture = fixture.put(format!("key{}", i).as_bytes(), b"value");
+        }
+        let (disk, _cu) = fixture.delete(b"key10").build().await?;
+        drop(disk);
+        let _cu_snapshot = CleanUp::dir(SNAPSHOT_DIR);
+
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let stats = db.snapshot(SNAPSHOT_DIR).await?;
+        assert!(stats.keys == 49, "Got: {:?}", stats);
+
+        // Not part of the snapshot
+        db.pc
+            .write_entry(
+                &mut db.pc.get_current().await,
+                &Entry::new(b"key0", b"updated", EntryType::Put),
+            )
+            .await?;
+
+        let data = format!("{}/{}", SNAPSHOT_DIR, SNAPSHOT_DATA_FILE);
+        let keydir = std::fs::read(format!("{}/{}", SNAPSHOT_DIR, SNAPSHOT_KEYDIR_FILE))?;
+        let expected = KeyDir::decode(&keydir)?;
+        let snapshot = Db::open(&data, Options::default()).await?;
+        let got = snapshot.kd.read().await.clone();
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        for (k, expected) in [
+            ("key0", Some("value")),
+            ("key10", None),
+            ("key49", Some("value")),
+        ] {
+            let got = snapshot.get(k.as_bytes()).await?;
+            let got = got.as_ref().map(|entry| &entry.value[..]);
+            assert!(
+                got == expected.map(str::as_bytes),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_bulk_load() -> io::Result<()> {
+        const DB_FILE: &str = "./test_bulk_load.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+
+        let keys: Vec<_> = (0..100).map(|i| format!("key{:03}", i)).collect();
+        let n = db.bulk_load(keys.iter().map(|k| (k, "value"))).await?;
+        assert!(n == 100, "Got: {}", n);
+
+        let got = db.bulk_load([("key2", "value"), ("key1", "value")]).await;
+        assert!(got.is_err(), "unsorted keys should be rejected");
+
+        // Written after the load, so it has to win on restart
+        let mut current = db.pc.get_current().await;
+        let entry = Entry::new(b"key050", b"updated", EntryType::Put);
+        db.pc.write_entry(&mut current, &entry).await?;
+        drop(current);
+        db.flush().await?;
+
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        for (k, expected) in [
+            ("key000", "value"),
+            ("key050", "updated"),
+            ("key099", "value"),
+        ] {
+            let got = db.get(k.as_bytes()).await?;
+            let got = got.as_ref().map(|entry| &entry.value[..]);
+            assert!(
+                got == Some(expected.as_bytes()),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+        let got = db.get(b"key1").await?;
+        assert!(got.is_none(), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_bulk_load_packing() -> io::Result<()> {
+        const DB_FILE: &str = "./test_bulk_load_packing.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+
+        // 185 byte entries leave room for one 45 byte entry each. Written in order the second
+        // small entry would need a third page
+        let (big, small) = ([b'b'; 150], [b's'; 10]);
+        let entries: [(&[u8], &[u8]); 4] = [
+            (b"key000", &big),
+            (b"key001", &big),
+            (b"key002", &small),
+            (b"key003", &small),
+        ];
+        db.bulk_load(entries).await?;
+
+        // The empty current page replaced at the end of the load, and two pages 230 bytes full
+        let got = db.pc.fill().buckets;
+        let expected = [1, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        for (k, v) in entries {
+            let got = db.get(k).await?;
+            assert!(
+                got.as_ref().map(|entry| &entry.value[..]) == Some(v),
+                "Got: {:?}",
+                got
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_bulk_load_size_classes() -> io::Result<()> {
+        const FIRST_FIT_FILE: &str = "./test_bulk_load_size_classes_first_fit.db";
+        const SIZE_CLASS_FILE: &str = "./test_bulk_load_size_classes.db";
+        let _cu_first_fit = CleanUp::file(FIRST_FIT_FILE);
+        let _cu_size_class = CleanUp::file(SIZE_CLASS_FILE);
+
+        // Mostly values that leave a gap too small for the next one
+        let entries: Vec<_> = (0..200)
+            .map(|i| (format!("key{:03}", i), vec![b'v'; (i * 37) % 150]))
+            .collect();
+
+        let mut wasted = Vec::new();
+        for (file, packing) in [
+            (FIRST_FIT_FILE, Packing::FirstFit),
+            (SIZE_CLASS_FILE, Packing::SizeClass),
+        ] {
+            let options = Options {
+                packing,
+                ..Default::default()
+            };
+            let db = Db::open(file, options).await?;
+            db.bulk_load(entries.iter().map(|(k, v)| (k, v))).await?;
+            wasted.push(db.pc.fill().wasted_bytes);
+
+            for (k, v) in &entries {
+                let got = db.get(k.as_bytes()).await?;
+                assert!(
+                    got.as_ref().map(|entry| &entry.value[..]) == Some(&v[..]),
+                    "Got: {:?}",
+                    got
+                );
+            }
+        }
+
+        assert!(wasted[1] < wasted[0], "Got: {:?}", wasted);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compression() -> io::Result<()> {
+        const DB_FILE: &str = "./test_compression.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let options = Options {
+            compression_threshold: Some(32),
+            ..Default::default()
+        };
+        let db = Db::open(DB_FILE, options).await?;
+
+        // Only fits a page compressed
+        let large = r#"{"key":"value"}"#.repeat(64);
+        let entries = [
+            ("key1", large.as_str()),
+            ("key2", "short"),
+            ("key3", "incompressible 0123456789abcdefghij"),
+        ];
+        db.load_entries(
+            entries
+                .iter()
+                .map(|(k, v)| Entry::new(k.as_bytes(), v.as_bytes(), EntryType::Put)),
+        )
+        .await?;
+        drop(db);
+
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        for (k, expected) in entries {
+            let got = db.get(k.as_bytes()).await?;
+            let got = got.as_ref().map(|entry| &entry.value[..]);
+            assert!(
+                got == Some(expected.as_bytes()),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_checkpoint() -> io::Result<()> {
+        const DB_FILE: &str = "./test_db_checkpoint.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        db.bulk_load((0..32).map(|i| (format!("key{:02}", i), format!("value{}", i))))
+            .await?;
+        db.get(b"key00").await?;
+        let hot = db.pc.cached_pages().await;
+        db.checkpoint().await?;
+        // Reclaiming leaves the checkpoint valid, so the page has to stay on disk
+        db.pc.reclaim_page(0).await?;
+        assert!(db.pc.is_deferred(0));
+        drop(db);
+
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let report = db.bootstrap_report();
+        assert!(report.checkpoint && report.keys == 32, "Got: {:?}", report);
+        let got = db.pc.cached_pages().await;
+        assert!(got == hot, "\nExpected: {:?}\nGot: {:?}\n", hot, got);
+        for (k, expected) in [(&b"key00"[..], &b"value0"[..]), (b"key31", b"value31")] {
+            let got = db.get(k).await?.map(|entry| entry.value);
+            assert!(got.as_deref() == Some(expected), "Got: {:?}", got);
+        }
+
+        // A write after the checkpoint makes it stale
+        db.checkpoint().await?;
+        db.load_entries([db.entry(b"key32", b"value32", EntryType::Put)])
+            .await?;
+        db.flush().await?;
+        drop(db);
+
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let report = db.bootstrap_report();
+        assert!(!report.checkpoint && report.keys == 33, "Got: {:?}", report);
+        drop(db);
+
+        // Only the first open after a checkpoint uses it
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        assert!(!db.bootstrap_report().checkpoint);
+        assert!(!std::path::Path::new("./test_db_checkpoint.db.checkpoint").exists());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_builder() -> io::Result<()> {
+        const DB_FILE: &str = "./test_db_builder.db";
+        let _cu = CleanUp::file(DB_FILE);
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let open = || {
+            let (before, after, shutdown) = (calls.clone(), calls.clone(), calls.clone());
+            DbBuilder::new(DB_FILE)
+                .with_page_cache_size(3)
+                .before_bootstrap(move |path| {
+                    before
+                        .lock()
+                        .unwrap()
+                        .push(format!("before {}", path.display()));
+                    Ok(())
+                })
+                .after_bootstrap(move |report| {
+                    let checkpoint = report.checkpoint;
+                    after.lock().unwrap().push(format!("after {}", checkpoint));
+                })
+                .before_shutdown(move || shutdown.lock().unwrap().push("shutdown".into()))
+                .open()
+        };
+
+        let db = open().await?;
+        assert!(db.pc.frames().await == 3);
+        db.shutdown().await?;
+        drop(db);
+        open().await?;
+
+        let got = calls.lock().unwrap().clone();
+        let expected = [
+            "before ./test_db_builder.db",
+            "after false",
+            "shutdown",
+            "before ./test_db_builder.db",
+            "after true",
+        ];
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        // A failing hook fails the open
+        let got = DbBuilder::new(DB_FILE)
+            .before_bootstrap(|_| Err(io::Error::other("no backup")))
+            .open()
+            .await;
+        assert!(got.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_clock() -> io::Result<()> {
+        const DB_FILE: &str = "./test_clock.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let clock = TestClock::new(1_000);
+        let db = Db::open_with_clock(DB_FILE, Options::default(), clock.clone()).await?;
+
+        db.load_entries([
+            db.entry(b"key1", b"value1", EntryType::Put)
+                .with_expiry(1_010),
+            db.entry(b"key2", b"value2", EntryType::Put),
+        ])
+        .await?;
+        let got = db.get(b"key1").await?.map(|entry| entry.time);
+        assert!(got == Some(1_000), "Got: {:?}", got);
+
+        clock.advance(Duration::from_secs(10));
+        for (k, expected) in [(&b"key1"[..], false), (b"key2", true)] {
+            let got = db.get(k).await?.is_some();
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export_import() -> io::Result<()> {
+        const DB_FILE: &str = "./test_export_import.db";
+        const JSON_FILE: &str = "./test_export_import_json.db";
+        const CSV_FILE: &str = "./test_export_import_csv.db";
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .put(b"key1", b"value1")
+            .put(b"key,2", b"line\n\"quoted\"")
+            .put(b"key\xff", b"\x00\x01")
+            .entry(Entry::new(b"key3", b"value3", EntryType::Put).with_expiry(u64::MAX))
+            .entry(Entry::new(b"key4", b"value4", EntryType::Put).with_expiry(1))
+            .build()
+            .await?;
+        drop(disk);
+        let _cu_json = CleanUp::file(JSON_FILE);
+        let _cu_csv = CleanUp::file(CSV_FILE);
+
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        for (file, format) in [(JSON_FILE, Format::Json), (CSV_FILE, Format::Csv)] {
+            let mut exported = Vec::new();
+            let n = db.export_to(&mut exported, format).await?;
+            assert!(n == 4, "Got: {}", n);
+
+            let imported = Db::open(file, Options::default()).await?;
+            let n = imported.import_from(&exported[..]).await?;
+            assert!(n == 4, "Got: {}", n);
+
+            let mut got = Vec::new();
+            imported.export_to(&mut got, format).await?;
+            assert!(
+                got == exported,
+                "\nExpected: {}\nGot: {}\n",
+                String::from_utf8_lossy(&exported),
+                String::from_utf8_lossy(&got)
+            );
+        }
+
+        let json = concat!(
+            r#"{"key":"a","value":"1"}"#,
+            "\n",
+            r#"{"key_hex":"ff00","value":"2","expires":1}"#,
+            "\n",
+            r#"{"key":"a","value":"3"}"#,
+        );
+        let n = db.import_from(json.as_bytes()).await?;
+        assert!(n == 1, "Got: {}", n);
+        let got = db.get(b"a").await?;
+        assert!(
+            got.as_ref().map(|e| &e.value[..]) == Some(b"3"),
+            "Got: {:?}",
+            got
+        );
+
+        for invalid in [
+            r#"{"key":"a"}"#,
+            r#"{"key":"a","key_hex":"61","value":"1"}"#,
+            r#"{"key_hex":"6","value":"1"}"#,
+            "key,value,expires\na,1,soon",
+            "k,v\na,1",
+        ] {
+            assert!(
+                db.import_from(invalid.as_bytes()).await.is_err(),
+                "{:?} should be rejected",
+                invalid
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -1,54 +1,913 @@
-use std::{io, os::fd::AsRawFd, path::Path};
+use std::{
+    fmt, io,
+    os::fd::{AsRawFd, OwnedFd},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex as StdMutex, RwLock as StdRwLock,
+    },
+};
 
-use nix::sys::uio;
-use tokio::fs::{File, OpenOptions};
+use bytes::{Buf, BufMut};
+use nix::{
+    errno::Errno,
+    fcntl::{self, FallocateFlags},
+    sys::{stat, uio},
+    unistd,
+};
+use tokio::{fs::OpenOptions, sync::Mutex};
 
-use crate::storagev2::page::{PageID, PAGE_SIZE};
+#[cfg(feature = "failpoints")]
+use crate::storagev2::failpoint;
+#[cfg(feature = "io-uring")]
+use crate::storagev2::uring::Uring;
+use crate::storagev2::{
+    mmap::Mmap,
+    page::{self, PageID, PageTrailer, PAGE_SIZE},
+    segment::{self, Manifest},
+};
 
+/// When writes are fsynced, trading write latency for how much can be lost in a crash.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Durability {
+    /// The current page is written and fsynced before every write is acknowledged. Concurrent
+    /// writes share an fsync, see `PageCache::commit`.
+    Always,
+    /// The current page is written and fsynced once a second by a background flusher.
+    EverySec,
+    /// Pages are written when they fill up and the OS decides when they reach the disk.
+    #[default]
+    Never,
+}
+
+impl FromStr for Durability {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Durability::Always),
+            "everysec" => Ok(Durability::EverySec),
+            "never" => Ok(Durability::Never),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown durability {:?}", s),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Durability::Always => write!(f, "always"),
+            Durability::EverySec => write!(f, "everysec"),
+            Durability::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// How pages are read and written, picked when the `Disk` is opened.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// pread and pwrite, on whichever runtime thread is reading or writing.
+    #[default]
+    Pread,
+    /// An io_uring driven by a thread of its own, see `Uring`. Needs the io-uring feature.
+    IoUring,
+}
+
+impl FromStr for Backend {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pread" => Ok(Backend::Pread),
+            "io_uring" => Ok(Backend::IoUring),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown disk backend {:?}", s),
+            )),
+        }
+    }
+}
+
+/// Start of the header of data files that have one, see `Header`.
+const MAGIC: &[u8; 8] = b"hash_db\0";
+
+/// Version of the data file layout this build writes and the newest it reads. Version 3 seals
+/// every page with a `PageTrailer`.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// Flags for features of a data file a reader has to understand to read it, none yet. Files with
+/// flags a build doesn't know are refused rather than misread.
+pub const KNOWN_FLAGS: u32 = 0;
+
+/// Describes the layout of a data file. It takes up the first page of the file so the pages
+/// after it keep their alignment. Files written before it was added start with their first page
+/// and are read as version 1. Encoded as: magic | version u32 | flags u32, zero padded.
+///
+/// The layout of an entry is extended with flags on its type byte instead, see `Entry::FLAGS`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub version: u32,
+    pub flags: u32,
+}
+
+impl Header {
+    pub const LEGACY: Header = Header {
+        version: 1,
+        flags: 0,
+    };
+
+    pub const CURRENT: Header = Header {
+        version: FORMAT_VERSION,
+        flags: 0,
+    };
+
+    pub fn encode(&self) -> [u8; PAGE_SIZE] {
+        let mut dst = [0; PAGE_SIZE];
+        let mut buf = &mut dst[..];
+        buf.put_slice(MAGIC);
+        buf.put_u32(self.version);
+        buf.put_u32(self.flags);
+
+        dst
+    }
+
+    /// `None` if `src` doesn't start with a header, e.g. it's the first page of a version 1 file.
+    pub fn decode(mut src: &[u8]) -> Option<Header> {
+        if src.len() < MAGIC.len() + 8 || !src.starts_with(MAGIC) {
+            return None;
+        }
+        src.advance(MAGIC.len());
+
+        Some(Header {
+            version: src.get_u32(),
+            flags: src.get_u32(),
+        })
+    }
+
+    fn check(&self) -> io::Result<()> {
+        if self.version > FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "data file format version {} is newer than the {} this build reads",
+                    self.version, FORMAT_VERSION
+                ),
+            ));
+        }
+        if self.flags & !KNOWN_FLAGS != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "data file uses unknown features {:#x}",
+                    self.flags & !KNOWN_FLAGS
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Whether pages are sealed with a `PageTrailer` as they're written. Files from before
+    /// trailers were added carry on without them.
+    pub fn has_trailers(&self) -> bool {
+        self.version >= 3
+    }
+
+    /// Where the first page starts in the file.
+    fn base(&self) -> usize {
+        match *self == Header::LEGACY {
+            true => 0,
+            false => PAGE_SIZE,
+        }
+    }
+}
+
+/// One segment file of the data file, see `segment`.
+struct Segment {
+    fd: Arc<OwnedFd>,
+    /// Where the first page starts in the file, after the header if it's the data file itself.
+    base: usize,
+    mmap: Option<Mmap>,
+    /// Writes to the file so far, and how many of them had been written when it was last fsynced.
+    written: AtomicU64,
+    synced: Mutex<u64>,
+}
+
+impl Segment {
+    fn open(path: &Path, base: usize, mmap_reads: bool) -> io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        Self::new(file.into(), base, mmap_reads)
+    }
+
+    fn new(fd: OwnedFd, base: usize, mmap_reads: bool) -> io::Result<Self> {
+        let mut segment = Self {
+            fd: Arc::new(fd),
+            base,
+            mmap: None,
+            written: AtomicU64::new(0),
+            synced: Mutex::new(0),
+        };
+        segment.map(mmap_reads)?;
+
+        Ok(segment)
+    }
+
+    /// The same file, mapped afresh, e.g. after it was truncated under the old mapping.
+    fn remap(&self) -> io::Result<Self> {
+        let mut segment = Self {
+            fd: self.fd.clone(),
+            base: self.base,
+            mmap: None,
+            written: AtomicU64::new(self.written.load(Ordering::Acquire)),
+            synced: Mutex::new(0),
+        };
+        segment.map(self.mmap.is_some())?;
+
+        Ok(segment)
+    }
+
+    fn map(&mut self, enabled: bool) -> io::Result<()> {
+        self.mmap = match enabled {
+            true => Some(Mmap::new(self.fd.as_raw_fd(), self.base)?),
+            false => None,
+        };
+
+        Ok(())
+    }
+
+    fn offset(&self, index: PageID) -> i64 {
+        self.base as i64 + PAGE_SIZE as i64 * i64::from(index)
+    }
+
+    /// Length of the pages in the file.
+    fn len(&self) -> io::Result<usize> {
+        let len = stat::fstat(self.fd.as_raw_fd())?.st_size as usize;
+
+        Ok(len.saturating_sub(self.base))
+    }
+}
+
+/// The data file and its segments, see `segment`. Pages are addressed by their `PageID` across
+/// all of them, pages in segments that were deleted or haven't been written yet read as empty.
 pub struct Disk {
-    file: File,
+    path: PathBuf,
+    header: Header,
+    /// Can be changed while open, see `set_durability`.
+    durability: StdMutex<Durability>,
+    #[cfg(feature = "io-uring")]
+    uring: Option<Uring>,
+    mmap_reads: bool,
+    /// Cleared once the filesystem turns out not to support punching holes, see `punch_page`.
+    punch_holes: AtomicBool,
+    /// Of the last page written, see `PageTrailer::lsn`.
+    lsn: AtomicU64,
+    segment_pages: PageID,
+    manifest: StdMutex<Manifest>,
+    /// Indexed by segment, `None` for the ones that were deleted or haven't been written yet.
+    segments: StdRwLock<Vec<Option<Arc<Segment>>>>,
 }
 
 impl Disk {
     pub async fn new(file: impl AsRef<Path>) -> io::Result<Self> {
+        let path = file.as_ref().to_path_buf();
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(file)
+            .truncate(false)
+            .open(&path)
             .await?;
 
-        Ok(Self { file })
+        let len = file.metadata().await?.len();
+        let header = match len {
+            // New files get a header, synced so a crash can't leave one half written
+            0 => {
+                uio::pwrite(file.as_raw_fd(), &Header::CURRENT.encode(), 0)?;
+                unistd::fsync(file.as_raw_fd())?;
+                Header::CURRENT
+            }
+            _ => {
+                let mut buf = [0; MAGIC.len() + 8];
+                uio::pread(file.as_raw_fd(), &mut buf, 0)?;
+                Header::decode(&buf).unwrap_or(Header::LEGACY)
+            }
+        };
+        header.check()?;
+
+        let base = header.base();
+        let manifest = match segment::read(&path)? {
+            Some(manifest) => manifest,
+            None => {
+                let pages = (len as usize).saturating_sub(base).div_ceil(PAGE_SIZE);
+                Manifest::for_pages(pages as PageID)
+            }
+        };
+
+        let fd = OwnedFd::from(file.into_std().await);
+        let mut segments = match manifest.first {
+            0 => vec![Some(Arc::new(Segment::new(fd, base, false)?))],
+            _ => {
+                // Left behind if deleting it was cut short
+                unistd::ftruncate(fd.as_raw_fd(), base as i64)?;
+                vec![None]
+            }
+        };
+        for n in segment::list(&path)? {
+            let segment_path = segment::path(&path, n);
+            if n < manifest.first {
+                std::fs::remove_file(segment_path)?;
+                continue;
+            }
+
+            if segments.len() <= n as usize {
+                segments.resize(n as usize + 1, None);
+            }
+            segments[n as usize] = Some(Arc::new(Segment::open(&segment_path, 0, false)?));
+        }
+
+        Ok(Self {
+            path,
+            header,
+            durability: StdMutex::new(Durability::default()),
+            #[cfg(feature = "io-uring")]
+            uring: None,
+            mmap_reads: false,
+            punch_holes: AtomicBool::new(true),
+            lsn: AtomicU64::new(0),
+            segment_pages: manifest.segment_pages,
+            manifest: StdMutex::new(manifest),
+            segments: StdRwLock::new(segments),
+        })
     }
 
-    pub fn read_page(&self, page_id: PageID) -> io::Result<[u8; PAGE_SIZE]> {
-        let offset = PAGE_SIZE as i64 * i64::from(page_id);
-        let fd = self.file.as_raw_fd();
+    /// Splits the pages into segments of `pages` pages each. Only a new data file can be split
+    /// differently, one that already has pages keeps the segments it was created with.
+    pub fn with_segment_pages(mut self, pages: PageID) -> io::Result<Self> {
+        if pages == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "segments have to hold at least one page",
+            ));
+        }
+        if pages == self.segment_pages || self.pages_len()? > 0 {
+            return Ok(self);
+        }
 
-        let mut buf = [0; PAGE_SIZE];
-        match uio::pread(fd, &mut buf, offset) {
-            Ok(_) => {}
-            Err(e) => panic!("{e}"),
+        let manifest = Manifest {
+            segment_pages: pages,
+            first: 0,
+        };
+        segment::write(&self.path, &manifest)?;
+        *self.manifest.get_mut().unwrap() = manifest;
+        self.segment_pages = pages;
+
+        Ok(self)
+    }
+
+    pub fn segment_pages(&self) -> PageID {
+        self.segment_pages
+    }
+
+    /// First page that isn't in a deleted segment, every page before it is empty.
+    pub fn first_page(&self) -> PageID {
+        self.manifest.lock().unwrap().first_page()
+    }
+
+    /// Maps the segment files so `read_mapped` can read pages without copying them.
+    pub fn with_mmap_reads(mut self, enabled: bool) -> io::Result<Self> {
+        self.mmap_reads = enabled;
+        for segment in self.segments.get_mut().unwrap().iter_mut().flatten() {
+            Arc::get_mut(segment)
+                .expect("segments aren't shared outside of a call")
+                .map(enabled)?;
         }
 
+        Ok(self)
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.mmap_reads
+    }
+
+    /// Calls `f` with the page straight from the mapping, see `Mmap::read`. Returns `None` if the
+    /// file isn't mapped, the page is past its end or it's stored compressed.
+    pub fn read_mapped<T>(
+        &self,
+        page_id: PageID,
+        f: impl FnOnce(&[u8; PAGE_SIZE]) -> T,
+    ) -> io::Result<Option<T>> {
+        let (n, index) = self.locate(page_id);
+
+        // Held while reading, so the segment can't be deleted underneath the mapping
+        let segments = self.segments.read().unwrap();
+        let Some(mmap) = segments
+            .get(n as usize)
+            .and_then(|segment| segment.as_ref()?.mmap.as_ref())
+        else {
+            return Ok(None);
+        };
+
+        Ok(mmap
+            .read(index, |data| (!page::is_compressed(data)).then(|| f(data)))?
+            .flatten())
+    }
+
+    /// Drops the mapped pages from memory, if the files are mapped, see `Mmap::release`.
+    pub fn release_mapped(&self) -> io::Result<()> {
+        for segment in self.segments.read().unwrap().iter().flatten() {
+            if let Some(mmap) = &segment.mmap {
+                mmap.release()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Switches to `backend`, failing if it isn't available on this build or kernel.
+    pub fn with_backend(self, backend: Backend) -> io::Result<Self> {
+        match backend {
+            Backend::Pread => Ok(Self {
+                #[cfg(feature = "io-uring")]
+                uring: None,
+                ..self
+            }),
+            #[cfg(feature = "io-uring")]
+            Backend::IoUring => Ok(Self {
+                uring: Some(Uring::new()?),
+                ..self
+            }),
+            #[cfg(not(feature = "io-uring"))]
+            Backend::IoUring => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "built without the io-uring feature",
+            )),
+        }
+    }
+
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        *self.durability.get_mut().unwrap() = durability;
+
+        self
+    }
+
+    pub fn durability(&self) -> Durability {
+        *self.durability.lock().unwrap()
+    }
+
+    /// Writes already acknowledged keep the durability they were written with.
+    pub fn set_durability(&self, durability: Durability) {
+        *self.durability.lock().unwrap() = durability;
+    }
+
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    /// The segment `page_id` is in and its index within it.
+    fn locate(&self, page_id: PageID) -> (u32, PageID) {
+        (page_id / self.segment_pages, page_id % self.segment_pages)
+    }
+
+    /// The segment `page_id` is in and where the page starts in it, `None` if the segment was
+    /// deleted or hasn't been written yet.
+    fn segment(&self, page_id: PageID) -> Option<(Arc<Segment>, i64)> {
+        let (n, index) = self.locate(page_id);
+        let segment = self.segments.read().unwrap().get(n as usize)?.clone()?;
+        let offset = segment.offset(index);
+
+        Some((segment, offset))
+    }
+
+    /// As `segment`, creating the segment file if it hasn't been written yet. `None` if it was
+    /// deleted.
+    fn segment_for_write(&self, page_id: PageID) -> io::Result<Option<(Arc<Segment>, i64)>> {
+        if let Some(found) = self.segment(page_id) {
+            return Ok(Some(found));
+        }
+
+        let manifest = self.manifest.lock().unwrap();
+        let (n, index) = self.locate(page_id);
+        if n < manifest.first {
+            return Ok(None);
+        }
+        // Created while waiting for the manifest
+        if let Some(found) = self.segment(page_id) {
+            return Ok(Some(found));
+        }
+
+        let segment = Arc::new(Segment::open(
+            &segment::path(&self.path, n),
+            0,
+            self.mmap_reads,
+        )?);
+        // Also syncs the directory, so the new file is still there after a crash
+        segment::write(&self.path, &manifest)?;
+
+        let mut segments = self.segments.write().unwrap();
+        if segments.len() <= n as usize {
+            segments.resize(n as usize + 1, None);
+        }
+        segments[n as usize] = Some(segment.clone());
+        let offset = segment.offset(index);
+
+        Ok(Some((segment, offset)))
+    }
+
+    /// Reads a page, decompressing it if it was stored compressed.
+    pub async fn read_page(&self, page_id: PageID) -> io::Result<[u8; PAGE_SIZE]> {
+        let data = self.read_stored_page(page_id).await?;
+
+        page::decompress(&data).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupt compressed page {}", page_id),
+            )
+        })
+    }
+
+    /// Reads a page as it's stored, compressed or not.
+    pub async fn read_stored_page(&self, page_id: PageID) -> io::Result<[u8; PAGE_SIZE]> {
+        let Some((segment, offset)) = self.segment(page_id) else {
+            return Ok([0; PAGE_SIZE]);
+        };
+
+        #[cfg(feature = "io-uring")]
+        if let Some(uring) = &self.uring {
+            // Short reads past the end of the file leave the rest of the buffer zeroed too
+            let (_, buf) = uring.read(&segment.fd, offset as u64).await?;
+            return Ok(*buf);
+        }
+
+        // Reads past the end of the file leave the rest of the page zeroed
+        let mut buf = [0; PAGE_SIZE];
+        uio::pread(segment.fd.as_raw_fd(), &mut buf, offset)?;
+
         Ok(buf)
     }
 
-    pub fn write_page(&self, page_id: PageID, data: &[u8; PAGE_SIZE]) {
-        let offset = PAGE_SIZE as i64 * i64::from(page_id);
-        let fd = self.file.as_raw_fd();
+    /// Writes a page, sealing it with a `PageTrailer` if the file has them. Entries have to stay
+    /// clear of where it goes, see `PAGE_CAPACITY`.
+    pub async fn write_page(&self, page_id: PageID, data: &[u8; PAGE_SIZE]) -> io::Result<()> {
+        let mut sealed;
+        let data = match self.header.has_trailers() && data.iter().any(|b| *b != 0) {
+            true => {
+                sealed = *data;
+                PageTrailer::seal(&mut sealed, self.lsn.fetch_add(1, Ordering::Relaxed) + 1);
+                &sealed
+            }
+            false => data,
+        };
+
+        let Some((segment, offset)) = self.segment_for_write(page_id)? else {
+            // Every page of a segment is reclaimed before it's deleted, zeroing one again is fine
+            if data.iter().all(|b| *b == 0) {
+                return Ok(());
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("page {} is in a deleted segment", page_id),
+            ));
+        };
+
+        #[cfg(feature = "failpoints")]
+        if let Some(crash) = failpoint::eval("disk::write_page") {
+            if crash == failpoint::Crash::Torn {
+                let _ = uio::pwrite(segment.fd.as_raw_fd(), &data[..PAGE_SIZE / 2], offset);
+            }
+            return Err(failpoint::error("disk::write_page"));
+        }
+
+        let written = self.pwrite(&segment, offset, data).await?;
+        if written != PAGE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!("short write of page {}: {} bytes", page_id, written),
+            ));
+        }
+        segment.written.fetch_add(1, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Empties a page and gives its space back to the filesystem by punching a hole where it was,
+    /// which reads back as zeros, mapped or not. Falls back to writing zeros over it on
+    /// filesystems that can't punch holes.
+    pub async fn punch_page(&self, page_id: PageID) -> io::Result<()> {
+        // Deleted or never written, so already empty
+        let Some((segment, offset)) = self.segment(page_id) else {
+            return Ok(());
+        };
+
+        if self.punch_holes.load(Ordering::Relaxed) {
+            crate::fail_point!("disk::punch_page");
+
+            let flags = FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE;
+            match fcntl::fallocate(segment.fd.as_raw_fd(), flags, offset, PAGE_SIZE as i64) {
+                Ok(()) => {
+                    segment.written.fetch_add(1, Ordering::Release);
+                    return Ok(());
+                }
+                Err(Errno::EOPNOTSUPP) => self.punch_holes.store(false, Ordering::Relaxed),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        self.write_page(page_id, &[0; PAGE_SIZE]).await
+    }
+
+    async fn pwrite(
+        &self,
+        segment: &Segment,
+        offset: i64,
+        data: &[u8; PAGE_SIZE],
+    ) -> io::Result<usize> {
+        #[cfg(feature = "io-uring")]
+        if let Some(uring) = &self.uring {
+            return uring.write(&segment.fd, offset as u64, data).await;
+        }
+
+        Ok(uio::pwrite(segment.fd.as_raw_fd(), data, offset)?)
+    }
+
+    /// Fsyncs every segment written to since it was last fsynced.
+    pub async fn sync(&self) -> io::Result<()> {
+        crate::fail_point!("disk::sync");
+
+        let segments: Vec<_> = self
+            .segments
+            .read()
+            .unwrap()
+            .iter()
+            .flatten()
+            .cloned()
+            .collect();
+        for segment in segments {
+            let written = segment.written.load(Ordering::Acquire);
+            // Held while fsyncing, so a write isn't taken as synced before the fsync is done
+            let mut synced = segment.synced.lock().await;
+            if *synced >= written {
+                continue;
+            }
+
+            self.fsync(&segment).await?;
+            *synced = written;
+        }
+
+        Ok(())
+    }
+
+    async fn fsync(&self, segment: &Segment) -> io::Result<()> {
+        #[cfg(feature = "io-uring")]
+        if let Some(uring) = &self.uring {
+            return uring.fsync(&segment.fd).await;
+        }
+
+        unistd::fsync(segment.fd.as_raw_fd())?;
+
+        Ok(())
+    }
+
+    /// Length of the pages, leaving out the header. Pages in deleted segments count, they read as
+    /// empty.
+    pub async fn len(&self) -> io::Result<usize> {
+        self.pages_len()
+    }
+
+    fn pages_len(&self) -> io::Result<usize> {
+        let first = self.first_page() as usize * PAGE_SIZE;
+
+        let segments = self.segments.read().unwrap();
+        let last = segments
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(n, segment)| Some((n, segment.as_ref()?)));
+        let len = match last {
+            Some((n, segment)) => n * self.segment_pages as usize * PAGE_SIZE + segment.len()?,
+            None => 0,
+        };
+
+        Ok(len.max(first))
+    }
+
+    /// Carries on numbering writes after `lsn`, e.g. the highest found on bootstrap.
+    pub fn observe_lsn(&self, lsn: u64) {
+        self.lsn.fetch_max(lsn, Ordering::Relaxed);
+    }
+
+    /// Cuts the pages from `page_id` on off the end, e.g. a torn last page found on bootstrap.
+    /// Nothing can be reading them.
+    pub async fn truncate(&self, page_id: PageID) -> io::Result<()> {
+        let (n, index) = self.locate(page_id);
+
+        let mut segments = self.segments.write().unwrap();
+        if let Some(segment) = segments.get(n as usize).cloned().flatten() {
+            unistd::ftruncate(segment.fd.as_raw_fd(), segment.offset(index))?;
+            unistd::fsync(segment.fd.as_raw_fd())?;
+            // Reading the cut off pages through the old mapping would crash
+            segments[n as usize] = Some(Arc::new(segment.remap()?));
+        }
+        for later in (n as usize + 1)..segments.len() {
+            if segments[later].take().is_some() {
+                std::fs::remove_file(segment::path(&self.path, later as u32))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Bytes the data file and its segments take up on disk, which leaves out holes punched by
+    /// `punch_page` and deleted segments.
+    pub async fn allocated(&self) -> io::Result<u64> {
+        let mut bytes = 0;
+        for segment in self.segments.read().unwrap().iter().flatten() {
+            bytes += stat::fstat(segment.fd.as_raw_fd())?.st_blocks as u64 * 512;
+        }
+
+        Ok(bytes)
+    }
+
+    /// Deletes the segments every page of which is before `page_id`, which have to have been
+    /// reclaimed. The manifest is updated first, so a crash midway leaves files that are removed
+    /// on the next open. Returns how many were deleted.
+    pub async fn drop_segments(&self, page_id: PageID) -> io::Result<usize> {
+        let mut manifest = self.manifest.lock().unwrap();
+        let (first, _) = self.locate(page_id);
+        if first <= manifest.first {
+            return Ok(0);
+        }
+
+        let from = manifest.first;
+        segment::write(&self.path, &Manifest { first, ..*manifest })?;
+        manifest.first = first;
 
-        match uio::pwrite(fd, data, offset) {
-            Ok(_) => {}
-            Err(e) => panic!("{e}"),
+        let dropped: Vec<_> = {
+            let mut segments = self.segments.write().unwrap();
+            (from..first)
+                .map(|n| (n, segments.get_mut(n as usize).and_then(Option::take)))
+                .collect()
         };
+        for (n, segment) in dropped {
+            match (n, segment) {
+                // The data file keeps its header
+                (0, Some(segment)) => {
+                    unistd::ftruncate(segment.fd.as_raw_fd(), segment.base as i64)?
+                }
+                (0, None) => {}
+                (n, _) => match std::fs::remove_file(segment::path(&self.path, n)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                },
+            }
+        }
+
+        Ok((first - from) as usize)
     }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::storagev2::{
+        disk::{Disk, Header, FORMAT_VERSION},
+        page::{PAGE_CAPACITY, PAGE_SIZE},
+        segment,
+        test::CleanUp,
+    };
+
+    #[tokio::test]
+    async fn test_header() -> io::Result<()> {
+        const DB_FILE: &str = "./test_header.db";
+        let _cu = CleanUp::file(DB_FILE);
+
+        let disk = Disk::new(DB_FILE).await?;
+        assert!(disk.header() == Header::CURRENT, "Got: {:?}", disk.header());
+        assert!(disk.len().await? == 0);
+
+        // Pages start after the header
+        disk.write_page(0, &[1; PAGE_SIZE]).await?;
+        drop(disk);
+        let data = std::fs::read(DB_FILE)?;
+        assert!(data.len() == 2 * PAGE_SIZE, "Got: {}", data.len());
+        assert!(data[PAGE_SIZE..][..PAGE_CAPACITY] == [1; PAGE_CAPACITY]);
+
+        let disk = Disk::new(DB_FILE).await?;
+        assert!(disk.header() == Header::CURRENT, "Got: {:?}", disk.header());
+        assert!(disk.read_page(0).await?[..PAGE_CAPACITY] == [1; PAGE_CAPACITY]);
+        drop(disk);
+
+        // Written by a newer build
+        let newer = Header {
+            version: FORMAT_VERSION + 1,
+            flags: 0,
+        };
+        std::fs::write(DB_FILE, newer.encode())?;
+        let got = Disk::new(DB_FILE).await.err().map(|e| e.kind());
+        assert!(got == Some(io::ErrorKind::Unsupported), "Got: {:?}", got);
+
+        let unknown = Header {
+            version: FORMAT_VERSION,
+            flags: 1,
+        };
+        std::fs::write(DB_FILE, unknown.encode())?;
+        let got = Disk::new(DB_FILE).await.err().map(|e| e.kind());
+        assert!(got == Some(io::ErrorKind::Unsupported), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_segments() -> io::Result<()> {
+        const DB_FILE: &str = "./test_segments.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let path = std::path::Path::new(DB_FILE);
+
+        let disk = Disk::new(DB_FILE).await?.with_segment_pages(2)?;
+        for page_id in 0..5u8 {
+            disk.write_page(u32::from(page_id), &[page_id + 1; PAGE_SIZE])
+                .await?;
+        }
+        disk.sync().await?;
+        assert!(disk.len().await? == 5 * PAGE_SIZE);
+        let got = segment::list(path)?;
+        assert!(got == [1, 2], "Got: {:?}", got);
+        let got = std::fs::metadata(segment::path(path, 2))?.len();
+        assert!(got == PAGE_SIZE as u64, "Got: {}", got);
+
+        // The segments are kept across a reopen, even with another size asked for
+        drop(disk);
+        let disk = Disk::new(DB_FILE).await?.with_segment_pages(8)?;
+        assert!(disk.segment_pages() == 2);
+        assert!(disk.read_page(3).await?[..PAGE_CAPACITY] == [4; PAGE_CAPACITY]);
+
+        // Only whole segments before the page go
+        assert!(disk.drop_segments(3).await? == 1);
+        assert!(disk.first_page() == 2);
+        assert!(disk.read_page(1).await? == [0; PAGE_SIZE]);
+        assert!(disk.read_page(2).await?[..PAGE_CAPACITY] == [3; PAGE_CAPACITY]);
+        assert!(disk.len().await? == 5 * PAGE_SIZE);
+        // The data file keeps its header
+        let got = std::fs::metadata(DB_FILE)?.len();
+        assert!(got == PAGE_SIZE as u64, "Got: {}", got);
+
+        assert!(disk.drop_segments(4).await? == 1);
+        assert!(segment::list(path)? == [2]);
+        disk.write_page(0, &[0; PAGE_SIZE]).await?;
+        assert!(disk.write_page(0, &[1; PAGE_SIZE]).await.is_err());
+
+        drop(disk);
+        let disk = Disk::new(DB_FILE).await?;
+        assert!(disk.header() == Header::CURRENT, "Got: {:?}", disk.header());
+        assert!(disk.first_page() == 4, "Got: {}", disk.first_page());
+        assert!(disk.read_page(2).await? == [0; PAGE_SIZE]);
+        assert!(disk.read_page(4).await?[..PAGE_CAPACITY] == [5; PAGE_CAPACITY]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_punch_page() -> io::Result<()> {
+        const DB_FILE: &str = "./test_punch_page.db";
+        let _cu = CleanUp::file(DB_FILE);
+
+        let disk = Disk::new(DB_FILE).await?.with_mmap_reads(true)?;
+        for page_id in 0..64u8 {
+            disk.write_page(u32::from(page_id), &[page_id + 1; PAGE_SIZE])
+                .await?;
+        }
+        disk.sync().await?;
+        let before = disk.allocated().await?;
+
+        for page_id in 8..56 {
+            disk.punch_page(page_id).await?;
+        }
+        disk.sync().await?;
+        assert!(disk.read_page(7).await?[..PAGE_CAPACITY] == [8; PAGE_CAPACITY]);
+        assert!(disk.read_page(8).await? == [0; PAGE_SIZE]);
+        assert!(disk.read_mapped(55, |data| *data)? == Some([0; PAGE_SIZE]));
+        assert!(disk.read_page(56).await?[..PAGE_CAPACITY] == [57; PAGE_CAPACITY]);
+        // The pages after the holes stay where they are
+        assert!(disk.len().await? == 64 * PAGE_SIZE);
+
+        // Filesystems that can't punch holes get zeros written instead
+        let after = disk.allocated().await?;
+        assert!(after <= before, "\nBefore: {}\nAfter: {}\n", before, after);
+
+        // Past the end of the file
+        disk.punch_page(100).await?;
+        assert!(disk.len().await? == 64 * PAGE_SIZE);
 
-    pub async fn len(&self) -> usize {
-        self.file
-            .metadata()
-            .await
-            .expect("error getting metadata")
-            .len() as usize
+        Ok(())
     }
 }
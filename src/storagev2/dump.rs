@@ -0,0 +1,223 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Formats `Db::export_to` writes. `Db::import_from` reads either, telling them apart by the
+/// first byte.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// One object per line: `{"key":..,"value":..,"expires":..}`. Keys and values that aren't
+    /// UTF-8 are hex encoded as `key_hex`/`value_hex` instead.
+    #[default]
+    Json,
+    /// A `key,value,expires` header, then one row per key. `expires` is empty for keys that
+    /// don't expire.
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown format {:?}", s),
+            )),
+        }
+    }
+}
+
+const CSV_HEADER: [&str; 3] = ["key", "value", "expires"];
+
+/// A key and value being moved in or out, with when it expires in seconds since the epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub expires: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_hex: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value_hex: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<u64>,
+}
+
+pub enum Writer<W: Write> {
+    Json(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(w: W, format: Format) -> io::Result<Self> {
+        match format {
+            Format::Json => Ok(Writer::Json(w)),
+            Format::Csv => {
+                let mut w = csv::Writer::from_writer(w);
+                w.write_record(CSV_HEADER)?;
+
+                Ok(Writer::Csv(Box::new(w)))
+            }
+        }
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        match self {
+            Writer::Json(w) => {
+                let (key, key_hex) = text_or_hex(&record.key);
+                let (value, value_hex) = text_or_hex(&record.value);
+                let json = JsonRecord {
+                    key,
+                    key_hex,
+                    value,
+                    value_hex,
+                    expires: record.expires,
+                };
+
+                serde_json::to_writer(&mut *w, &json)?;
+                w.write_all(b"\n")
+            }
+            Writer::Csv(w) => {
+                let expires = record.expires.map(|e| e.to_string()).unwrap_or_default();
+                w.write_record([&record.key[..], &record.value[..], expires.as_bytes()])?;
+
+                Ok(())
+            }
+        }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Writer::Json(mut w) => w.flush(),
+            Writer::Csv(mut w) => w.flush(),
+        }
+    }
+}
+
+/// Reads every record from `r`, in either format.
+pub fn read_all(r: impl Read) -> io::Result<Vec<Record>> {
+    let mut r = BufReader::new(r);
+    let first = loop {
+        let buf = r.fill_buf()?;
+        match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(i) => break Some(buf[i]),
+            None if buf.is_empty() => break None,
+            None => {
+                let n = buf.len();
+                r.consume(n);
+            }
+        }
+    };
+
+    match first {
+        None => Ok(Vec::new()),
+        Some(b'{') => read_json(r),
+        Some(_) => read_csv(r),
+    }
+}
+
+fn read_json(r: impl BufRead) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for line in r.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let json: JsonRecord = serde_json::from_str(&line)?;
+        let key = bytes_from(json.key, json.key_hex, "key")?;
+        let value = bytes_from(json.value, json.value_hex, "value")?;
+        records.push(Record {
+            key,
+            value,
+            expires: json.expires,
+        });
+    }
+
+    Ok(records)
+}
+
+fn read_csv(r: impl Read) -> io::Result<Vec<Record>> {
+    let mut r = csv::Reader::from_reader(r);
+    let header = r.byte_headers()?;
+    if header.len() < 2
+        || header.len() > 3
+        || !header
+            .iter()
+            .eq(CSV_HEADER[..header.len()].iter().map(|h| h.as_bytes()))
+    {
+        return Err(invalid("csv header must be key,value[,expires]".into()));
+    }
+
+    let mut records = Vec::new();
+    for row in r.byte_records() {
+        let row = row?;
+        let expires = match row.get(2) {
+            None | Some(b"") => None,
+            Some(e) => Some(
+                std::str::from_utf8(e)
+                    .ok()
+                    .and_then(|e| e.parse().ok())
+                    .ok_or_else(|| invalid(format!("invalid expiry {:?}", e)))?,
+            ),
+        };
+
+        records.push(Record {
+            key: row[0].to_vec(),
+            value: row[1].to_vec(),
+            expires,
+        });
+    }
+
+    Ok(records)
+}
+
+fn text_or_hex(b: &[u8]) -> (Option<String>, Option<String>) {
+    match std::str::from_utf8(b) {
+        Ok(s) => (Some(s.into()), None),
+        Err(_) => (None, Some(b.iter().map(|b| format!("{:02x}", b)).collect())),
+    }
+}
+
+fn bytes_from(text: Option<String>, hex: Option<String>, field: &str) -> io::Result<Vec<u8>> {
+    match (text, hex) {
+        (Some(text), None) => Ok(text.into_bytes()),
+        (None, Some(hex)) => {
+            from_hex(&hex).ok_or_else(|| invalid(format!("invalid {}_hex {:?}", field, hex)))
+        }
+        _ => Err(invalid(format!(
+            "exactly one of {} or {}_hex is required",
+            field, field
+        ))),
+    }
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn invalid(e: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
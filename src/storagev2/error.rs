@@ -0,0 +1,47 @@
+use std::{fmt, io};
+
+use crate::storagev2::page::PageError;
+
+/// Why reading from or writing to storage failed.
+#[derive(Debug)]
+pub enum StorageError {
+    /// Reading or writing the data file failed.
+    Io(io::Error),
+    /// An entry failed its checksum or didn't make sense.
+    Corrupt,
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "{}", e),
+            StorageError::Corrupt => write!(f, "entry failed its checksum"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+impl From<PageError> for StorageError {
+    fn from(e: PageError) -> Self {
+        match e {
+            PageError::Corrupt => StorageError::Corrupt,
+            e => StorageError::Io(io::Error::other(format!("{:?}", e))),
+        }
+    }
+}
+
+impl From<StorageError> for io::Error {
+    fn from(e: StorageError) -> Self {
+        match e {
+            StorageError::Io(e) => e,
+            StorageError::Corrupt => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use bytes::Bytes;
+use tokio::sync::broadcast;
+
+use crate::storagev2::alarm::Alarm;
+
+/// Events buffered for each subscriber. A subscriber that falls further behind misses the oldest
+/// ones and is told how many it missed.
+pub const EVENT_QUEUE_SIZE: usize = 1024;
+
+/// Something the database did or noticed on its own, rather than because a client asked, that
+/// caches and operators layered on top need to hear about.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The key's TTL ran out and the expiry sweep removed it.
+    Expired(Bytes),
+    /// A threshold was crossed, or the database is back under it, see `alarm::check`.
+    Alarm {
+        alarm: Alarm,
+        raised: bool,
+        value: f64,
+        threshold: f64,
+    },
+}
+
+pub type Events = broadcast::Sender<Event>;
+
+pub fn channel() -> Events {
+    broadcast::channel(EVENT_QUEUE_SIZE).0
+}
+
+/// A key set or deleted by a client, for connections watching it, e.g. to invalidate a cache.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Set(Bytes),
+    Deleted(Bytes),
+}
+
+impl Change {
+    pub fn key(&self) -> &Bytes {
+        match self {
+            Change::Set(k) | Change::Deleted(k) => k,
+        }
+    }
+}
+
+pub type Changes = broadcast::Sender<Change>;
+
+pub fn changes() -> Changes {
+    broadcast::channel(EVENT_QUEUE_SIZE).0
+}
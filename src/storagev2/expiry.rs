@@ -0,0 +1,284 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::RwLock;
+
+use crate::storagev2::{
+    error::StorageError,
+    events::{Event, Events},
+    key_dir::KeyDir,
+    log::{Entry, EntryType},
+    page_manager::PageCache,
+};
+
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far ahead `forecast` looks by default: a minute, 5 and 15 minutes, an hour, 6 hours and a
+/// day.
+pub const FORECAST_HORIZONS: [Duration; 6] = [
+    Duration::from_secs(60),
+    Duration::from_secs(5 * 60),
+    Duration::from_secs(15 * 60),
+    Duration::from_secs(60 * 60),
+    Duration::from_secs(6 * 60 * 60),
+    Duration::from_secs(24 * 60 * 60),
+];
+
+/// Keys due to expire within a horizon from now, and the bytes their entries take up in the data
+/// file.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Forecast {
+    pub within: Duration,
+    pub keys: usize,
+    pub bytes: usize,
+}
+
+/// Number of keys expired between yields back to the executor.
+const YIELD_EVERY: usize = 64;
+
+pub async fn run(m: PageCache, kd: Arc<RwLock<KeyDir>>, events: Events) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        sweep(&m, &kd, &events).await;
+    }
+}
+
+/// Removes expired keys from the `KeyDir` and writes a tombstone for each so they stay deleted
+/// after a restart, publishing `Event::Expired` for each. Returns the number of keys removed.
+pub async fn sweep(m: &PageCache, kd: &RwLock<KeyDir>, events: &Events) -> usize {
+    let now = m.now();
+    let expired = kd.read().await.expired(now);
+
+    let mut removed = 0;
+    for k in expired {
+        let mut current = m.get_current().await;
+
+        // The key may have been overwritten or deleted since it was collected
+        match kd.read().await.get(&k) {
+            Some(data) if data.is_expired(now) => {}
+            _ => continue,
+        }
+
+        let entry = Entry::new(&k, &[], EntryType::Delete).with_time(now);
+        if let Err(e) = m.write_entry(&mut current, &entry).await {
+            eprintln!("error: could not write tombstone for expired key: {}", e);
+            continue;
+        }
+
+        kd.write().await.remove(&k);
+        drop(current);
+
+        // Fails only when nobody is subscribed
+        let _ = events.send(Event::Expired(k.freeze()));
+
+        removed += 1;
+        if removed % YIELD_EVERY == 0 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    removed
+}
+
+/// Counts the keys that will expire within each of `horizons`, cumulatively: a key expiring in 2
+/// minutes counts towards every horizon from 2 minutes on. Keys that already expired but haven't
+/// been swept yet count towards all of them. Their entries are read to size them, without
+/// disturbing the page cache, see `PageCache::scan`.
+pub async fn forecast(
+    m: &PageCache,
+    kd: &RwLock<KeyDir>,
+    horizons: &[Duration],
+) -> Result<Vec<Forecast>, StorageError> {
+    let now = m.now();
+    let max = horizons.iter().max().map_or(0, Duration::as_secs);
+
+    let mut expiring: Vec<_> = kd
+        .read()
+        .await
+        .iter()
+        .filter_map(|(k, data)| {
+            let remaining = data.expires?.saturating_sub(now);
+            (remaining <= max).then(|| (k.clone(), *data, remaining))
+        })
+        .collect();
+    // Reads each page once, while it's in the scan buffer
+    expiring.sort_unstable_by_key(|(_, data, _)| (data.page_id, data.offset));
+
+    let mut scan = m.scan();
+    let mut sized = Vec::with_capacity(expiring.len());
+    for (k, data, remaining) in expiring {
+        let bytes = match scan.fetch_entry(data.page_id, data.offset).await? {
+            Some(entry) if entry.key == k => entry.len(),
+            // Moved by compaction since the KeyDir was read
+            _ => 0,
+        };
+        sized.push((remaining, bytes));
+    }
+
+    Ok(horizons
+        .iter()
+        .map(|within| {
+            let (keys, bytes) = sized
+                .iter()
+                .filter(|(remaining, _)| *remaining <= within.as_secs())
+                .fold((0, 0), |(keys, total), (_, bytes)| {
+                    (keys + 1, total + bytes)
+                });
+
+            Forecast {
+                within: *within,
+                keys,
+                bytes,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, sync::Arc, time::Duration};
+
+    use bytes::Bytes;
+    use tokio::sync::RwLock;
+
+    use crate::storagev2::{
+        clock::TestClock,
+        events::{self, Event},
+        expiry::{forecast, sweep, Forecast},
+        key_dir::bootstrap_with_report,
+        log::{Entry, EntryType},
+        page_manager::{PageCache, DEFAULT_READ_SIZE},
+        replacer::Policy,
+        testing::Fixture,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sweep() -> io::Result<()> {
+        const DB_FILE: &str = "./test_sweep.db";
+        let clock = TestClock::new(1_000_000);
+        let now = 1_000_000;
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .entry(Entry::new(b"key1", b"value1", EntryType::Put).with_expiry(now + 1))
+            .entry(Entry::new(b"key2", b"value2", EntryType::Put).with_expiry(now + 3600))
+            .put(b"key3", b"value3")
+            .build()
+            .await?;
+
+        let (kd, latest, latest_id, _) = bootstrap_with_report(&disk, now).await?;
+        assert!(kd.get(b"key1").is_some());
+
+        let kd = Arc::new(RwLock::new(kd));
+        let m = PageCache::new(
+            disk,
+            Policy::default(),
+            DEFAULT_READ_SIZE,
+            latest,
+            latest_id,
+        )
+        .with_clock(clock.clone());
+
+        let events = events::channel();
+        let mut rx = events.subscribe();
+
+        let removed = sweep(&m, &kd, &events).await;
+        assert!(removed == 0, "Got: {}", removed);
+
+        clock.advance(Duration::from_secs(2));
+        let removed = sweep(&m, &kd, &events).await;
+        assert!(removed == 1, "Got: {}", removed);
+
+        let got = rx.try_recv().ok();
+        let expected = Some(Event::Expired(Bytes::from_static(b"key1")));
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        let kd = kd.read().await;
+        assert!(kd.get(b"key1").is_none());
+        assert!(kd.get(b"key2").is_some());
+
+        // The tombstone is appended after the existing entries rather than over them
+        let data = kd.get(b"key3").expect("key3 has no expiry");
+        let page = m
+            .fetch_page(data.page_id)
+            .await?
+            .expect("should fetch page");
+        let entry = page
+            .read()
+            .await
+            .read_entry(data.offset as usize)
+            .unwrap()
+            .unwrap();
+        assert!(&entry.value[..] == b"value3", "Got: {:?}", entry);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_forecast() -> io::Result<()> {
+        const DB_FILE: &str = "./test_forecast.db";
+        let clock = TestClock::new(1_000_000);
+        let now = 1_000_000;
+        let entries = [
+            Entry::new(b"key1", b"value1", EntryType::Put).with_expiry(now + 30),
+            Entry::new(b"key2", b"value2", EntryType::Put).with_expiry(now + 600),
+            Entry::new(b"key3", b"value3", EntryType::Put).with_expiry(now + 7200),
+            Entry::new(b"key4", b"value4", EntryType::Put),
+        ];
+        let size = entries[0].len();
+        let mut fixture = Fixture::new(DB_FILE);
+        for entry in entries {
+            fixture = fixture.entry(entry);
+        }
+        let (disk, _cu) = fixture.build().await?;
+
+        let (kd, latest, latest_id, _) = bootstrap_with_report(&disk, now).await?;
+        let kd = RwLock::new(kd);
+        let m = PageCache::new(
+            disk,
+            Policy::default(),
+            DEFAULT_READ_SIZE,
+            latest,
+            latest_id,
+        )
+        .with_clock(clock.clone());
+
+        let horizons = [
+            Duration::from_secs(60),
+            Duration::from_secs(3600),
+            Duration::from_secs(86400),
+        ];
+        let got = forecast(&m, &kd, &horizons)
+            .await
+            .expect("should read entries");
+        let expected: Vec<_> = [1, 2, 3]
+            .into_iter()
+            .zip(horizons)
+            .map(|(keys, within)| Forecast {
+                within,
+                keys,
+                bytes: keys * size,
+            })
+            .collect();
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        // Due ones count towards the shortest horizon as time passes
+        clock.advance(Duration::from_secs(600));
+        let got = forecast(&m, &kd, &horizons[..1])
+            .await
+            .expect("should read entries");
+        assert!(got[0].keys == 2, "Got: {:?}", got);
+
+        Ok(())
+    }
+}
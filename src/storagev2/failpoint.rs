@@ -0,0 +1,104 @@
+//! Named points in the write path where crash tests can kill the engine, compiled in with the
+//! failpoints feature. Once the armed failpoint is reached the engine behaves as if the process
+//! died there: the operation at the failpoint fails, and so does every failpoint after it, so
+//! nothing more reaches the disk.
+
+/// Returns an error from the enclosing function if the engine crashes at failpoint `$name`.
+#[cfg(feature = "failpoints")]
+#[macro_export]
+macro_rules! fail_point {
+    ($name:expr) => {
+        if $crate::storagev2::failpoint::eval($name).is_some() {
+            return Err($crate::storagev2::failpoint::error($name).into());
+        }
+    };
+}
+
+#[cfg(not(feature = "failpoints"))]
+#[macro_export]
+macro_rules! fail_point {
+    ($name:expr) => {};
+}
+
+#[cfg(feature = "failpoints")]
+pub use armed::*;
+
+#[cfg(feature = "failpoints")]
+mod armed {
+    use std::{io, sync::Mutex};
+
+    /// Every failpoint, in the order a write reaches them.
+    pub const FAILPOINTS: &[&str] = &[
+        "disk::write_page",
+        "disk::sync",
+        "compaction::flush",
+        "compaction::reclaim",
+        "disk::punch_page",
+        "checkpoint::write",
+        "checkpoint::rename",
+    ];
+
+    /// What reaches the disk at the failpoint the engine crashes at.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Crash {
+        Nothing,
+        /// The first half of the page being written, for `disk::write_page`.
+        Torn,
+    }
+
+    struct State {
+        armed: Option<(&'static str, usize, Crash)>,
+        crashed: Option<&'static str>,
+    }
+
+    static STATE: Mutex<State> = Mutex::new(State {
+        armed: None,
+        crashed: None,
+    });
+
+    /// Crashes the engine the time `name` is passed after `skip` passes.
+    pub fn arm(name: &'static str, skip: usize, crash: Crash) {
+        let mut state = STATE.lock().unwrap();
+        state.armed = Some((name, skip, crash));
+        state.crashed = None;
+    }
+
+    /// Disarms the failpoint and brings the engine back, as a restart would.
+    pub fn reset() {
+        let mut state = STATE.lock().unwrap();
+        state.armed = None;
+        state.crashed = None;
+    }
+
+    /// The failpoint the engine crashed at, if it has.
+    pub fn crashed() -> Option<&'static str> {
+        STATE.lock().unwrap().crashed
+    }
+
+    /// Called at failpoint `name`, returns what reaches the disk if the engine crashes here or
+    /// already has.
+    pub fn eval(name: &str) -> Option<Crash> {
+        let mut state = STATE.lock().unwrap();
+        if state.crashed.is_some() {
+            return Some(Crash::Nothing);
+        }
+
+        let (armed, skip, crash) = state.armed.as_mut()?;
+        if *armed != name {
+            return None;
+        }
+        if *skip > 0 {
+            *skip -= 1;
+            return None;
+        }
+
+        let crash = *crash;
+        state.crashed = Some(state.armed.take()?.0);
+
+        Some(crash)
+    }
+
+    pub fn error(name: &str) -> io::Error {
+        io::Error::other(format!("crashed at failpoint {}", name))
+    }
+}
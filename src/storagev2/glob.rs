@@ -0,0 +1,133 @@
+//! Glob patterns over keys, which are bytes rather than strings: `*` matches any run of bytes,
+//! `?` any one byte, `[abc]`, `[a-z]` and `[^a]` a byte in or out of a class, and `\` escapes
+//! the byte after it.
+
+/// Whether `pattern` matches the whole of `s`.
+pub fn matches(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // Where to resume after the last `*`: the pattern after it, and the next byte it can absorb
+    let mut backtrack = None;
+
+    while i < s.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p + 1, i));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match_class(pattern, p, s[i]),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == s[i]).then_some(p + 2),
+            Some(b) => (*b == s[i]).then_some(p + 1),
+            None => None,
+        };
+
+        match (step, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                i += 1;
+            }
+            (None, Some((star, absorbed))) => {
+                p = star;
+                i = absorbed + 1;
+                backtrack = Some((star, absorbed + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|b| *b == b'*')
+}
+
+/// The bytes every match starts with, to narrow down the keys worth matching.
+pub fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|b| matches!(b, b'*' | b'?' | b'[' | b'\\'))
+        .unwrap_or(pattern.len());
+
+    &pattern[..end]
+}
+
+/// Matches `b` against the class starting at `pattern[start]`, returning where the pattern
+/// continues after it. An unclosed `[` is matched literally.
+fn match_class(pattern: &[u8], start: usize, b: u8) -> Option<usize> {
+    let mut p = start + 1;
+    let negated = matches!(pattern.get(p), Some(b'^' | b'!'));
+    if negated {
+        p += 1;
+    }
+
+    let mut found = false;
+    let mut first = true;
+    loop {
+        let c = match pattern.get(p) {
+            Some(b']') if !first => break,
+            Some(b'\\') if p + 1 < pattern.len() => {
+                p += 1;
+                pattern[p]
+            }
+            Some(c) => *c,
+            None => return (b == b'[').then_some(start + 1),
+        };
+        first = false;
+
+        match (pattern.get(p + 1), pattern.get(p + 2)) {
+            (Some(b'-'), Some(&hi)) if hi != b']' => {
+                found |= (c.min(hi)..=c.max(hi)).contains(&b);
+                p += 3;
+            }
+            _ => {
+                found |= c == b;
+                p += 1;
+            }
+        }
+    }
+
+    (found != negated).then_some(p + 1)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storagev2::glob::{literal_prefix, matches};
+
+    #[test]
+    fn test_glob() {
+        let cases: &[(&[u8], &[u8], bool)] = &[
+            (b"*", b"", true),
+            (b"user:*", b"user:1", true),
+            (b"user:*", b"users", false),
+            (b"*:name", b"user:1:name", true),
+            (b"a*b*c", b"axxbyyc", true),
+            (b"a*b*c", b"axxbyy", false),
+            (b"h?llo", b"hello", true),
+            (b"h?llo", b"hllo", false),
+            (b"h[ae]llo", b"hallo", true),
+            (b"h[ae]llo", b"hillo", false),
+            (b"h[^e]llo", b"hallo", true),
+            (b"h[^e]llo", b"hello", false),
+            (b"key[0-9]", b"key7", true),
+            (b"key[0-9]", b"keya", false),
+            (b"[]]", b"]", true),
+            (b"a\\*", b"a*", true),
+            (b"a\\*", b"ab", false),
+            (b"a[", b"a[", true),
+            (b"k*", b"k\xff\x00", true),
+        ];
+
+        for (pattern, s, expected) in cases {
+            let got = matches(pattern, s);
+            assert!(
+                got == *expected,
+                "{:?} {:?}\nExpected: {:?}\nGot: {:?}\n",
+                String::from_utf8_lossy(pattern),
+                String::from_utf8_lossy(s),
+                expected,
+                got
+            );
+        }
+
+        assert!(literal_prefix(b"user:*:name") == b"user:");
+        assert!(literal_prefix(b"plain") == b"plain");
+    }
+}
@@ -0,0 +1,159 @@
+//! A `KeyDir` laid out sorted by key behind a table of where each record starts, so it can be
+//! memory mapped and searched in place. A lookup only pages in the parts of the table and the
+//! records it bisects, and loading the whole `KeyDir` is a single pass in key order into a map
+//! that is sized up front.
+
+use std::{cmp::Ordering, io};
+
+use bytes::{BufMut, BytesMut};
+
+use crate::storagev2::key_dir::{self, KeyData, KeyDir};
+
+const OFFSET_LEN: usize = 8;
+
+/// Encodes `kd` as: count u64 | [record_offset u64; count] | records, with the records sorted by
+/// key, see `key_dir::encode_record`, and their offsets counted from the first one.
+pub fn encode(kd: &KeyDir) -> BytesMut {
+    let mut keys: Vec<_> = kd.iter().collect();
+    keys.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+    let mut records = BytesMut::new();
+    let mut dst = BytesMut::with_capacity(8 + keys.len() * OFFSET_LEN);
+    dst.put_u64(keys.len() as u64);
+    for (k, data) in keys {
+        dst.put_u64(records.len() as u64);
+        key_dir::encode_record(&mut records, k, data);
+    }
+    dst.put_slice(&records);
+
+    dst
+}
+
+/// An encoded hint, read where it lies.
+pub struct HintIndex<'a> {
+    offsets: &'a [u8],
+    records: &'a [u8],
+}
+
+impl<'a> HintIndex<'a> {
+    /// Only the size of the offset table is checked, records are checked as they're read.
+    pub fn new(src: &'a [u8]) -> io::Result<Self> {
+        let (count, rest) = src
+            .split_first_chunk::<8>()
+            .ok_or_else(|| corrupt("truncated"))?;
+        let table_len = u64::from_be_bytes(*count)
+            .checked_mul(OFFSET_LEN as u64)
+            .filter(|len| *len <= rest.len() as u64)
+            .ok_or_else(|| corrupt("truncated offsets"))?;
+        let (offsets, records) = rest.split_at(table_len as usize);
+
+        Ok(Self { offsets, records })
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len() / OFFSET_LEN
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Binary searches for `k`.
+    pub fn get(&self, k: &[u8]) -> io::Result<Option<KeyData>> {
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let (key, data) = self.record(mid)?;
+            match key.cmp(k) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Ok(Some(data)),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Every key and where it lives, in key order.
+    pub fn iter(&self) -> impl Iterator<Item = io::Result<(&'a [u8], KeyData)>> + '_ {
+        (0..self.len()).map(|i| self.record(i))
+    }
+
+    /// Loads every key into a `KeyDir`. Fails if the keys aren't in strictly increasing order, as
+    /// `get` relies on that.
+    pub fn to_key_dir(&self) -> io::Result<KeyDir> {
+        let mut keys = Vec::with_capacity(self.len());
+        for record in self.iter() {
+            let (k, data) = record?;
+            if keys.last().is_some_and(|(last, _)| *last >= k) {
+                return Err(corrupt("keys out of order"));
+            }
+            keys.push((k, data));
+        }
+
+        Ok(KeyDir::from_unique(keys.len(), keys))
+    }
+
+    fn record(&self, i: usize) -> io::Result<(&'a [u8], KeyData)> {
+        let offset = &self.offsets[i * OFFSET_LEN..(i + 1) * OFFSET_LEN];
+        let offset = u64::from_be_bytes(offset.try_into().expect("offset is 8 bytes"));
+
+        let mut src = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.records.get(offset..))
+            .ok_or_else(|| corrupt("record out of bounds"))?;
+
+        key_dir::decode_record(&mut src)
+    }
+}
+
+fn corrupt(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt hint: {}", reason),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::storagev2::{
+        hint::{self, HintIndex},
+        key_dir::{KeyData, KeyDir},
+    };
+
+    #[test]
+    fn test_hint() -> io::Result<()> {
+        let mut kd = KeyDir::from_unique(0, []);
+        for i in 0..100u32 {
+            let data = KeyData::new(i / 10, i as u64 * 16).with_owner((i % 2 == 0).then_some(i));
+            kd.insert(format!("key{:03}", i).as_bytes(), data);
+        }
+
+        let encoded = hint::encode(&kd);
+        let index = HintIndex::new(&encoded)?;
+        assert!(index.len() == 100, "Got: {}", index.len());
+
+        for (k, expected) in kd.iter() {
+            let got = index.get(k)?;
+            assert!(
+                got.as_ref() == Some(expected),
+                "{:?}\nExpected: {:?}\nGot: {:?}\n",
+                k,
+                expected,
+                got
+            );
+        }
+        for k in [&b"key"[..], b"key0005", b"key100", b"zzz"] {
+            assert!(index.get(k)?.is_none(), "{:?} should be missing", k);
+        }
+
+        let got = index.to_key_dir()?;
+        assert!(got == kd, "\nExpected: {:?}\nGot: {:?}\n", kd, got);
+
+        assert!(HintIndex::new(&encoded[..12]).is_err());
+
+        Ok(())
+    }
+}
@@ -1,30 +1,109 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeSet, BinaryHeap, HashMap},
+    io,
+    ops::Bound,
+    time::{Duration, Instant},
+};
 
-use bytes::BytesMut;
+use bytes::{Buf, BufMut, BytesMut};
 
 use crate::storagev2::{
     disk::Disk,
-    log::EntryType,
-    page::{Page, PageID, PAGE_SIZE},
+    glob,
+    log::{self, EntryType},
+    page::{self, Page, PageID, PageInner, PageTrailer, PAGE_SIZE},
 };
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct KeyData {
     pub page_id: PageID,
     pub offset: u64,
+    pub expires: Option<u64>,
+    pub owner: Option<u32>,
+    /// Highest fencing token a write to the key has carried. Writes with a lower token are
+    /// rejected.
+    pub fence: Option<u64>,
+    /// Length of the entry in the data file, 0 if it isn't known.
+    pub len: u32,
 }
 
 impl KeyData {
     pub fn new(page_id: PageID, offset: u64) -> Self {
-        Self { page_id, offset }
+        Self {
+            page_id,
+            offset,
+            expires: None,
+            owner: None,
+            fence: None,
+            len: 0,
+        }
+    }
+
+    pub fn with_expiry(mut self, expires: Option<u64>) -> Self {
+        self.expires = expires;
+
+        self
+    }
+
+    pub fn with_owner(mut self, owner: Option<u32>) -> Self {
+        self.owner = owner;
+
+        self
+    }
+
+    pub fn with_fence(mut self, fence: Option<u64>) -> Self {
+        self.fence = fence;
+
+        self
+    }
+
+    pub fn with_len(mut self, len: usize) -> Self {
+        self.len = len as u32;
+
+        self
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires, Some(e) if e <= now)
     }
 }
 
 type KeyDirMap = HashMap<BytesMut, KeyData>;
 
-#[derive(Debug, PartialEq)]
+// Flags for the optional fields of an encoded `KeyData`, matching the entry flags in log.rs
+const EXPIRES_FLAG: u8 = 0x80;
+const OWNER_FLAG: u8 = 0x20;
+const FENCE_FLAG: u8 = 0x10;
+// Entries have no length field, records written before it was added have no length
+const LEN_FLAG: u8 = 0x01;
+
+/// Number of pages scanned between yields back to the executor, so a long bootstrap doesn't
+/// starve other tasks on the same worker.
+const YIELD_EVERY: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct KeyDir {
     inner: KeyDirMap,
+    /// Total length of the keys, kept up to date as they come and go.
+    key_bytes: usize,
+    /// Total length of the entries the keys point at, see `KeyData::len`.
+    entry_bytes: usize,
+    /// The keys in order, if kept, so scans and ranges don't have to visit every key.
+    ordered: Option<BTreeSet<BytesMut>>,
+}
+
+impl From<KeyDirMap> for KeyDir {
+    fn from(inner: KeyDirMap) -> Self {
+        let key_bytes = inner.keys().map(|k| k.len()).sum();
+        let entry_bytes = inner.values().map(|data| data.len as usize).sum();
+
+        Self {
+            inner,
+            key_bytes,
+            entry_bytes,
+            ordered: None,
+        }
+    }
 }
 
 impl KeyDir {
@@ -34,52 +113,462 @@ impl KeyDir {
 
     pub fn insert(&mut self, k: &[u8], v: KeyData) -> Option<KeyData> {
         let k = BytesMut::from(k);
+        let len = k.len();
 
-        self.inner.insert(k, v)
+        self.entry_bytes += v.len as usize;
+        let prev = self.inner.insert(k.clone(), v);
+        match prev {
+            Some(prev) => self.entry_bytes -= prev.len as usize,
+            None => {
+                self.key_bytes += len;
+                if let Some(ordered) = &mut self.ordered {
+                    ordered.insert(k);
+                }
+            }
+        }
+
+        prev
     }
 
     pub fn remove(&mut self, k: &[u8]) -> Option<KeyData> {
-        self.inner.remove(k)
+        let prev = self.inner.remove(k);
+        if let Some(prev) = prev {
+            self.key_bytes -= k.len();
+            self.entry_bytes -= prev.len as usize;
+            if let Some(ordered) = &mut self.ordered {
+                ordered.remove(k);
+            }
+        }
+
+        prev
+    }
+
+    /// Keeps the keys in order as well, making `scan`, `scan_glob` and `range` proportional to
+    /// the keys they visit rather than to every key, for the memory of a second copy of them.
+    pub fn with_ordered_index(mut self) -> Self {
+        self.ordered = Some(self.inner.keys().cloned().collect());
+
+        self
+    }
+
+    pub fn has_ordered_index(&self) -> bool {
+        self.ordered.is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&BytesMut, &KeyData)> {
+        self.inner.iter()
+    }
+
+    pub fn expired(&self, now: u64) -> Vec<BytesMut> {
+        self.inner
+            .iter()
+            .filter(|(_, data)| data.is_expired(now))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Total length of the keys, without their values.
+    pub fn key_bytes(&self) -> usize {
+        self.key_bytes
+    }
+
+    /// Total length of the live entries in the data file, keys and values included.
+    pub fn entry_bytes(&self) -> usize {
+        self.entry_bytes
+    }
+
+    /// Roughly how many bytes a live key takes up, in memory and in the data file together.
+    pub fn memory_usage(&self, k: &[u8], now: u64) -> Option<usize> {
+        let data = self.inner.get(k).filter(|data| !data.is_expired(now))?;
+        let index = match self.ordered {
+            Some(_) => size_of::<BytesMut>() + k.len(),
+            None => 0,
+        };
+
+        Some(size_of::<BytesMut>() + k.len() + size_of::<KeyData>() + index + data.len as usize)
+    }
+
+    /// Serializes the `KeyDir` so it can be loaded without scanning the data file, as a run of
+    /// records, see `encode_record`.
+    pub fn encode(&self) -> BytesMut {
+        let mut dst = BytesMut::new();
+        for (k, data) in &self.inner {
+            encode_record(&mut dst, k, data);
+        }
+
+        dst
+    }
+
+    pub fn decode(mut src: &[u8]) -> io::Result<Self> {
+        let mut inner = HashMap::new();
+        while src.has_remaining() {
+            let (k, data) = decode_record(&mut src)?;
+            inner.insert(BytesMut::from(k), data);
+        }
+
+        Ok(Self::from(inner))
+    }
+
+    /// Builds a `KeyDir` from keys that are known to be unique, sized for all of them up front.
+    pub fn from_unique<'a>(
+        len: usize,
+        keys: impl IntoIterator<Item = (&'a [u8], KeyData)>,
+    ) -> Self {
+        let mut inner = HashMap::with_capacity(len);
+        for (k, data) in keys {
+            inner.insert(BytesMut::from(k), data);
+        }
+
+        Self::from(inner)
+    }
+
+    /// Returns up to `count` live keys starting with `prefix` that sort after `after`, in order.
+    /// Continuing from the last key returned visits every key that exists for the whole scan
+    /// exactly once, however the map is modified in between.
+    pub fn scan(
+        &self,
+        after: Option<&[u8]>,
+        prefix: &[u8],
+        count: usize,
+        now: u64,
+    ) -> Vec<BytesMut> {
+        self.scan_by(after, prefix, count, now, |_| true)
+    }
+
+    /// Like `scan`, over the keys matching a glob `pattern`, see `glob::matches`.
+    pub fn scan_glob(
+        &self,
+        after: Option<&[u8]>,
+        pattern: &[u8],
+        count: usize,
+        now: u64,
+    ) -> Vec<BytesMut> {
+        let prefix = glob::literal_prefix(pattern);
+
+        self.scan_by(after, prefix, count, now, |k| glob::matches(pattern, k))
+    }
+
+    /// Returns up to `count` live keys from `start` up to but not including `end`, in order.
+    pub fn range(&self, start: &[u8], end: &[u8], count: usize, now: u64) -> Vec<BytesMut> {
+        if start >= end {
+            return Vec::new();
+        }
+
+        match &self.ordered {
+            Some(ordered) => ordered
+                .range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+                .filter(|k| !self.inner[&k[..]].is_expired(now))
+                .take(count)
+                .cloned()
+                .collect(),
+            None => self.filter_sorted(count, now, |k| k >= start && k < end),
+        }
+    }
+
+    fn scan_by(
+        &self,
+        after: Option<&[u8]>,
+        prefix: &[u8],
+        count: usize,
+        now: u64,
+        f: impl Fn(&[u8]) -> bool,
+    ) -> Vec<BytesMut> {
+        let Some(ordered) = &self.ordered else {
+            return self.filter_sorted(count, now, |k| {
+                after.is_none_or(|after| k > after) && k.starts_with(prefix) && f(k)
+            });
+        };
+
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+        ordered
+            .range::<[u8], _>((start, Bound::Unbounded))
+            .take_while(|k| k.starts_with(prefix))
+            .filter(|k| !self.inner[&k[..]].is_expired(now) && f(k))
+            .take(count)
+            .cloned()
+            .collect()
+    }
+
+    /// The `count` smallest live keys that `f` accepts, visiting every key.
+    fn filter_sorted(&self, count: usize, now: u64, f: impl Fn(&[u8]) -> bool) -> Vec<BytesMut> {
+        // Max-heap of the smallest keys seen so far
+        let mut heap = BinaryHeap::with_capacity(count + 1);
+        for (k, data) in &self.inner {
+            if data.is_expired(now) || !f(k) {
+                continue;
+            }
+
+            heap.push(k);
+            if heap.len() > count {
+                heap.pop();
+            }
+        }
+
+        heap.into_sorted_vec().into_iter().cloned().collect()
+    }
+}
+
+/// Encodes a key and where it lives as: key_len u32 | key | page_id u32 | offset u64 | flags u8 |
+/// [expires u64] | [owner u32] | [fence u64] | [len u32], with the optional fields present when
+/// their flag is set.
+pub fn encode_record(dst: &mut BytesMut, k: &[u8], data: &KeyData) {
+    dst.put_u32(k.len() as u32);
+    dst.put_slice(k);
+    dst.put_u32(data.page_id);
+    dst.put_u64(data.offset);
+
+    let mut flags = 0;
+    if data.expires.is_some() {
+        flags |= EXPIRES_FLAG;
+    }
+    if data.owner.is_some() {
+        flags |= OWNER_FLAG;
+    }
+    if data.fence.is_some() {
+        flags |= FENCE_FLAG;
+    }
+    if data.len != 0 {
+        flags |= LEN_FLAG;
+    }
+    dst.put_u8(flags);
+
+    if let Some(expires) = data.expires {
+        dst.put_u64(expires);
+    }
+    if let Some(owner) = data.owner {
+        dst.put_u32(owner);
+    }
+    if let Some(fence) = data.fence {
+        dst.put_u64(fence);
+    }
+    if data.len != 0 {
+        dst.put_u32(data.len);
+    }
+}
+
+/// Reads the record at the start of `src` and advances past it, see `encode_record`.
+pub fn decode_record<'a>(src: &mut &'a [u8]) -> io::Result<(&'a [u8], KeyData)> {
+    let key_len = take(src, 4)?.get_u32() as usize;
+    let k = take(src, key_len)?;
+    let page_id = take(src, 4)?.get_u32();
+    let offset = take(src, 8)?.get_u64();
+    let flags = take(src, 1)?.get_u8();
+
+    let mut data = KeyData::new(page_id, offset);
+    if flags & EXPIRES_FLAG != 0 {
+        data.expires = Some(take(src, 8)?.get_u64());
     }
+    if flags & OWNER_FLAG != 0 {
+        data.owner = Some(take(src, 4)?.get_u32());
+    }
+    if flags & FENCE_FLAG != 0 {
+        data.fence = Some(take(src, 8)?.get_u64());
+    }
+    if flags & LEN_FLAG != 0 {
+        data.len = take(src, 4)?.get_u32();
+    }
+
+    Ok((k, data))
 }
 
-pub async fn bootstrap(disk: &Disk) -> (KeyDir, Page, PageID) {
-    let len = disk.len().await;
-    let pages = len / PAGE_SIZE;
+fn take<'a>(src: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if src.len() < n {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupt keydir: truncated",
+        ));
+    }
+
+    let (b, rest) = src.split_at(n);
+    *src = rest;
+
+    Ok(b)
+}
+
+/// What bootstrapping a data file found, kept for support requests.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BootstrapReport {
+    pub pages: usize,
+    pub entries: usize,
+    pub tombstones: usize,
+    /// Puts that had already expired, which are dropped like tombstones.
+    pub expired: usize,
+    /// Entries that failed to parse or their checksum. The rest of their page is skipped.
+    pub corrupt: usize,
+    /// Pages that didn't match their trailer, see `PageTrailer`. A torn last page is rolled back
+    /// to its previous write, or cut off if it can't be. Entries in any other are still read,
+    /// each checked on its own.
+    pub torn: usize,
+    /// Bytes cut off the end of the data file: a torn last page that couldn't be rolled back, or
+    /// a page a crash left short.
+    pub discarded_bytes: usize,
+    pub duration: Duration,
+    /// Live keys in the resulting `KeyDir`.
+    pub keys: usize,
+    /// The `KeyDir` was loaded from a shutdown checkpoint rather than by scanning the data file.
+    pub checkpoint: bool,
+}
+
+pub async fn bootstrap(disk: &Disk) -> io::Result<(KeyDir, Page, PageID)> {
+    let (kd, page, latest_id, _) = bootstrap_with_report(disk, log::now()).await?;
+
+    Ok((kd, page, latest_id))
+}
+
+/// Rebuilds the `KeyDir` from every entry in the data file, newest last, and returns the last
+/// page to carry on writing to. Entries that expired by `now` are left out.
+pub async fn bootstrap_with_report(
+    disk: &Disk,
+    now: u64,
+) -> io::Result<(KeyDir, Page, PageID, BootstrapReport)> {
+    let start = Instant::now();
+    let mut report = BootstrapReport::default();
+
+    let len = disk.len().await?;
+    let trailers = disk.header().has_trailers();
+    if trailers && len % PAGE_SIZE != 0 {
+        eprintln!(
+            "error: data file ends {} bytes into a page, cutting them off",
+            len % PAGE_SIZE
+        );
+        disk.truncate((len / PAGE_SIZE) as PageID).await?;
+        report.discarded_bytes += len % PAGE_SIZE;
+    }
+
+    // Pages in deleted segments are empty. Writes carry on after them even if nothing was written
+    // past them before a crash.
+    let first = disk.first_page();
+    let pages = match first {
+        0 => len / PAGE_SIZE,
+        first => (len / PAGE_SIZE).max(first as usize + 1),
+    };
 
     let page = Page::default();
     let mut page_w = page.write().await;
     let mut inner = HashMap::new();
-    for page_id in 0..pages as u32 {
-        page_w.data = disk.read_page(page_id).expect("should read page");
-        page_w.id = page_id;
-        // Could probably get away with not fully resetting the page on each iteration
+    let mut lsn = 0;
+    for page_id in first..pages as u32 {
+        let mut data = disk.read_stored_page(page_id).await?;
+        if trailers {
+            let last = page_id as usize + 1 == pages;
+            lsn = lsn.max(check_page(disk, page_id, &mut data, last, &mut report).await?);
+        }
+        let data = page::decompress(&data).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupt compressed page {}", page_id),
+            )
+        })?;
+        *page_w = PageInner::from_bytes(page_id, data);
+        report.pages += 1;
 
         let mut offset = 0;
-        while let Some(entry) = page_w.read_entry(offset) {
+        loop {
+            let entry = match page_w.read_entry(offset) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!(
+                        "error: {:?} entry at page {} offset {}, skipping rest of page",
+                        e, page_id, offset
+                    );
+                    report.corrupt += 1;
+                    break;
+                }
+            };
+            report.entries += 1;
+
             match entry.t {
+                EntryType::Put if entry.is_expired(now) => {
+                    inner.remove(&entry.key);
+                    report.expired += 1;
+                }
                 EntryType::Put => {
                     inner.insert(
                         entry.key.clone(),
-                        KeyData {
-                            page_id,
-                            offset: offset as u64,
-                        },
+                        KeyData::new(page_id, offset as u64)
+                            .with_expiry(entry.expires)
+                            .with_owner(entry.owner)
+                            .with_fence(entry.fence)
+                            .with_len(entry.len()),
                     );
                 }
                 EntryType::Delete => {
                     inner.remove(&entry.key);
+                    report.tombstones += 1;
                 }
             };
 
-            offset = offset + entry.len();
+            offset += entry.len();
+        }
+
+        if (page_id as usize + 1).is_multiple_of(YIELD_EVERY) {
+            tokio::task::yield_now().await;
         }
     }
 
     let latest_id = page_w.id;
     drop(page_w);
+    disk.observe_lsn(lsn);
+
+    report.keys = inner.len();
+    report.duration = start.elapsed();
 
-    (KeyDir { inner }, page, latest_id)
+    Ok((KeyDir::from(inner), page, latest_id, report))
+}
+
+/// Checks page `page_id` against its trailer, rolling a torn last page back to its previous write
+/// or cutting it off. Returns the page's LSN.
+async fn check_page(
+    disk: &Disk,
+    page_id: PageID,
+    data: &mut [u8; PAGE_SIZE],
+    last: bool,
+    report: &mut BootstrapReport,
+) -> io::Result<u64> {
+    match PageTrailer::check(data) {
+        Ok(trailer) => return Ok(trailer.map_or(0, |t| t.lsn)),
+        Err(_) => report.torn += 1,
+    }
+
+    if !last {
+        eprintln!(
+            "error: page {} doesn't match its trailer, checking its entries one by one",
+            page_id
+        );
+        return Ok(0);
+    }
+
+    match PageTrailer::roll_back(data) {
+        Some(rolled_back) => {
+            eprintln!(
+                "error: last page {} was torn, rolling it back to its previous write",
+                page_id
+            );
+            *data = rolled_back;
+            disk.write_page(page_id, data).await?;
+            disk.sync().await?;
+        }
+        None => {
+            eprintln!("error: last page {} was torn, cutting it off", page_id);
+            *data = [0; PAGE_SIZE];
+            disk.truncate(page_id).await?;
+            report.discarded_bytes += PAGE_SIZE;
+        }
+    }
+
+    Ok(PageTrailer::check(data).ok().flatten().map_or(0, |t| t.lsn))
 }
 
 #[cfg(test)]
@@ -87,79 +576,154 @@ mod test {
     use std::{collections::HashMap, io};
 
     use crate::storagev2::{
-        disk::Disk,
-        key_dir::{bootstrap, KeyData, KeyDir},
-        log::{Entry, EntryType},
-        page::PageInner,
+        db::{Db, Options},
+        disk::{Disk, Header},
+        key_dir::{bootstrap, bootstrap_with_report, BootstrapReport, KeyData, KeyDir},
+        log::{self, Entry, EntryType},
+        page::{PageError, PageInner, PageTrailer, PAGE_SIZE},
         test::CleanUp,
+        testing::Fixture,
     };
 
-    #[tokio::test]
-    async fn test_bootstrap() -> io::Result<()> {
-        const DB_FILE: &str = "./test_bootstrap.db";
-        let _cu = CleanUp::file(DB_FILE);
-        let disk = Disk::new(DB_FILE).await?;
+    #[test]
+    fn test_encode() -> io::Result<()> {
+        let mut key_dir = KeyDir::from(HashMap::new());
+        key_dir.insert(b"a", KeyData::new(0, 0));
+        key_dir.insert(b"b", KeyData::new(1, 20).with_expiry(Some(5)));
+        key_dir.insert(
+            b"c",
+            KeyData::new(2, 40)
+                .with_owner(Some(1000))
+                .with_fence(Some(7))
+                .with_len(30),
+        );
+
+        let encoded = key_dir.encode();
+        let got = KeyDir::decode(&encoded)?;
+        assert!(
+            got == key_dir,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            key_dir,
+            got
+        );
+
+        assert!(KeyDir::decode(&encoded[..encoded.len() - 1]).is_err());
+
+        // Overwriting a key doesn't count it twice
+        key_dir.insert(b"bb", KeyData::new(3, 0).with_len(10));
+        key_dir.insert(b"bb", KeyData::new(3, 20).with_len(20));
+        key_dir.remove(b"a");
+        key_dir.remove(b"c");
+        assert!(got.key_bytes() == 3, "Got: {}", got.key_bytes());
+        assert!(got.entry_bytes() == 30, "Got: {}", got.entry_bytes());
+        assert!(key_dir.key_bytes() == 3, "Got: {}", key_dir.key_bytes());
+        assert!(
+            key_dir.entry_bytes() == 20,
+            "Got: {}",
+            key_dir.entry_bytes()
+        );
 
-        let entries = [
-            Entry::new(b"key1", b"value1", EntryType::Put),
-            Entry::new(b"key2", b"value2", EntryType::Put),
-            Entry::new(b"key3", b"value3", EntryType::Put),
-            Entry::new(b"key4", b"value4", EntryType::Put),
-            Entry::new(b"key1", b"value1", EntryType::Delete),
-            Entry::new(b"key5", b"value5", EntryType::Put),
-            Entry::new(b"key5", b"value5", EntryType::Delete),
-            Entry::new(b"key4", b"latest", EntryType::Put),
-            Entry::new(b"key5", b"latest", EntryType::Put),
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan() {
+        for ordered in [false, true] {
+            let mut key_dir = KeyDir::from(HashMap::new());
+            if ordered {
+                key_dir = key_dir.with_ordered_index();
+            }
+            for k in ["a1", "a2", "b1", "a3", "a4", "a5"] {
+                key_dir.insert(k.as_bytes(), KeyData::new(0, 0));
+            }
+            key_dir.insert(b"a0", KeyData::new(0, 0).with_expiry(Some(1)));
+
+            let mut got = Vec::new();
+            let mut after = None;
+            loop {
+                let keys = key_dir.scan(after.as_deref(), b"a", 2, 2);
+                let Some(last) = keys.last().cloned() else {
+                    break;
+                };
+
+                // Keys changed between calls are picked up or skipped depending on where they sort
+                key_dir.insert(b"a00", KeyData::new(0, 0));
+                key_dir.remove(b"a5");
+
+                got.extend(keys);
+                after = Some(last);
+            }
+
+            let expected = ["a1", "a2", "a3", "a4"];
+            assert!(
+                got == expected,
+                "\nordered: {}\nExpected: {:?}\nGot: {:?}\n",
+                ordered,
+                expected,
+                got
+            );
+        }
+    }
+
+    #[test]
+    fn test_range() {
+        let mut unordered = KeyDir::from(HashMap::new());
+        for k in ["a", "b", "ba", "c", "d"] {
+            unordered.insert(k.as_bytes(), KeyData::new(0, 0));
+        }
+        let mut ordered = unordered.clone().with_ordered_index();
+        ordered.insert(b"bb", KeyData::new(0, 0).with_expiry(Some(1)));
+        ordered.remove(b"c");
+        ordered.insert(b"c", KeyData::new(0, 0));
+
+        let tests: [(&str, &str, usize, &[&str]); 5] = [
+            ("b", "d", 10, &["b", "ba", "c"]),
+            ("b", "d", 2, &["b", "ba"]),
+            ("", "~", 10, &["a", "b", "ba", "c", "d"]),
+            ("d", "b", 10, &[]),
+            ("c", "c", 10, &[]),
         ];
 
-        let mut current_id = 0;
-        let mut current = PageInner::new(current_id);
-        for e in entries {
-            if let Err(_) = current.write_entry(&e) {
-                disk.write_page(current.id, &current.data);
-                current_id += 1;
-                current = PageInner::new(current_id);
-                current
-                    .write_entry(&e)
-                    .expect("new current should have space");
+        for (start, end, count, expected) in tests {
+            for key_dir in [&unordered, &ordered] {
+                let got = key_dir.range(start.as_bytes(), end.as_bytes(), count, 2);
+                assert!(
+                    got == expected,
+                    "\n{:?}..{:?} ordered: {}\nExpected: {:?}\nGot: {:?}\n",
+                    start,
+                    end,
+                    key_dir.has_ordered_index(),
+                    expected,
+                    got
+                );
             }
         }
-        disk.write_page(current.id, &current.data);
-
-        let (key_dir, _, _) = bootstrap(&disk).await;
-
-        let expected = KeyDir {
-            inner: HashMap::from([
-                (
-                    "key2".into(),
-                    KeyData {
-                        page_id: 0,
-                        offset: 35,
-                    },
-                ),
-                (
-                    "key3".into(),
-                    KeyData {
-                        page_id: 0,
-                        offset: 70,
-                    },
-                ),
-                (
-                    "key4".into(),
-                    KeyData {
-                        page_id: 1,
-                        offset: 0,
-                    },
-                ),
-                (
-                    "key5".into(),
-                    KeyData {
-                        page_id: 1,
-                        offset: 35,
-                    },
-                ),
-            ]),
-        };
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap() -> io::Result<()> {
+        const DB_FILE: &str = "./test_bootstrap.db";
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .put(b"key1", b"value1")
+            .put(b"key2", b"value2")
+            .put(b"key3", b"value3")
+            .put(b"key4", b"value4")
+            .delete(b"key1")
+            .put(b"key5", b"value5")
+            .delete(b"key5")
+            .put(b"key4", b"latest")
+            .put(b"key5", b"latest")
+            .build()
+            .await?;
+
+        let (key_dir, _, _) = bootstrap(&disk).await?;
+
+        let expected = KeyDir::from(HashMap::from([
+            ("key2".into(), KeyData::new(0, 39).with_len(39)),
+            ("key3".into(), KeyData::new(0, 78).with_len(39)),
+            ("key4".into(), KeyData::new(1, 33).with_len(39)),
+            ("key5".into(), KeyData::new(1, 72).with_len(39)),
+        ]));
 
         assert!(
             key_dir == expected,
@@ -170,4 +734,228 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_bootstrap_corrupt() -> io::Result<()> {
+        const DB_FILE: &str = "./test_bootstrap_corrupt.db";
+
+        let mut corrupt = Entry::new(b"key3", b"value3", EntryType::Put).as_bytes();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .put(b"key1", b"value1")
+            .put(b"key2", b"value2")
+            .raw(&corrupt)
+            .next_page()
+            .put(b"key4", b"value4")
+            .build()
+            .await?;
+
+        let (key_dir, _, _, report) = bootstrap_with_report(&disk, log::now()).await?;
+        let expected = BootstrapReport {
+            pages: 2,
+            entries: 3,
+            corrupt: 1,
+            keys: 3,
+            duration: report.duration,
+            ..Default::default()
+        };
+        assert!(
+            report == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            report
+        );
+
+        assert!(key_dir.get(b"key1").is_some());
+        assert!(key_dir.get(b"key2").is_some());
+        assert!(key_dir.get(b"key3").is_none());
+        assert!(key_dir.get(b"key4").is_some());
+
+        let mut page = PageInner::new(0);
+        page.data = disk.read_page(0).await?;
+        let key2 = Entry::new(b"key2", b"value2", EntryType::Put);
+        let offset = key_dir.get(b"key2").unwrap().offset as usize + key2.len();
+        let got = page.read_entry(offset);
+        assert!(got == Err(PageError::Corrupt), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_full_page() -> io::Result<()> {
+        const DB_FILE: &str = "./test_bootstrap_full_page.db";
+        // 39 bytes each, ending 6 bytes short of the trailer
+        let mut fixture = Fixture::new(DB_FILE);
+        for i in 0..6 {
+            fixture = fixture.put(format!("key{}", i).as_bytes(), b"value1");
+        }
+        let (disk, _cu) = fixture.build().await?;
+
+        let (_, _, _, report) = bootstrap_with_report(&disk, log::now()).await?;
+        assert!(
+            (report.entries, report.corrupt, report.keys) == (6, 0, 6),
+            "Got: {:?}",
+            report
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_torn() -> io::Result<()> {
+        const DB_FILE: &str = "./test_bootstrap_torn.db";
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .put(b"key1", b"value1")
+            .next_page()
+            .put(b"key2", b"value2")
+            .build()
+            .await?;
+        drop(disk);
+
+        // A crash midway through appending key3 to the last page, and part of a page after it
+        let mut data = std::fs::read(DB_FILE)?;
+        let key3 = Entry::new(b"key3", b"value3", EntryType::Put).as_bytes();
+        let offset = 2 * PAGE_SIZE + key3.len();
+        data[offset..offset + key3.len()].copy_from_slice(&key3);
+        data.extend_from_slice(&[1; 10]);
+        std::fs::write(DB_FILE, &data)?;
+
+        let disk = Disk::new(DB_FILE).await?;
+        let (key_dir, _, _, report) = bootstrap_with_report(&disk, log::now()).await?;
+        assert!(
+            (report.torn, report.discarded_bytes) == (1, 10),
+            "Got: {:?}",
+            report
+        );
+        assert!(key_dir.get(b"key2").is_some());
+        assert!(key_dir.get(b"key3").is_none());
+        let got = PageTrailer::check(&disk.read_page(1).await?).map(|t| t.map(|t| t.entries));
+        assert!(got == Ok(Some(1)), "Got: {:?}", got);
+        drop(disk);
+
+        // A last page that can't be rolled back is cut off
+        let mut data = std::fs::read(DB_FILE)?;
+        data[2 * PAGE_SIZE + 1] ^= 0xff;
+        std::fs::write(DB_FILE, &data)?;
+
+        let disk = Disk::new(DB_FILE).await?;
+        let (key_dir, _, _, report) = bootstrap_with_report(&disk, log::now()).await?;
+        assert!(
+            (report.torn, report.discarded_bytes) == (1, PAGE_SIZE),
+            "Got: {:?}",
+            report
+        );
+        assert!(key_dir.get(b"key1").is_some());
+        assert!(key_dir.get(b"key2").is_none());
+        let got = std::fs::metadata(DB_FILE)?.len();
+        assert!(got == 2 * PAGE_SIZE as u64, "Got: {}", got);
+
+        Ok(())
+    }
+
+    // Databases written by earlier versions must stay readable. If this test breaks, the on-disk
+    // format changed and needs versioning or a migration rather than an updated fixture.
+    #[tokio::test]
+    async fn test_golden_v2() -> io::Result<()> {
+        const DB_FILE: &str = "./test_golden_v2.db";
+        let _cu = CleanUp::file(DB_FILE);
+        std::fs::write(DB_FILE, include_bytes!("fixtures/v2_page256.db"))?;
+        let disk = Disk::new(DB_FILE).await?;
+        assert!(disk.header() == Header::LEGACY, "Got: {:?}", disk.header());
+
+        let (key_dir, _, latest_id) = bootstrap(&disk).await?;
+        assert!(latest_id == 1, "Got: {}", latest_id);
+
+        let expected = [
+            (&b"key1"[..], None),
+            (b"key2", Some((1, 0, &b"updated"[..]))),
+            (b"key3", None),
+            (b"key4", Some((1, 36, b"value4"))),
+        ];
+
+        for (key, want) in expected {
+            let got = key_dir.get(key);
+            let Some((page_id, offset, value)) = want else {
+                assert!(got.is_none(), "{:?} should be deleted", key);
+                continue;
+            };
+
+            let data = got.expect("key should be live");
+            assert!(
+                (data.page_id, data.offset) == (page_id, offset),
+                "Got: {:?}",
+                data
+            );
+
+            let mut page = PageInner::new(page_id);
+            page.data = disk.read_page(page_id).await?;
+            let entry = page
+                .read_entry(offset as usize)
+                .expect("entry should not be corrupt")
+                .expect("entry should be readable");
+            let expected_entry = Entry {
+                t: EntryType::Put,
+                time: 1700000000,
+                expires: None,
+                owner: None,
+                fence: None,
+                group: None,
+                checksum: false,
+                key: key.into(),
+                value: value.into(),
+                compressed: None,
+            };
+
+            assert!(
+                entry == expected_entry,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected_entry,
+                entry
+            );
+            assert!(data.len as usize == entry.len(), "Got: {:?}", data);
+        }
+
+        Ok(())
+    }
+
+    // The last page of a file from before trailers were added can be filled up to `PAGE_SIZE`,
+    // past `PAGE_CAPACITY`. Writes carry on after it.
+    #[tokio::test]
+    async fn test_golden_v2_full_last_page() -> io::Result<()> {
+        const DB_FILE: &str = "./test_golden_v2_full_last_page.db";
+        let _cu = CleanUp::file(DB_FILE);
+        std::fs::write(DB_FILE, include_bytes!("fixtures/v2_full_page256.db"))?;
+
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let mut current = db.pc.get_current().await;
+        assert!(
+            (current.id, current.remaining()) == (0, 0),
+            "Got: {} {}",
+            current.id,
+            current.remaining()
+        );
+
+        let entry = Entry::new(b"key5", b"value5", EntryType::Put);
+        let offset = db.pc.write_entry(&mut current, &entry).await?;
+        assert!(
+            (current.id, offset) == (1, 0),
+            "Got: {} {}",
+            current.id,
+            offset
+        );
+        drop(current);
+        db.flush().await?;
+        drop(db);
+
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        for i in 1..=5 {
+            let k = format!("key{}", i);
+            let got = db.get(k.as_bytes()).await?;
+            assert!(got.is_some(), "{} should be live", k);
+        }
+
+        Ok(())
+    }
 }
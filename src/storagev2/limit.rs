@@ -0,0 +1,83 @@
+use std::sync::atomic::{AtomicUsize, Ordering::*};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Default for how many GETs can fetch pages from disk at once.
+pub const DEFAULT_MAX_FETCHES: usize = 64;
+
+/// Default for how many inserts can be in progress at once.
+pub const DEFAULT_MAX_INSERTS: usize = 64;
+
+/// Caps how many tasks do something at once, counting the ones waiting for their turn.
+#[derive(Debug)]
+pub struct Limit {
+    permits: Semaphore,
+    queued: AtomicUsize,
+}
+
+/// Counts a task as queued until it's dropped, including when the task gives up waiting.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Relaxed);
+    }
+}
+
+impl Limit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            permits: Semaphore::new(max),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Waits for a turn, which lasts until the permit is dropped.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.queued.fetch_add(1, Relaxed);
+        let _queued = Queued(&self.queued);
+
+        self.permits.acquire().await.expect("limit closed")
+    }
+
+    /// Number of tasks waiting for a turn.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::storagev2::limit::Limit;
+
+    #[tokio::test]
+    async fn test_limit() {
+        let limit = Arc::new(Limit::new(1));
+        let first = limit.acquire().await;
+
+        let waiting = tokio::spawn({
+            let limit = limit.clone();
+            async move {
+                let _permit = limit.acquire().await;
+            }
+        });
+        let cancelled = tokio::spawn({
+            let limit = limit.clone();
+            async move {
+                let _permit = limit.acquire().await;
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(limit.queued() == 2, "Got: {}", limit.queued());
+
+        cancelled.abort();
+        let _ = cancelled.await;
+        assert!(limit.queued() == 1, "Got: {}", limit.queued());
+
+        drop(first);
+        waiting.await.expect("waiting task panicked");
+        assert!(limit.queued() == 0, "Got: {}", limit.queued());
+    }
+}
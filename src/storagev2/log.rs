@@ -18,53 +18,259 @@ impl From<u8> for EntryType {
     }
 }
 
-impl Into<u8> for EntryType {
-    fn into(self) -> u8 {
-        match self {
+impl From<EntryType> for u8 {
+    fn from(t: EntryType) -> u8 {
+        match t {
             EntryType::Put => 0,
             EntryType::Delete => 1,
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time before UNIX epoch")
+        .as_secs()
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Entry {
     pub t: EntryType,
     pub time: u64,
+    pub expires: Option<u64>,
+    pub owner: Option<u32>,
+    pub fence: Option<u64>,
+    /// Locality group the entry was written under, see `group_id`.
+    pub group: Option<u32>,
+    pub checksum: bool,
     pub key: BytesMut,
     pub value: BytesMut,
+    /// The value as it's stored when compressed, see `with_compression`.
+    pub compressed: Option<BytesMut>,
 }
 
 impl Entry {
     // t + time + key_s + value_s
     pub const METADATA_LEN: usize = 1 + 8 + 8 + 8;
+
+    // Set on the type byte when an expiry timestamp follows the metadata
+    pub const EXPIRES_FLAG: u8 = 0x80;
+    pub const EXPIRES_LEN: usize = 8;
+
+    // Set on the type byte when a CRC32 of the rest of the entry follows the metadata (and
+    // expiry). Entries written before checksums were added don't have one
+    pub const CHECKSUM_FLAG: u8 = 0x40;
+    pub const CHECKSUM_LEN: usize = 4;
+
+    // Set on the type byte when the uid of the user that owns the key follows the expiry
+    pub const OWNER_FLAG: u8 = 0x20;
+    pub const OWNER_LEN: usize = 4;
+
+    // Set on the type byte when the fencing token the write carried follows the owner
+    pub const FENCE_FLAG: u8 = 0x10;
+    pub const FENCE_LEN: usize = 8;
+
+    // Set on the type byte when the id of the entry's locality group follows the fence
+    pub const GROUP_FLAG: u8 = 0x04;
+    pub const GROUP_LEN: usize = 4;
+
+    // Set on the type byte when the stored value is LZ4 compressed, prefixed with its
+    // uncompressed length
+    pub const COMPRESSED_FLAG: u8 = 0x08;
+
+    /// Largest value a compressed entry is allowed to decompress to.
+    pub const MAX_DECOMPRESSED_LEN: usize = 16 * 1024 * 1024;
+
+    /// Longest an entry's header can be, with every optional field set.
+    pub const MAX_HEADER_LEN: usize = Self::METADATA_LEN
+        + Self::EXPIRES_LEN
+        + Self::CHECKSUM_LEN
+        + Self::OWNER_LEN
+        + Self::FENCE_LEN
+        + Self::GROUP_LEN;
+
+    pub const FLAGS: u8 = Self::EXPIRES_FLAG
+        | Self::CHECKSUM_FLAG
+        | Self::OWNER_FLAG
+        | Self::FENCE_FLAG
+        | Self::GROUP_FLAG
+        | Self::COMPRESSED_FLAG;
+
     pub fn len(&self) -> usize {
-        Self::METADATA_LEN + self.key.len() + self.value.len()
+        let expires = match self.expires {
+            Some(_) => Self::EXPIRES_LEN,
+            None => 0,
+        };
+        let owner = match self.owner {
+            Some(_) => Self::OWNER_LEN,
+            None => 0,
+        };
+        let fence = match self.fence {
+            Some(_) => Self::FENCE_LEN,
+            None => 0,
+        };
+        let group = match self.group {
+            Some(_) => Self::GROUP_LEN,
+            None => 0,
+        };
+        let checksum = match self.checksum {
+            true => Self::CHECKSUM_LEN,
+            false => 0,
+        };
+
+        Self::METADATA_LEN
+            + expires
+            + owner
+            + fence
+            + group
+            + checksum
+            + self.key.len()
+            + self.stored().len()
     }
 
-    pub fn new(key: &[u8], value: &[u8], t: EntryType) -> Entry {
-        let time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time before UNIX epoch")
-            .as_secs();
+    /// The value as written to disk.
+    pub fn stored(&self) -> &[u8] {
+        self.compressed.as_deref().unwrap_or(&self.value)
+    }
 
+    pub fn new(key: &[u8], value: &[u8], t: EntryType) -> Entry {
         Entry {
             t,
-            time,
+            time: now(),
+            expires: None,
+            owner: None,
+            fence: None,
+            group: None,
+            checksum: true,
             key: key.into(),
             value: value.into(),
+            compressed: None,
+        }
+    }
+
+    /// Stores the value compressed if it's longer than `threshold` bytes and compressing it
+    /// saves space.
+    pub fn with_compression(mut self, threshold: usize) -> Entry {
+        if self.value.len() <= threshold || self.value.len() > Self::MAX_DECOMPRESSED_LEN {
+            return self;
         }
+
+        let compressed = lz4_flex::compress_prepend_size(&self.value);
+        if compressed.len() < self.value.len() {
+            self.compressed = Some(compressed[..].into());
+        }
+
+        self
+    }
+
+    pub fn with_expiry(mut self, expires: u64) -> Entry {
+        self.expires = Some(expires);
+
+        self
+    }
+
+    pub fn with_time(mut self, time: u64) -> Entry {
+        self.time = time;
+
+        self
+    }
+
+    pub fn with_owner(mut self, owner: Option<u32>) -> Entry {
+        self.owner = owner;
+
+        self
+    }
+
+    pub fn with_fence(mut self, fence: Option<u64>) -> Entry {
+        self.fence = fence;
+
+        self
+    }
+
+    pub fn with_group(mut self, group: Option<u32>) -> Entry {
+        self.group = group;
+
+        self
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires, Some(e) if e <= now)
     }
 
     pub fn as_bytes(&self) -> BytesMut {
+        let mut t: u8 = self.t.into();
+        if self.expires.is_some() {
+            t |= Self::EXPIRES_FLAG;
+        }
+        if self.owner.is_some() {
+            t |= Self::OWNER_FLAG;
+        }
+        if self.fence.is_some() {
+            t |= Self::FENCE_FLAG;
+        }
+        if self.group.is_some() {
+            t |= Self::GROUP_FLAG;
+        }
+        if self.checksum {
+            t |= Self::CHECKSUM_FLAG;
+        }
+        if self.compressed.is_some() {
+            t |= Self::COMPRESSED_FLAG;
+        }
+
         let mut ret = BytesMut::with_capacity(self.len());
-        ret.put_u8(self.t.into());
+        ret.put_u8(t);
         ret.put_u64(self.time);
         ret.put_u64(self.key.len() as u64);
-        ret.put_u64(self.value.len() as u64);
+        ret.put_u64(self.stored().len() as u64);
+        if let Some(expires) = self.expires {
+            ret.put_u64(expires);
+        }
+        if let Some(owner) = self.owner {
+            ret.put_u32(owner);
+        }
+        if let Some(fence) = self.fence {
+            ret.put_u64(fence);
+        }
+        if let Some(group) = self.group {
+            ret.put_u32(group);
+        }
+        if self.checksum {
+            let crc = checksum(&ret, &self.key, self.stored());
+            ret.put_u32(crc);
+        }
         ret.put(self.key.clone());
-        ret.put(self.value.clone());
+        ret.put_slice(self.stored());
 
         ret
     }
 }
+
+/// Reverses `Entry::with_compression`. Returns `None` if `stored` isn't a valid compressed value.
+pub fn decompress(stored: &[u8]) -> Option<BytesMut> {
+    let len = u32::from_le_bytes(stored.get(..4)?.try_into().ok()?) as usize;
+    if len > Entry::MAX_DECOMPRESSED_LEN {
+        return None;
+    }
+
+    lz4_flex::decompress_size_prepended(stored)
+        .ok()
+        .map(|v| v[..].into())
+}
+
+/// Id a locality group is stored under. Groups whose names collide just share pages.
+pub fn group_id(name: &[u8]) -> u32 {
+    crc32fast::hash(name)
+}
+
+/// CRC32 over the entry header (including the expiry, owner, fence and group), key and stored
+/// value.
+pub fn checksum(header: &[u8], key: &[u8], value: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(key);
+    hasher.update(value);
+
+    hasher.finalize()
+}
@@ -0,0 +1,152 @@
+//! Merge operators, for changing a value based on what it is now in a single write, rather than
+//! a client reading it, computing the new value and racing other writers to insert it.
+
+use std::{fmt, io, str::FromStr};
+
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeOp {
+    /// Appends the operand to the value.
+    Append,
+    /// Adds the operand to the value, both signed 64-bit integers in decimal.
+    Add,
+    /// Applies the operand to the value as a JSON merge patch, see RFC 7396: objects are merged
+    /// key by key, nulls remove keys and anything else replaces what was there.
+    JsonPatch,
+}
+
+impl FromStr for MergeOp {
+    type Err = io::Error;
+
+    /// Parses `append`, `add` or `json-patch`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "append" => Ok(MergeOp::Append),
+            "add" => Ok(MergeOp::Add),
+            "json-patch" => Ok(MergeOp::JsonPatch),
+            _ => Err(invalid(format!("unknown merge operator {:?}", s))),
+        }
+    }
+}
+
+impl fmt::Display for MergeOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeOp::Append => write!(f, "append"),
+            MergeOp::Add => write!(f, "add"),
+            MergeOp::JsonPatch => write!(f, "json-patch"),
+        }
+    }
+}
+
+impl MergeOp {
+    /// The value `current` becomes with `operand` merged in. A key that isn't set merges as an
+    /// empty value, zero or null.
+    pub fn apply(&self, current: Option<&[u8]>, operand: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            MergeOp::Append => Ok([current.unwrap_or_default(), operand].concat()),
+            MergeOp::Add => {
+                let n = match current {
+                    Some(current) => {
+                        integer(current).ok_or_else(|| invalid("value is not an integer"))?
+                    }
+                    None => 0,
+                };
+                let operand =
+                    integer(operand).ok_or_else(|| invalid("operand is not an integer"))?;
+
+                n.checked_add(operand)
+                    .map(|n| n.to_string().into_bytes())
+                    .ok_or_else(|| invalid("addition would overflow"))
+            }
+            MergeOp::JsonPatch => {
+                let mut doc = match current {
+                    Some(current) => serde_json::from_slice(current)
+                        .map_err(|_| invalid("value is not valid JSON"))?,
+                    None => Value::Null,
+                };
+                let patch = serde_json::from_slice(operand)
+                    .map_err(|_| invalid("operand is not valid JSON"))?;
+                merge_patch(&mut doc, patch);
+
+                Ok(serde_json::to_vec(&doc)?)
+            }
+        }
+    }
+}
+
+fn integer(b: &[u8]) -> Option<i64> {
+    std::str::from_utf8(b).ok()?.parse().ok()
+}
+
+fn merge_patch(doc: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *doc = patch;
+        return;
+    };
+
+    if !doc.is_object() {
+        *doc = Value::Object(Map::new());
+    }
+    if let Value::Object(doc) = doc {
+        for (k, v) in patch {
+            match v {
+                Value::Null => {
+                    doc.remove(&k);
+                }
+                v => merge_patch(doc.entry(k).or_insert(Value::Null), v),
+            }
+        }
+    }
+}
+
+fn invalid(e: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.into())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storagev2::merge::MergeOp;
+
+    #[test]
+    fn test_merge_op() {
+        let tests = [
+            (MergeOp::Append, None, "abc", Some("abc")),
+            (MergeOp::Append, Some("abc"), "def", Some("abcdef")),
+            (MergeOp::Add, None, "5", Some("5")),
+            (MergeOp::Add, Some("5"), "-7", Some("-2")),
+            (MergeOp::Add, Some("five"), "1", None),
+            (MergeOp::Add, Some("9223372036854775807"), "1", None),
+            (
+                MergeOp::JsonPatch,
+                Some(r#"{"a":1,"b":{"c":2,"d":3}}"#),
+                r#"{"a":null,"b":{"c":4},"e":[5]}"#,
+                Some(r#"{"b":{"c":4,"d":3},"e":[5]}"#),
+            ),
+            (MergeOp::JsonPatch, None, r#"{"a":null}"#, Some("{}")),
+            (MergeOp::JsonPatch, Some("not json"), "{}", None),
+        ];
+
+        for (op, current, operand, expected) in tests {
+            let got = op
+                .apply(current.map(str::as_bytes), operand.as_bytes())
+                .ok();
+            assert!(
+                got.as_deref() == expected.map(str::as_bytes),
+                "\n{} {:?} {:?}\nExpected: {:?}\nGot: {:?}\n",
+                op,
+                current,
+                operand,
+                expected,
+                got
+            );
+        }
+
+        for op in [MergeOp::Append, MergeOp::Add, MergeOp::JsonPatch] {
+            let got = op.to_string().parse::<MergeOp>().ok();
+            assert!(got == Some(op), "\nExpected: {:?}\nGot: {:?}\n", op, got);
+        }
+        assert!("max".parse::<MergeOp>().is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    fs::File,
+    io,
+    num::NonZeroUsize,
+    os::fd::{AsRawFd, RawFd},
+    path::Path,
+    sync::RwLock,
+};
+
+use nix::{
+    libc::c_void,
+    sys::{
+        mman::{self, MapFlags, MmapAdvise, ProtFlags},
+        stat,
+    },
+};
+
+use crate::storagev2::page::{PageID, PAGE_SIZE};
+
+/// A read only mapping of the whole data file.
+struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+// The mapping is only read through, and unmapped once nothing can be reading it
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize) -> io::Result<Self> {
+        let Some(length) = NonZeroUsize::new(len) else {
+            return Ok(Self {
+                ptr: std::ptr::null_mut(),
+                len: 0,
+            });
+        };
+
+        // Safety: a fresh shared read only mapping, nothing else refers to its address range
+        let ptr = unsafe {
+            mman::mmap(
+                None,
+                length,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_SHARED,
+                fd,
+                0,
+            )?
+        };
+
+        Ok(Self { ptr, len })
+    }
+
+    /// Safety: the page starting at `start` has to be within the mapping.
+    unsafe fn page(&self, start: usize) -> &[u8; PAGE_SIZE] {
+        &*(self.ptr.cast::<u8>().add(start) as *const [u8; PAGE_SIZE])
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+
+        // Safety: callers only hold references into the mapping while holding the lock it's
+        // replaced under
+        if let Err(e) = unsafe { mman::munmap(self.ptr, self.len) } {
+            eprintln!("error: could not unmap data file - {}", e);
+        }
+    }
+}
+
+/// Reads pages straight out of a memory mapping of the data file instead of copying them into a
+/// page cache frame. The mapping covers the file as it was when it was made and is remapped when
+/// a page past its end is read. The file can't be truncated while it's mapped, reading a page
+/// that's no longer in the file would crash.
+pub struct Mmap {
+    fd: RawFd,
+    /// Where the first page starts in the file, after its header.
+    base: usize,
+    mapping: RwLock<Mapping>,
+}
+
+impl Mmap {
+    /// Maps the file `fd` refers to, which has to stay open for as long as the `Mmap` is around.
+    pub fn new(fd: RawFd, base: usize) -> io::Result<Self> {
+        let mapping = Mapping::new(fd, mapped_len(fd)?)?;
+
+        Ok(Self {
+            fd,
+            base,
+            mapping: RwLock::new(mapping),
+        })
+    }
+
+    /// Calls `f` with page `page_id` as it is in the file, `None` if it's past the end of the
+    /// file. Pages other than the current one are only rewritten in place by compaction, so `f`
+    /// could see one midway through, see `PageCache::compress_page`.
+    pub fn read<T>(
+        &self,
+        page_id: PageID,
+        f: impl FnOnce(&[u8; PAGE_SIZE]) -> T,
+    ) -> io::Result<Option<T>> {
+        let start = self.base + page_id as usize * PAGE_SIZE;
+        let end = start + PAGE_SIZE;
+
+        let mapping = self.mapping.read().unwrap();
+        if end <= mapping.len {
+            // Safety: the page is within the mapping, which stays mapped while the lock is held
+            return Ok(Some(f(unsafe { mapping.page(start) })));
+        }
+        drop(mapping);
+
+        let mut mapping = self.mapping.write().unwrap();
+        if end > mapping.len {
+            let len = mapped_len(self.fd)?;
+            if end > len {
+                return Ok(None);
+            }
+            *mapping = Mapping::new(self.fd, len)?;
+        }
+
+        // Safety: as above
+        Ok(Some(f(unsafe { mapping.page(start) })))
+    }
+
+    /// Drops the mapped pages from memory, e.g. under memory pressure. They're read back from
+    /// the file as they're next read.
+    pub fn release(&self) -> io::Result<()> {
+        let mapping = self.mapping.read().unwrap();
+        if mapping.len == 0 {
+            return Ok(());
+        }
+
+        // Safety: the mapping is shared and read only, dropping its pages loses nothing
+        unsafe { mman::madvise(mapping.ptr, mapping.len, MmapAdvise::MADV_DONTNEED)? };
+
+        Ok(())
+    }
+}
+
+/// A read only mapping of a whole file that isn't pages, e.g. a checkpoint. Only the parts that
+/// are read are paged in. The file can't be truncated while it's mapped, but it can be removed.
+pub struct MappedFile(Mapping);
+
+impl MappedFile {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "file too large to map"))?;
+
+        // The mapping outlives the descriptor it was made from
+        Ok(Self(Mapping::new(file.as_raw_fd(), len)?))
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        if self.0.len == 0 {
+            return &[];
+        }
+
+        // Safety: the mapping covers `len` bytes and stays mapped for as long as `self` does
+        unsafe { std::slice::from_raw_parts(self.0.ptr.cast::<u8>(), self.0.len) }
+    }
+}
+
+/// Length of the file in whole pages, what can be mapped without reading past its end.
+fn mapped_len(fd: RawFd) -> io::Result<usize> {
+    let len = stat::fstat(fd)?.st_size as usize;
+
+    Ok(len - len % PAGE_SIZE)
+}
@@ -1,9 +1,35 @@
+pub mod alarm;
+pub mod checkpoint;
+pub mod clock;
+pub mod compaction;
+pub mod db;
 pub mod disk;
+pub mod dump;
+pub mod error;
+pub mod events;
+pub mod expiry;
+pub mod failpoint;
+pub mod glob;
+pub mod hint;
 pub mod key_dir;
+pub mod limit;
 pub mod log;
+pub mod merge;
+pub mod mmap;
+pub mod oplog;
 pub mod page;
 pub mod page_manager;
+pub mod pressure;
 pub mod replacer;
+pub mod segment;
+pub mod sstable;
+pub mod testing;
+pub mod txn;
+#[cfg(feature = "io-uring")]
+pub mod uring;
+pub mod validate;
+pub mod value_cache;
+pub mod verify;
 
 pub mod test {
     pub enum Type {
@@ -30,6 +56,10 @@ pub mod test {
                     if let Err(e) = std::fs::remove_file(self.0) {
                         eprintln!("error: could not remove {} - {}", self.0, e);
                     }
+                    // Data files can have segments next to them
+                    if let Err(e) = super::segment::remove(std::path::Path::new(self.0)) {
+                        eprintln!("error: could not remove segments of {} - {}", self.0, e);
+                    }
                 }
                 Type::Dir => {
                     if let Err(e) = std::fs::remove_dir_all(self.0) {
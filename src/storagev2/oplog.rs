@@ -0,0 +1,179 @@
+//! Reads the log back in the order entries were written, for tailing every write to a database,
+//! e.g. to keep an external index or another store in step with it.
+//!
+//! An entry's sequence number is where it is in the data file, so later writes have higher ones
+//! and a reader can pick up where it left off. Compaction moves live entries and the tombstones
+//! it keeps to the end of the log, so they're read again with new sequence numbers, and zeroes
+//! the pages they were on, so a reader that falls behind it misses what was overwritten there.
+
+use crate::storagev2::{
+    error::StorageError,
+    log::Entry,
+    page::{PageID, PageInner, PAGE_SIZE},
+    page_manager::PageCache,
+};
+
+#[derive(Debug, PartialEq)]
+pub struct Op {
+    pub seq: u64,
+    pub entry: Entry,
+}
+
+pub fn seq(page_id: PageID, offset: usize) -> u64 {
+    page_id as u64 * PAGE_SIZE as u64 + offset as u64
+}
+
+pub struct Oplog {
+    pc: PageCache,
+    next: u64,
+}
+
+impl Oplog {
+    /// Starts reading at the first entry with a sequence number of at least `from`.
+    pub fn new(pc: PageCache, from: u64) -> Self {
+        Self { pc, next: from }
+    }
+
+    /// Reads the entries written since the last read, stopping at the end of the current page or
+    /// after the page that takes it past `max` entries. Pages are read without evicting hot ones,
+    /// see `ScanReader`.
+    pub async fn read(&mut self, max: usize) -> Result<Vec<Op>, StorageError> {
+        // Read first, so pages this read treats as finished can't still be written to
+        let current = self.pc.current_id().await;
+        let mut scan = self.pc.scan();
+
+        let mut ops = Vec::new();
+        while ops.len() < max {
+            let page_id = (self.next / PAGE_SIZE as u64) as PageID;
+            if page_id > current {
+                break;
+            }
+
+            let from = self.next;
+            let end = scan
+                .with_page(page_id, |page| read_page(page, from, &mut ops))
+                .await?;
+            if page_id == current {
+                self.next = end.max(from);
+                break;
+            }
+            self.next = seq(page_id + 1, 0);
+        }
+
+        Ok(ops)
+    }
+
+    /// Sequence number the next read starts from.
+    pub fn next_seq(&self) -> u64 {
+        self.next
+    }
+}
+
+/// Reads the entries in `page` from sequence number `from` on. Returns where the last one ends.
+/// A corrupt entry ends the page, as it does on bootstrap.
+fn read_page(page: &PageInner, from: u64, ops: &mut Vec<Op>) -> u64 {
+    let mut offset = 0;
+    while let Ok(Some(entry)) = page.read_entry(offset) {
+        let seq = seq(page.id, offset);
+        offset += entry.len();
+        if seq >= from {
+            ops.push(Op { seq, entry });
+        }
+    }
+
+    seq(page.id, offset)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::storagev2::{
+        key_dir::bootstrap,
+        log::{Entry, EntryType},
+        oplog::{seq, Oplog},
+        page::PAGE_SIZE,
+        page_manager::{PageCache, DEFAULT_READ_SIZE},
+        replacer::Policy,
+        testing::Fixture,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_oplog() -> io::Result<()> {
+        const DB_FILE: &str = "./test_oplog.db";
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .put(b"key1", b"value1")
+            .put(b"key2", b"value2")
+            .next_page()
+            .delete(b"key1")
+            .build()
+            .await?;
+
+        let (_, latest, latest_id) = bootstrap(&disk).await?;
+        let m = PageCache::new(
+            disk,
+            Policy::default(),
+            DEFAULT_READ_SIZE,
+            latest,
+            latest_id,
+        );
+
+        let mut oplog = Oplog::new(m.clone(), 0);
+        let got: Vec<_> = oplog
+            .read(100)
+            .await?
+            .into_iter()
+            .map(|op| (op.seq, op.entry.t, op.entry.key.to_vec()))
+            .collect();
+        let key2 = Entry::new(b"key1", b"value1", EntryType::Put).len() as u64;
+        let expected = vec![
+            (0, EntryType::Put, b"key1".to_vec()),
+            (key2, EntryType::Put, b"key2".to_vec()),
+            (PAGE_SIZE as u64, EntryType::Delete, b"key1".to_vec()),
+        ];
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        // Caught up, until something else is written
+        assert!(oplog.read(100).await?.is_empty());
+        {
+            let mut current = m.get_current().await;
+            m.write_entry(
+                &mut current,
+                &Entry::new(b"key3", b"value3", EntryType::Put),
+            )
+            .await?;
+        }
+        let got = oplog.read(100).await?;
+        assert!(
+            got.len() == 1 && &got[0].entry.key[..] == b"key3",
+            "Got: {:?}",
+            got
+        );
+        assert!(
+            oplog.next_seq() > PAGE_SIZE as u64,
+            "Got: {}",
+            oplog.next_seq()
+        );
+
+        // Starting part way through an entry skips it
+        let got = Oplog::new(m.clone(), 1).read(1).await?;
+        assert!(
+            got.len() == 1 && got[0].seq == key2,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            key2,
+            got
+        );
+
+        // Past the end of the log
+        let mut oplog = Oplog::new(m.clone(), seq(5, 0));
+        assert!(oplog.read(100).await?.is_empty());
+        assert!(oplog.next_seq() == seq(5, 0));
+
+        Ok(())
+    }
+}
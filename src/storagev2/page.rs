@@ -1,7 +1,9 @@
-use bytes::Buf;
+use std::{io, str::FromStr};
+
+use bytes::{Buf, BufMut};
 use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use crate::storagev2::log::Entry;
+use crate::storagev2::log::{self, Entry, EntryType};
 
 #[cfg(not(test))]
 pub const PAGE_SIZE: usize = 4 * 1024;
@@ -11,6 +13,13 @@ pub const PAGE_SIZE: usize = 256;
 
 pub type PageID = u32;
 
+/// Bytes at the end of a page taken up by its trailer, in data files that have them, see
+/// `PageTrailer`.
+pub const PAGE_TRAILER_LEN: usize = 16;
+
+/// Bytes of a page entries can take up, the rest is left to the trailer.
+pub const PAGE_CAPACITY: usize = PAGE_SIZE - PAGE_TRAILER_LEN;
+
 #[macro_export]
 macro_rules! put_bytes {
     ($dst:expr, $src:expr, $o:expr, $l:expr) => {
@@ -27,6 +36,7 @@ macro_rules! get_bytes {
 #[derive(Debug, PartialEq)]
 pub enum PageError {
     NotEnoughSpace,
+    Corrupt,
 }
 
 pub struct Page(RwLock<PageInner>);
@@ -56,6 +66,10 @@ pub struct PageInner {
     pub id: PageID,
     pub data: [u8; PAGE_SIZE],
     len: usize,
+    /// Entries were written since the page was read or last written to disk.
+    dirty: bool,
+    /// Locality groups with entries in the page, see `Entry::group`.
+    groups: Vec<u32>,
 }
 
 impl Default for PageInner {
@@ -64,6 +78,8 @@ impl Default for PageInner {
             id: 0,
             data: [0; PAGE_SIZE],
             len: 0,
+            dirty: false,
+            groups: Vec::new(),
         }
     }
 }
@@ -73,76 +89,424 @@ impl PageInner {
         let data = [0; PAGE_SIZE];
         let len = 0;
 
-        Self { id, data, len }
+        Self {
+            id,
+            data,
+            len,
+            dirty: false,
+            groups: Vec::new(),
+        }
     }
 
     pub fn from_bytes(id: PageID, data: [u8; PAGE_SIZE]) -> Self {
-        let mut empty = 0;
-
-        const WINDOW: usize = 8;
-        for w in data.windows(WINDOW).rev() {
-            if u64::from_be_bytes(w.try_into().unwrap()) == 0 {
-                empty += WINDOW;
-            }
+        let mut page = Self {
+            id,
+            data,
+            len: 0,
+            dirty: false,
+            groups: Vec::new(),
+        };
+        while let Ok(Some(entry)) = page.read_entry(page.len) {
+            page.len += entry.len();
+            page.add_group(entry.group);
         }
 
-        let len = PAGE_SIZE - empty;
-        Self { id, data, len }
+        page
     }
 
     pub fn write_entry(&mut self, entry: &Entry) -> Result<u64, PageError> {
         let len = entry.len();
 
         let offset = self.len;
-        if offset + len > PAGE_SIZE {
+        if offset + len > PAGE_CAPACITY {
             return Err(PageError::NotEnoughSpace);
         }
         self.len += len;
+        self.dirty = true;
+        self.add_group(entry.group);
 
         put_bytes!(self.data, entry.as_bytes(), offset, len);
 
         Ok(offset as u64)
     }
 
-    // TODO: handle invalid bounds
-    pub fn read_entry(&self, offset: usize) -> Option<Entry> {
-        let mut src = &self.data[offset..];
+    /// Pages written before trailers were added can be filled past `PAGE_CAPACITY`, up to
+    /// `PAGE_SIZE`, and have no room left.
+    pub fn remaining(&self) -> usize {
+        PAGE_CAPACITY.saturating_sub(self.len)
+    }
 
-        let rm = offset + Entry::METADATA_LEN;
-        if rm >= PAGE_SIZE {
-            return None;
+    pub fn holds_group(&self, group: u32) -> bool {
+        self.groups.contains(&group)
+    }
+
+    fn add_group(&mut self, group: Option<u32>) {
+        if let Some(group) = group.filter(|g| !self.groups.contains(g)) {
+            self.groups.push(group);
+        }
+    }
+
+    /// Reads the entry at `offset`, returning `None` once there are no more entries in the page
+    /// and `PageError::Corrupt` if the entry fails its checksum or doesn't make sense.
+    pub fn read_entry(&self, offset: usize) -> Result<Option<Entry>, PageError> {
+        read_entry(&self.data, offset)
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Marks the page as matching what's on disk, after writing it.
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+
+    pub fn reset(&mut self) {
+        self.data = [0; PAGE_SIZE];
+        self.len = 0;
+        self.dirty = false;
+        self.groups.clear();
+    }
+}
+
+/// Reads the entry at `offset` of a page's data, see `PageInner::read_entry`.
+pub fn read_entry(data: &[u8; PAGE_SIZE], offset: usize) -> Result<Option<Entry>, PageError> {
+    let mut src = &data[offset.min(PAGE_SIZE)..];
+
+    let mut rm = offset + Entry::METADATA_LEN;
+    if rm > PAGE_SIZE || is_end(data, offset) {
+        return Ok(None);
+    }
+
+    let t = src.get_u8();
+    let time = src.get_u64();
+    let key_len = src.get_u64() as usize;
+    let value_len = src.get_u64() as usize;
+
+    let entry_type = match t & !Entry::FLAGS {
+        0 => EntryType::Put,
+        1 => EntryType::Delete,
+        _ => return Err(PageError::Corrupt),
+    };
+
+    let expires = match t & Entry::EXPIRES_FLAG {
+        0 => None,
+        _ => {
+            rm += Entry::EXPIRES_LEN;
+            if rm > PAGE_SIZE {
+                return Err(PageError::Corrupt);
+            }
+
+            Some(src.get_u64())
+        }
+    };
+
+    let owner = match t & Entry::OWNER_FLAG {
+        0 => None,
+        _ => {
+            rm += Entry::OWNER_LEN;
+            if rm > PAGE_SIZE {
+                return Err(PageError::Corrupt);
+            }
+
+            Some(src.get_u32())
+        }
+    };
+
+    let fence = match t & Entry::FENCE_FLAG {
+        0 => None,
+        _ => {
+            rm += Entry::FENCE_LEN;
+            if rm > PAGE_SIZE {
+                return Err(PageError::Corrupt);
+            }
+
+            Some(src.get_u64())
+        }
+    };
+
+    let group = match t & Entry::GROUP_FLAG {
+        0 => None,
+        _ => {
+            rm += Entry::GROUP_LEN;
+            if rm > PAGE_SIZE {
+                return Err(PageError::Corrupt);
+            }
+
+            Some(src.get_u32())
+        }
+    };
+
+    let checksum = match t & Entry::CHECKSUM_FLAG {
+        0 => None,
+        _ => {
+            rm += Entry::CHECKSUM_LEN;
+            if rm > PAGE_SIZE {
+                return Err(PageError::Corrupt);
+            }
+
+            Some(src.get_u32())
+        }
+    };
+
+    if key_len > PAGE_SIZE || value_len > PAGE_SIZE || rm + key_len + value_len > PAGE_SIZE {
+        return Err(PageError::Corrupt);
+    }
+
+    let key = get_bytes!(src, 0, key_len);
+    let value = get_bytes!(src, key_len, value_len);
+
+    if let Some(crc) = checksum {
+        let header_len = rm - offset - Entry::CHECKSUM_LEN;
+        let header = get_bytes!(data, offset, header_len);
+
+        if crc != log::checksum(header, key, value) {
+            return Err(PageError::Corrupt);
+        }
+    }
+
+    let (value, compressed) = match t & Entry::COMPRESSED_FLAG {
+        0 => (value.into(), None),
+        _ => match log::decompress(value) {
+            Some(decompressed) => (decompressed, Some(value.into())),
+            None => return Err(PageError::Corrupt),
+        },
+    };
+
+    Ok(Some(Entry {
+        t: entry_type,
+        time,
+        expires,
+        owner,
+        fence,
+        group,
+        checksum: checksum.is_some(),
+        key: key.into(),
+        value,
+        compressed,
+    }))
+}
+
+/// Written over the end of a page as it goes to disk, so a page that was only partly written can
+/// be told apart from one that was written whole. It's at the end rather than the start so entries
+/// are at the same offsets with or without one. Encoded as: lsn u64 | entries u32 | crc u32, with
+/// the crc over the rest of the page. Empty pages, never written or reclaimed, have none.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageTrailer {
+    /// Where the write was among every page write, later writes have higher ones.
+    pub lsn: u64,
+    /// Entries in the page, before it was compressed if it's stored compressed.
+    pub entries: u32,
+}
+
+impl PageTrailer {
+    /// Seals `data` with a trailer for write `lsn`.
+    pub fn seal(data: &mut [u8; PAGE_SIZE], lsn: u64) -> Self {
+        let trailer = Self {
+            lsn,
+            entries: count_entries(data),
+        };
+
+        let mut dst = &mut data[PAGE_CAPACITY..];
+        dst.put_u64(trailer.lsn);
+        dst.put_u32(trailer.entries);
+        let crc = crc32fast::hash(&data[..PAGE_SIZE - 4]);
+        (&mut data[PAGE_SIZE - 4..]).put_u32(crc);
+
+        trailer
+    }
+
+    /// The trailer `data` was sealed with, `None` if it's empty. `PageError::Corrupt` if the page
+    /// doesn't match it, e.g. the write was torn by a crash.
+    pub fn check(data: &[u8; PAGE_SIZE]) -> Result<Option<Self>, PageError> {
+        if data.iter().all(|b| *b == 0) {
+            return Ok(None);
         }
 
-        let t = src.get_u8();
-        let time = src.get_u64();
-        let key_len = src.get_u64();
-        let value_len = src.get_u64();
+        let (trailer, crc) = Self::decode(data);
+        if crc32fast::hash(&data[..PAGE_SIZE - 4]) != crc {
+            return Err(PageError::Corrupt);
+        }
 
-        // Commented out: index out of bounds errors
-        // Uncommented: key returns wrong value
-        // if rm + (key_len + value_len) as usize > PAGE_SIZE {
-        //     eprintln!("error: log entry was written that exceeded page size");
-        //     return None;
-        // }
+        Ok(Some(trailer))
+    }
 
-        if time == 0 && key_len == 0 && value_len == 0 {
+    /// The page as it was sealed by its previous write, if a torn write only got as far as
+    /// changing what came after its entries. Pages are only ever appended to before they're
+    /// rewritten whole, so an older trailer still describes the start of the page.
+    pub fn roll_back(data: &[u8; PAGE_SIZE]) -> Option<[u8; PAGE_SIZE]> {
+        let (trailer, _) = Self::decode(data);
+        if trailer.lsn == 0 || is_compressed(data) {
             return None;
         }
 
-        // let rest = &src[0..];
-        let key = get_bytes!(&src[0..], 0, key_len);
-        let value = get_bytes!(&src[0..], key_len as usize, value_len);
+        let mut end = 0;
+        for _ in 0..trailer.entries {
+            end = entry_end(data, end)?;
+        }
+        let mut page = [0; PAGE_SIZE];
+        page[..end].copy_from_slice(&data[..end]);
+        page[PAGE_CAPACITY..].copy_from_slice(&data[PAGE_CAPACITY..]);
 
-        Some(Entry {
-            t: t.into(),
-            time,
-            key: key.into(),
-            value: value.into(),
-        })
+        matches!(Self::check(&page), Ok(Some(_))).then_some(page)
     }
 
-    pub fn reset(&mut self) {
-        self.data = [0; PAGE_SIZE];
-        self.len = 0;
+    fn decode(data: &[u8; PAGE_SIZE]) -> (Self, u32) {
+        let mut src = &data[PAGE_CAPACITY..];
+        let trailer = Self {
+            lsn: src.get_u64(),
+            entries: src.get_u32(),
+        };
+
+        (trailer, src.get_u32())
+    }
+}
+
+/// Counts the entries in a page as it's stored, without decoding them.
+fn count_entries(data: &[u8; PAGE_SIZE]) -> u32 {
+    let data = match decompress(data) {
+        Ok(data) => data,
+        Err(_) => return 0,
+    };
+
+    let (mut n, mut offset) = (0, 0);
+    while let Some(end) = entry_end(&data, offset) {
+        n += 1;
+        offset = end;
+    }
+
+    n
+}
+
+/// Whether the entries of a page end at `offset`: everything from there is zeroed. Only what's
+/// before the trailer is looked at, a header near the end of a sealed page would run into it.
+fn is_end(data: &[u8; PAGE_SIZE], offset: usize) -> bool {
+    let end = (offset + Entry::METADATA_LEN).min(PAGE_CAPACITY.max(offset));
+    data[offset..end].iter().all(|b| *b == 0)
+}
+
+/// Where the entry at `offset` ends going by its header alone, `None` if there isn't one.
+fn entry_end(data: &[u8; PAGE_SIZE], offset: usize) -> Option<usize> {
+    let mut src = data.get(offset..)?;
+    if src.len() < Entry::METADATA_LEN || is_end(data, offset) {
+        return None;
+    }
+
+    let t = src.get_u8();
+    src.advance(8);
+    let key_len = usize::try_from(src.get_u64()).ok()?;
+    let value_len = usize::try_from(src.get_u64()).ok()?;
+
+    let optional: usize = [
+        (Entry::EXPIRES_FLAG, Entry::EXPIRES_LEN),
+        (Entry::OWNER_FLAG, Entry::OWNER_LEN),
+        (Entry::FENCE_FLAG, Entry::FENCE_LEN),
+        (Entry::GROUP_FLAG, Entry::GROUP_LEN),
+        (Entry::CHECKSUM_FLAG, Entry::CHECKSUM_LEN),
+    ]
+    .iter()
+    .filter(|(flag, _)| t & flag != 0)
+    .map(|(_, len)| len)
+    .sum();
+
+    let end = (offset + Entry::METADATA_LEN + optional)
+        .checked_add(key_len)?
+        .checked_add(value_len)?;
+
+    (end <= PAGE_SIZE).then_some(end)
+}
+
+/// First byte of a page that's stored whole-page compressed. It can never be the type byte of an
+/// entry, so a compressed page can't be mistaken for one with entries in it.
+pub const COMPRESSED_PAGE: u8 = 0x02;
+
+/// Header of a compressed page: marker | codec u8 | compressed_len u32.
+const COMPRESSED_HEADER_LEN: usize = 6;
+
+/// How cold pages are compressed. The codec is recorded in the page, so pages compressed with
+/// different codecs can sit in the same file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageCodec {
+    Lz4,
+}
+
+impl PageCodec {
+    fn id(self) -> u8 {
+        match self {
+            PageCodec::Lz4 => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(PageCodec::Lz4),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for PageCodec {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lz4" => Ok(PageCodec::Lz4),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown page codec {:?}", s),
+            )),
+        }
+    }
+}
+
+pub fn is_compressed(data: &[u8; PAGE_SIZE]) -> bool {
+    data[0] == COMPRESSED_PAGE
+}
+
+/// Compresses a page as it's stored on disk. Returns `None` if it's already compressed or
+/// wouldn't get any smaller.
+pub fn compress(data: &[u8; PAGE_SIZE], codec: PageCodec) -> Option<[u8; PAGE_SIZE]> {
+    if is_compressed(data) {
+        return None;
+    }
+
+    let compressed = match codec {
+        PageCodec::Lz4 => lz4_flex::compress(data),
+    };
+    // Leaves room for the trailer
+    if COMPRESSED_HEADER_LEN + compressed.len() >= PAGE_CAPACITY {
+        return None;
+    }
+
+    let mut dst = [0; PAGE_SIZE];
+    let mut header = &mut dst[..COMPRESSED_HEADER_LEN];
+    header.put_u8(COMPRESSED_PAGE);
+    header.put_u8(codec.id());
+    header.put_u32(compressed.len() as u32);
+    put_bytes!(dst, compressed, COMPRESSED_HEADER_LEN, compressed.len());
+
+    Some(dst)
+}
+
+/// Reverses `compress`, leaving pages that aren't compressed as they are.
+pub fn decompress(data: &[u8; PAGE_SIZE]) -> Result<[u8; PAGE_SIZE], PageError> {
+    if !is_compressed(data) {
+        return Ok(*data);
+    }
+
+    let mut header = &data[1..COMPRESSED_HEADER_LEN];
+    let codec = PageCodec::from_id(header.get_u8()).ok_or(PageError::Corrupt)?;
+    let len = header.get_u32() as usize;
+    if COMPRESSED_HEADER_LEN + len > PAGE_SIZE {
+        return Err(PageError::Corrupt);
+    }
+    let src = get_bytes!(data, COMPRESSED_HEADER_LEN, len);
+
+    let mut dst = [0; PAGE_SIZE];
+    match codec {
+        PageCodec::Lz4 => match lz4_flex::decompress_into(src, &mut dst) {
+            Ok(PAGE_SIZE) => Ok(dst),
+            _ => Err(PageError::Corrupt),
+        },
     }
 }
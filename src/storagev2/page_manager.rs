@@ -1,18 +1,22 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, VecDeque},
     io,
     sync::{
-        atomic::{AtomicU32, Ordering::*},
+        atomic::{AtomicU32, AtomicU64, Ordering::*},
         Arc,
     },
+    time::Duration,
 };
 
-use tokio::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::sync::{watch, Mutex, Notify, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use crate::storagev2::{
-    disk::Disk,
-    page::{Page, PageID, PageInner},
-    replacer::LRUKHandle,
+    clock::{self, SharedClock},
+    disk::{Disk, Durability},
+    error::StorageError,
+    log::Entry,
+    page::{self, Page, PageCodec, PageError, PageID, PageInner, PAGE_CAPACITY, PAGE_SIZE},
+    replacer::{Policy, ReplacerHandle},
 };
 
 #[derive(Debug, PartialEq)]
@@ -23,24 +27,144 @@ pub enum PageIndex {
 
 pub const DEFAULT_READ_SIZE: usize = 8;
 
+/// Pages a `ScanReader` keeps that weren't in the cache, so entries read one after the other
+/// from the same few pages don't read them from disk again.
+pub const SCAN_BUFFER_SIZE: usize = 4;
+
+/// How often the current page is flushed and fsynced with `Durability::EverySec`.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the committer waits for more writes to share an fsync with `Durability::Always`.
+pub const GROUP_COMMIT_WINDOW: Duration = Duration::from_millis(2);
+
+/// Room the first entry of a locality group wants left on the current page. With less it starts
+/// a new page, so the entries of the group written after it can join it.
+pub const GROUP_ROOM: usize = PAGE_SIZE / 4;
+
+/// Distribution of how full pages were when they were finished and no more entries went into
+/// them, in tenths. The tail of a page is wasted when the next entry doesn't fit.
+#[derive(Debug, Default)]
+pub struct FillHistogram {
+    buckets: [AtomicU64; 10],
+    wasted: AtomicU64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FillSnapshot {
+    /// Pages filled to 0-9%, 10-19%, ... 90-100%.
+    pub buckets: [u64; 10],
+    pub pages: u64,
+    pub wasted_bytes: u64,
+}
+
+impl FillHistogram {
+    pub fn record(&self, page: &PageInner) {
+        let used = PAGE_CAPACITY - page.remaining();
+        let bucket = (used * 10 / PAGE_CAPACITY).min(9);
+
+        self.buckets[bucket].fetch_add(1, Relaxed);
+        self.wasted.fetch_add(page.remaining() as u64, Relaxed);
+    }
+
+    pub fn snapshot(&self) -> FillSnapshot {
+        let buckets = std::array::from_fn(|i| self.buckets[i].load(Relaxed));
+
+        FillSnapshot {
+            buckets,
+            pages: buckets.iter().sum(),
+            wasted_bytes: self.wasted.load(Relaxed),
+        }
+    }
+}
+
+/// How well the page cache is serving reads, and what compaction has done, see
+/// `PageCache::stats`.
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    prefetched: AtomicU64,
+    compacted_at: AtomicU64,
+    pages_reclaimed: AtomicU64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    /// Page fetches served from a cached frame, and those that read the page from disk.
+    pub hits: u64,
+    pub misses: u64,
+    /// Cached pages dropped to make room for another.
+    pub evictions: u64,
+    /// Pages read ahead of a miss, see `PageCache::with_read_ahead`.
+    pub prefetched: u64,
+    /// When compaction last finished, in seconds since the epoch.
+    pub last_compaction: Option<u64>,
+    /// Pages reclaimed by compaction since the database was opened.
+    pub pages_reclaimed: u64,
+}
+
+/// Read transactions open at each epoch, and the pages reclaimed while they were open, with the
+/// epoch they were reclaimed in. A page can only be zeroed once every transaction that began at
+/// or before its epoch has ended, see `PageCache::pin_epoch`.
+#[derive(Debug, Default)]
+struct Epochs {
+    current: u64,
+    open: BTreeMap<u64, usize>,
+    deferred: Vec<(u64, PageID)>,
+}
+
+impl Epochs {
+    /// Takes the deferred pages no open transaction can read.
+    fn take_reclaimable(&mut self) -> Vec<PageID> {
+        let oldest = self.open.keys().next().copied().unwrap_or(u64::MAX);
+        let (reclaimable, deferred) = std::mem::take(&mut self.deferred)
+            .into_iter()
+            .partition(|(epoch, _)| *epoch < oldest);
+        self.deferred = deferred;
+
+        reclaimable
+            .into_iter()
+            .map(|(_, page_id)| page_id)
+            .collect()
+    }
+}
+
+/// Keeps pages that were reclaimed after it was taken on disk until it's dropped, see
+/// `PageCache::pin_epoch`.
+pub struct EpochPin {
+    pc: PageCache,
+    epoch: u64,
+}
+
+impl Drop for EpochPin {
+    fn drop(&mut self) {
+        let mut epochs = self.pc.0.epochs.lock().expect("epochs lock poisoned");
+        if let Some(n) = epochs.open.get_mut(&self.epoch) {
+            *n -= 1;
+            if *n == 0 {
+                epochs.open.remove(&self.epoch);
+            }
+        }
+    }
+}
+
 pub struct Pin<'a> {
     pub page: &'a Page,
     i: PageIndex,
-    replacer: LRUKHandle,
+    replacer: ReplacerHandle,
 }
 
 impl Drop for Pin<'_> {
     fn drop(&mut self) {
         if let PageIndex::Read(i) = self.i {
-            tokio::task::block_in_place(|| {
-                self.replacer.blocking_unpin(i);
-            });
+            self.replacer.unpin(i);
         };
     }
 }
 
 impl<'a> Pin<'a> {
-    pub fn new(page: &'a Page, i: PageIndex, replacer: LRUKHandle) -> Self {
+    pub fn new(page: &'a Page, i: PageIndex, replacer: ReplacerHandle) -> Self {
         Self { page, i, replacer }
     }
 
@@ -57,8 +181,42 @@ impl<'a> Pin<'a> {
 pub struct PageCache(Arc<PageCacheInner>);
 
 impl PageCache {
-    pub fn new(disk: Disk, lruk: usize, latest: Page, latest_id: PageID) -> Self {
-        Self(Arc::new(PageCacheInner::new(disk, lruk, latest, latest_id)))
+    /// Creates a page cache with `read_size` frames for pages other than the current one.
+    pub fn new(
+        disk: Disk,
+        policy: Policy,
+        read_size: usize,
+        latest: Page,
+        latest_id: PageID,
+    ) -> Self {
+        Self(Arc::new(PageCacheInner::new(
+            disk, policy, read_size, latest, latest_id,
+        )))
+    }
+
+    /// Reads the time from `clock` instead of the wall clock. Has to be called before the cache
+    /// is cloned.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        Arc::get_mut(&mut self.0)
+            .expect("clock set after the page cache was shared")
+            .clock = clock;
+
+        self
+    }
+
+    /// Reads up to `pages` pages following one that had to be read from disk into the cache in
+    /// the background, see `run_read_ahead`. Has to be called before the cache is cloned.
+    pub fn with_read_ahead(mut self, pages: usize) -> Self {
+        Arc::get_mut(&mut self.0)
+            .expect("read-ahead set after the page cache was shared")
+            .read_ahead = pages;
+
+        self
+    }
+
+    /// The current time in seconds since the epoch, see `Clock`.
+    pub fn now(&self) -> u64 {
+        self.0.clock.now()
     }
 
     pub fn inc_id(&self) -> PageID {
@@ -72,43 +230,547 @@ impl PageCache {
         self.0.replace_current(current).await
     }
 
+    /// Writes `entry` to the current page, replacing it first if the entry doesn't fit, or if it
+    /// starts a locality group on a page without `GROUP_ROOM` left.
+    pub async fn write_entry(
+        &self,
+        current: &mut RwLockWriteGuard<'_, PageInner>,
+        entry: &Entry,
+    ) -> io::Result<u64> {
+        if let Some(group) = entry.group {
+            if !current.holds_group(group) && current.remaining() < GROUP_ROOM {
+                self.replace_current(current).await?;
+            }
+        }
+
+        let offset = match current.write_entry(entry) {
+            Ok(offset) => offset,
+            Err(PageError::NotEnoughSpace) => {
+                self.replace_current(current).await?;
+
+                current.write_entry(entry).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "entry larger than page")
+                })?
+            }
+            Err(e) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?}", e),
+                ))
+            }
+        };
+        self.0.written(current);
+
+        Ok(offset)
+    }
+
+    /// Writes `entries` to a single page, replacing the current page first if they don't all fit,
+    /// so they are flushed to disk together.
+    pub async fn write_entries(
+        &self,
+        current: &mut RwLockWriteGuard<'_, PageInner>,
+        entries: &[Entry],
+    ) -> io::Result<Vec<u64>> {
+        let len: usize = entries.iter().map(Entry::len).sum();
+        if len > PAGE_CAPACITY {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "entries larger than page",
+            ));
+        }
+        if len > current.remaining() {
+            self.replace_current(current).await?;
+        }
+
+        let offsets = entries
+            .iter()
+            .map(|entry| {
+                current
+                    .write_entry(entry)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+            })
+            .collect::<io::Result<_>>()?;
+        self.0.written(current);
+
+        Ok(offsets)
+    }
+
     #[cfg(test)]
-    pub async fn new_page<'a>(&mut self) -> Option<PageID> {
+    pub async fn new_page(&mut self) -> Option<PageID> {
         self.0.new_page().await
     }
 
-    pub async fn fetch_page(&self, page_id: PageID) -> Option<Pin<'_>> {
+    /// Pins the page, reading it into the cache if it isn't there. Returns `None` if every frame
+    /// is pinned.
+    pub async fn fetch_page(&self, page_id: PageID) -> io::Result<Option<Pin<'_>>> {
         self.0.fetch_page(page_id).await
     }
 
+    /// Reads entries for a one-shot bulk read, see `ScanReader`.
+    pub fn scan(&self) -> ScanReader<'_> {
+        ScanReader {
+            pc: &self.0,
+            buffer: VecDeque::with_capacity(SCAN_BUFFER_SIZE),
+        }
+    }
+
+    /// Whether fetching the page would be served from memory rather than reading it from disk.
+    pub async fn is_cached(&self, page_id: PageID) -> bool {
+        self.0.page_table.read().await.contains_key(&page_id)
+    }
+
+    pub async fn fetch_entry(
+        &self,
+        page_id: PageID,
+        offset: u64,
+    ) -> Result<Option<Entry>, StorageError> {
+        self.0.fetch_entry(page_id, offset).await
+    }
+
+    /// Reads the entries at `offsets` in one page, fetching it once.
+    pub async fn fetch_entries(
+        &self,
+        page_id: PageID,
+        offsets: &[u64],
+    ) -> Result<Vec<Option<Entry>>, StorageError> {
+        self.0.fetch_entries(page_id, offsets).await
+    }
+
     pub async fn get_current(&self) -> RwLockWriteGuard<'_, PageInner> {
         self.0.get_current().await
     }
 
-    pub async fn flush_current(&self) {
+    /// Id of the page being written to. Pages before it are finished.
+    pub async fn current_id(&self) -> PageID {
+        self.0.current.read().await.id
+    }
+
+    pub async fn flush_current(&self) -> io::Result<()> {
         self.0.flush_current().await
     }
+
+    /// Writes and fsyncs the current page the caller is holding.
+    pub async fn sync_current(&self, current: &PageInner) -> io::Result<()> {
+        self.0.disk.write_page(current.id, &current.data).await?;
+        self.sync().await
+    }
+
+    /// Length of the data file in pages.
+    pub async fn pages(&self) -> io::Result<u32> {
+        Ok((self.0.disk.len().await? / PAGE_SIZE) as u32)
+    }
+
+    /// Bytes the data file takes up on disk, see `Disk::allocated`.
+    pub async fn disk_bytes(&self) -> io::Result<u64> {
+        self.0.disk.allocated().await
+    }
+
+    /// First page that isn't in a deleted segment, see `Disk::first_page`.
+    pub fn first_page(&self) -> PageID {
+        self.0.disk.first_page()
+    }
+
+    /// Deletes the segments every page of which is before `page_id`, see `Disk::drop_segments`.
+    pub async fn drop_segments(&self, page_id: PageID) -> io::Result<usize> {
+        self.0.disk.drop_segments(page_id).await
+    }
+
+    /// Ids of the pages cached besides the current one.
+    pub async fn cached_pages(&self) -> Vec<PageID> {
+        self.0
+            .page_table
+            .read()
+            .await
+            .iter()
+            .filter(|(_, i)| matches!(i, PageIndex::Read(_)))
+            .map(|(page_id, _)| *page_id)
+            .collect()
+    }
+
+    /// Reads `pages` into the cache, up to as many as it holds, e.g. the ones that were cached
+    /// before a restart.
+    pub async fn warm(&self, pages: &[PageID]) -> io::Result<()> {
+        for page_id in pages.iter().take(self.0.read.len()) {
+            self.fetch_page(*page_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Fsyncs everything written so far, regardless of the durability setting.
+    pub async fn sync(&self) -> io::Result<()> {
+        self.0.disk.sync().await
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.0.disk.durability()
+    }
+
+    /// Takes effect for the next write, both background tasks keep running and check it.
+    pub fn set_durability(&self, durability: Durability) {
+        self.0.disk.set_durability(durability)
+    }
+
+    /// Waits until everything written so far is on disk, if every write has to be durable. Call
+    /// it after releasing the current page, the committer needs it.
+    pub async fn commit(&self) {
+        self.0.commit().await
+    }
+
+    /// Writes and fsyncs the current page for everyone waiting in `commit`, once per
+    /// `GROUP_COMMIT_WINDOW` rather than once per write, for `Durability::Always`. Writes stay
+    /// unacknowledged while the disk fails, the committer keeps retrying.
+    pub async fn run_committer(self) {
+        loop {
+            self.0.pending.notified().await;
+            tokio::time::sleep(GROUP_COMMIT_WINDOW).await;
+
+            let mut current = self.get_current().await;
+            let seq = self.0.written.load(SeqCst);
+            let written = self.0.disk.write_page(current.id, &current.data).await;
+            if written.is_ok() {
+                current.mark_clean();
+            }
+            drop(current);
+
+            let synced = match written {
+                Ok(()) => self.sync().await,
+                Err(e) => Err(e),
+            };
+            match synced {
+                Ok(()) => {
+                    self.0.committed.send_replace(seq);
+                }
+                Err(e) => {
+                    eprintln!("error: commit failed, retrying: {}", e);
+                    self.0.pending.notify_one();
+                }
+            }
+        }
+    }
+
+    /// Flushes and fsyncs the current page every `FLUSH_INTERVAL`, while the durability is
+    /// `Durability::EverySec`.
+    pub async fn run_flusher(self) {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            if self.durability() != Durability::EverySec {
+                continue;
+            }
+
+            let flushed = match self.flush_current().await {
+                Ok(()) => self.sync().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = flushed {
+                eprintln!("error: flush failed: {}", e);
+            }
+        }
+    }
+
+    /// Reads the pages following each miss that aren't cached into frames, like fetching them
+    /// would. A sequential reader then misses once every `read_ahead` + 1 pages rather than on
+    /// every page. Misses while it's reading are coalesced into the latest one.
+    pub async fn run_read_ahead(self) {
+        let mut missed = self.0.missed.subscribe();
+
+        loop {
+            // Including a miss from before the task started
+            let page_id = *missed.borrow_and_update();
+            if let Some(page_id) = page_id {
+                if let Err(e) = self.0.read_ahead(page_id).await {
+                    eprintln!("error: read-ahead failed: {}", e);
+                }
+            }
+
+            if missed.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Writes cached pages other than the current one that were written to since they were
+    /// read. Returns the number of pages written.
+    pub async fn flush_dirty(&self) -> io::Result<usize> {
+        self.0.flush_dirty().await
+    }
+
+    /// Writes dirty cached pages every `FLUSH_INTERVAL`, see `flush_dirty`. The current page is
+    /// written according to the durability setting instead.
+    pub async fn run_dirty_flusher(self) {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = self.flush_dirty().await {
+                eprintln!("error: flushing dirty pages failed: {}", e);
+            }
+        }
+    }
+
+    /// Reads a page straight from disk, bypassing the cache.
+    pub async fn read_page(&self, page_id: PageID) -> io::Result<PageInner> {
+        self.0.read_page(page_id).await
+    }
+
+    /// Writes a finished page straight to disk, bypassing the cache.
+    pub async fn write_page(&self, page: &PageInner) -> io::Result<()> {
+        self.0.disk.write_page(page.id, &page.data).await?;
+        self.0.fill.record(page);
+
+        Ok(())
+    }
+
+    /// How full pages were when they were replaced.
+    pub fn fill(&self) -> FillSnapshot {
+        self.0.fill.snapshot()
+    }
+
+    /// Rewrites a page on disk compressed with `codec`. Returns whether it was, pages that are
+    /// cached, empty, already compressed or that don't compress are left alone. Reads decompress
+    /// it as it's loaded, so entries keep their offsets and the `KeyDir` doesn't change.
+    pub async fn compress_page(&self, page_id: PageID, codec: PageCodec) -> io::Result<bool> {
+        // Cached pages are hot, and could be written back over the compressed copy. Held until
+        // it's written so the page can't be loaded in the meantime.
+        let page_table = self.0.page_table.read().await;
+        if page_table.contains_key(&page_id) {
+            return Ok(false);
+        }
+
+        let data = self.0.disk.read_stored_page(page_id).await?;
+        if data.iter().all(|b| *b == 0) {
+            return Ok(false);
+        }
+
+        match page::compress(&data, codec) {
+            Some(compressed) => {
+                self.0.disk.write_page(page_id, &compressed).await?;
+                drop(page_table);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Zeroes a page on disk once nothing in the `KeyDir` points into it, giving its space back
+    /// where the filesystem can, see `Disk::punch_page`. Pages that were ever
+    /// current have to be reclaimed while holding `reclaiming`. While an `EpochPin` taken before
+    /// is held the page is only zeroed later, by a reclaim once it's dropped, see
+    /// `reclaim_deferred`.
+    pub async fn reclaim_page(&self, page_id: PageID) -> io::Result<()> {
+        {
+            let mut epochs = self.0.epochs.lock().expect("epochs lock poisoned");
+            if !epochs.open.is_empty() {
+                if !epochs.deferred.iter().any(|(_, id)| *id == page_id) {
+                    let epoch = epochs.current;
+                    epochs.deferred.push((epoch, page_id));
+                    epochs.current += 1;
+                }
+                return Ok(());
+            }
+        }
+
+        self.reclaim_deferred().await?;
+        self.0.reclaim_page(page_id).await
+    }
+
+    /// Zeroes the deferred pages no `EpochPin` can read anymore. Has to be called while holding
+    /// `reclaiming`.
+    pub async fn reclaim_deferred(&self) -> io::Result<()> {
+        let reclaimable = self
+            .0
+            .epochs
+            .lock()
+            .expect("epochs lock poisoned")
+            .take_reclaimable();
+
+        for (i, page_id) in reclaimable.iter().enumerate() {
+            if let Err(e) = self.0.reclaim_page(*page_id).await {
+                // Put back what's left, so it's tried again
+                let mut epochs = self.0.epochs.lock().expect("epochs lock poisoned");
+                epochs
+                    .deferred
+                    .extend(reclaimable[i..].iter().map(|page_id| (0, *page_id)));
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the page was reclaimed but is kept on disk for an `EpochPin`.
+    pub fn is_deferred(&self, page_id: PageID) -> bool {
+        self.0
+            .epochs
+            .lock()
+            .expect("epochs lock poisoned")
+            .deferred
+            .iter()
+            .any(|(_, id)| *id == page_id)
+    }
+
+    /// Keeps every page that's reclaimed from now on on disk until the pin is dropped, so
+    /// entries a copy of the `KeyDir` taken afterwards points to stay readable while compaction
+    /// moves them. Has to be taken before the `KeyDir` is read.
+    pub fn pin_epoch(&self) -> EpochPin {
+        let mut epochs = self.0.epochs.lock().expect("epochs lock poisoned");
+        let epoch = epochs.current;
+        *epochs.open.entry(epoch).or_default() += 1;
+
+        EpochPin {
+            pc: self.clone(),
+            epoch,
+        }
+    }
+
+    /// Held while reclaiming a page that could be part of a snapshot.
+    pub async fn reclaiming(&self) -> RwLockReadGuard<'_, ()> {
+        self.0.reclaims.read().await
+    }
+
+    /// Holds off reclaiming pages, so a snapshot can copy them without writers waiting.
+    pub async fn pause_reclaims(&self) -> RwLockWriteGuard<'_, ()> {
+        self.0.reclaims.write().await
+    }
+
+    /// Evicts frames until at most `keep` are in use, writing dirty pages first, and parks them
+    /// so they aren't used again until `unpark`. Pinned frames are left alone. Also drops the
+    /// data file's mapped pages. Returns the number of frames parked.
+    pub async fn shrink(&self, keep: usize) -> io::Result<usize> {
+        self.0.shrink(keep).await
+    }
+
+    /// Makes every parked frame available again. Returns how many there were.
+    pub async fn unpark(&self) -> usize {
+        self.0.unpark().await
+    }
+
+    /// Frames that can hold pages, those that aren't parked.
+    pub async fn frames(&self) -> usize {
+        self.0.read.len() - self.0.parked.lock().await.len()
+    }
+
+    /// Records a finished compaction run.
+    pub fn compacted(&self, pages_reclaimed: usize) {
+        let counters = &self.0.counters;
+        counters.compacted_at.store(self.now(), Relaxed);
+        counters
+            .pages_reclaimed
+            .fetch_add(pages_reclaimed as u64, Relaxed);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let counters = &self.0.counters;
+        let compacted_at = counters.compacted_at.load(Relaxed);
+
+        CacheStats {
+            hits: counters.hits.load(Relaxed),
+            misses: counters.misses.load(Relaxed),
+            evictions: counters.evictions.load(Relaxed),
+            prefetched: counters.prefetched.load(Relaxed),
+            last_compaction: (compacted_at > 0).then_some(compacted_at),
+            pages_reclaimed: counters.pages_reclaimed.load(Relaxed),
+        }
+    }
+}
+
+/// Reads entries for exports and other reads that touch every key once, without evicting hot
+/// pages or skewing the LRU-K history. Cached pages are read without recording an access, the rest
+/// are read from disk into a buffer of `SCAN_BUFFER_SIZE` pages the reader owns.
+pub struct ScanReader<'a> {
+    pc: &'a PageCacheInner,
+    buffer: VecDeque<PageInner>,
+}
+
+impl ScanReader<'_> {
+    pub async fn fetch_entry(
+        &mut self,
+        page_id: PageID,
+        offset: u64,
+    ) -> Result<Option<Entry>, StorageError> {
+        Ok(self
+            .with_page(page_id, |page| page.read_entry(offset as usize))
+            .await??)
+    }
+
+    /// Runs `f` on a page, the cached copy if there is one.
+    pub async fn with_page<T>(
+        &mut self,
+        page_id: PageID,
+        f: impl FnOnce(&PageInner) -> T,
+    ) -> io::Result<T> {
+        if let Some(pin) = self.pc.fetch_cached(page_id).await {
+            let page = pin.read().await;
+            // Replaced since the page table was read, fall back to reading it from disk
+            if page.id == page_id {
+                return Ok(f(&page));
+            }
+        }
+
+        if let Some(page) = self.buffer.iter().find(|page| page.id == page_id) {
+            return Ok(f(page));
+        }
+
+        let page = self.pc.read_page(page_id).await?;
+        self.pc.missed(page_id);
+        let t = f(&page);
+        if self.buffer.len() == SCAN_BUFFER_SIZE {
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back(page);
+
+        Ok(t)
+    }
 }
 
-struct PageCacheInner<const READ_SIZE: usize = DEFAULT_READ_SIZE> {
+struct PageCacheInner {
     disk: Disk,
     page_table: RwLock<HashMap<PageID, PageIndex>>,
     current: Page,
-    read: [Page; READ_SIZE],
+    read: Box<[Page]>,
     free: Mutex<Vec<usize>>,
+    /// Frames taken out of use by `shrink`. They stay pinned, so they're never evicted.
+    parked: Mutex<Vec<usize>>,
     next_id: AtomicU32,
-    replacer: LRUKHandle,
+    replacer: ReplacerHandle,
+
+    /// Number of writes to the current page, and how many of them the committer has made durable.
+    written: AtomicU64,
+    committed: watch::Sender<u64>,
+    /// Wakes the committer when someone is waiting in `commit`.
+    pending: Notify,
+
+    /// Pages to read after a miss, 0 for none, and the last page that missed.
+    read_ahead: usize,
+    missed: watch::Sender<Option<PageID>>,
+
+    fill: FillHistogram,
+    counters: Counters,
+    reclaims: RwLock<()>,
+    epochs: std::sync::Mutex<Epochs>,
+    clock: SharedClock,
 }
 
-impl<const READ_SIZE: usize> PageCacheInner<READ_SIZE> {
-    pub fn new(disk: Disk, lruk: usize, latest: Page, latest_id: PageID) -> Self {
+impl PageCacheInner {
+    pub fn new(
+        disk: Disk,
+        policy: Policy,
+        read_size: usize,
+        latest: Page,
+        latest_id: PageID,
+    ) -> Self {
         let next_id = latest_id + 1;
         let page_table = RwLock::new(HashMap::from([(latest_id, PageIndex::Write)]));
         let current = latest;
-        let read: [_; READ_SIZE] = std::array::from_fn(|_| Page::default());
+        let read = (0..read_size).map(|_| Page::default()).collect();
         let next_id = AtomicU32::new(next_id);
-        let free = Mutex::new((0..READ_SIZE).rev().collect());
-        let replacer = LRUKHandle::new(lruk);
+        let free = Mutex::new((0..read_size).rev().collect());
+        let replacer = ReplacerHandle::new(policy);
+        let (committed, _) = watch::channel(0);
+        let (missed, _) = watch::channel(None);
 
         Self {
             disk,
@@ -116,8 +778,19 @@ impl<const READ_SIZE: usize> PageCacheInner<READ_SIZE> {
             current,
             read,
             free,
+            parked: Mutex::new(Vec::new()),
             next_id,
             replacer,
+            written: AtomicU64::new(0),
+            committed,
+            pending: Notify::new(),
+            read_ahead: 0,
+            missed,
+            fill: FillHistogram::default(),
+            counters: Counters::default(),
+            reclaims: RwLock::new(()),
+            epochs: std::sync::Mutex::new(Epochs::default()),
+            clock: clock::system(),
         }
     }
 
@@ -129,12 +802,13 @@ impl<const READ_SIZE: usize> PageCacheInner<READ_SIZE> {
         &self,
         current: &mut RwLockWriteGuard<'_, PageInner>,
     ) -> io::Result<()> {
-        self.disk.write_page(current.id, &current.data);
+        self.disk.write_page(current.id, &current.data).await?;
+        self.fill.record(current);
 
         let mut page_table = self.page_table.write().await;
 
         let old_id = current.id;
-        if let None = page_table.remove(&old_id) {
+        if page_table.remove(&old_id).is_none() {
             eprintln!("No write page while replacing write page");
         }
 
@@ -146,15 +820,27 @@ impl<const READ_SIZE: usize> PageCacheInner<READ_SIZE> {
         Ok(())
     }
 
+    /// Counts a write to the current page. Taking the page keeps it in order with the committer.
+    fn written(&self, _current: &PageInner) {
+        self.written.fetch_add(1, SeqCst);
+    }
+
+    async fn commit(&self) {
+        if self.disk.durability() != Durability::Always {
+            return;
+        }
+
+        let seq = self.written.load(SeqCst);
+        let mut committed = self.committed.subscribe();
+        self.pending.notify_one();
+        if committed.wait_for(|c| *c >= seq).await.is_err() {
+            eprintln!("error: committer stopped");
+        }
+    }
+
     #[cfg(test)]
-    pub async fn new_page<'a>(&self) -> Option<PageID> {
-        let i = match self.free.lock().await.pop() {
-            Some(i) => i,
-            None => self.replacer.evict().await?,
-        };
-        self.replacer.remove(i).await;
-        self.replacer.record_access(i).await;
-        self.replacer.pin(i).await;
+    pub async fn new_page(&self) -> Option<PageID> {
+        let i = self.claim_frame().await?;
 
         let page_id = self.inc_id();
 
@@ -163,7 +849,10 @@ impl<const READ_SIZE: usize> PageCacheInner<READ_SIZE> {
         page.reset();
         page.id = page_id;
 
-        self.disk.write_page(page.id, &page.data);
+        self.disk
+            .write_page(page.id, &page.data)
+            .await
+            .expect("should write page");
         self.page_table
             .write()
             .await
@@ -172,18 +861,30 @@ impl<const READ_SIZE: usize> PageCacheInner<READ_SIZE> {
         Some(page_id)
     }
 
-    pub async fn fetch_page(&self, page_id: PageID) -> Option<Pin<'_>> {
+    async fn claim_frame(&self) -> Option<usize> {
+        let free = self.free.lock().await.pop();
+        match free {
+            Some(i) => {
+                self.replacer.claim(i);
+                Some(i)
+            }
+            None => self.replacer.evict().await,
+        }
+    }
+
+    pub async fn fetch_page(&self, page_id: PageID) -> io::Result<Option<Pin<'_>>> {
         if let Some(i) = self.page_table.read().await.get(&page_id) {
-            return match i {
+            self.counters.hits.fetch_add(1, Relaxed);
+            return Ok(match i {
                 PageIndex::Write => Some(Pin::new(
                     &self.current,
                     PageIndex::Write,
                     self.replacer.clone(),
                 )),
                 PageIndex::Read(i) => {
-                    assert!(*i < READ_SIZE);
-                    self.replacer.record_access(*i).await;
-                    self.replacer.pin(*i).await;
+                    assert!(*i < self.read.len());
+                    self.replacer.record_access(*i);
+                    self.replacer.pin(*i);
 
                     Some(Pin::new(
                         &self.read[*i],
@@ -191,59 +892,278 @@ impl<const READ_SIZE: usize> PageCacheInner<READ_SIZE> {
                         self.replacer.clone(),
                     ))
                 }
-            };
+            });
         };
 
-        let i = match self.free.lock().await.pop() {
-            Some(i) => i,
-            None => self.replacer.evict().await?,
-        };
-        self.replacer.remove(i).await;
-        self.replacer.record_access(i).await;
-        self.replacer.pin(i).await;
+        // Read before claiming a frame, so a failed read doesn't cost one
+        let page_data = self.disk.read_page(page_id).await?;
+        self.counters.misses.fetch_add(1, Relaxed);
+        self.missed(page_id);
 
-        assert!(i < READ_SIZE);
+        self.load(page_id, page_data).await
+    }
+
+    /// Puts a page read from disk in a frame, evicting another page if there are none free.
+    async fn load(
+        &self,
+        page_id: PageID,
+        page_data: [u8; PAGE_SIZE],
+    ) -> io::Result<Option<Pin<'_>>> {
+        let Some(i) = self.claim_frame().await else {
+            return Ok(None);
+        };
+        assert!(i < self.read.len());
+        let pin = Pin::new(&self.read[i], PageIndex::Read(i), self.replacer.clone());
 
         // Replace page
-        let page_data = self.disk.read_page(page_id).expect("Couldn't read page");
-        let mut page = self.read[i].write().await;
+        let mut page = pin.write().await;
+        // Written to while cached, so it has to reach disk before the frame is reused. If it
+        // can't, the page stays cached and dropping the pin releases the frame
+        if page.is_dirty() {
+            self.disk.write_page(page.id, &page.data).await?;
+        }
+
+        let mut page_table = self.page_table.write().await;
+        if page_table.get(&page.id) == Some(&PageIndex::Read(i)) {
+            page_table.remove(&page.id);
+            self.counters.evictions.fetch_add(1, Relaxed);
+        }
+
         page.reset();
         page.id = page_id;
         page.data = page_data;
 
-        self.page_table
-            .write()
-            .await
-            .insert(page.id, PageIndex::Read(i));
+        page_table.insert(page.id, PageIndex::Read(i));
+        drop(page_table);
+        drop(page);
+
+        Ok(Some(pin))
+    }
 
-        Some(Pin::new(
-            &self.read[i],
-            PageIndex::Read(i),
-            self.replacer.clone(),
-        ))
+    /// Tells the read-ahead task, if there is one, that `page_id` had to be read from disk.
+    fn missed(&self, page_id: PageID) {
+        if self.read_ahead > 0 {
+            self.missed.send_replace(Some(page_id));
+        }
+    }
+
+    /// Loads up to `read_ahead` pages after `page_id` that aren't cached, stopping at the last
+    /// page written or once every frame is pinned. Returns the number loaded.
+    async fn read_ahead(&self, page_id: PageID) -> io::Result<usize> {
+        let end = page_id
+            .saturating_add(self.read_ahead as PageID)
+            .min(self.next_id.load(SeqCst).saturating_sub(1));
+
+        let mut n = 0;
+        for page_id in page_id + 1..=end {
+            if self.page_table.read().await.contains_key(&page_id) {
+                continue;
+            }
+
+            let page_data = self.disk.read_page(page_id).await?;
+            if self.load(page_id, page_data).await?.is_none() {
+                break;
+            }
+            self.counters.prefetched.fetch_add(1, Relaxed);
+            n += 1;
+        }
+
+        Ok(n)
+    }
+
+    async fn shrink(&self, keep: usize) -> io::Result<usize> {
+        let mut parked = self.parked.lock().await;
+
+        let mut n = 0;
+        while self.read.len() - parked.len() > keep {
+            let Some(i) = self.claim_frame().await else {
+                break;
+            };
+
+            let mut page = self.read[i].write().await;
+            if page.is_dirty() {
+                if let Err(e) = self.disk.write_page(page.id, &page.data).await {
+                    // Still cached, so it's written the next time the frame is reused
+                    self.replacer.unpin(i);
+                    return Err(e);
+                }
+            }
+
+            let mut page_table = self.page_table.write().await;
+            if page_table.get(&page.id) == Some(&PageIndex::Read(i)) {
+                page_table.remove(&page.id);
+            }
+            drop(page_table);
+
+            page.reset();
+            parked.push(i);
+            n += 1;
+        }
+
+        self.disk.release_mapped()?;
+
+        Ok(n)
+    }
+
+    async fn unpark(&self) -> usize {
+        let mut parked = self.parked.lock().await;
+        for i in parked.iter() {
+            self.replacer.unpin(*i);
+        }
+
+        std::mem::take(&mut *parked).len()
+    }
+
+    /// Pins the page if it's cached, without recording an access or loading it.
+    async fn fetch_cached(&self, page_id: PageID) -> Option<Pin<'_>> {
+        match self.page_table.read().await.get(&page_id)? {
+            PageIndex::Write => Some(Pin::new(
+                &self.current,
+                PageIndex::Write,
+                self.replacer.clone(),
+            )),
+            PageIndex::Read(i) => {
+                self.replacer.pin(*i);
+
+                Some(Pin::new(
+                    &self.read[*i],
+                    PageIndex::Read(*i),
+                    self.replacer.clone(),
+                ))
+            }
+        }
+    }
+
+    pub async fn fetch_entry(
+        &self,
+        page_id: PageID,
+        offset: u64,
+    ) -> Result<Option<Entry>, StorageError> {
+        if let Some(entry) = self
+            .read_mapped(page_id, |data| page::read_entry(data, offset as usize))
+            .await?
+        {
+            return Ok(entry);
+        }
+
+        loop {
+            let Some(pin) = self.fetch_page(page_id).await? else {
+                return Ok(None);
+            };
+            let page = pin.read().await;
+
+            // The frame can be replaced or reused between the page table lookup and taking the
+            // lock, in which case look the page up again
+            if page.id != page_id {
+                continue;
+            }
+
+            return Ok(page.read_entry(offset as usize)?);
+        }
+    }
+
+    pub async fn fetch_entries(
+        &self,
+        page_id: PageID,
+        offsets: &[u64],
+    ) -> Result<Vec<Option<Entry>>, StorageError> {
+        let read = |data: &[u8; PAGE_SIZE]| {
+            offsets
+                .iter()
+                .map(|offset| page::read_entry(data, *offset as usize))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        if let Some(entries) = self.read_mapped(page_id, read).await? {
+            return Ok(entries);
+        }
+
+        loop {
+            let Some(pin) = self.fetch_page(page_id).await? else {
+                return Ok(offsets.iter().map(|_| None).collect());
+            };
+            let page = pin.read().await;
+
+            // See fetch_entry
+            if page.id != page_id {
+                continue;
+            }
+
+            return Ok(offsets
+                .iter()
+                .map(|offset| page.read_entry(*offset as usize))
+                .collect::<Result<_, _>>()?);
+        }
     }
 
     pub async fn get_current(&self) -> RwLockWriteGuard<'_, PageInner> {
         self.current.write().await
     }
 
-    pub async fn flush_current(&self) {
-        let current = self.current.write().await;
-        self.disk.write_page(current.id, &current.data);
+    /// Reads a page that isn't cached straight from the mapped data file, so cold reads don't
+    /// take a frame from the hot pages. Returns `None` to read it through the cache instead: if
+    /// the file isn't mapped, the page is cached or compressed, or it was caught midway through
+    /// being rewritten and didn't read back.
+    async fn read_mapped<T>(
+        &self,
+        page_id: PageID,
+        f: impl FnOnce(&[u8; PAGE_SIZE]) -> Result<T, PageError>,
+    ) -> io::Result<Option<T>> {
+        if !self.disk.is_mapped() || self.page_table.read().await.contains_key(&page_id) {
+            return Ok(None);
+        }
+
+        Ok(self.disk.read_mapped(page_id, f)?.and_then(Result::ok))
+    }
+
+    pub async fn flush_current(&self) -> io::Result<()> {
+        let mut current = self.current.write().await;
+        self.disk.write_page(current.id, &current.data).await?;
+        current.mark_clean();
+
+        Ok(())
+    }
+
+    async fn flush_dirty(&self) -> io::Result<usize> {
+        let mut flushed = 0;
+        for frame in self.read.iter() {
+            let mut page = frame.write().await;
+            if page.is_dirty() {
+                self.disk.write_page(page.id, &page.data).await?;
+                page.mark_clean();
+                flushed += 1;
+            }
+        }
+
+        Ok(flushed)
+    }
+
+    pub async fn read_page(&self, page_id: PageID) -> io::Result<PageInner> {
+        let data = self.disk.read_page(page_id).await?;
+
+        Ok(PageInner::from_bytes(page_id, data))
+    }
+
+    // Cached copies are left alone: readers holding an old KeyData can still be served from them
+    pub async fn reclaim_page(&self, page_id: PageID) -> io::Result<()> {
+        self.disk.punch_page(page_id).await
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::io;
+    use std::{io, sync::Arc, time::Duration};
+
+    use tokio::sync::RwLock;
 
     use crate::storagev2::{
-        disk::Disk,
-        key_dir::KeyData,
+        disk::{Disk, Durability},
+        key_dir::{self, KeyData},
         log::{Entry, EntryType},
-        page::Page,
-        page_manager::{PageCacheInner, DEFAULT_READ_SIZE},
+        page::{Page, PageCodec, PageInner, PAGE_SIZE},
+        page_manager::{FillSnapshot, PageCache, PageCacheInner, DEFAULT_READ_SIZE},
+        replacer::Policy,
         test::CleanUp,
+        testing::Fixture,
     };
 
     #[tokio::test(flavor = "multi_thread")]
@@ -252,7 +1172,7 @@ mod test {
         let _cu = CleanUp::file(DB_FILE);
         let disk = Disk::new(DB_FILE).await?;
 
-        let m = PageCacheInner::<DEFAULT_READ_SIZE>::new(disk, 2, Page::new(0), 0);
+        let m = PageCacheInner::new(disk, Policy::default(), DEFAULT_READ_SIZE, Page::new(0), 0);
 
         let mut page_w = m.get_current().await;
 
@@ -265,25 +1185,29 @@ mod test {
         assert!(offset_b as usize == entry_a.len());
         drop(page_w);
 
-        let kda = KeyData {
-            page_id: 0,
-            offset: 0,
-        };
-        let kdb = KeyData {
-            page_id: 0,
-            offset: entry_a.len() as u64,
-        };
+        let kda = KeyData::new(0, 0);
+        let kdb = KeyData::new(0, entry_a.len() as u64);
         let page_a = m
             .fetch_page(kda.page_id)
-            .await
+            .await?
             .expect("should fetch current page");
-        let got_a = page_a.read().await.read_entry(kda.offset as usize).unwrap();
+        let got_a = page_a
+            .read()
+            .await
+            .read_entry(kda.offset as usize)
+            .unwrap()
+            .unwrap();
 
         let page_b = m
             .fetch_page(kdb.page_id)
-            .await
+            .await?
             .expect("should fetch current page");
-        let got_b = page_b.read().await.read_entry(kdb.offset as usize).unwrap();
+        let got_b = page_b
+            .read()
+            .await
+            .read_entry(kdb.offset as usize)
+            .unwrap()
+            .unwrap();
 
         assert!(
             entry_a == got_a,
@@ -302,41 +1226,117 @@ mod test {
     }
 
     #[tokio::test(flavor = "multi_thread")]
+    async fn test_durability_always() -> io::Result<()> {
+        const DB_FILE: &str = "./test_durability_always.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let disk = Disk::new(DB_FILE)
+            .await?
+            .with_durability(Durability::Always);
+        let m = PageCache::new(disk, Policy::default(), DEFAULT_READ_SIZE, Page::new(0), 0);
+        tokio::spawn(m.clone().run_committer());
+
+        let entry = Entry::new(b"key1", b"value1", EntryType::Put);
+        let offset = m.write_entry(&mut m.get_current().await, &entry).await?;
+        m.commit().await;
+
+        // Written through without a flush
+        let page = PageInner::from_bytes(0, Disk::new(DB_FILE).await?.read_page(0).await?);
+        let got = page.read_entry(offset as usize).unwrap();
+        assert!(got.as_ref() == Some(&entry), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_durability() -> io::Result<()> {
+        const DB_FILE: &str = "./test_set_durability.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let m = PageCache::new(disk, Policy::default(), DEFAULT_READ_SIZE, Page::new(0), 0);
+        tokio::spawn(m.clone().run_committer());
+
+        let entry = Entry::new(b"key1", b"value1", EntryType::Put);
+        let offset = m.write_entry(&mut m.get_current().await, &entry).await?;
+        m.commit().await;
+
+        let page = PageInner::from_bytes(0, Disk::new(DB_FILE).await?.read_page(0).await?);
+        let got = page.read_entry(offset as usize).unwrap();
+        assert!(got.is_none(), "Got: {:?}", got);
+
+        m.set_durability(Durability::Always);
+        let entry = Entry::new(b"key2", b"value2", EntryType::Put);
+        let offset = m.write_entry(&mut m.get_current().await, &entry).await?;
+        m.commit().await;
+
+        let page = PageInner::from_bytes(0, Disk::new(DB_FILE).await?.read_page(0).await?);
+        let got = page.read_entry(offset as usize).unwrap();
+        assert!(got.as_ref() == Some(&entry), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_read_ahead() -> io::Result<()> {
+        const DB_FILE: &str = "./test_read_ahead.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        for page_id in 0..6 {
+            disk.write_page(page_id, &[0; PAGE_SIZE]).await?;
+        }
+        let m = PageCache::new(disk, Policy::default(), 4, Page::new(6), 6).with_read_ahead(2);
+        tokio::spawn(m.clone().run_read_ahead());
+
+        m.fetch_page(1).await?.expect("should fetch page 1");
+        let prefetched = async {
+            while !m.is_cached(3).await {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), prefetched)
+            .await
+            .expect("should read ahead");
+        assert!(m.is_cached(2).await);
+        assert!(!m.is_cached(4).await);
+
+        m.fetch_page(2).await?.expect("should fetch page 2");
+        let got = m.stats();
+        assert!(
+            (got.hits, got.misses, got.prefetched) == (1, 1, 2),
+            "Got: {:?}",
+            got
+        );
+
+        Ok(())
+    }
+
+    // Pins are dropped on a current thread runtime, so unpinning can't block
+    #[tokio::test]
     async fn test_replacer() -> io::Result<()> {
         const DB_FILE: &str = "./test_replacer.db";
         let _cu = CleanUp::file(DB_FILE);
         let disk = Disk::new(DB_FILE).await?;
 
-        let m = PageCacheInner::<3>::new(disk, 2, Page::new(0), 0);
+        let m = PageCacheInner::new(disk, Policy::default(), 3, Page::new(0), 0);
 
         {
             let _ = m.new_page().await.expect("should have space for page 1"); // ts = 0
             let _ = m.new_page().await.expect("should have space for page 2"); // ts = 1
             let _ = m.new_page().await.expect("should have space for page 3"); // ts = 2
 
-            let kd1 = KeyData {
-                page_id: 1,
-                offset: 0,
-            };
-            let kd2 = KeyData {
-                page_id: 2,
-                offset: 0,
-            };
-            let kd3 = KeyData {
-                page_id: 3,
-                offset: 0,
-            };
+            let kd1 = KeyData::new(1, 0);
+            let kd2 = KeyData::new(2, 0);
+            let kd3 = KeyData::new(3, 0);
 
-            m.fetch_page(kd1.page_id).await; // ts = 3
-            m.fetch_page(kd2.page_id).await; // ts = 4
-            m.fetch_page(kd1.page_id).await; // ts = 5
+            m.fetch_page(kd1.page_id).await?; // ts = 3
+            m.fetch_page(kd2.page_id).await?; // ts = 4
+            m.fetch_page(kd1.page_id).await?; // ts = 5
 
-            m.fetch_page(kd1.page_id).await; // ts = 6
-            m.fetch_page(kd2.page_id).await; // ts = 7
-            m.fetch_page(kd1.page_id).await; // ts = 8
-            m.fetch_page(kd2.page_id).await; // ts = 9
+            m.fetch_page(kd1.page_id).await?; // ts = 6
+            m.fetch_page(kd2.page_id).await?; // ts = 7
+            m.fetch_page(kd1.page_id).await?; // ts = 8
+            m.fetch_page(kd2.page_id).await?; // ts = 9
 
-            m.fetch_page(kd3.page_id).await; // ts = 10 - Least accessed, should get evicted
+            m.fetch_page(kd3.page_id).await?; // ts = 10 - Least accessed, should get evicted
         }
 
         let new_page_id = m.new_page().await.expect("a page should have been evicted");
@@ -358,4 +1358,307 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dirty_pages() -> io::Result<()> {
+        const DB_FILE: &str = "./test_dirty_pages.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+
+        let m = PageCacheInner::new(disk, Policy::default(), 1, Page::new(0), 0);
+        let page_id = m.new_page().await.expect("should have space for page 1");
+        let entry_a = Entry::new(b"key_a", b"value_a", EntryType::Put);
+        let entry_b = Entry::new(b"key_b", b"value_b", EntryType::Put);
+
+        // Written back when the read slot is reused for another page
+        {
+            let pin = m.fetch_page(page_id).await?.expect("should fetch page");
+            let mut page = pin.write().await;
+            page.write_entry(&entry_a).expect("should fit");
+            assert!(page.is_dirty());
+        }
+        m.fetch_page(2).await?.expect("should evict page 1");
+        let got = m.fetch_entry(page_id, 0).await.expect("should read entry");
+        assert!(
+            got.as_ref() == Some(&entry_a),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            entry_a,
+            got
+        );
+
+        // And by the flusher while it stays cached
+        let offset = {
+            let pin = m.fetch_page(page_id).await?.expect("should fetch page");
+            let mut page = pin.write().await;
+            page.write_entry(&entry_b).expect("should fit")
+        };
+        let flushed = m.flush_dirty().await?;
+        assert!(flushed == 1, "Got: {}", flushed);
+        let flushed = m.flush_dirty().await?;
+        assert!(flushed == 0, "Got: {}", flushed);
+
+        let got = m.read_page(page_id).await?.read_entry(offset as usize);
+        assert!(got == Ok(Some(entry_b)), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    // Writers fill and replace the current page while readers fetch through the read slots,
+    // checking every entry they get back is the one the KeyDir pointed at
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_access() -> io::Result<()> {
+        const DB_FILE: &str = "./test_concurrent_access.db";
+        const WRITERS: usize = 4;
+        const READERS: usize = 4;
+        const KEYS: usize = 200;
+
+        let _cu = CleanUp::file(DB_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let (kd, latest, latest_id) = key_dir::bootstrap(&disk).await?;
+        let kd = Arc::new(RwLock::new(kd));
+        let m = PageCache::new(
+            disk,
+            Policy::default(),
+            DEFAULT_READ_SIZE,
+            latest,
+            latest_id,
+        );
+
+        let mut handles = Vec::new();
+        for w in 0..WRITERS {
+            let (m, kd) = (m.clone(), kd.clone());
+            handles.push(tokio::spawn(async move {
+                for i in 0..KEYS {
+                    let key = format!("key_{w}_{i}");
+                    let value = format!("value_{w}_{i}");
+                    let entry = Entry::new(key.as_bytes(), value.as_bytes(), EntryType::Put);
+
+                    let mut current = m.get_current().await;
+                    let offset = m.write_entry(&mut current, &entry).await.unwrap();
+                    kd.write()
+                        .await
+                        .insert(key.as_bytes(), KeyData::new(current.id, offset));
+                }
+            }));
+        }
+
+        for r in 0..READERS {
+            let (m, kd) = (m.clone(), kd.clone());
+            handles.push(tokio::spawn(async move {
+                for i in 0..KEYS {
+                    for w in 0..WRITERS {
+                        let key = format!("key_{}_{}", (w + r) % WRITERS, i);
+
+                        let Some(data) = kd.read().await.get(key.as_bytes()).copied() else {
+                            continue;
+                        };
+
+                        let entry = m.fetch_entry(data.page_id, data.offset).await;
+
+                        assert!(
+                            entry
+                                .as_ref()
+                                .is_ok_and(|e| e.as_ref().is_some_and(|e| e.key == key.as_bytes())),
+                            "\nExpected: {}\nGot: {:?}\n",
+                            key,
+                            entry
+                        );
+                    }
+                }
+            }));
+        }
+
+        let all = futures_join(handles);
+        tokio::time::timeout(Duration::from_secs(30), all)
+            .await
+            .expect("deadlocked");
+
+        Ok(())
+    }
+
+    async fn futures_join(handles: Vec<tokio::task::JoinHandle<()>>) {
+        for h in handles {
+            h.await.expect("task panicked");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fill_histogram() -> io::Result<()> {
+        const DB_FILE: &str = "./test_fill_histogram.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let m = PageCache::new(disk, Policy::default(), DEFAULT_READ_SIZE, Page::new(0), 0);
+
+        // 39 bytes each, the seventh doesn't fit and replaces the page 234 bytes in
+        let entry = Entry::new(b"key1", b"value1", EntryType::Put);
+        for _ in 0..7 {
+            m.write_entry(&mut m.get_current().await, &entry).await?;
+        }
+
+        let got = m.fill();
+        let expected = FillSnapshot {
+            buckets: [0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            pages: 1,
+            wasted_bytes: 6,
+        };
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_scan() -> io::Result<()> {
+        const DB_FILE: &str = "./test_scan.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let m = PageCache::new(disk, Policy::default(), 1, Page::new(0), 0);
+
+        let mut entries = Vec::new();
+        let mut current = m.get_current().await;
+        for i in 0..3 {
+            let entry = Entry::new(format!("key{}", i).as_bytes(), b"value", EntryType::Put);
+            let offset = current.write_entry(&entry).expect("should not be full");
+            entries.push((current.id, offset as u64, entry));
+            m.replace_current(&mut current).await?;
+        }
+        drop(current);
+
+        let (hot, offset, _) = &entries[0];
+        m.fetch_entry(*hot, *offset)
+            .await
+            .expect("should read entry");
+
+        let mut scan = m.scan();
+        for (page_id, offset, expected) in &entries {
+            let got = scan
+                .fetch_entry(*page_id, *offset)
+                .await
+                .expect("should read entry");
+            assert!(
+                got.as_ref() == Some(expected),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        let cached = [
+            m.is_cached(0).await,
+            m.is_cached(1).await,
+            m.is_cached(2).await,
+        ];
+        let expected = [true, false, false];
+        assert!(
+            cached == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            cached
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mmap_reads() -> io::Result<()> {
+        const DB_FILE: &str = "./test_mmap_reads.db";
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .put(b"key1", b"value1")
+            .next_page()
+            .put(b"key2", b"value2")
+            .next_page()
+            .put(b"key3", b"value3")
+            .build()
+            .await?;
+        let (kd, latest, latest_id) = key_dir::bootstrap(&disk).await?;
+        let disk = disk.with_mmap_reads(true)?;
+        let m = PageCache::new(
+            disk,
+            Policy::default(),
+            DEFAULT_READ_SIZE,
+            latest,
+            latest_id,
+        );
+
+        let get = |k: &'static [u8]| {
+            let (m, data) = (&m, *kd.get(k).unwrap());
+            async move {
+                let entry = m.fetch_entry(data.page_id, data.offset).await.unwrap();
+                (
+                    entry.map(|e| e.value.to_vec()),
+                    m.is_cached(data.page_id).await,
+                )
+            }
+        };
+
+        // Cold pages are read from the mapping and don't take a frame
+        let got = get(b"key1").await;
+        assert!(got == (Some(b"value1".to_vec()), false), "Got: {:?}", got);
+
+        // Pages written after the file was mapped are picked up by remapping
+        let mut page = PageInner::new(m.inc_id());
+        page.write_entry(&Entry::new(b"key4", b"value4", EntryType::Put))
+            .expect("should fit");
+        m.write_page(&page).await?;
+        let got = m.fetch_entry(page.id, 0).await?.map(|e| e.value.to_vec());
+        assert!(got.as_deref() == Some(&b"value4"[..]), "Got: {:?}", got);
+        assert!(!m.is_cached(page.id).await);
+
+        // Compressed pages are read through the cache, which decompresses them. Cached pages,
+        // like the current one, are left alone
+        let current = m.get_current().await.id;
+        assert!(!m.compress_page(current, PageCodec::Lz4).await?);
+        assert!(m.compress_page(1, PageCodec::Lz4).await?);
+        let got = get(b"key2").await;
+        assert!(got == (Some(b"value2".to_vec()), true), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shrink() -> io::Result<()> {
+        const DB_FILE: &str = "./test_shrink.db";
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .put(b"key1", b"value1")
+            .next_page()
+            .put(b"key2", b"value2")
+            .next_page()
+            .put(b"key3", b"value3")
+            .build()
+            .await?;
+        let (kd, latest, latest_id) = key_dir::bootstrap(&disk).await?;
+        let m = PageCache::new(disk, Policy::default(), 4, latest, latest_id);
+
+        for page_id in [0, 1] {
+            drop(m.fetch_page(page_id).await?);
+        }
+
+        // Free frames are parked first, then cached pages are dropped
+        let got = m.shrink(1).await?;
+        assert!(got == 3, "Got: {}", got);
+        assert!(m.frames().await == 1);
+        let cached = m.cached_pages().await;
+        assert!(cached.len() == 1, "Got: {:?}", cached);
+
+        // Every page can still be read through the one frame left
+        for (k, v) in [(b"key1", b"value1"), (b"key2", b"value2")] {
+            let data = kd.get(k).unwrap();
+            let got = m.fetch_entry(data.page_id, data.offset).await?;
+            assert!(got.is_some_and(|e| e.value == v[..]));
+        }
+
+        let got = m.unpark().await;
+        assert!(got == 3, "Got: {}", got);
+        assert!(m.frames().await == 4);
+        drop(m.fetch_page(0).await?);
+        drop(m.fetch_page(1).await?);
+        let cached = m.cached_pages().await;
+        assert!(cached.len() == 2, "Got: {:?}", cached);
+
+        Ok(())
+    }
 }
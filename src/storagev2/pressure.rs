@@ -0,0 +1,147 @@
+//! Watches for memory pressure, from the cgroup's pressure stall information or the process's
+//! resident set size, and shrinks the page cache while it lasts so the database behaves in
+//! containers with tight memory limits, see `PageCache::shrink`.
+
+use std::{io, time::Duration};
+
+use nix::unistd::{self, SysconfVar};
+
+use crate::storagev2::page_manager::PageCache;
+
+pub const PRESSURE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Frames the page cache keeps under pressure by default.
+pub const DEFAULT_PRESSURE_FRAMES: usize = 1;
+
+/// Checks in a row without pressure before parked frames are used again, so the cache doesn't
+/// flap at the edge of a limit.
+const CALM_CHECKS: usize = 10;
+
+/// The cgroup v2 memory pressure of the cgroup the process is in, as seen from inside a
+/// container.
+const PSI_FILE: &str = "/sys/fs/cgroup/memory.pressure";
+const STATM_FILE: &str = "/proc/self/statm";
+
+/// Levels past which the database is under memory pressure. Unset ones are never checked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pressure {
+    /// Percentage of the last 10 seconds some task in the cgroup was stalled waiting on memory,
+    /// the `some avg10` of `memory.pressure`.
+    pub max_stall: Option<f64>,
+    pub max_rss: Option<u64>,
+    /// Frames the page cache keeps while under pressure.
+    pub frames: usize,
+}
+
+impl Default for Pressure {
+    fn default() -> Self {
+        Self {
+            max_stall: None,
+            max_rss: None,
+            frames: DEFAULT_PRESSURE_FRAMES,
+        }
+    }
+}
+
+impl Pressure {
+    pub fn is_empty(&self) -> bool {
+        self.max_stall.is_none() && self.max_rss.is_none()
+    }
+}
+
+pub async fn run(m: PageCache, pressure: Pressure) {
+    let mut interval = tokio::time::interval(PRESSURE_INTERVAL);
+
+    let mut calm = 0;
+    loop {
+        interval.tick().await;
+
+        match check(&pressure).await {
+            Ok(true) => {
+                calm = 0;
+                match m.shrink(pressure.frames).await {
+                    Ok(0) => {}
+                    Ok(n) => eprintln!("warning: memory pressure, parked {} cache frames", n),
+                    Err(e) => eprintln!("error: could not shrink page cache: {}", e),
+                }
+            }
+            Ok(false) => {
+                calm += 1;
+                if calm == CALM_CHECKS {
+                    let n = m.unpark().await;
+                    if n > 0 {
+                        eprintln!("memory pressure subsided, unparked {} cache frames", n);
+                    }
+                }
+            }
+            Err(e) => eprintln!("error: could not check memory pressure: {}", e),
+        }
+    }
+}
+
+/// Whether the process is past any of the levels in `pressure`.
+pub async fn check(pressure: &Pressure) -> io::Result<bool> {
+    if let Some(max) = pressure.max_stall {
+        if stall(&tokio::fs::read_to_string(PSI_FILE).await?)? > max {
+            return Ok(true);
+        }
+    }
+
+    if let Some(max) = pressure.max_rss {
+        if rss(&tokio::fs::read_to_string(STATM_FILE).await?)? > max {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Reads `some avg10` out of a pressure file, e.g.
+/// `some avg10=1.53 avg60=0.87 avg300=0.30 total=1234`.
+fn stall(psi: &str) -> io::Result<f64> {
+    psi.lines()
+        .find_map(|line| line.strip_prefix("some "))
+        .and_then(|some| {
+            some.split(' ')
+                .find_map(|field| field.strip_prefix("avg10="))
+        })
+        .and_then(|avg| avg.parse().ok())
+        .ok_or_else(|| invalid(PSI_FILE))
+}
+
+/// Reads the resident set size in bytes out of `/proc/self/statm`, which counts pages.
+fn rss(statm: &str) -> io::Result<u64> {
+    let pages: u64 = statm
+        .split(' ')
+        .nth(1)
+        .and_then(|pages| pages.parse().ok())
+        .ok_or_else(|| invalid(STATM_FILE))?;
+    let page_size = unistd::sysconf(SysconfVar::PAGE_SIZE)?.unwrap_or(4096);
+
+    Ok(pages * page_size as u64)
+}
+
+fn invalid(file: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("could not parse {}", file),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storagev2::pressure::{rss, stall};
+
+    #[test]
+    fn test_pressure() {
+        let psi = "some avg10=1.53 avg60=0.87 avg300=0.30 total=1234\n\
+                   full avg10=0.50 avg60=0.20 avg300=0.10 total=567\n";
+        let got = stall(psi).ok();
+        assert!(got == Some(1.53), "Got: {:?}", got);
+        assert!(stall("full avg10=0.50\n").is_err());
+
+        let got = rss("627 377 351 6 0 89 0\n").ok();
+        assert!(got.is_some_and(|b| b >= 377 * 4096), "Got: {:?}", got);
+        assert!(rss("627\n").is_err());
+    }
+}
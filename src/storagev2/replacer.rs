@@ -1,7 +1,84 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io,
+    str::FromStr,
+};
 
 use tokio::sync::{mpsc, oneshot};
 
+/// Picks which page cache frame to reuse for a page that isn't cached. Frames are identified by
+/// their index, and pinned frames are in use and can't be evicted.
+pub trait Replacer: Send {
+    /// Picks an unpinned frame to evict, `None` if every frame is pinned.
+    fn evict(&mut self) -> Option<usize>;
+
+    fn record_access(&mut self, i: usize);
+
+    fn pin(&mut self, i: usize);
+
+    fn unpin(&mut self, i: usize);
+
+    /// Forgets the frame's history. It has to be unpinned.
+    fn remove(&mut self, i: usize);
+
+    /// Resets the frame's history and pins it for a new page.
+    fn claim(&mut self, i: usize) {
+        self.remove(i);
+        self.record_access(i);
+        self.pin(i);
+    }
+}
+
+/// Accesses LRU-K looks back over.
+pub const DEFAULT_K: usize = 2;
+
+/// Which `Replacer` a page cache evicts with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// Evicts the frame whose kth most recent access is the oldest, see `LRUKReplacer`.
+    LruK(usize),
+    /// Evicts the least recently used frame.
+    Lru,
+    /// Second chance: sweeps the frames evicting the first one not accessed since the last
+    /// sweep passed it.
+    Clock,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy::LruK(DEFAULT_K)
+    }
+}
+
+impl Policy {
+    pub fn replacer(self) -> Box<dyn Replacer> {
+        match self {
+            Policy::LruK(k) => Box::new(LRUKReplacer::new(k)),
+            Policy::Lru => Box::new(LRUReplacer::default()),
+            Policy::Clock => Box::new(ClockReplacer::default()),
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = io::Error;
+
+    /// Parses `lru`, `clock` or `lru-k` for LRU-K with a particular k, e.g. `lru-2`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(Policy::Lru),
+            "clock" => Ok(Policy::Clock),
+            _ => match s.strip_prefix("lru-").map(str::parse) {
+                Some(Ok(k)) if k > 0 => Ok(Policy::LruK(k)),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown replacer {:?}", s),
+                )),
+            },
+        }
+    }
+}
+
 #[derive(Debug)]
 struct LRUKNode {
     i: usize,
@@ -45,8 +122,10 @@ impl LRUKReplacer {
             ..Default::default()
         }
     }
+}
 
-    pub fn evict(&mut self) -> Option<usize> {
+impl Replacer for LRUKReplacer {
+    fn evict(&mut self) -> Option<usize> {
         let mut max: (usize, u64) = (0, 0);
         let mut single_access: Vec<&LRUKNode> = Vec::new();
         for (id, node) in &self.nodes {
@@ -83,7 +162,7 @@ impl LRUKReplacer {
         Some(earliest.0)
     }
 
-    pub fn record_access(&mut self, i: usize) {
+    fn record_access(&mut self, i: usize) {
         match self.nodes.entry(i) {
             Entry::Occupied(mut node) => {
                 node.get_mut().history.push(self.current_ts);
@@ -96,19 +175,19 @@ impl LRUKReplacer {
         }
     }
 
-    pub fn pin(&mut self, i: usize) {
+    fn pin(&mut self, i: usize) {
         if let Some(node) = self.nodes.get_mut(&i) {
             node.pin += 1;
         }
     }
 
-    pub fn unpin(&mut self, i: usize) {
+    fn unpin(&mut self, i: usize) {
         if let Some(node) = self.nodes.get_mut(&i) {
             node.pin -= 1;
         }
     }
 
-    pub fn remove(&mut self, i: usize) {
+    fn remove(&mut self, i: usize) {
         match self.nodes.entry(i) {
             Entry::Occupied(node) => {
                 assert!(node.get().pin == 0);
@@ -119,24 +198,135 @@ impl LRUKReplacer {
     }
 }
 
-pub enum LRUKMessage {
+/// Evicts the unpinned frame that was accessed longest ago.
+#[derive(Default, Debug)]
+struct LRUReplacer {
+    /// Each frame's last access and pin count.
+    nodes: HashMap<usize, (u64, u64)>,
+    current_ts: u64,
+}
+
+impl Replacer for LRUReplacer {
+    fn evict(&mut self) -> Option<usize> {
+        self.nodes
+            .iter()
+            .filter(|(_, (_, pin))| *pin == 0)
+            .min_by_key(|(_, (ts, _))| *ts)
+            .map(|(i, _)| *i)
+    }
+
+    fn record_access(&mut self, i: usize) {
+        self.nodes.entry(i).or_insert((0, 0)).0 = self.current_ts;
+        self.current_ts += 1;
+    }
+
+    fn pin(&mut self, i: usize) {
+        if let Some((_, pin)) = self.nodes.get_mut(&i) {
+            *pin += 1;
+        }
+    }
+
+    fn unpin(&mut self, i: usize) {
+        if let Some((_, pin)) = self.nodes.get_mut(&i) {
+            *pin -= 1;
+        }
+    }
+
+    fn remove(&mut self, i: usize) {
+        if let Some((_, pin)) = self.nodes.remove(&i) {
+            assert!(pin == 0);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ClockFrame {
+    referenced: bool,
+    pin: u64,
+}
+
+/// Sweeps the frames like a clock hand, clearing the referenced bit of each unpinned frame it
+/// passes and evicting the first one that was already clear.
+#[derive(Default, Debug)]
+struct ClockReplacer {
+    /// Indexed by frame, `None` for frames that aren't tracked.
+    frames: Vec<Option<ClockFrame>>,
+    hand: usize,
+}
+
+impl Replacer for ClockReplacer {
+    fn evict(&mut self) -> Option<usize> {
+        // Two sweeps clear every referenced bit and come back round to them
+        for _ in 0..2 * self.frames.len() {
+            let i = self.hand;
+            self.hand = (self.hand + 1) % self.frames.len();
+
+            match &mut self.frames[i] {
+                Some(frame) if frame.pin == 0 && frame.referenced => frame.referenced = false,
+                Some(frame) if frame.pin == 0 => return Some(i),
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    fn record_access(&mut self, i: usize) {
+        if i >= self.frames.len() {
+            self.frames.resize_with(i + 1, || None);
+        }
+
+        match &mut self.frames[i] {
+            Some(frame) => frame.referenced = true,
+            None => {
+                self.frames[i] = Some(ClockFrame {
+                    referenced: true,
+                    pin: 0,
+                })
+            }
+        }
+    }
+
+    fn pin(&mut self, i: usize) {
+        if let Some(Some(frame)) = self.frames.get_mut(i) {
+            frame.pin += 1;
+        }
+    }
+
+    fn unpin(&mut self, i: usize) {
+        if let Some(Some(frame)) = self.frames.get_mut(i) {
+            frame.pin -= 1;
+        }
+    }
+
+    fn remove(&mut self, i: usize) {
+        if let Some(slot) = self.frames.get_mut(i) {
+            if let Some(frame) = slot.take() {
+                assert!(frame.pin == 0);
+            }
+        }
+    }
+}
+
+pub enum ReplacerMessage {
     Evict {
         reply: oneshot::Sender<Option<usize>>,
     },
+    Claim(usize),
     RecordAccess(usize),
     Pin(usize),
     Unpin(usize),
     Remove(usize),
 }
 
-pub struct LRUKActor {
-    inner: LRUKReplacer,
-    rx: mpsc::Receiver<LRUKMessage>,
+pub struct ReplacerActor {
+    inner: Box<dyn Replacer>,
+    rx: mpsc::UnboundedReceiver<ReplacerMessage>,
 }
 
-impl LRUKActor {
-    pub fn new(k: usize, rx: mpsc::Receiver<LRUKMessage>) -> Self {
-        let inner = LRUKReplacer::new(k);
+impl ReplacerActor {
+    pub fn new(policy: Policy, rx: mpsc::UnboundedReceiver<ReplacerMessage>) -> Self {
+        let inner = policy.replacer();
 
         Self { inner, rx }
     }
@@ -144,74 +334,125 @@ impl LRUKActor {
     pub async fn run(&mut self) {
         while let Some(m) = self.rx.recv().await {
             match m {
-                LRUKMessage::Evict { reply } => {
+                ReplacerMessage::Evict { reply } => {
+                    // Claim the frame in the same step so nothing can pin it in between
                     let ret = self.inner.evict();
+                    if let Some(i) = ret {
+                        self.inner.claim(i);
+                    }
 
                     if reply.send(ret).is_err() {
                         eprintln!("replacer channel error: could not reply to evict message");
                     }
                 }
-                LRUKMessage::RecordAccess(i) => self.inner.record_access(i),
-                LRUKMessage::Pin(i) => self.inner.pin(i),
-                LRUKMessage::Unpin(i) => self.inner.unpin(i),
-                LRUKMessage::Remove(i) => self.inner.remove(i),
+                ReplacerMessage::Claim(i) => self.inner.claim(i),
+                ReplacerMessage::RecordAccess(i) => self.inner.record_access(i),
+                ReplacerMessage::Pin(i) => self.inner.pin(i),
+                ReplacerMessage::Unpin(i) => self.inner.unpin(i),
+                ReplacerMessage::Remove(i) => self.inner.remove(i),
             }
         }
     }
 }
 
+/// Sends to the replacer's actor. Only `evict` waits, for the reply, everything else is sent
+/// without waiting so a `Pin` can unpin its frame when it's dropped on any runtime. The channel
+/// is unbounded, as what's queued is bounded by the frames pinned and accessed.
 #[derive(Clone)]
-pub struct LRUKHandle {
-    tx: mpsc::Sender<LRUKMessage>,
+pub struct ReplacerHandle {
+    tx: mpsc::UnboundedSender<ReplacerMessage>,
 }
 
-impl LRUKHandle {
-    pub fn new(k: usize) -> Self {
-        let (tx, rx) = mpsc::channel(256);
+impl ReplacerHandle {
+    pub fn new(policy: Policy) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
 
-        let mut replacer = LRUKActor::new(k, rx);
+        let mut replacer = ReplacerActor::new(policy, rx);
         let _jh = tokio::spawn(async move { replacer.run().await });
 
         Self { tx }
     }
 
+    /// Picks a frame to evict and claims it for the caller, leaving it pinned.
     pub async fn evict(&self) -> Option<usize> {
         let (tx, rx) = oneshot::channel();
 
-        if let Err(e) = self.tx.send(LRUKMessage::Evict { reply: tx }).await {
-            eprintln!("replacer channel error: {e}");
-        }
+        self.send(ReplacerMessage::Evict { reply: tx });
 
         rx.await.expect("replacer has been killed")
     }
 
-    pub async fn record_access(&self, i: usize) {
-        if let Err(e) = self.tx.send(LRUKMessage::RecordAccess(i)).await {
-            eprintln!("replacer channel error: {e}");
-        }
+    /// Claims a free frame for the caller, leaving it pinned.
+    pub fn claim(&self, i: usize) {
+        self.send(ReplacerMessage::Claim(i));
     }
 
-    pub async fn pin(&self, i: usize) {
-        if let Err(e) = self.tx.send(LRUKMessage::Pin(i)).await {
-            eprintln!("replacer channel error: {e}");
-        }
+    pub fn record_access(&self, i: usize) {
+        self.send(ReplacerMessage::RecordAccess(i));
     }
 
-    pub async fn unpin(&self, i: usize) {
-        if let Err(e) = self.tx.send(LRUKMessage::Unpin(i)).await {
-            eprintln!("replacer channel error: {e}");
-        }
+    pub fn pin(&self, i: usize) {
+        self.send(ReplacerMessage::Pin(i));
     }
 
-    pub fn blocking_unpin(&self, i: usize) {
-        if let Err(e) = self.tx.blocking_send(LRUKMessage::Unpin(i)) {
+    pub fn unpin(&self, i: usize) {
+        self.send(ReplacerMessage::Unpin(i));
+    }
+
+    pub fn remove(&self, i: usize) {
+        self.send(ReplacerMessage::Remove(i));
+    }
+
+    fn send(&self, m: ReplacerMessage) {
+        if let Err(e) = self.tx.send(m) {
             eprintln!("replacer channel error: {e}");
         }
     }
+}
 
-    pub async fn remove(&self, i: usize) {
-        if let Err(e) = self.tx.send(LRUKMessage::Remove(i)).await {
-            eprintln!("replacer channel error: {e}");
+#[cfg(test)]
+mod test {
+    use crate::storagev2::replacer::Policy;
+
+    #[test]
+    fn test_policies() {
+        // Frame 0 is accessed most, frame 1 least recently and frame 2 is pinned
+        let accesses = [0, 1, 0, 2, 0];
+        let cases = [
+            // Frame 0 is the only one with k accesses, so it has the largest k-distance
+            (Policy::LruK(2), Some(0)),
+            (Policy::Lru, Some(1)),
+            // The hand clears 0 and 1, skips 2 and comes back round to 0
+            (Policy::Clock, Some(0)),
+        ];
+
+        for (policy, expected) in cases {
+            let mut replacer = policy.replacer();
+            for i in accesses {
+                replacer.record_access(i);
+            }
+            replacer.pin(2);
+
+            let got = replacer.evict();
+            assert!(
+                got == expected,
+                "{:?}\nExpected: {:?}\nGot: {:?}\n",
+                policy,
+                expected,
+                got
+            );
+
+            replacer.claim(0);
+            replacer.claim(1);
+            assert!(
+                replacer.evict().is_none(),
+                "{:?} evicted a pinned frame",
+                policy
+            );
         }
+
+        assert!("lru-3".parse::<Policy>().unwrap() == Policy::LruK(3));
+        assert!("lru-0".parse::<Policy>().is_err());
+        assert!("fifo".parse::<Policy>().is_err());
     }
 }
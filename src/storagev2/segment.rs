@@ -0,0 +1,188 @@
+//! Splits the pages of a database across segment files of a fixed number of pages each, so the
+//! data file doesn't grow forever: once compaction has reclaimed every page of a segment, the
+//! whole file is deleted, and a corrupt file only takes the pages in it down with it.
+//!
+//! The first segment is the data file itself, after its header, segment `n` is the file next to
+//! it with `.n` added to its name. A manifest next to the data file records how many pages a
+//! segment holds and the first segment that hasn't been deleted.
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use bytes::{Buf, BufMut};
+
+use crate::storagev2::page::{PageID, PAGE_SIZE};
+
+/// Size of a segment unless the database was created with another.
+pub const DEFAULT_SEGMENT_BYTES: usize = 64 << 20;
+
+pub const DEFAULT_SEGMENT_PAGES: PageID = (DEFAULT_SEGMENT_BYTES / PAGE_SIZE) as PageID;
+
+/// Extension added to the data file's name for its manifest.
+pub const MANIFEST_EXTENSION: &str = "manifest";
+
+/// Identifies a manifest file and its layout.
+const MAGIC: &[u8; 4] = b"HDM1";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Manifest {
+    /// Pages in each segment.
+    pub segment_pages: PageID,
+    /// Every segment before this one has been deleted.
+    pub first: u32,
+}
+
+impl Manifest {
+    /// The manifest of a data file that has none, either a new one or one written before
+    /// segments were added. Its first segment is made large enough to hold the `pages` already
+    /// in it, so they stay where they are.
+    pub fn for_pages(pages: PageID) -> Self {
+        Self {
+            segment_pages: pages.div_ceil(DEFAULT_SEGMENT_PAGES).max(1) * DEFAULT_SEGMENT_PAGES,
+            first: 0,
+        }
+    }
+
+    /// Encoded as: magic | segment_pages u32 | first u32 | crc u32, with the crc over everything
+    /// before it.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dst = Vec::with_capacity(MAGIC.len() + 12);
+        dst.put_slice(MAGIC);
+        dst.put_u32(self.segment_pages);
+        dst.put_u32(self.first);
+        let crc = crc32fast::hash(&dst);
+        dst.put_u32(crc);
+
+        dst
+    }
+
+    pub fn decode(src: &[u8]) -> io::Result<Self> {
+        if src.len() != MAGIC.len() + 12 || &src[..MAGIC.len()] != MAGIC {
+            return Err(corrupt("bad magic"));
+        }
+        let (body, mut crc) = src.split_at(src.len() - 4);
+        if crc32fast::hash(body) != crc.get_u32() {
+            return Err(corrupt("checksum mismatch"));
+        }
+
+        let mut src = &body[MAGIC.len()..];
+        let manifest = Self {
+            segment_pages: src.get_u32(),
+            first: src.get_u32(),
+        };
+        if manifest.segment_pages == 0 {
+            return Err(corrupt("empty segments"));
+        }
+
+        Ok(manifest)
+    }
+
+    /// First page that isn't in a deleted segment.
+    pub fn first_page(&self) -> PageID {
+        self.first.saturating_mul(self.segment_pages)
+    }
+}
+
+/// Where segment `segment` of the data file `file` lives.
+pub fn path(file: &Path, segment: u32) -> PathBuf {
+    if segment == 0 {
+        return file.to_path_buf();
+    }
+
+    let mut path = file.as_os_str().to_owned();
+    path.push(format!(".{}", segment));
+
+    PathBuf::from(path)
+}
+
+/// Where the manifest for the data file `file` lives.
+pub fn manifest_path(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".");
+    path.push(MANIFEST_EXTENSION);
+
+    PathBuf::from(path)
+}
+
+/// Reads the manifest of `file`, `None` if it has none.
+pub fn read(file: &Path) -> io::Result<Option<Manifest>> {
+    match fs::read(manifest_path(file)) {
+        Ok(data) => Manifest::decode(&data).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes the manifest of `file` atomically, so a crash midway leaves the old one in place.
+pub fn write(file: &Path, manifest: &Manifest) -> io::Result<()> {
+    let path = manifest_path(file);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    let mut w = File::create(&tmp)?;
+    w.write_all(&manifest.encode())?;
+    w.sync_all()?;
+    fs::rename(&tmp, &path)?;
+
+    sync_dir(file)
+}
+
+/// The segments of `file` other than the data file itself that are on disk, in order.
+pub fn list(file: &Path) -> io::Result<Vec<u32>> {
+    let Some(name) = file.file_name().and_then(|name| name.to_str()) else {
+        return Ok(Vec::new());
+    };
+    let prefix = format!("{}.", name);
+
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir(file))? {
+        let entry = entry?;
+        let Some(segment) = entry
+            .file_name()
+            .to_str()
+            .and_then(|s| s.strip_prefix(&prefix))
+            .and_then(|n| n.parse::<u32>().ok().filter(|s| s.to_string() == n))
+        else {
+            continue;
+        };
+        if segment > 0 {
+            segments.push(segment);
+        }
+    }
+    segments.sort_unstable();
+
+    Ok(segments)
+}
+
+/// Removes the manifest and segment files of `file`, leaving the data file itself.
+pub fn remove(file: &Path) -> io::Result<()> {
+    for segment in list(file)? {
+        fs::remove_file(path(file, segment))?;
+    }
+    match fs::remove_file(manifest_path(file)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Fsyncs the directory `file` is in, so files created, renamed or removed in it stay that way.
+pub fn sync_dir(file: &Path) -> io::Result<()> {
+    File::open(dir(file))?.sync_all()
+}
+
+fn dir(file: &Path) -> &Path {
+    match file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+fn corrupt(e: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt manifest: {}", e),
+    )
+}
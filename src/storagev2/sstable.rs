@@ -0,0 +1,245 @@
+use std::{io, path::Path};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+};
+
+/// Last bytes of every table, so readers can tell it apart from a truncated or foreign file.
+pub const MAGIC: &[u8; 8] = b"HDBSST01";
+
+/// Entries are grouped into blocks of about this many bytes, with one index entry per block.
+pub const BLOCK_SIZE: usize = 4096;
+
+// index offset + index len + entry count + magic
+const FOOTER_LEN: usize = 8 + 8 + 8 + 8;
+
+/// Writes an immutable table of key/values sorted by key, for tools that don't speak the page
+/// format. The file is laid out as:
+///
+/// - data blocks of entries: key_len u32 | value_len u32 | key | value
+/// - the index, one entry per block: key_len u32 | first key | block offset u64 | block len u32
+/// - the footer: index offset u64 | index len u64 | entry count u64 | `MAGIC`
+///
+/// All integers are big endian.
+pub struct SsTableWriter {
+    w: BufWriter<File>,
+    block: BytesMut,
+    block_key: Option<Vec<u8>>,
+    index: BytesMut,
+    offset: u64,
+    last: Option<Vec<u8>>,
+    count: u64,
+}
+
+impl SsTableWriter {
+    pub async fn create(file: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            w: BufWriter::new(File::create(file).await?),
+            block: BytesMut::with_capacity(BLOCK_SIZE),
+            block_key: None,
+            index: BytesMut::new(),
+            offset: 0,
+            last: None,
+            count: 0,
+        })
+    }
+
+    /// Appends an entry. Keys have to be added in sorted order with no duplicates.
+    pub async fn add(&mut self, k: &[u8], v: &[u8]) -> io::Result<()> {
+        if self.last.as_deref().is_some_and(|last| k <= last) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sstable keys must be sorted and unique",
+            ));
+        }
+
+        if self.block_key.is_none() {
+            self.block_key = Some(k.to_vec());
+        }
+        self.block.put_u32(k.len() as u32);
+        self.block.put_u32(v.len() as u32);
+        self.block.put_slice(k);
+        self.block.put_slice(v);
+        self.last = Some(k.to_vec());
+        self.count += 1;
+
+        if self.block.len() >= BLOCK_SIZE {
+            self.flush_block().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the index and footer. Returns the number of entries in the table.
+    pub async fn finish(mut self) -> io::Result<u64> {
+        self.flush_block().await?;
+
+        let mut footer = BytesMut::with_capacity(FOOTER_LEN);
+        footer.put_u64(self.offset);
+        footer.put_u64(self.index.len() as u64);
+        footer.put_u64(self.count);
+        footer.put_slice(MAGIC);
+
+        self.w.write_all(&self.index).await?;
+        self.w.write_all(&footer).await?;
+        self.w.flush().await?;
+        self.w.get_ref().sync_all().await?;
+
+        Ok(self.count)
+    }
+
+    async fn flush_block(&mut self) -> io::Result<()> {
+        let Some(k) = self.block_key.take() else {
+            return Ok(());
+        };
+
+        self.index.put_u32(k.len() as u32);
+        self.index.put_slice(&k);
+        self.index.put_u64(self.offset);
+        self.index.put_u32(self.block.len() as u32);
+
+        self.w.write_all(&self.block).await?;
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+
+        Ok(())
+    }
+}
+
+/// Reads a table written by `SsTableWriter`, looking keys up through the block index.
+pub struct SsTable {
+    data: Vec<u8>,
+    // First key of each block, with the block's offset and length
+    index: Vec<(Vec<u8>, usize, usize)>,
+    pub count: u64,
+}
+
+impl SsTable {
+    pub fn open(file: impl AsRef<Path>) -> io::Result<Self> {
+        let data = std::fs::read(file)?;
+        if data.len() < FOOTER_LEN || !data.ends_with(MAGIC) {
+            return Err(corrupt("missing footer"));
+        }
+
+        let mut footer = &data[data.len() - FOOTER_LEN..];
+        let index_offset = footer.get_u64() as usize;
+        let index_len = footer.get_u64() as usize;
+        let count = footer.get_u64();
+        let mut src = data
+            .get(index_offset..index_offset.saturating_add(index_len))
+            .ok_or_else(|| corrupt("index out of bounds"))?;
+
+        let mut index = Vec::new();
+        while src.has_remaining() {
+            let k = take(&mut src, 4)?.get_u32() as usize;
+            let k = take(&mut src, k)?.to_vec();
+            let offset = take(&mut src, 8)?.get_u64() as usize;
+            let len = take(&mut src, 4)?.get_u32() as usize;
+            if offset + len > index_offset {
+                return Err(corrupt("block out of bounds"));
+            }
+
+            index.push((k, offset, len));
+        }
+
+        Ok(Self { data, index, count })
+    }
+
+    pub fn get(&self, k: &[u8]) -> io::Result<Option<&[u8]>> {
+        // The last block whose first key is not after k
+        let i = self.index.partition_point(|(first, _, _)| &first[..] <= k);
+        let Some((_, offset, len)) = i.checked_sub(1).map(|i| &self.index[i]) else {
+            return Ok(None);
+        };
+
+        let mut src = &self.data[*offset..offset + len];
+        while src.has_remaining() {
+            let key_len = take(&mut src, 4)?.get_u32() as usize;
+            let value_len = take(&mut src, 4)?.get_u32() as usize;
+            let key = take(&mut src, key_len)?;
+            let value = take(&mut src, value_len)?;
+
+            if key == k {
+                return Ok(Some(value));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+fn take<'a>(src: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if src.len() < n {
+        return Err(corrupt("truncated"));
+    }
+
+    let (b, rest) = src.split_at(n);
+    *src = rest;
+
+    Ok(b)
+}
+
+fn corrupt(e: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt sstable: {}", e),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::storagev2::{
+        db::{Db, Options},
+        sstable::{SsTable, SsTableWriter},
+        test::CleanUp,
+        testing::Fixture,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export_sstable() -> io::Result<()> {
+        const DB_FILE: &str = "./test_export_sstable.db";
+        const SSTABLE_FILE: &str = "./test_export_sstable.sst";
+        let mut fixture = Fixture::new(DB_FILE);
+        for i in (0..300).rev() {
+            let k = format!("key{:03}", i);
+            fixture = fixture.put(k.as_bytes(), format!("value{}", i).as_bytes());
+        }
+        let (disk, _cu) = fixture.delete(b"key150").build().await?;
+        drop(disk);
+        let _cu_sstable = CleanUp::file(SSTABLE_FILE);
+
+        let db = Db::open(DB_FILE, Options::default()).await?;
+        let n = db.export_sstable(SSTABLE_FILE).await?;
+        assert!(n == 299, "Got: {}", n);
+
+        let table = SsTable::open(SSTABLE_FILE)?;
+        assert!(table.count == 299, "Got: {}", table.count);
+        let cases = [
+            ("key000", Some("value0")),
+            ("key149", Some("value149")),
+            ("key150", None),
+            ("key299", Some("value299")),
+            ("key", None),
+            ("key999", None),
+        ];
+        for (k, expected) in cases {
+            let got = table.get(k.as_bytes())?;
+            assert!(
+                got == expected.map(str::as_bytes),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        let mut w = SsTableWriter::create(SSTABLE_FILE).await?;
+        w.add(b"key2", b"value2").await?;
+        assert!(w.add(b"key1", b"value1").await.is_err());
+
+        Ok(())
+    }
+}
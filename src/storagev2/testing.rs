@@ -0,0 +1,96 @@
+use std::io;
+
+use crate::storagev2::{
+    disk::Disk,
+    log::{Entry, EntryType},
+    page::{PageID, PageInner, PAGE_CAPACITY, PAGE_SIZE},
+    test::CleanUp,
+};
+
+/// Builds an on-disk database page by page for storage tests. Entries roll over into a new page
+/// when the current one is full, the same way the write path does.
+pub struct Fixture {
+    file: &'static str,
+    pages: Vec<PageInner>,
+    truncate: usize,
+}
+
+impl Fixture {
+    pub fn new(file: &'static str) -> Self {
+        Self {
+            file,
+            pages: vec![PageInner::new(0)],
+            truncate: 0,
+        }
+    }
+
+    pub fn put(self, k: &[u8], v: &[u8]) -> Self {
+        self.entry(Entry::new(k, v, EntryType::Put))
+    }
+
+    pub fn delete(self, k: &[u8]) -> Self {
+        self.entry(Entry::new(k, &[], EntryType::Delete))
+    }
+
+    pub fn entry(mut self, entry: Entry) -> Self {
+        if self.current().write_entry(&entry).is_err() {
+            self = self.next_page();
+            self.current()
+                .write_entry(&entry)
+                .expect("entry should fit in an empty page");
+        }
+
+        self
+    }
+
+    /// Appends arbitrary bytes to the current page, e.g. a corrupt or half-written entry. Nothing
+    /// else should be written to the page afterwards.
+    pub fn raw(mut self, bytes: &[u8]) -> Self {
+        let current = self.current();
+        let offset = PAGE_SIZE - current.data.iter().rev().take_while(|b| **b == 0).count();
+        assert!(
+            offset + bytes.len() <= PAGE_CAPACITY,
+            "raw bytes exceed page"
+        );
+
+        crate::put_bytes!(current.data, bytes, offset, bytes.len());
+
+        self
+    }
+
+    /// Starts a new page, leaving the rest of the current one empty.
+    pub fn next_page(mut self) -> Self {
+        let id = self.pages.len() as PageID;
+        self.pages.push(PageInner::new(id));
+
+        self
+    }
+
+    /// Cuts `n` bytes off the end of the file, leaving a partially written last page.
+    pub fn truncate(mut self, n: usize) -> Self {
+        self.truncate = n;
+
+        self
+    }
+
+    pub async fn build(self) -> io::Result<(Disk, CleanUp)> {
+        let cu = CleanUp::file(self.file);
+        let disk = Disk::new(self.file).await?;
+
+        for page in &self.pages {
+            disk.write_page(page.id, &page.data).await?;
+        }
+
+        if self.truncate > 0 {
+            let file = std::fs::OpenOptions::new().write(true).open(self.file)?;
+            let len = file.metadata()?.len().saturating_sub(self.truncate as u64);
+            file.set_len(len)?;
+        }
+
+        Ok((disk, cu))
+    }
+
+    fn current(&mut self) -> &mut PageInner {
+        self.pages.last_mut().expect("fixture always has a page")
+    }
+}
@@ -0,0 +1,82 @@
+//! Read-only transactions, for reading many keys as they all were at one point without holding
+//! off writers like `Db::read_consistent` does.
+
+use crate::storagev2::{
+    db::Db, error::StorageError, key_dir::KeyDir, log::Entry, page_manager::EpochPin,
+};
+
+/// A view of a database as it was when the transaction began. Keys are looked up in a copy of
+/// the `KeyDir`, and the pages it points to are kept on disk until the transaction ends, so
+/// writers and compaction carry on in the meantime, see `PageCache::pin_epoch`. Expiry is
+/// judged as of the start too.
+pub struct ReadTxn {
+    db: Db,
+    kd: KeyDir,
+    now: u64,
+    pin: EpochPin,
+}
+
+impl ReadTxn {
+    pub async fn begin(db: &Db) -> Self {
+        // Pinned first, so nothing the copy points to is reclaimed in between
+        let pin = db.pc.pin_epoch();
+        let kd = db.kd.read().await.clone();
+
+        Self {
+            db: db.clone(),
+            kd,
+            now: db.now(),
+            pin,
+        }
+    }
+
+    /// Reads the entry for `k` as of the start of the transaction.
+    pub async fn read(&self, k: &[u8]) -> Result<Option<Entry>, StorageError> {
+        let Some(data) = self.kd.get(k).filter(|data| !data.is_expired(self.now)) else {
+            return Ok(None);
+        };
+
+        let _permit = self.db.fetch_permit(data.page_id).await;
+        let entry = self.db.pc.fetch_entry(data.page_id, data.offset).await?;
+
+        Ok(entry.filter(|entry| entry.key == k))
+    }
+
+    pub async fn read_many<K: AsRef<[u8]>>(
+        &self,
+        keys: &[K],
+    ) -> Result<Vec<Option<Entry>>, StorageError> {
+        let mut entries = Vec::with_capacity(keys.len());
+        for k in keys {
+            entries.push(self.read(k.as_ref()).await?);
+        }
+
+        Ok(entries)
+    }
+
+    /// The keys as of the start of the transaction, expired ones included.
+    pub fn keys(&self) -> &KeyDir {
+        &self.kd
+    }
+
+    /// The time the transaction began, in seconds since the epoch.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    /// Ends the transaction, zeroing pages that were only kept for it. Dropping it instead
+    /// leaves them to the next compaction.
+    pub async fn end(self) {
+        let Self { db, pin, .. } = self;
+        drop(pin);
+
+        let _reclaiming = db.pc.reclaiming().await;
+        if let Err(e) = db.pc.reclaim_deferred().await {
+            eprintln!("error: could not reclaim pages - {}", e);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, OwnedFd},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+};
+
+use io_uring::{opcode, squeue, types, IoUring};
+use tokio::sync::oneshot;
+
+use crate::storagev2::page::PAGE_SIZE;
+
+/// Operations submitted to the ring at once. Callers waiting beyond this queue up for the next
+/// submission.
+const RING_ENTRIES: u32 = 64;
+
+type Buf = Box<[u8; PAGE_SIZE]>;
+
+// Every operation holds on to the descriptor it's for, so a cancelled one can't land on a reused one
+enum Op {
+    Read(
+        Arc<OwnedFd>,
+        u64,
+        Buf,
+        oneshot::Sender<(io::Result<usize>, Buf)>,
+    ),
+    Write(
+        Arc<OwnedFd>,
+        u64,
+        Buf,
+        oneshot::Sender<(io::Result<usize>, Buf)>,
+    ),
+    Fsync(Arc<OwnedFd>, oneshot::Sender<io::Result<usize>>),
+}
+
+/// Page reads and writes submitted to an io_uring by a thread of its own, so they don't block the
+/// runtime. Operations that arrive while a submission is in flight go in the next one together,
+/// whichever segment file they're for.
+pub struct Uring {
+    ops: Sender<Op>,
+}
+
+impl Uring {
+    /// Sets up a ring. Fails if the kernel doesn't support io_uring or it's disabled.
+    pub fn new() -> io::Result<Self> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+        let (ops, rx) = mpsc::channel();
+
+        thread::Builder::new()
+            .name("hash_db-uring".into())
+            .spawn(move || run(ring, rx))?;
+
+        Ok(Self { ops })
+    }
+
+    pub async fn read(&self, fd: &Arc<OwnedFd>, offset: u64) -> io::Result<(usize, Buf)> {
+        let (tx, rx) = oneshot::channel();
+        self.submit(Op::Read(fd.clone(), offset, Box::new([0; PAGE_SIZE]), tx))?;
+
+        let (res, buf) = rx.await.map_err(|_| stopped())?;
+        Ok((res?, buf))
+    }
+
+    pub async fn write(
+        &self,
+        fd: &Arc<OwnedFd>,
+        offset: u64,
+        data: &[u8; PAGE_SIZE],
+    ) -> io::Result<usize> {
+        let (tx, rx) = oneshot::channel();
+        self.submit(Op::Write(fd.clone(), offset, Box::new(*data), tx))?;
+
+        rx.await.map_err(|_| stopped())?.0
+    }
+
+    pub async fn fsync(&self, fd: &Arc<OwnedFd>) -> io::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.submit(Op::Fsync(fd.clone(), tx))?;
+
+        rx.await.map_err(|_| stopped())?.map(|_| ())
+    }
+
+    fn submit(&self, op: Op) -> io::Result<()> {
+        self.ops.send(op).map_err(|_| stopped())
+    }
+}
+
+/// Submits whatever operations are waiting, up to `RING_ENTRIES`, waits for all of them and
+/// hands back the results. Returns once the `Uring` is dropped.
+fn run(mut ring: IoUring, rx: Receiver<Op>) {
+    while let Ok(op) = rx.recv() {
+        let mut batch = vec![op];
+        while batch.len() < RING_ENTRIES as usize {
+            match rx.try_recv() {
+                Ok(op) => batch.push(op),
+                Err(_) => break,
+            }
+        }
+
+        let mut results: Vec<Option<io::Result<usize>>> = batch.iter().map(|_| None).collect();
+        match submit(&mut ring, &mut batch) {
+            Ok(()) => {
+                for cqe in ring.completion() {
+                    results[cqe.user_data() as usize] = Some(match cqe.result() {
+                        n if n >= 0 => Ok(n as usize),
+                        errno => Err(io::Error::from_raw_os_error(-errno)),
+                    });
+                }
+            }
+            Err(e) => {
+                eprintln!("error: io_uring submission failed: {}", e);
+                // The kernel may still be using the buffers, so they're leaked rather than freed
+                for op in batch.iter_mut() {
+                    if let Op::Read(_, _, buf, _) | Op::Write(_, _, buf, _) = op {
+                        Box::leak(std::mem::replace(buf, Box::new([0; PAGE_SIZE])));
+                    }
+                }
+            }
+        }
+
+        for (op, res) in batch.into_iter().zip(results) {
+            let res = res.unwrap_or_else(|| Err(io::Error::other("io_uring submission failed")));
+            match op {
+                Op::Read(_, _, buf, done) | Op::Write(_, _, buf, done) => {
+                    let _ = done.send((res, buf));
+                }
+                Op::Fsync(_, done) => {
+                    let _ = done.send(res);
+                }
+            }
+        }
+    }
+}
+
+fn submit(ring: &mut IoUring, batch: &mut [Op]) -> io::Result<()> {
+    let entries = batch.iter_mut().enumerate().map(|(i, op)| {
+        let entry: squeue::Entry = match op {
+            Op::Read(fd, offset, buf, _) => opcode::Read::new(
+                types::Fd(fd.as_raw_fd()),
+                buf.as_mut_ptr(),
+                PAGE_SIZE as u32,
+            )
+            .offset(*offset)
+            .build(),
+            Op::Write(fd, offset, buf, _) => {
+                opcode::Write::new(types::Fd(fd.as_raw_fd()), buf.as_ptr(), PAGE_SIZE as u32)
+                    .offset(*offset)
+                    .build()
+            }
+            Op::Fsync(fd, _) => opcode::Fsync::new(types::Fd(fd.as_raw_fd())).build(),
+        };
+
+        entry.user_data(i as u64)
+    });
+
+    for entry in entries {
+        // Safety: the buffers are owned by `batch`, which outlives the submission as `run` waits
+        // for every completion before handing them back
+        unsafe { ring.submission().push(&entry) }
+            .map_err(|_| io::Error::other("io_uring submission queue full"))?;
+    }
+    loop {
+        match ring.submit_and_wait(batch.len()) {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("io_uring thread stopped")
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, sync::Arc};
+
+    use crate::storagev2::{
+        disk::{Backend, Disk},
+        page::PAGE_SIZE,
+        test::CleanUp,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_uring() -> io::Result<()> {
+        const DB_FILE: &str = "./test_uring.db";
+        let _cu = CleanUp::file(DB_FILE);
+        let disk = match Disk::new(DB_FILE).await?.with_backend(Backend::IoUring) {
+            Ok(disk) => disk,
+            // Kernels and sandboxes can have io_uring disabled
+            Err(e) => {
+                eprintln!("skipping, io_uring unavailable: {}", e);
+                return Ok(());
+            }
+        };
+
+        // Concurrent writes share submissions
+        let disk = Arc::new(disk);
+        let handles: Vec<_> = (0..8u8)
+            .map(|i| {
+                let disk = disk.clone();
+                tokio::spawn(
+                    async move { disk.write_page(u32::from(i), &[b'a' + i; PAGE_SIZE]).await },
+                )
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap()?;
+        }
+        disk.sync().await?;
+
+        for i in 0..8u8 {
+            let got = disk.read_page(u32::from(i)).await?;
+            assert!(
+                got == [b'a' + i; PAGE_SIZE],
+                "page {} Got: {:?}",
+                i,
+                &got[..8]
+            );
+        }
+        // Past the end of the file reads as an empty page
+        assert!(disk.read_page(8).await? == [0; PAGE_SIZE]);
+
+        let pread = Disk::new(DB_FILE).await?;
+        assert!(pread.read_page(7).await? == [b'h'; PAGE_SIZE]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+use std::{fmt, io, str::FromStr, sync::RwLock};
+
+use regex::bytes::Regex;
+use serde::de::IgnoredAny;
+
+/// A check values written under a prefix have to pass.
+#[derive(Debug, Clone)]
+pub enum Rule {
+    /// The value is a JSON document.
+    Json,
+    /// The value is at most this many bytes.
+    MaxSize(usize),
+    /// The value matches the pattern somewhere, anchor it to match the whole value.
+    Regex(Regex),
+}
+
+impl FromStr for Rule {
+    type Err = io::Error;
+
+    /// Parses `json`, `size <bytes>` or `regex <pattern>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+
+        match s.split_once(' ').unwrap_or((s, "")) {
+            ("json", "") => Ok(Rule::Json),
+            ("size", n) => n
+                .parse()
+                .map(Rule::MaxSize)
+                .map_err(|_| invalid(format!("invalid size {:?}", n))),
+            ("regex", pattern) if !pattern.is_empty() => Regex::new(pattern)
+                .map(Rule::Regex)
+                .map_err(|e| invalid(e.to_string())),
+            _ => Err(invalid(format!("unknown rule {:?}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::Json => write!(f, "json"),
+            Rule::MaxSize(n) => write!(f, "size {}", n),
+            Rule::Regex(r) => write!(f, "regex {}", r.as_str()),
+        }
+    }
+}
+
+impl Rule {
+    fn check(&self, v: &[u8]) -> Result<(), String> {
+        match self {
+            Rule::Json if serde_json::from_slice::<IgnoredAny>(v).is_err() => {
+                Err("is not valid JSON".into())
+            }
+            Rule::MaxSize(n) if v.len() > *n => Err(format!("is larger than {} bytes", n)),
+            Rule::Regex(r) if !r.is_match(v) => Err(format!("does not match {}", r.as_str())),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Rules enforced on values written under key prefixes. A value has to pass every rule of every
+/// prefix its key starts with. Rules live in memory and are set at runtime.
+#[derive(Debug, Default)]
+pub struct Validators(RwLock<Vec<(Vec<u8>, Rule)>>);
+
+impl Validators {
+    pub fn add(&self, prefix: &[u8], rule: Rule) {
+        self.0
+            .write()
+            .expect("validators poisoned")
+            .push((prefix.to_vec(), rule));
+    }
+
+    /// Removes every rule for `prefix`. Returns the number removed.
+    pub fn remove(&self, prefix: &[u8]) -> usize {
+        let mut rules = self.0.write().expect("validators poisoned");
+        let before = rules.len();
+        rules.retain(|(p, _)| p != prefix);
+
+        before - rules.len()
+    }
+
+    pub fn list(&self) -> Vec<(Vec<u8>, Rule)> {
+        self.0.read().expect("validators poisoned").clone()
+    }
+
+    /// Returns why `v` can't be written to `k`, if it can't.
+    pub fn check(&self, k: &[u8], v: &[u8]) -> Result<(), String> {
+        let rules = self.0.read().expect("validators poisoned");
+        for (prefix, rule) in rules.iter().filter(|(p, _)| k.starts_with(p)) {
+            if let Err(e) = rule.check(v) {
+                return Err(format!(
+                    "value under {:?} {}",
+                    String::from_utf8_lossy(prefix),
+                    e
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storagev2::validate::{Rule, Validators};
+
+    #[test]
+    fn test_validators() {
+        let validators = Validators::default();
+        validators.add(b"user:", "json".parse().expect("valid rule"));
+        validators.add(b"user:", "size 16".parse().expect("valid rule"));
+        validators.add(b"code:", "regex ^[A-Z]{3}$".parse().expect("valid rule"));
+
+        let cases: [(&[u8], &[u8], bool); 6] = [
+            (b"user:1", br#"{"a":1}"#, true),
+            (b"user:1", b"{", false),
+            (b"user:1", br#"{"a":"0123456789"}"#, false),
+            (b"code:1", b"ABC", true),
+            (b"code:1", b"ABCD", false),
+            (b"other", b"{", true),
+        ];
+        for (k, v, expected) in cases {
+            let got = validators.check(k, v);
+            assert!(
+                got.is_ok() == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        let removed = validators.remove(b"user:");
+        assert!(removed == 2, "Got: {}", removed);
+        assert!(validators.check(b"user:1", b"{").is_ok());
+
+        for rule in ["xml", "size", "size -1", "regex", "regex ("] {
+            assert!(rule.parse::<Rule>().is_err(), "{:?} should not parse", rule);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering::*},
+        Mutex,
+    },
+};
+
+use crate::storagev2::{key_dir::KeyData, log::Entry};
+
+/// Lookups in a `ValueCache` since the database was opened, and how many keys it holds.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ValueCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub keys: usize,
+}
+
+/// The entries of the most recently read keys, decoded, so reads of hot keys skip fetching and
+/// pinning their page and reading the entry out of it. An entry is only returned while the key's
+/// `KeyData` is the one it was read at, so writes, deletes, expiry changes and compaction moving
+/// it make it miss without having to remove it. It's replaced on the next read.
+pub struct ValueCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<Vec<u8>, Cached>,
+    /// Keys by when they were last read, least recently first.
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+
+struct Cached {
+    data: KeyData,
+    entry: Entry,
+    tick: u64,
+}
+
+impl ValueCache {
+    /// Creates a cache of up to `capacity` keys.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lru: Mutex::new(Lru::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The entry for `k` if it was cached when the key was at `data`.
+    pub fn get(&self, k: &[u8], data: &KeyData) -> Option<Entry> {
+        let mut lru = self.lru.lock().unwrap();
+        let lru = &mut *lru;
+
+        let entry = match lru.entries.get_mut(k) {
+            Some(cached) if cached.data == *data => {
+                lru.tick += 1;
+                let key = lru
+                    .order
+                    .remove(&cached.tick)
+                    .expect("cached key is ordered");
+                lru.order.insert(lru.tick, key);
+                cached.tick = lru.tick;
+
+                Some(cached.entry.clone())
+            }
+            Some(_) => {
+                lru.remove(k);
+                None
+            }
+            None => None,
+        };
+
+        match entry {
+            Some(_) => self.hits.fetch_add(1, Relaxed),
+            None => self.misses.fetch_add(1, Relaxed),
+        };
+
+        entry
+    }
+
+    /// Caches the entry read for `k` at `data`, evicting the least recently read key if full.
+    pub fn insert(&self, k: &[u8], data: KeyData, entry: &Entry) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut lru = self.lru.lock().unwrap();
+        lru.remove(k);
+        while lru.entries.len() >= self.capacity {
+            let Some((_, key)) = lru.order.pop_first() else {
+                break;
+            };
+            lru.entries.remove(&key);
+        }
+
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.order.insert(tick, k.to_vec());
+        lru.entries.insert(
+            k.to_vec(),
+            Cached {
+                data,
+                entry: entry.clone(),
+                tick,
+            },
+        );
+    }
+
+    pub fn stats(&self) -> ValueCacheStats {
+        ValueCacheStats {
+            hits: self.hits.load(Relaxed),
+            misses: self.misses.load(Relaxed),
+            keys: self.lru.lock().unwrap().entries.len(),
+        }
+    }
+}
+
+impl Lru {
+    fn remove(&mut self, k: &[u8]) {
+        if let Some(cached) = self.entries.remove(k) {
+            self.order.remove(&cached.tick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storagev2::{
+        key_dir::KeyData,
+        log::{Entry, EntryType},
+        value_cache::{ValueCache, ValueCacheStats},
+    };
+
+    #[test]
+    fn test_value_cache() {
+        let cache = ValueCache::new(2);
+        let entry = |k: &[u8], v: &[u8]| Entry::new(k, v, EntryType::Put);
+
+        cache.insert(b"key1", KeyData::new(0, 0), &entry(b"key1", b"value1"));
+        cache.insert(b"key2", KeyData::new(0, 40), &entry(b"key2", b"value2"));
+
+        let got = cache.get(b"key1", &KeyData::new(0, 0));
+        assert!(got == Some(entry(b"key1", b"value1")), "Got: {:?}", got);
+
+        // key2 was read least recently
+        cache.insert(b"key3", KeyData::new(1, 0), &entry(b"key3", b"value3"));
+        let got = cache.get(b"key2", &KeyData::new(0, 40));
+        assert!(got.is_none(), "Got: {:?}", got);
+
+        // Written again since it was cached
+        let got = cache.get(b"key3", &KeyData::new(1, 40));
+        assert!(got.is_none(), "Got: {:?}", got);
+        let got = cache.get(b"key3", &KeyData::new(1, 0));
+        assert!(got.is_none(), "Got: {:?}", got);
+
+        let got = cache.stats();
+        let expected = ValueCacheStats {
+            hits: 1,
+            misses: 3,
+            keys: 1,
+        };
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+    }
+}
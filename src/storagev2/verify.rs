@@ -0,0 +1,316 @@
+//! Offline checks of a data file, for when a database won't open or is suspected to be corrupt.
+//! Unlike bootstrap, nothing is repaired: every page is read as it's stored and what's wrong with
+//! it is reported. Bad pages can be quarantined, copied out and zeroed so the next open skips them.
+
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::storagev2::{
+    checkpoint::{self, Checkpoint},
+    disk::Disk,
+    key_dir::{KeyData, KeyDir},
+    log::{self, EntryType},
+    page::{self, PageError, PageID, PageTrailer, PAGE_SIZE},
+};
+
+/// What's wrong with a page.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// The page doesn't match its trailer, see `PageTrailer`.
+    Torn,
+    /// The page is compressed but doesn't decompress.
+    Compression,
+    /// The entry at this offset failed to parse or its checksum. Nothing after it in the page can
+    /// be read.
+    Entry(usize),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Torn => write!(f, "doesn't match its trailer"),
+            Problem::Compression => write!(f, "doesn't decompress"),
+            Problem::Entry(offset) => write!(f, "corrupt entry at offset {}", offset),
+        }
+    }
+}
+
+/// How the checkpoint next to the data file compares to the `KeyDir` rebuilt from it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckpointState {
+    Missing,
+    /// Doesn't decode, the next open ignores it.
+    Corrupt,
+    /// Taken of the data file as it was before later writes, the next open ignores it.
+    Stale,
+    /// Matches the data file but this many keys differ from the rebuilt `KeyDir`, the next open
+    /// would use it as is.
+    Differs(usize),
+    Matches,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyReport {
+    pub pages: usize,
+    /// Pages with nothing in them, reclaimed or never written.
+    pub empty: usize,
+    pub entries: usize,
+    /// Live keys in the `KeyDir` rebuilt from the entries that could be read.
+    pub keys: usize,
+    /// Bytes after the last whole page, left by a crash midway through extending the file.
+    pub partial_bytes: usize,
+    pub bad: Vec<(PageID, Problem)>,
+    pub checkpoint: CheckpointState,
+    /// Where the bad pages were copied to before being zeroed, if they were quarantined.
+    pub quarantined: Option<PathBuf>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.bad.is_empty()
+            && self.partial_bytes == 0
+            && !matches!(self.checkpoint, CheckpointState::Differs(_))
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "pages: {} ({} empty)", self.pages, self.empty)?;
+        writeln!(f, "entries: {}", self.entries)?;
+        writeln!(f, "keys: {}", self.keys)?;
+        if self.partial_bytes > 0 {
+            writeln!(f, "partial page: {} bytes", self.partial_bytes)?;
+        }
+        for (page_id, problem) in &self.bad {
+            writeln!(f, "page {}: {}", page_id, problem)?;
+        }
+        match self.checkpoint {
+            CheckpointState::Missing => writeln!(f, "checkpoint: none")?,
+            CheckpointState::Corrupt => writeln!(f, "checkpoint: corrupt")?,
+            CheckpointState::Stale => writeln!(f, "checkpoint: stale")?,
+            CheckpointState::Differs(n) => writeln!(f, "checkpoint: {} keys differ", n)?,
+            CheckpointState::Matches => writeln!(f, "checkpoint: ok")?,
+        }
+        if let Some(dir) = &self.quarantined {
+            writeln!(
+                f,
+                "quarantined {} pages to {}",
+                self.bad.len(),
+                dir.display()
+            )?;
+        }
+
+        write!(f, "{}", if self.is_ok() { "ok" } else { "FAILED" })
+    }
+}
+
+/// Checks every page of the data file `file` and that the `KeyDir` can be rebuilt from it. With
+/// `quarantine`, each bad page is copied into that directory as `page-<id>` and zeroed in the data
+/// file, and the checkpoint is removed so the next open rebuilds the `KeyDir` without them.
+pub async fn verify(file: impl AsRef<Path>, quarantine: Option<&Path>) -> io::Result<VerifyReport> {
+    let file = file.as_ref();
+    // Disk::new would create it
+    fs::metadata(file)?;
+    let disk = Disk::new(file).await?;
+
+    let len = disk.len().await?;
+    let trailers = disk.header().has_trailers();
+    let mut report = VerifyReport {
+        pages: 0,
+        empty: 0,
+        entries: 0,
+        keys: 0,
+        partial_bytes: if trailers { len % PAGE_SIZE } else { 0 },
+        bad: Vec::new(),
+        checkpoint: CheckpointState::Missing,
+        quarantined: None,
+    };
+
+    let now = log::now();
+    let mut kd = HashMap::new();
+    for page_id in disk.first_page()..(len / PAGE_SIZE) as PageID {
+        report.pages += 1;
+        let stored = disk.read_stored_page(page_id).await?;
+        if trailers {
+            match PageTrailer::check(&stored) {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    report.empty += 1;
+                    continue;
+                }
+                Err(_) => report.bad.push((page_id, Problem::Torn)),
+            }
+        }
+        let Ok(data) = page::decompress(&stored) else {
+            report.bad.push((page_id, Problem::Compression));
+            continue;
+        };
+
+        let mut offset = 0;
+        loop {
+            let entry = match page::read_entry(&data, offset) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(PageError::Corrupt | PageError::NotEnoughSpace) => {
+                    report.bad.push((page_id, Problem::Entry(offset)));
+                    break;
+                }
+            };
+            report.entries += 1;
+
+            match entry.t {
+                EntryType::Put if !entry.is_expired(now) => {
+                    let data = KeyData::new(page_id, offset as u64)
+                        .with_expiry(entry.expires)
+                        .with_owner(entry.owner)
+                        .with_fence(entry.fence)
+                        .with_len(entry.len());
+                    kd.insert(entry.key.clone(), data);
+                }
+                _ => {
+                    kd.remove(&entry.key);
+                }
+            }
+
+            offset += entry.len();
+        }
+        if !trailers && data.iter().all(|b| *b == 0) {
+            report.empty += 1;
+        }
+    }
+    let kd = KeyDir::from(kd);
+    report.keys = kd.len();
+    // A page that's bad in more than one way is only reported once
+    report.bad.dedup_by_key(|(page_id, _)| *page_id);
+
+    report.checkpoint = check_checkpoint(&disk, file, &kd, now).await?;
+
+    if let Some(dir) = quarantine.filter(|_| !report.bad.is_empty()) {
+        fs::create_dir_all(dir)?;
+        for (page_id, _) in &report.bad {
+            let stored = disk.read_stored_page(*page_id).await?;
+            fs::write(dir.join(format!("page-{}", page_id)), stored)?;
+            disk.punch_page(*page_id).await?;
+        }
+        disk.sync().await?;
+        match fs::remove_file(checkpoint::path(file)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        report.quarantined = Some(dir.to_path_buf());
+    }
+
+    Ok(report)
+}
+
+async fn check_checkpoint(
+    disk: &Disk,
+    file: &Path,
+    kd: &KeyDir,
+    now: u64,
+) -> io::Result<CheckpointState> {
+    let data = match fs::read(checkpoint::path(file)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(CheckpointState::Missing),
+        Err(e) => return Err(e),
+    };
+    let Ok(checkpoint) = Checkpoint::decode(&data) else {
+        return Ok(CheckpointState::Corrupt);
+    };
+    if !checkpoint.matches(disk).await? {
+        return Ok(CheckpointState::Stale);
+    }
+
+    // Keys that expired since the checkpoint was taken are still in it
+    let live = checkpoint
+        .kd
+        .iter()
+        .filter(|(_, data)| !data.is_expired(now))
+        .count();
+    let same = kd
+        .iter()
+        .filter(|(k, data)| checkpoint.kd.get(k) == Some(data))
+        .count();
+
+    match kd.len() + live - 2 * same {
+        0 => Ok(CheckpointState::Matches),
+        n => Ok(CheckpointState::Differs(n)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::storagev2::{
+        disk::Disk,
+        log::{Entry, EntryType},
+        page::PAGE_SIZE,
+        test::CleanUp,
+        testing::Fixture,
+        verify::{verify, CheckpointState, Problem, VerifyReport},
+    };
+
+    #[tokio::test]
+    async fn test_verify() -> io::Result<()> {
+        const DB_FILE: &str = "./test_verify.db";
+        const QUARANTINE: &str = "./test_verify_quarantine";
+        let _cu_dir = CleanUp::dir(QUARANTINE);
+
+        let mut corrupt = Entry::new(b"key2", b"value2", EntryType::Put).as_bytes();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+
+        let (disk, _cu) = Fixture::new(DB_FILE)
+            .put(b"key1", b"value1")
+            .raw(&corrupt)
+            .next_page()
+            .put(b"key3", b"value3")
+            .next_page()
+            .put(b"key4", b"value4")
+            .build()
+            .await?;
+        drop(disk);
+
+        // Page 1 torn by a crash
+        let mut data = std::fs::read(DB_FILE)?;
+        data[2 * PAGE_SIZE + 1] ^= 0xff;
+        std::fs::write(DB_FILE, &data)?;
+
+        let report = verify(DB_FILE, None).await?;
+        let expected = VerifyReport {
+            pages: 3,
+            empty: 0,
+            entries: 2,
+            keys: 2,
+            partial_bytes: 0,
+            bad: vec![(0, Problem::Entry(39)), (1, Problem::Torn)],
+            checkpoint: CheckpointState::Missing,
+            quarantined: None,
+        };
+        assert!(
+            report == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            report
+        );
+        assert!(!report.is_ok());
+
+        let report = verify(DB_FILE, Some(QUARANTINE.as_ref())).await?;
+        assert!(report.quarantined.is_some(), "Got: {:?}", report);
+        let got = std::fs::read(format!("{}/page-1", QUARANTINE))?;
+        assert!(got == data[2 * PAGE_SIZE..3 * PAGE_SIZE], "Got: {:?}", got);
+
+        // Quarantined pages are empty
+        let report = verify(DB_FILE, None).await?;
+        assert!(report.is_ok(), "Got: {:?}", report);
+        assert!((report.empty, report.keys) == (2, 1), "Got: {:?}", report);
+        let disk = Disk::new(DB_FILE).await?;
+        assert!(disk.read_page(0).await? == [0; PAGE_SIZE]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+//! Kills the engine at every failpoint, at several points into a workload, and checks that
+//! bootstrap recovers a consistent `KeyDir` each time: every entry it points to can be read, and
+//! every acknowledged write is there. Run with `cargo test --features failpoints`.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use hash_db::{
+    serverv2::{auth::User, message::Message},
+    storagev2::{
+        db::{Db, Options},
+        disk::Durability,
+        failpoint::{self, Crash, FAILPOINTS},
+        test::CleanUp,
+    },
+};
+use tokio::runtime::Runtime;
+
+const DB_FILE: &str = "./test_crash_recovery.db";
+const CHECKPOINT_FILE: &str = "./test_crash_recovery.db.checkpoint";
+const CHECKPOINT_TMP_FILE: &str = "./test_crash_recovery.db.checkpoint.tmp";
+
+/// Passes through the failpoint before crashing at it.
+const SKIPS: &[usize] = &[0, 1, 4, 16];
+
+const WRITERS: usize = 4;
+const KEYS_PER_WRITER: usize = 16;
+const ROUNDS: usize = 6;
+
+/// How long writes get to carry on after the crash, which they can't as nothing reaches disk.
+const CRASH_GRACE: Duration = Duration::from_millis(50);
+
+/// How long to wait for the runtime's threads to stop after it's killed.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a key can be after recovery: what was last acknowledged, or the write that was in flight
+/// when the engine crashed. `None` is absent.
+#[derive(Debug, Default, Clone)]
+struct Expected {
+    acked: Option<Bytes>,
+    in_flight: Option<Option<Bytes>>,
+}
+
+type State = Arc<Mutex<HashMap<Bytes, Expected>>>;
+
+#[test]
+fn test_crash_recovery() -> io::Result<()> {
+    let _cu = [
+        CleanUp::file(DB_FILE),
+        CleanUp::file(CHECKPOINT_FILE),
+        CleanUp::file(CHECKPOINT_TMP_FILE),
+    ];
+
+    for name in FAILPOINTS {
+        let mut crashes = 0;
+        for &skip in SKIPS {
+            for crash in [Crash::Nothing, Crash::Torn] {
+                if crash == Crash::Torn && *name != "disk::write_page" {
+                    continue;
+                }
+
+                for file in [DB_FILE, CHECKPOINT_FILE, CHECKPOINT_TMP_FILE] {
+                    let _ = std::fs::remove_file(file);
+                }
+
+                failpoint::arm(name, skip, crash);
+                let state = State::default();
+                run_until_crash(state.clone())?;
+                if failpoint::crashed().is_some() {
+                    crashes += 1;
+                }
+                failpoint::reset();
+
+                let case = format!("{} skip {} {:?}", name, skip, crash);
+                recover(&state, &case)?;
+            }
+        }
+
+        assert!(crashes > 0, "failpoint {} was never reached", name);
+    }
+
+    Ok(())
+}
+
+/// Runs the workload, then a graceful shutdown, and kills the runtime once the engine crashes or
+/// it's done.
+fn run_until_crash(state: State) -> io::Result<()> {
+    let rt = Runtime::new()?;
+
+    let res = rt.block_on(async move {
+        let options = Options {
+            durability: Durability::Always,
+            compaction_interval: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let db = Arc::new(Db::open(DB_FILE, options).await?);
+
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|w| tokio::spawn(write(db.clone(), w, state.clone())))
+            .collect();
+        for writer in writers {
+            loop {
+                if writer.is_finished() {
+                    break;
+                }
+                if failpoint::crashed().is_some() {
+                    tokio::time::sleep(CRASH_GRACE).await;
+                    return Ok(());
+                }
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+
+        // Shutting down can crash too, which is no different from being killed
+        let _ = db.checkpoint().await;
+
+        Ok(())
+    });
+
+    // Nothing gets to finish, as if the process was killed. A worker can be partway through a
+    // task's disk I/O, so wait for it to stop before failpoints are reset and it could succeed
+    rt.shutdown_timeout(SHUTDOWN_TIMEOUT);
+
+    res
+}
+
+async fn write(db: Arc<Db>, w: usize, state: State) {
+    let user = User::default();
+
+    for round in 0..ROUNDS {
+        for i in 0..KEYS_PER_WRITER {
+            let k = Bytes::from(format!("key{}-{}", w, i));
+            // Every third write of a key deletes it, so tombstones are in the mix
+            let v = match (round + i) % 3 {
+                2 => None,
+                _ => Some(Bytes::from(format!("{:0>96}", round))),
+            };
+
+            state
+                .lock()
+                .unwrap()
+                .entry(k.clone())
+                .or_default()
+                .in_flight = Some(v.clone());
+
+            let message = match &v {
+                Some(v) => Message::Insert(k.clone(), v.clone()),
+                None => Message::Delete(k.clone()),
+            };
+            if message.exec(&db, &user).await != Message::Success {
+                return;
+            }
+
+            let mut state = state.lock().unwrap();
+            let expected = state.get_mut(&k).unwrap();
+            expected.acked = v;
+            expected.in_flight = None;
+        }
+    }
+}
+
+/// Reopens the database and checks it against what the workload was told.
+fn recover(state: &State, case: &str) -> io::Result<()> {
+    let rt = Runtime::new()?;
+    let expected = state.lock().unwrap().clone();
+
+    rt.block_on(async move {
+        let db = Db::open(DB_FILE, Options::default()).await?;
+
+        let kd = db.kd.read().await.clone();
+        for (k, data) in kd.iter() {
+            let entry = db.pc.fetch_entry(data.page_id, data.offset).await;
+            assert!(
+                matches!(&entry, Ok(Some(entry)) if entry.key == k),
+                "{}: {:?} points to {:?}\nGot: {:?}\n",
+                case,
+                k,
+                data,
+                entry
+            );
+        }
+
+        for (k, expected) in expected {
+            let got = match Message::Get(k.clone()).exec(&db, &User::default()).await {
+                Message::Result(_, v) => Some(v),
+                Message::NotFound => None,
+                other => panic!("{}: get {:?} failed: {:?}", case, k, other),
+            };
+
+            let ok = got == expected.acked || expected.in_flight.as_ref() == Some(&got);
+            assert!(
+                ok,
+                "{}: {:?}\nExpected: {:?}\nGot: {:?}\n",
+                case, k, expected, got
+            );
+        }
+
+        Ok(())
+    })
+}
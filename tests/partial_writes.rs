@@ -0,0 +1,148 @@
+//! Kills the engine partway through requests that write pages, and checks the keys they leave
+//! visible against what bootstrap recovers. The pages written before the crash are replayed on
+//! restart, so what was visible has to already include them. Run with
+//! `cargo test --features failpoints`.
+
+use std::{collections::HashMap, io, sync::Mutex, time::Duration};
+
+use bytes::Bytes;
+use hash_db::{
+    serverv2::{auth::User, message::Message},
+    storagev2::{
+        db::{Db, Options},
+        failpoint::{self, Crash},
+        test::CleanUp,
+    },
+};
+use tokio::runtime::Runtime;
+
+/// Enough for the writes of a flushdb to span several pages.
+const KEYS: usize = 500;
+
+/// Pairs in an mset, a page holds two of them but not three.
+const MSET_PAIRS: usize = 30;
+
+/// How long to wait for the runtime's threads to stop after it's killed.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Failpoints are global, so tests can't run at the same time.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// The keys that are set, and their values.
+type Live = HashMap<Bytes, Bytes>;
+
+#[test]
+fn test_partial_mset() -> io::Result<()> {
+    const DB_FILE: &str = "./test_partial_mset.db";
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let _cu = CleanUp::file(DB_FILE);
+
+    // The first two fill the current page, so the third has to write it out first
+    let mut msets = msets();
+    let request = msets.remove(2);
+    msets.truncate(2);
+    let (got, before, after) = crash_during(DB_FILE, msets, request, 0)?;
+    assert!(matches!(got, Message::Error(_, _)), "Got: {:?}", got);
+
+    // None of the failed mset is set, before or after a restart
+    assert!(before.len() == 2 * MSET_PAIRS, "Got: {}", before.len());
+    for k in &keys()[2 * MSET_PAIRS..3 * MSET_PAIRS] {
+        assert!(!before.contains_key(k), "{:?} set before restart", k);
+        assert!(!after.contains_key(k), "{:?} set by restart", k);
+    }
+    for (k, v) in &after {
+        assert!(before.get(k) == Some(v), "{:?} set by restart", k);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_partial_flush_db() -> io::Result<()> {
+    const DB_FILE: &str = "./test_partial_flush_db.db";
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let _cu = CleanUp::file(DB_FILE);
+
+    let (got, before, after) = crash_during(DB_FILE, msets(), Message::FlushDb, 1)?;
+    assert!(matches!(got, Message::Error(_, _)), "Got: {:?}", got);
+
+    // Nothing deleted by a restart that was still visible before it
+    assert!(
+        !before.is_empty() && before.len() < KEYS,
+        "Got: {}",
+        before.len()
+    );
+    for (k, v) in &before {
+        assert!(after.get(k) == Some(v), "{:?} deleted by restart", k);
+    }
+
+    Ok(())
+}
+
+fn keys() -> Vec<Bytes> {
+    (0..KEYS).map(|i| format!("key{:03}", i).into()).collect()
+}
+
+fn value() -> Bytes {
+    Bytes::from(format!("{:0>32}", 1))
+}
+
+/// Sets every key, `MSET_PAIRS` at a time.
+fn msets() -> Vec<Message> {
+    keys()
+        .chunks(MSET_PAIRS)
+        .map(|keys| Message::MSet(keys.iter().map(|k| (k.clone(), value())).collect()))
+        .collect()
+}
+
+/// Runs `setup`, then `request` with the engine crashing once `skip` pages have been written,
+/// then reopens the database. Returns the reply to `request` and the keys that were set before
+/// and after the restart.
+fn crash_during(
+    file: &str,
+    setup: Vec<Message>,
+    request: Message,
+    skip: usize,
+) -> io::Result<(Message, Live, Live)> {
+    let rt = Runtime::new()?;
+    let (got, before) = rt.block_on(async {
+        let db = Db::open(file, Options::default()).await?;
+        for message in setup {
+            let got = message.exec(&db, &User::default()).await;
+            assert!(got == Message::Success, "Got: {:?}", got);
+        }
+
+        failpoint::arm("disk::write_page", skip, Crash::Nothing);
+        let got = request.exec(&db, &User::default()).await;
+
+        io::Result::Ok((got, read_all(&db).await))
+    })?;
+    // Nothing gets to finish, as if the process was killed. A worker can be partway through a
+    // task's disk I/O, so wait for it to stop before failpoints are reset and it could succeed
+    rt.shutdown_timeout(SHUTDOWN_TIMEOUT);
+    failpoint::reset();
+
+    let rt = Runtime::new()?;
+    let after = rt.block_on(async {
+        let db = Db::open(file, Options::default()).await?;
+
+        io::Result::Ok(read_all(&db).await)
+    })?;
+
+    Ok((got, before, after))
+}
+
+async fn read_all(db: &Db) -> Live {
+    let mut set = Live::new();
+    for k in keys() {
+        match Message::Get(k.clone()).exec(db, &User::default()).await {
+            Message::Result(_, v) => {
+                set.insert(k, v);
+            }
+            Message::NotFound => {}
+            other => panic!("get {:?} failed: {:?}", k, other),
+        }
+    }
+
+    set
+}